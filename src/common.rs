@@ -36,8 +36,12 @@ use nix::unistd::sync;
 pub use options::Options;
 
 pub(crate) mod api_calls;
+pub(crate) mod backup_crypt;
+pub(crate) mod compression;
 pub(crate) mod debug;
+pub(crate) mod device_type;
 pub(crate) mod disk_util;
+pub(crate) mod efivars;
 pub(crate) mod stream_progress;
 
 const OS_NAME_REGEX: &str = r#"^PRETTY_NAME="([^"]+)"$"#;
@@ -215,6 +219,16 @@ pub(crate) fn get_os_name() -> Result<String> {
     }
 }
 
+/******************************************************************
+ * Get a value like ID or VERSION_ID from /etc/os-release
+ ******************************************************************/
+
+pub(crate) fn get_os_release_value(key: &str) -> Result<Option<String>> {
+    trace!("get_os_release_value: entered with key '{}'", key);
+    let regex = Regex::new(&format!(r#"^{}="?([^"]*)"?$"#, regex::escape(key))).unwrap();
+    Ok(parse_file(OS_RELEASE_FILE, &regex)?.map(|captures| captures[1].clone()))
+}
+
 pub(crate) fn is_admin() -> Result<bool> {
     trace!("is_admin: entered");
     let admin = unsafe { libc::getuid() } == 0;
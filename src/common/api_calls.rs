@@ -1,10 +1,15 @@
 use std::io::Read;
+use std::time::Duration;
 
-use log::debug;
+use log::{debug, warn};
 
-use reqwest::{blocking::Client, header};
+use openssl::base64::decode_block;
+use reqwest::{
+    blocking::{Client, Response},
+    header, StatusCode,
+};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{json, Value};
 
 use crate::common::{Error, ErrorKind, Result, ToError};
 
@@ -14,8 +19,33 @@ const OS_IMG_URL: &str = "/download";
 
 const DEVICE__TYPE_URL_ENDPOINT: &str = "/v6/device_type";
 
+// progress is reported while the network may be going away, do not hang on it
+const HUP_PROGRESS_TIMEOUT: Duration = Duration::from_secs(20);
+
 pub(crate) type Versions = Vec<String>;
 
+/// Checksum of the image file as announced by the download server
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ImageChecksum {
+    Sha256(Vec<u8>),
+    Md5(Vec<u8>),
+    /// MD5 sum taken from an S3 style ETag. Some servers, S3 with SSE-KMS or
+    /// SSE-C encryption among them, send ETags of this form that are not the
+    /// MD5 sum, so a mismatch is only warned about.
+    EtagMd5(Vec<u8>),
+}
+
+/// The response to an image download request, possibly for a range of the image
+pub(crate) struct ImageDownload {
+    pub stream: Box<dyn Read>,
+    /// offset of the first byte in stream, only non zero if a range request was honoured
+    pub offset: u64,
+    /// size of the complete image file, if known
+    pub size: Option<u64>,
+    pub checksum: Option<ImageChecksum>,
+    pub etag: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct ImageRequestData {
     #[serde(rename = "deviceType")]
@@ -48,6 +78,24 @@ struct DeviceIdEntry {
     id: u32,
 }
 
+/// Structs corresponding to API response for endpoint /v6/application with the
+/// target release expanded
+#[derive(Deserialize, Debug)]
+struct ApplicationReleaseApiResponse {
+    d: Vec<ApplicationReleaseEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ApplicationReleaseEntry {
+    #[serde(rename = "should_be_running__release")]
+    release: Vec<ReleaseEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ReleaseEntry {
+    composition: Option<Value>,
+}
+
 /// Structs corresponding to API response for DeviceType Contract
 #[derive(Debug, Deserialize)]
 struct ContractData {
@@ -146,13 +194,21 @@ fn get_header(api_key: &str) -> Result<header::HeaderMap> {
     Ok(headers)
 }
 
+/// Request the OS image, starting at offset.
+/// If etag is given the range is only requested if the image is unchanged, otherwise
+/// the server responds with the complete image.
+/// Returns an error of kind InvParam if the server can not satisfy the requested range.
 pub(crate) fn get_os_image(
     api_endpoint: &str,
     api_key: &str,
     device: &str,
     version: &str,
-) -> Result<Box<dyn Read>> {
-    let headers = get_header(api_key)?;
+    offset: u64,
+    etag: Option<&str>,
+) -> Result<ImageDownload> {
+    let mut headers = get_header(api_key)?;
+    add_range_headers(&mut headers, offset, etag)?;
+
     let request_url = format!("{}{}", api_endpoint, OS_IMG_URL);
 
     let post_data = if is_device_image_flasher(api_endpoint, api_key, device)? {
@@ -173,7 +229,7 @@ pub(crate) fn get_os_image(
     };
 
     debug!("get_os_image: request_url: '{}'", request_url);
-    debug!("get_os_image: data: '{:?}'", post_data);
+    debug!("get_os_image: data: '{:?}', offset: {}", post_data, offset);
 
     let res = Client::builder()
         .default_headers(headers)
@@ -189,7 +245,146 @@ pub(crate) fn get_os_image(
 
     debug!("Result = {:?}", res);
 
-    Ok(Box::new(res))
+    to_image_download(res, offset)
+}
+
+/// Add headers requesting the image from offset, see get_os_image
+pub(crate) fn add_range_headers(
+    headers: &mut header::HeaderMap,
+    offset: u64,
+    etag: Option<&str>,
+) -> Result<()> {
+    if offset > 0 {
+        headers.insert(
+            header::RANGE,
+            header::HeaderValue::from_str(&format!("bytes={}-", offset))
+                .upstream_with_context("Failed to create range header")?,
+        );
+        // weak ETags must not be used in If-Range, see RFC 9110 13.1.5
+        if let Some(etag) = etag.filter(|etag| !etag.starts_with("W/")) {
+            headers.insert(
+                header::IF_RANGE,
+                header::HeaderValue::from_str(etag)
+                    .upstream_with_context("Failed to create if-range header")?,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Evaluate the response to a (range) request for an image
+pub(crate) fn to_image_download(res: Response, offset: u64) -> Result<ImageDownload> {
+    let status = res.status();
+    let res_headers = res.headers();
+    let (offset, size) = match status {
+        StatusCode::OK => (0, res.content_length()),
+        StatusCode::PARTIAL_CONTENT => {
+            let content_range = res_headers
+                .get(header::CONTENT_RANGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_content_range);
+            if let Some((start, size)) = content_range {
+                (start, size)
+            } else {
+                return Err(Error::with_context(
+                    ErrorKind::InvState,
+                    "Invalid or missing content-range header in partial response",
+                ));
+            }
+        }
+        StatusCode::RANGE_NOT_SATISFIABLE => {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!("Image download range starting at {} is invalid", offset),
+            ));
+        }
+        _ => {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!("Image download failed with status: {}", status),
+            ));
+        }
+    };
+
+    let checksum = parse_checksum(res_headers, status == StatusCode::OK);
+    let etag = res_headers
+        .get(header::ETAG)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
+    debug!(
+        "to_image_download: offset: {}, size: {:?}, checksum: {:?}, etag: {:?}",
+        offset, size, checksum, etag
+    );
+
+    Ok(ImageDownload {
+        stream: Box::new(res),
+        offset,
+        size,
+        checksum,
+        etag,
+    })
+}
+
+/// Parse a content-range header value like 'bytes 100-199/1000' into start and
+/// complete size, the size is None if given as '*'
+fn parse_content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (range, size) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _end) = range.split_once('-')?;
+    let size = if size == "*" {
+        None
+    } else {
+        Some(size.parse::<u64>().ok()?)
+    };
+    Some((start.parse::<u64>().ok()?, size))
+}
+
+/// Extract the image checksum from the response headers.
+/// Digest and ETag describe the complete image, content-md5 only the
+/// transferred content, so it is only used for complete responses.
+fn parse_checksum(headers: &header::HeaderMap, complete: bool) -> Option<ImageChecksum> {
+    let header_str = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(digest) = header_str("digest") {
+        let mut md5 = None;
+        for entry in digest.split(',') {
+            if let Some((alg, value)) = entry.trim().split_once('=') {
+                match (alg.to_lowercase().as_str(), decode_block(value)) {
+                    ("sha-256", Ok(sum)) => return Some(ImageChecksum::Sha256(sum)),
+                    ("md5", Ok(sum)) => md5 = Some(ImageChecksum::Md5(sum)),
+                    (_, Err(why)) => warn!("Failed to decode digest '{}', error: {}", entry, why),
+                    _ => (),
+                }
+            }
+        }
+        if md5.is_some() {
+            return md5;
+        }
+    }
+
+    if complete {
+        if let Some(sum) = header_str("content-md5").and_then(|value| decode_block(value).ok()) {
+            return Some(ImageChecksum::Md5(sum));
+        }
+    }
+
+    // S3 style ETags are the hex encoded MD5 sum of the object unless it was
+    // uploaded in multiple parts or encrypted, weak ETags are never a checksum
+    let etag = header_str("etag")?;
+    if etag.starts_with("W/") {
+        return None;
+    }
+    let etag = etag.trim_matches('"');
+    if etag.len() == 32 && etag.chars().all(|c| c.is_ascii_hexdigit()) {
+        let sum = (0..etag.len())
+            .step_by(2)
+            .map(|idx| u8::from_str_radix(&etag[idx..idx + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .ok()?;
+        Some(ImageChecksum::EtagMd5(sum))
+    } else {
+        None
+    }
 }
 
 pub(crate) fn patch_device_type(
@@ -283,6 +478,71 @@ pub(crate) fn patch_device_type(
     }
 }
 
+/// Retrieve the named volumes of the release the application should be running.
+/// Returns None if the application has no target release.
+pub(crate) fn get_release_volumes(
+    api_endpoint: &str,
+    api_key: &str,
+    app_id: u64,
+) -> Result<Option<Vec<String>>> {
+    let headers = get_header(api_key)?;
+    let request_url = format!(
+        "{}/v6/application({})?$select=id&$expand=should_be_running__release($select=composition)",
+        api_endpoint, app_id
+    );
+
+    let res = Client::builder()
+        .default_headers(headers)
+        .build()
+        .upstream_with_context("Failed to create https client")?
+        .get(&request_url)
+        .send()
+        .upstream_with_context(&format!(
+            "Failed to send https request url: '{}'",
+            request_url
+        ))?;
+
+    debug!("release request Result = {:?}", res);
+
+    let status = res.status();
+    if status.is_success() {
+        let parsed_resp = res
+            .json::<ApplicationReleaseApiResponse>()
+            .upstream_with_context("Failed to parse request results")?;
+
+        let application = parsed_resp.d.first().ok_or_else(|| {
+            Error::with_context(
+                ErrorKind::NotFound,
+                &format!("Application {} was not found", app_id),
+            )
+        })?;
+
+        Ok(application
+            .release
+            .first()
+            .map(|release| composition_volumes(release.composition.as_ref())))
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "Balena API GET release request failed with status: {}",
+                status
+            ),
+        ))
+    }
+}
+
+/// Extract the names of the top level volumes from a release composition
+fn composition_volumes(composition: Option<&Value>) -> Vec<String> {
+    let mut volumes: Vec<String> = composition
+        .and_then(|composition| composition.get("volumes"))
+        .and_then(|volumes| volumes.as_object())
+        .map(|volumes| volumes.keys().cloned().collect())
+        .unwrap_or_default();
+    volumes.sort();
+    volumes
+}
+
 // PATCH device state with HUP details
 pub(crate) fn notify_hup_progress(
     api_endpoint: &str,
@@ -301,6 +561,7 @@ pub(crate) fn notify_hup_progress(
 
     let res = Client::builder()
         .default_headers(headers.clone())
+        .timeout(HUP_PROGRESS_TIMEOUT)
         .build()
         .upstream_with_context("Failed to create https client")?
         .patch(&api_url)
@@ -383,3 +644,94 @@ fn is_device_image_flasher(api_endpoint: &str, api_key: &str, device: &str) -> R
 fn get_device_type_info_url(api_endpoint: &str, select: &str, device: &str) -> String {
     format!("{api_endpoint}{DEVICE__TYPE_URL_ENDPOINT}?$orderby=name%20asc&$top=1&$select={select}&$filter=device_type_alias/any(dta:dta/is_referenced_by__alias%20eq%20%27{device}%27)")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_content_range() {
+        assert_eq!(
+            parse_content_range("bytes 100-199/1000"),
+            Some((100, Some(1000)))
+        );
+        assert_eq!(parse_content_range("bytes 100-199/*"), Some((100, None)));
+        assert_eq!(parse_content_range("items 100-199/1000"), None);
+        assert_eq!(parse_content_range("bytes */1000"), None);
+    }
+
+    #[test]
+    fn parses_composition_volumes() {
+        let composition = json!({
+            "version": "2.1",
+            "services": { "main": { "volumes": ["resin-data:/data"] } },
+            "volumes": { "resin-data": {}, "cache": null }
+        });
+        assert_eq!(
+            composition_volumes(Some(&composition)),
+            vec!["cache".to_string(), "resin-data".to_string()]
+        );
+        assert!(composition_volumes(Some(&json!({ "services": {} }))).is_empty());
+        assert!(composition_volumes(None).is_empty());
+    }
+
+    #[test]
+    fn parses_checksum_headers() {
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            header::ETAG,
+            header::HeaderValue::from_static("\"0123456789abcdef0123456789abcdef\""),
+        );
+        let etag_md5 = ImageChecksum::EtagMd5(vec![
+            0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab,
+            0xcd, 0xef,
+        ]);
+        assert_eq!(parse_checksum(&headers, false), Some(etag_md5));
+
+        // content-md5 takes precedence over the ETag for complete responses only
+        headers.insert(
+            "content-md5",
+            header::HeaderValue::from_static("AAECAwQFBgcICQoLDA0ODw=="),
+        );
+        let content_md5 = ImageChecksum::Md5((0..16).collect());
+        assert_eq!(parse_checksum(&headers, true), Some(content_md5));
+
+        headers.insert(
+            "digest",
+            header::HeaderValue::from_static(
+                "md5=AAECAwQFBgcICQoLDA0ODw==, SHA-256=AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=",
+            ),
+        );
+        assert_eq!(
+            parse_checksum(&headers, false),
+            Some(ImageChecksum::Sha256((0..32).collect()))
+        );
+
+        // multipart ETags are not a checksum of the file
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            header::ETAG,
+            header::HeaderValue::from_static("\"0123456789abcdef0123456789abcdef-4\""),
+        );
+        assert_eq!(parse_checksum(&headers, true), None);
+
+        // weak ETags are not a checksum of the file
+        headers.insert(
+            header::ETAG,
+            header::HeaderValue::from_static("W/\"0123456789abcdef0123456789abcdef\""),
+        );
+        assert_eq!(parse_checksum(&headers, true), None);
+    }
+
+    #[test]
+    fn skips_weak_etag_in_range_headers() {
+        let mut headers = header::HeaderMap::new();
+        add_range_headers(&mut headers, 100, Some("W/\"abc\"")).unwrap();
+        assert_eq!(headers.get(header::RANGE).unwrap(), "bytes=100-");
+        assert!(headers.get(header::IF_RANGE).is_none());
+
+        let mut headers = header::HeaderMap::new();
+        add_range_headers(&mut headers, 100, Some("\"abc\"")).unwrap();
+        assert_eq!(headers.get(header::IF_RANGE).unwrap(), "\"abc\"");
+    }
+}
@@ -0,0 +1,404 @@
+use std::cmp::min;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use log::info;
+use openssl::{
+    base64::{decode_block, encode_block},
+    encrypt::{Decrypter, Encrypter},
+    pkey::{PKey, Private, Public},
+    rand::rand_bytes,
+    rsa::Padding,
+    symm::{Cipher, Crypter, Mode},
+};
+
+use crate::common::{Error, ErrorKind, Result, ToError};
+
+// Layout of an encrypted backup:
+// magic, length of the wrapped key as u16 big endian, the AES key wrapped
+// with RSA-OAEP, the AES-GCM IV, the encrypted archive and the GCM tag.
+const BACKUP_CRYPT_MAGIC: &[u8; 8] = b"TKOVENC1";
+const KEY_SIZE: usize = 32;
+const IV_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+// size of the ciphertext chunks read while decrypting
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Encrypts everything written to it with a random AES-256-GCM key that is
+/// stored in the header, wrapped with the public key.
+/// finish must be called to write the authentication tag.
+pub(crate) struct EncryptWriter<W: Write> {
+    inner: W,
+    crypter: Crypter,
+    buffer: Vec<u8>,
+    finished: bool,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(mut inner: W, public_key: &PKey<Public>) -> Result<EncryptWriter<W>> {
+        let mut key = [0u8; KEY_SIZE];
+        let mut iv = [0u8; IV_SIZE];
+        rand_bytes(&mut key).upstream_with_context("Failed to create backup encryption key")?;
+        rand_bytes(&mut iv).upstream_with_context("Failed to create backup encryption IV")?;
+
+        let mut encrypter = Encrypter::new(public_key)
+            .upstream_with_context("Failed to set up backup key encryption")?;
+        encrypter
+            .set_rsa_padding(Padding::PKCS1_OAEP)
+            .upstream_with_context("Failed to set up backup key encryption")?;
+        let mut wrapped_key = vec![
+            0u8;
+            encrypter
+                .encrypt_len(&key)
+                .upstream_with_context("Failed to encrypt backup key")?
+        ];
+        let wrapped_len = encrypter
+            .encrypt(&key, &mut wrapped_key)
+            .upstream_with_context("Failed to encrypt backup key")?;
+        wrapped_key.truncate(wrapped_len);
+
+        let crypter = Crypter::new(Cipher::aes_256_gcm(), Mode::Encrypt, &key, Some(&iv))
+            .upstream_with_context("Failed to set up backup encryption")?;
+
+        inner
+            .write_all(BACKUP_CRYPT_MAGIC)
+            .and_then(|_| inner.write_all(&(wrapped_key.len() as u16).to_be_bytes()))
+            .and_then(|_| inner.write_all(&wrapped_key))
+            .and_then(|_| inner.write_all(&iv))
+            .upstream_with_context("Failed to write encrypted backup header")?;
+
+        Ok(EncryptWriter {
+            inner,
+            crypter,
+            buffer: Vec::new(),
+            finished: false,
+        })
+    }
+
+    /// Write the authentication tag, nothing can be written afterwards
+    pub fn finish(&mut self) -> io::Result<()> {
+        if !self.finished {
+            self.buffer.resize(Cipher::aes_256_gcm().block_size(), 0);
+            let count = self
+                .crypter
+                .finalize(&mut self.buffer)
+                .map_err(io::Error::other)?;
+            self.inner.write_all(&self.buffer[..count])?;
+
+            let mut tag = [0u8; TAG_SIZE];
+            self.crypter.get_tag(&mut tag).map_err(io::Error::other)?;
+            self.inner.write_all(&tag)?;
+            self.finished = true;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(io::Error::other("Encrypted backup is already finished"));
+        }
+        self.buffer
+            .resize(buf.len() + Cipher::aes_256_gcm().block_size(), 0);
+        let count = self
+            .crypter
+            .update(buf, &mut self.buffer)
+            .map_err(io::Error::other)?;
+        self.inner.write_all(&self.buffer[..count])?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts a backup written by EncryptWriter. The authentication tag is
+/// checked at the end of the stream, reading fails if it does not match, so
+/// open_backup authenticates the whole backup before returning a reader.
+pub(crate) struct DecryptReader<R: Read> {
+    inner: R,
+    crypter: Crypter,
+    // ciphertext not yet decrypted, the last TAG_SIZE bytes might be the tag
+    pending: Vec<u8>,
+    out: Vec<u8>,
+    out_pos: usize,
+    done: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// Read the header following the magic and unwrap the AES key
+    fn new(mut inner: R, private_key: &PKey<Private>) -> Result<DecryptReader<R>> {
+        let mut len_buf = [0u8; 2];
+        inner
+            .read_exact(&mut len_buf)
+            .upstream_with_context("Failed to read encrypted backup header")?;
+        let mut wrapped_key = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+        let mut iv = [0u8; IV_SIZE];
+        inner
+            .read_exact(&mut wrapped_key)
+            .and_then(|_| inner.read_exact(&mut iv))
+            .upstream_with_context("Failed to read encrypted backup header")?;
+
+        let mut decrypter = Decrypter::new(private_key)
+            .upstream_with_context("Failed to set up backup key decryption")?;
+        decrypter
+            .set_rsa_padding(Padding::PKCS1_OAEP)
+            .upstream_with_context("Failed to set up backup key decryption")?;
+        let mut key = vec![
+            0u8;
+            decrypter
+                .decrypt_len(&wrapped_key)
+                .upstream_with_context("Failed to decrypt backup key")?
+        ];
+        let key_len = decrypter
+            .decrypt(&wrapped_key, &mut key)
+            .upstream_with_context(
+                "Failed to decrypt backup key, the backup was encrypted for a different key",
+            )?;
+        if key_len != KEY_SIZE {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!("Invalid backup key size {}", key_len),
+            ));
+        }
+
+        let crypter = Crypter::new(
+            Cipher::aes_256_gcm(),
+            Mode::Decrypt,
+            &key[..KEY_SIZE],
+            Some(&iv),
+        )
+        .upstream_with_context("Failed to set up backup decryption")?;
+
+        Ok(DecryptReader {
+            inner,
+            crypter,
+            pending: Vec::new(),
+            out: Vec::new(),
+            out_pos: 0,
+            done: false,
+        })
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        let read = self.inner.read(&mut chunk)?;
+        self.out_pos = 0;
+        let block_size = Cipher::aes_256_gcm().block_size();
+
+        if read == 0 {
+            if self.pending.len() != TAG_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Encrypted backup is truncated",
+                ));
+            }
+            self.crypter
+                .set_tag(&self.pending)
+                .map_err(io::Error::other)?;
+            self.out.resize(block_size, 0);
+            let count = self.crypter.finalize(&mut self.out).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Failed to authenticate encrypted backup, the archive was modified",
+                )
+            })?;
+            self.out.truncate(count);
+            self.done = true;
+        } else {
+            self.pending.extend_from_slice(&chunk[..read]);
+            let avail = self.pending.len().saturating_sub(TAG_SIZE);
+            self.out.resize(avail + block_size, 0);
+            let count = self
+                .crypter
+                .update(&self.pending[..avail], &mut self.out)
+                .map_err(io::Error::other)?;
+            self.out.truncate(count);
+            self.pending.drain(..avail);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.out_pos == self.out.len() && !self.done {
+            self.fill()?;
+        }
+        let count = min(buf.len(), self.out.len() - self.out_pos);
+        buf[..count].copy_from_slice(&self.out[self.out_pos..self.out_pos + count]);
+        self.out_pos += count;
+        Ok(count)
+    }
+}
+
+/// Open a backup archive for reading, decrypting it if it was encrypted.
+/// Encrypted archives are authenticated completely first, nothing from a
+/// modified archive is returned.
+pub(crate) fn open_backup(
+    path: &Path,
+    private_key: Option<&PKey<Private>>,
+) -> Result<Box<dyn Read>> {
+    let mut file = File::open(path).upstream_with_context(&format!(
+        "Failed to open backup archive '{}'",
+        path.display()
+    ))?;
+
+    let mut magic = Vec::new();
+    Read::by_ref(&mut file)
+        .take(BACKUP_CRYPT_MAGIC.len() as u64)
+        .read_to_end(&mut magic)
+        .upstream_with_context(&format!(
+            "Failed to read backup archive '{}'",
+            path.display()
+        ))?;
+
+    if magic == BACKUP_CRYPT_MAGIC {
+        if let Some(private_key) = private_key {
+            info!("Authenticating backup archive '{}'", path.display());
+            io::copy(
+                &mut DecryptReader::new(&mut file, private_key)?,
+                &mut io::sink(),
+            )
+            .upstream_with_context(&format!(
+                "Failed to authenticate backup archive '{}'",
+                path.display()
+            ))?;
+
+            file.seek(SeekFrom::Start(BACKUP_CRYPT_MAGIC.len() as u64))
+                .upstream_with_context(&format!(
+                    "Failed to seek in backup archive '{}'",
+                    path.display()
+                ))?;
+            info!("Decrypting backup archive '{}'", path.display());
+            Ok(Box::new(DecryptReader::new(file, private_key)?))
+        } else {
+            Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "The backup archive '{}' is encrypted but no backup key was provided",
+                    path.display()
+                ),
+            ))
+        }
+    } else {
+        file.seek(SeekFrom::Start(0))
+            .upstream_with_context(&format!(
+                "Failed to seek in backup archive '{}'",
+                path.display()
+            ))?;
+        Ok(Box::new(file))
+    }
+}
+
+/// Parse a PEM encoded private key as found in config.json or the environment
+pub(crate) fn private_key_from_pem(pem: &str) -> Result<PKey<Private>> {
+    PKey::private_key_from_pem(pem.as_bytes())
+        .upstream_with_context("Failed to parse the backup key, expected a PEM encoded private key")
+}
+
+/// Encode a private key as single line for the stage2 config
+pub(crate) fn encode_private_key(private_key: &PKey<Private>) -> Result<String> {
+    Ok(encode_block(
+        &private_key
+            .private_key_to_der()
+            .upstream_with_context("Failed to encode the backup key")?,
+    ))
+}
+
+pub(crate) fn decode_private_key(encoded: &str) -> Result<PKey<Private>> {
+    PKey::private_key_from_der(
+        &decode_block(encoded).upstream_with_context("Failed to decode the backup key")?,
+    )
+    .upstream_with_context("Failed to decode the backup key")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::rsa::Rsa;
+    use std::env::temp_dir;
+    use std::fs::{read, remove_file, write};
+
+    fn key_pair() -> (PKey<Private>, PKey<Public>) {
+        let private_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let public_key =
+            PKey::public_key_from_pem(&private_key.public_key_to_pem().unwrap()).unwrap();
+        (private_key, public_key)
+    }
+
+    fn encrypt(path: &Path, data: &[u8], public_key: &PKey<Public>) {
+        let mut writer = EncryptWriter::new(File::create(path).unwrap(), public_key).unwrap();
+        // several writes to cross chunk boundaries when decrypting
+        for chunk in data.chunks(10000) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn encrypts_and_decrypts() {
+        let (private_key, public_key) = key_pair();
+        let data: Vec<u8> = (0..200000u32).map(|val| (val % 251) as u8).collect();
+        let path = temp_dir().join("takeover-backup-crypt.enc");
+        encrypt(&path, &data, &public_key);
+        assert_ne!(&read(&path).unwrap()[BACKUP_CRYPT_MAGIC.len()..], &data[..]);
+
+        let encoded = encode_private_key(&private_key).unwrap();
+        let decoded = decode_private_key(&encoded).unwrap();
+        let mut decrypted = Vec::new();
+        open_backup(&path, Some(&decoded))
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, data);
+
+        assert!(open_backup(&path, None).is_err());
+        let (other_key, _) = key_pair();
+        assert!(open_backup(&path, Some(&other_key)).is_err());
+
+        // unencrypted archives are passed through
+        write(&path, b"plain archive").unwrap();
+        let mut plain = Vec::new();
+        open_backup(&path, Some(&private_key))
+            .unwrap()
+            .read_to_end(&mut plain)
+            .unwrap();
+        assert_eq!(plain, b"plain archive");
+
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_modified_backup() {
+        let (private_key, public_key) = key_pair();
+        let path = temp_dir().join("takeover-backup-crypt-modified.enc");
+        encrypt(&path, b"some backup data", &public_key);
+
+        let mut content = read(&path).unwrap();
+        let last = content.len() - TAG_SIZE - 1;
+        content[last] ^= 1;
+        write(&path, &content).unwrap();
+
+        // nothing is returned before the archive is authenticated
+        assert!(open_backup(&path, Some(&private_key)).is_err());
+
+        content.truncate(content.len() - 4);
+        write(&path, &content).unwrap();
+        assert!(open_backup(&path, Some(&private_key)).is_err());
+
+        // a stream failing at the tag still returns the plaintext before it
+        let mut file = File::open(&path).unwrap();
+        file.seek(SeekFrom::Start(BACKUP_CRYPT_MAGIC.len() as u64))
+            .unwrap();
+        assert!(DecryptReader::new(file, &private_key)
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .is_err());
+
+        remove_file(&path).unwrap();
+    }
+}
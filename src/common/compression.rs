@@ -0,0 +1,146 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use log::debug;
+use xz2::read::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+use crate::common::error::{Error, ErrorKind, Result, ToError};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+// number of bytes needed to tell the supported formats apart
+const MAGIC_LEN: usize = 6;
+
+/// Compression formats supported for balenaOS images
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Compression {
+    Gzip,
+    Xz,
+    Zstd,
+    None,
+}
+
+impl Compression {
+    pub fn from_magic(magic: &[u8]) -> Compression {
+        if magic.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if magic.starts_with(XZ_MAGIC) {
+            Compression::Xz
+        } else if magic.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Compression> {
+        let path = path.as_ref();
+        let mut file = File::open(path).upstream_with_context(&format!(
+            "Failed to open file '{}' for reading",
+            path.display()
+        ))?;
+        let magic = read_magic(&mut file)?;
+        Ok(Compression::from_magic(&magic))
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Xz => write!(f, "xz"),
+            Compression::Zstd => write!(f, "zstd"),
+            Compression::None => write!(f, "uncompressed"),
+        }
+    }
+}
+
+fn read_magic<R: Read>(input: &mut R) -> Result<Vec<u8>> {
+    let mut magic = vec![0u8; MAGIC_LEN];
+    let mut filled = 0;
+    while filled < MAGIC_LEN {
+        let bytes_read = input
+            .read(&mut magic[filled..])
+            .upstream_with_context("Failed to read from input stream")?;
+        if bytes_read == 0 {
+            break;
+        }
+        filled += bytes_read;
+    }
+    magic.truncate(filled);
+    Ok(magic)
+}
+
+/// Sniff the compression format of the stream and return a reader that
+/// provides the decompressed data
+pub(crate) fn decompress<R: Read + 'static>(mut input: R) -> Result<Box<dyn Read>> {
+    let magic = read_magic(&mut input)?;
+    let compression = Compression::from_magic(&magic);
+    debug!("decompress: detected {} stream", compression);
+
+    // put the sniffed bytes back in front of the stream
+    let input = Cursor::new(magic).chain(input);
+    Ok(match compression {
+        Compression::Gzip => Box::new(GzDecoder::new(input)),
+        Compression::Xz => Box::new(XzDecoder::new(input)),
+        Compression::Zstd => Box::new(ZstdDecoder::new(input).map_err(|why| {
+            Error::with_context(
+                ErrorKind::Upstream,
+                &format!("Failed to create zstd decoder, error: {}", why),
+            )
+        })?),
+        Compression::None => Box::new(input),
+    })
+}
+
+/// Open an image file for reading its decompressed contents
+pub(crate) fn open_image<P: AsRef<Path>>(path: P) -> Result<Box<dyn Read>> {
+    let path = path.as_ref();
+    let file = File::open(path)
+        .upstream_with_context(&format!("Failed to open image file '{}'", path.display()))?;
+    decompress(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression as GzLevel};
+    use std::io::Write;
+    use xz2::write::XzEncoder;
+
+    const TEST_DATA: &[u8] = b"balenaOS image data balenaOS image data balenaOS image data";
+
+    fn round_trip(compressed: Vec<u8>, expected: Compression) {
+        assert_eq!(Compression::from_magic(&compressed), expected);
+        let mut decoded = Vec::new();
+        decompress(Cursor::new(compressed))
+            .unwrap()
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, TEST_DATA);
+    }
+
+    #[test]
+    fn decompress_all_formats() {
+        let mut encoder = GzEncoder::new(Vec::new(), GzLevel::default());
+        encoder.write_all(TEST_DATA).unwrap();
+        round_trip(encoder.finish().unwrap(), Compression::Gzip);
+
+        let mut encoder = XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(TEST_DATA).unwrap();
+        round_trip(encoder.finish().unwrap(), Compression::Xz);
+
+        round_trip(
+            zstd::stream::encode_all(TEST_DATA, 0).unwrap(),
+            Compression::Zstd,
+        );
+
+        round_trip(TEST_DATA.to_vec(), Compression::None);
+    }
+}
@@ -1,16 +1,12 @@
 pub(crate) const SWAPOFF_CMD: &str = "swapoff";
 pub(crate) const TELINIT_CMD: &str = "telinit";
 
-pub(crate) const MOKUTIL_CMD: &str = "mokutil";
 pub(crate) const WHEREIS_CMD: &str = "whereis";
 pub(crate) const PIDOF_CMD: &str = "pidof";
 pub(crate) const PIVOT_ROOT_CMD: &str = "pivot_root";
 pub(crate) const MOUNT_CMD: &str = "mount";
 pub(crate) const BLKID_CMD: &str = "blkid";
 
-pub(crate) const EFIBOOTMGR_CMD: &str = "efibootmgr";
-pub(crate) const DD_CMD: &str = "dd";
-
 // The mdtd_debug tool is used on Xavier NX devices to clear and write the QSPI
 // with the boot blob included in the target OS image.
 // This tools is provided by the mtd-utils package at
@@ -19,12 +15,26 @@ pub(crate) const MTD_DEBUG_CMD: &str = "mtd_debug";
 
 pub(crate) const TAR_CMD: &str = "tar";
 
+// used to change the boot order of Raspberry Pi boards when migrating to
+// another disk, provided by the rpi-eeprom and raspberrypi userland packages
+pub(crate) const RPI_EEPROM_CONFIG_CMD: &str = "rpi-eeprom-config";
+pub(crate) const RPI_EEPROM_UPDATE_CMD: &str = "rpi-eeprom-update";
+pub(crate) const VCGENCMD_CMD: &str = "vcgencmd";
+
+// used to stop services and containers before creating the backup
+pub(crate) const SYSTEMCTL_CMD: &str = "systemctl";
+pub(crate) const SERVICE_CMD: &str = "service";
+pub(crate) const DOCKER_CMD: &str = "docker";
+pub(crate) const BALENA_ENGINE_CMD: &str = "balena-engine";
+pub(crate) const SHELL_CMD: &str = "sh";
+
 // below path is used as the root mountpoint during migration
 pub(crate) const TAKEOVER_DIR: &str = "/tmp/balena-takeover";
 pub(crate) const STAGE2_CONFIG_NAME: &str = "stage2-config.yml";
 
-pub(crate) const BALENA_IMAGE_NAME: &str = "balena.img.gz";
-pub(crate) const BALENA_IMAGE_PATH: &str = "/balena.img.gz";
+// the image may be gzip, xz or zstd compressed or uncompressed
+pub(crate) const BALENA_IMAGE_NAME: &str = "balena.img";
+pub(crate) const BALENA_IMAGE_PATH: &str = "/balena.img";
 
 pub(crate) const BALENA_CONFIG_PATH: &str = "/config.json";
 
@@ -70,11 +80,6 @@ pub const BALENA_SYSTEM_CONNECTIONS_BOOT_PATH: &str = "/mnt/boot/system-connecti
 pub const BALENA_SYSTEM_PROXY_BOOT_PATH: &str = "/mnt/boot/system-proxy/";
 
 pub const BALENA_NETWORK_MANAGER_BIND_MOUNT: &str = "/etc/NetworkManager/";
-// Enables writing to the hardware-defined boot partition on AGX Xavier.
-// For details on boot partitions access in Linux,
-// see https://www.kernel.org/doc/Documentation/mmc/mmc-dev-parts.txt
-pub const JETSON_XAVIER_HW_PART_FORCE_RO_FILE: &str = "/sys/block/mmcblk0boot0/force_ro";
-
 // Hardware-defined boot partition for Jetson AGX Xavier
 pub const BOOT_BLOB_PARTITION_JETSON_XAVIER: &str = "/dev/mmcblk0boot0";
 
@@ -87,11 +92,29 @@ pub const BOOT_BLOB_PARTITION_JETSON_XAVIER_NX: &str = "/dev/mtd0";
 // the Nvidia flashing tools.
 pub const BOOT_BLOB_NAME_JETSON_XAVIER: &str = "boot0_mmcblk0boot0.img";
 pub const BOOT_BLOB_NAME_JETSON_XAVIER_NX: &str = "boot0_mtdblock0.img";
+// Boot firmware manifest in the root partition of the balenaOS image we migrate
+// to, listing the boot firmware images and the regions they are written to.
+pub const BOOT_FIRMWARE_MANIFEST_NAME: &str = "takeover-boot-firmware.json";
 
 pub const SYS_EFI_DIR: &str = "/sys/firmware/efi";
 pub const SYS_EFIVARS_DIR: &str = "/sys/firmware/efi/efivars";
+// EFI loader in the boot partition of balenaOS images for the architecture
+// takeover runs on
+#[cfg(target_arch = "aarch64")]
+pub const EFI_LOADER_FILE: &str = "EFI/BOOT/bootaa64.efi";
+#[cfg(not(target_arch = "aarch64"))]
+pub const EFI_LOADER_FILE: &str = "EFI/BOOT/bootx64.efi";
 
 pub const BACKUP_ARCH_NAME: &str = "backup.tgz";
+// space kept free in the stage2 RAMFS on top of the transferred files
+pub const S2_XTRA_FS_SIZE: u64 = 10 * 1024 * 1024;
+// balena-engine volumes directory relative to the data partition
+pub const BALENA_VOLUMES_DIR: &str = "docker/volumes";
+
+// written by stage2 next to the fallback logs on the data partition
+pub const MIGRATION_REPORT_NAME: &str = "migration-report.json";
+// written to the work directory when the original OS was restored after a failure
+pub const RECOVERY_MARKER_NAME: &str = "takeover-recovery.json";
 
 pub const NIX_NONE: Option<&'static [u8]> = None;
 
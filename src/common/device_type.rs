@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+
+/// The board identified in stage1, passed to stage2 in the stage2 config
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) enum DeviceType {
+    BeagleboneGreen,
+    BeagleboneBlack,
+    BeagleboardXM,
+    IntelNuc,
+    RaspberryPi1,
+    RaspberryPi2,
+    RaspberryPi3,
+    RaspberryPi4,
+    RaspberryPi400,
+    RaspberryPiCM4,
+    RaspberryPiZero2W,
+    RaspberryPi5,
+    Dummy,
+    JetsonXavier,
+    JetsonXavierNX,
+    GenericArm,
+}
+
+impl Display for DeviceType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::IntelNuc => "X68_64/Intel Nuc",
+                Self::BeagleboneGreen => "Beaglebone Green",
+                Self::BeagleboneBlack => "Beaglebone Black",
+                Self::BeagleboardXM => "Beagleboard XM",
+                Self::RaspberryPi1 => "Raspberry Pi 1/Zero",
+                Self::RaspberryPi2 => "Raspberry Pi 2",
+                Self::RaspberryPi3 => "Raspberry Pi 3",
+                Self::RaspberryPi4 => "Raspberry Pi 4",
+                Self::RaspberryPi400 => "Raspberry Pi 400",
+                Self::RaspberryPiCM4 => "Raspberry Pi Compute Module 4",
+                Self::RaspberryPiZero2W => "Raspberry Pi Zero 2 W",
+                Self::RaspberryPi5 => "Raspberry Pi 5",
+                Self::Dummy => "Dummy",
+                Self::JetsonXavier => "Jetson Xavier AGX",
+                Self::JetsonXavierNX => "Jetson Xavier NX",
+                Self::GenericArm => "Generic ARM",
+            }
+        )
+    }
+}
@@ -1,26 +1,29 @@
 use log::{debug, error, trace};
-use std::fs::File;
-use std::io::{self, Read};
+use std::io::{self, Read, Seek, SeekFrom};
 use std::mem;
 use std::path::{Path, PathBuf};
 use std::result;
 
 use gptman::GPT;
 
-use crate::common::{Error, ErrorKind, Result};
+use crate::common::{
+    compression::Compression,
+    defs::{BALENA_BOOT_PART, BALENA_DATA_PART, BALENA_ROOTA_PART},
+    Error, ErrorKind, Result,
+};
 
 mod image_file;
 pub(crate) use image_file::ImageFile;
 
 #[cfg(target_os = "linux")]
-mod gzip_file;
+mod compressed_file;
 #[cfg(target_os = "linux")]
-pub(crate) use gzip_file::GZipFile;
+pub(crate) use compressed_file::CompressedFile;
 
 #[cfg(target_os = "linux")]
-mod gzip_stream;
+mod compressed_stream;
 #[cfg(target_os = "linux")]
-pub(crate) use gzip_stream::GZipStream;
+pub(crate) use compressed_stream::CompressedStream;
 
 mod plain_file;
 
@@ -145,21 +148,27 @@ pub(crate) struct Disk {
 
 #[allow(dead_code)]
 impl Disk {
-    pub fn from_gzip_stream<R: Read + 'static>(stream: R) -> Result<Disk> {
+    pub fn from_stream<R: Read + 'static>(stream: R) -> Result<Disk> {
         Ok(Disk {
-            disk: Box::new(GZipStream::new(stream)?),
+            disk: Box::new(CompressedStream::new(stream)?),
             // writable: false,
             block_size: DEF_BLOCK_SIZE as u64,
         })
     }
 
+    /// Open an image file, decompressing it on the fly if required
     #[cfg(target_os = "linux")]
-    pub fn from_gzip_img<P: AsRef<Path>>(image: P) -> Result<Disk> {
-        Ok(Disk {
-            disk: Box::new(GZipFile::new(image.as_ref())?),
-            // writable: false,
-            block_size: DEF_BLOCK_SIZE as u64,
-        })
+    pub fn from_image<P: AsRef<Path>>(image: P) -> Result<Disk> {
+        let image = image.as_ref();
+        if Compression::from_file(image)? == Compression::None {
+            Disk::from_drive_file(image, None)
+        } else {
+            Ok(Disk {
+                disk: Box::new(CompressedFile::new(image)?),
+                // writable: false,
+                block_size: DEF_BLOCK_SIZE as u64,
+            })
+        }
     }
 
     pub fn from_drive_file<P: AsRef<Path>>(
@@ -220,9 +229,204 @@ impl Disk {
     }
 
     pub fn read_gpt(&mut self) -> gptman::Result<GPT> {
-        let mut f = File::open(self.get_image_file())?;
-        //let len = f.seek(SeekFrom::End(0))?;
-        GPT::find_from(&mut f)
+        let mut reader = DiskReader {
+            disk: self,
+            offset: 0,
+        };
+        GPT::find_from(&mut reader)
+    }
+
+    /// Find the boot, rootA and data partitions of a balenaOS image or device
+    pub fn get_balena_partitions(&mut self) -> Result<(PartInfo, PartInfo, PartInfo)> {
+        let device = self.get_image_file();
+
+        let mut boot_part: Option<PartInfo> = None;
+        let mut root_a_part: Option<PartInfo> = None;
+        let mut data_part: Option<PartInfo> = None;
+
+        // GPT provides a 'protective MBR' at LBA 0 to identify itself in a backward
+        // compatible way. So, read the GPT header if so.
+        let is_gpt = self.get_label()? == LabelType::GPT;
+
+        if !is_gpt {
+            let part_iterator = PartitionIterator::new(self)?;
+            for partition in part_iterator {
+                debug!(
+                    "partition: {}, start: {}, sectors: {}",
+                    partition.index, partition.start_lba, partition.num_sectors
+                );
+
+                match partition.index {
+                    1 => {
+                        boot_part = Some(partition);
+                    }
+                    // rootA partition is needed solely in the context of Jetson migrations,
+                    // in which case we will mount it to extract the new Jetson specific boot blob
+                    // to be written to the QSPI flash or boot partition.
+                    2 => {
+                        root_a_part = Some(partition);
+                    }
+                    3..=5 => debug!("Skipping partition {}", partition.index),
+                    6 => {
+                        data_part = Some(partition);
+                        break;
+                    }
+                    _ => {
+                        return Err(Error::with_context(
+                            ErrorKind::InvParam,
+                            &format!("Invalid partition index encountered: {}", partition.index),
+                        ));
+                    }
+                }
+            }
+        } else {
+            // Use the iterator built into gptman and populate the PartInfo structs
+            // for the boot and data partitions as best we can.
+            let gpt = match self.read_gpt() {
+                Ok(gpt_res) => gpt_res,
+                Err(e) => {
+                    return Err(Error::with_context(
+                        ErrorKind::InvState,
+                        &format!("Failed to read GPT header, error: {} ", e),
+                    ));
+                }
+            };
+            for (i, p) in gpt.iter() {
+                if p.is_used() {
+                    debug!(
+                        "Partition #{}: type = {:?}, size = {} bytes, starting lba = {}, name = {}",
+                        i,
+                        p.partition_type_guid,
+                        p.size().unwrap() * gpt.sector_size,
+                        p.starting_lba,
+                        p.partition_name.as_str()
+                    );
+
+                    if p.partition_name.as_str() == BALENA_BOOT_PART {
+                        boot_part = Some(PartInfo {
+                            index: i as usize,
+                            ptype: 0x83, // MBR Linux byte; really this is the EFI system
+                            // partition, but MBR doesn't define this type.
+                            status: 0, // Not clear what this should be
+                            start_lba: p.starting_lba,
+                            num_sectors: p.size().unwrap(),
+                        })
+                    } else if p.partition_name.as_str() == BALENA_ROOTA_PART {
+                        root_a_part = Some(PartInfo {
+                            index: i as usize,
+                            ptype: 0x83, // MBR Linux byte; really this is the EFI system
+                            // partition, but MBR doesn't define this type.
+                            status: 0, // Not clear what this should be
+                            start_lba: p.starting_lba,
+                            num_sectors: p.size().unwrap(),
+                        })
+                    } else if p.partition_name.as_str() == BALENA_DATA_PART {
+                        data_part = Some(PartInfo {
+                            index: i as usize,
+                            ptype: 0x83, // MBR Linux byte
+                            status: 0,   // not clear what this should be
+                            start_lba: p.starting_lba,
+                            num_sectors: p.size().unwrap(),
+                        });
+                    }
+                }
+            }
+        }
+
+        if let Some(boot_part) = boot_part {
+            if let Some(data_part) = data_part {
+                if let Some(root_a_part) = root_a_part {
+                    Ok((boot_part, root_a_part, data_part))
+                } else {
+                    Err(Error::with_context(
+                        ErrorKind::NotFound,
+                        &format!(
+                            "RootA partition could not be found on '{}",
+                            device.display()
+                        ),
+                    ))
+                }
+            } else {
+                Err(Error::with_context(
+                    ErrorKind::NotFound,
+                    &format!("Data partition could not be found on '{}", device.display()),
+                ))
+            }
+        } else {
+            Err(Error::with_context(
+                ErrorKind::NotFound,
+                &format!("Boot partition could not be found on '{}", device.display()),
+            ))
+        }
+    }
+
+    /// Number of bytes the partitions of the image or device extend over
+    pub fn get_required_size(&mut self) -> Result<u64> {
+        if self.get_label()? == LabelType::GPT {
+            let gpt = self.read_gpt().map_err(|why| {
+                Error::with_context(
+                    ErrorKind::InvState,
+                    &format!("Failed to read GPT header, error: {} ", why),
+                )
+            })?;
+            // the backup GPT header occupies the last sector
+            Ok((gpt.header.backup_lba + 1) * gpt.sector_size)
+        } else {
+            let block_size = self.block_size;
+            let mut max_lba: u64 = 0;
+            for partition in PartitionIterator::new(self)? {
+                max_lba = max_lba.max(partition.start_lba + partition.num_sectors);
+            }
+            Ok(max_lba * block_size)
+        }
+    }
+}
+
+/// Read + Seek adapter on a disk, seeking from the end only works on
+/// uncompressed images
+struct DiskReader<'a> {
+    disk: &'a mut Disk,
+    offset: u64,
+}
+
+impl<'a> Read for DiskReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> result::Result<usize, io::Error> {
+        match self.disk.disk.fill(self.offset, buf) {
+            Ok(_) => {
+                self.offset += buf.len() as u64;
+                Ok(buf.len())
+            }
+            Err(why) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, why)),
+        }
+    }
+}
+
+impl<'a> Seek for DiskReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> result::Result<u64, io::Error> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => match self.disk.disk.get_size() {
+                Some(size) => size.checked_add_signed(delta),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "cannot seek from the end of a compressed image",
+                    ))
+                }
+            },
+        };
+
+        match offset {
+            Some(offset) => {
+                self.offset = offset;
+                Ok(offset)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
     }
 }
 
@@ -430,6 +634,15 @@ pub(crate) struct PartitionReader<'a> {
 
 #[allow(dead_code)]
 impl<'a> PartitionReader<'a> {
+    pub fn from_disk(part: &PartInfo, disk: &'a mut Disk) -> PartitionReader<'a> {
+        let block_size = disk.block_size;
+        PartitionReader {
+            disk,
+            offset: part.start_lba * block_size,
+            bytes_left: part.num_sectors * block_size,
+        }
+    }
+
     pub fn from_part_iterator(
         part: &PartInfo,
         iterator: &'a mut PartitionIterator,
@@ -505,10 +718,17 @@ mod test {
         test_file
     }
 
+    #[test]
+    fn required_size() {
+        let mut disk = Disk::from_image(get_test_file()).unwrap();
+        // the last logical partition ends at sector 10240
+        assert_eq!(disk.get_required_size().unwrap(), 10240 * 512);
+    }
+
     //    #[test]
     #[allow(dead_code)]
     fn read_gzipped_part() {
-        let mut disk = Disk::from_gzip_img(get_test_file()).unwrap();
+        let mut disk = Disk::from_image(get_test_file()).unwrap();
         if let LabelType::Dos = disk.get_label().unwrap() {
             let mut count = 0;
             let iterator = PartitionIterator::new(&mut disk).unwrap();
@@ -1,21 +1,23 @@
-use flate2::read::GzDecoder;
 use log::{debug, trace};
-use std::fs::{File, OpenOptions};
+use std::fs::OpenOptions;
 use std::io::Read;
 use std::path::{Path, PathBuf};
 
 const DEF_READ_BUFFER: usize = 1024 * 1024;
 
-use crate::common::{disk_util::image_file::ImageFile, Error, ErrorKind, Result};
+use crate::common::{
+    compression::decompress, disk_util::image_file::ImageFile, Error, ErrorKind, Result,
+};
 
-pub(crate) struct GZipFile {
+/// Image file in any of the supported compression formats
+pub(crate) struct CompressedFile {
     path: PathBuf,
-    decoder: GzDecoder<File>,
+    decoder: Box<dyn Read>,
     bytes_read: u64,
 }
 
-impl GZipFile {
-    pub fn new(path: &Path) -> Result<GZipFile> {
+impl CompressedFile {
+    pub fn new(path: &Path) -> Result<CompressedFile> {
         trace!("new: entered with '{}'", path.display());
         let file = match OpenOptions::new()
             .write(false)
@@ -36,9 +38,9 @@ impl GZipFile {
             }
         };
 
-        Ok(GZipFile {
+        Ok(CompressedFile {
             path: path.to_path_buf(),
-            decoder: GzDecoder::new(file),
+            decoder: decompress(file)?,
             bytes_read: 0,
         })
     }
@@ -64,7 +66,7 @@ impl GZipFile {
             }
         };
 
-        self.decoder = GzDecoder::new(file);
+        self.decoder = decompress(file)?;
         self.bytes_read = 0;
         Ok(())
     }
@@ -143,7 +145,7 @@ impl GZipFile {
     }
 }
 
-impl ImageFile for GZipFile {
+impl ImageFile for CompressedFile {
     fn fill(&mut self, offset: u64, buffer: &mut [u8]) -> Result<()> {
         trace!(
             "fill: entered with offset {}, size {}",
@@ -1,24 +1,26 @@
 use std::io::Read;
 use std::path::PathBuf;
 
-use flate2::read::GzDecoder;
 use log::{debug, trace};
 
-use crate::common::{disk_util::image_file::ImageFile, Error, ErrorKind, Result};
+use crate::common::{
+    compression::decompress, disk_util::image_file::ImageFile, Error, ErrorKind, Result,
+};
 
 const DEF_READ_BUFFER: usize = 1024 * 1024;
 
-pub(crate) struct GZipStream<R> {
-    decoder: GzDecoder<R>,
+/// Stream in any of the supported compression formats
+pub(crate) struct CompressedStream {
+    decoder: Box<dyn Read>,
     bytes_read: u64,
 }
 
-impl<R: Read> GZipStream<R> {
-    pub fn new(stream: R) -> Result<GZipStream<R>> {
+impl CompressedStream {
+    pub fn new<R: Read + 'static>(stream: R) -> Result<CompressedStream> {
         trace!("new: entered ");
 
-        Ok(GZipStream {
-            decoder: GzDecoder::new(stream),
+        Ok(CompressedStream {
+            decoder: decompress(stream)?,
             bytes_read: 0,
         })
     }
@@ -92,7 +94,7 @@ impl<R: Read> GZipStream<R> {
     }
 }
 
-impl<R: Read> ImageFile for GZipStream<R> {
+impl ImageFile for CompressedStream {
     fn fill(&mut self, offset: u64, buffer: &mut [u8]) -> Result<()> {
         trace!(
             "fill: entered with offset {}, size {}",
@@ -5,4 +5,8 @@ use crate::common::Result;
 pub(crate) trait ImageFile {
     fn fill(&mut self, offset: u64, buffer: &mut [u8]) -> Result<()>;
     fn get_path(&self) -> PathBuf;
+    // size of the uncompressed image if it can be determined without reading it
+    fn get_size(&mut self) -> Option<u64> {
+        None
+    }
 }
@@ -57,4 +57,7 @@ impl ImageFile for PlainFile {
     fn get_path(&self) -> PathBuf {
         self.path.clone()
     }
+    fn get_size(&mut self) -> Option<u64> {
+        self.file.seek(SeekFrom::End(0)).ok()
+    }
 }
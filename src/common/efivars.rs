@@ -0,0 +1,130 @@
+use std::fs::{read, read_dir, remove_file, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use libc::{c_int, ioctl, FS_IOC_GETFLAGS, FS_IOC_SETFLAGS};
+use log::debug;
+
+use crate::common::{defs::SYS_EFIVARS_DIR, path_append, Error, ErrorKind, Result, ToError};
+
+/// Vendor GUID of the variables defined by the UEFI specification, like
+/// SecureBoot, SetupMode or the Boot#### entries
+pub(crate) const EFI_GLOBAL_VARIABLE: &str = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+/// Vendor GUID of the Secure Boot signature databases db and dbx
+pub(crate) const EFI_IMAGE_SECURITY_DATABASE: &str = "d719b2cb-3d3a-4596-a3bc-dad00e67656f";
+
+// efivarfs files start with the 4 byte attributes of the variable
+const EFI_VAR_ATTR_SIZE: usize = 4;
+// EFI_VARIABLE_NON_VOLATILE | EFI_VARIABLE_BOOTSERVICE_ACCESS | EFI_VARIABLE_RUNTIME_ACCESS
+const EFI_VAR_ATTR_NV_BS_RT: u32 = 0x7;
+// efivarfs marks most existing variables immutable to protect them from rm -rf
+const FS_IMMUTABLE_FL: c_int = 0x10;
+
+/// Read the value of an EFI variable from efivarfs, None if the variable does
+/// not exist
+pub(crate) fn read_efi_var(name: &str, guid: &str) -> Result<Option<Vec<u8>>> {
+    let path = path_append(SYS_EFIVARS_DIR, format!("{}-{}", name, guid));
+    match read(&path) {
+        Ok(content) => {
+            debug!(
+                "read_efi_var: read {} bytes from '{}'",
+                content.len(),
+                path.display()
+            );
+            Ok(Some(
+                content
+                    .get(EFI_VAR_ATTR_SIZE..)
+                    .map_or_else(Vec::new, |value| value.to_vec()),
+            ))
+        }
+        Err(why) if why.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(why) => Err(why).upstream_with_context(&format!("Failed to read '{}'", path.display())),
+    }
+}
+
+/// The names of all EFI variables of the vendor GUID
+pub(crate) fn list_efi_vars(guid: &str) -> Result<Vec<String>> {
+    let suffix = format!("-{}", guid);
+    let mut names = Vec::new();
+    for entry in read_dir(SYS_EFIVARS_DIR)
+        .upstream_with_context(&format!("Failed to read directory '{}'", SYS_EFIVARS_DIR))?
+    {
+        let entry = entry.upstream_with_context(&format!(
+            "Failed to read directory entry from '{}'",
+            SYS_EFIVARS_DIR
+        ))?;
+        if let Some(name) = entry.file_name().to_string_lossy().strip_suffix(&suffix) {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// Create or replace a non volatile EFI variable accessible at runtime
+pub(crate) fn write_efi_var(name: &str, guid: &str, value: &[u8]) -> Result<()> {
+    let path = path_append(SYS_EFIVARS_DIR, format!("{}-{}", name, guid));
+    if path.exists() {
+        clear_immutable(&path)?;
+    }
+
+    let mut content = EFI_VAR_ATTR_NV_BS_RT.to_le_bytes().to_vec();
+    content.extend_from_slice(value);
+
+    // efivarfs expects the attributes and the value in a single write and
+    // replaces the variable, it does not support truncating
+    let written = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .and_then(|mut file| file.write(&content))
+        .upstream_with_context(&format!("Failed to write '{}'", path.display()))?;
+    if written != content.len() {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "Wrote only {} of {} bytes to '{}'",
+                written,
+                content.len(),
+                path.display()
+            ),
+        ));
+    }
+
+    debug!(
+        "write_efi_var: wrote {} bytes to '{}'",
+        value.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Delete an EFI variable
+pub(crate) fn delete_efi_var(name: &str, guid: &str) -> Result<()> {
+    let path = path_append(SYS_EFIVARS_DIR, format!("{}-{}", name, guid));
+    clear_immutable(&path)?;
+    remove_file(&path).upstream_with_context(&format!("Failed to remove '{}'", path.display()))?;
+    debug!("delete_efi_var: removed '{}'", path.display());
+    Ok(())
+}
+
+fn clear_immutable(path: &Path) -> Result<()> {
+    let file =
+        File::open(path).upstream_with_context(&format!("Failed to open '{}'", path.display()))?;
+
+    let mut flags: c_int = 0;
+    if unsafe { ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS, &mut flags) } != 0 {
+        return Err(io::Error::last_os_error())
+            .upstream_with_context(&format!("Failed to read the flags of '{}'", path.display()));
+    }
+
+    if flags & FS_IMMUTABLE_FL != 0 {
+        flags &= !FS_IMMUTABLE_FL;
+        if unsafe { ioctl(file.as_raw_fd(), FS_IOC_SETFLAGS, &flags) } != 0 {
+            return Err(io::Error::last_os_error())
+                .upstream_with_context(&format!("Failed to make '{}' writable", path.display()));
+        }
+    }
+    Ok(())
+}
@@ -19,6 +19,7 @@ pub enum ErrorKind {
     Permission,
     FileExists,
     NotPermitted,
+    Timeout,
     Displayed,
 }
 
@@ -38,6 +39,7 @@ impl Display for ErrorKind {
             Self::Permission => "Permission was denied",
             Self::NotPermitted => "Operation is not permitted",
             Self::FileExists => "The file exists",
+            Self::Timeout => "An operation did not finish in time",
             Self::Displayed => "The error was displayed upstream",
         };
         write!(f, "{}", output)
@@ -114,6 +114,29 @@ pub fn persist_fallback_log_to_data_partition(
 ) -> Result<()> {
     let source_tmpfs_log_path = format!("/tmp/{}", s2_config.fallback_log_filename);
 
+    write_to_data_partition(s2_config, is_new_image_flashed, |dest_dir| {
+        copy_file_to_destination_dir(&source_tmpfs_log_path, dest_dir)
+    })
+}
+
+/// Helper function to write files to the fallback log directory on the data partition
+///
+/// # Arguments
+/// * `s2_config` - The stage2 config file
+/// * `is_new_image_flashed` - indicates if the function is being called after
+///   the target disk has been flashed with the new os image.
+/// * `write` - called with the path of the fallback log directory
+///
+/// Before flashing the directory is only available when migrating from balenaOS.
+/// In that case nothing is written and Ok is returned.
+pub fn write_to_data_partition<F>(
+    s2_config: &Stage2Config,
+    is_new_image_flashed: bool,
+    write: F,
+) -> Result<()>
+where
+    F: FnOnce(&str) -> Result<()>,
+{
     // If true, we need to mount the raw data partition and write to it
     if is_new_image_flashed {
         let device = &s2_config.flash_dev;
@@ -133,9 +156,7 @@ pub fn persist_fallback_log_to_data_partition(
             loop_device.get_path().display()
         );
 
-        loop_device
-            .setup(device, Some(byte_offset), Some(size_limit))
-            .unwrap();
+        loop_device.setup(device, Some(byte_offset), Some(size_limit))?;
         info!(
             "Setup device '{}' with offset {}, sizelimit {} on '{}'",
             device.display(),
@@ -164,20 +185,19 @@ pub fn persist_fallback_log_to_data_partition(
         );
 
         let dest_dir = format!("{}/{}", BALENA_PART_MP, s2_config.fallback_log_dirname);
-        create_dir_if_not_exist(&dest_dir)?;
-
-        copy_file_to_destination_dir(&source_tmpfs_log_path, dest_dir.as_str())?;
+        let res = create_dir_if_not_exist(&dest_dir).and_then(|_| write(dest_dir.as_str()));
 
         sync();
         umount(BALENA_PART_MP).upstream_with_context("Failed to unmount data partition")?;
         info!("Unmounted data partition from {}", BALENA_PART_MP);
 
         loop_device.unset()?;
+        res?;
     } else if Path::new(BALENA_DATA_MP).exists() {
         let dest_dir = format!("{}/{}", BALENA_DATA_MP, s2_config.fallback_log_dirname);
         create_dir_if_not_exist(&dest_dir)?;
 
-        copy_file_to_destination_dir(&source_tmpfs_log_path, dest_dir.as_str())?;
+        write(dest_dir.as_str())?;
     } else if Path::new(path_append(OLD_ROOT_MP, BALENA_DATA_MP).as_os_str()).exists() {
         // else if data partition is relative to OLD_ROOT_MP
 
@@ -187,7 +207,7 @@ pub fn persist_fallback_log_to_data_partition(
         );
         create_dir_if_not_exist(&dest_dir)?;
 
-        copy_file_to_destination_dir(&source_tmpfs_log_path, dest_dir.as_str())?;
+        write(dest_dir.as_str())?;
     }
 
     Ok(())
@@ -4,6 +4,7 @@ use clap::Parser;
 use log::Level;
 
 const DEFAULT_CHECK_TIMEOUT: u64 = 10;
+const DEFAULT_FLASH_RETRIES: u32 = 2;
 
 #[derive(Parser, Debug, Clone)]
 #[clap(name = env!("CARGO_PKG_NAME"), author, about)]
@@ -32,6 +33,12 @@ pub struct Options {
         help = "Version of balena-os image to download"
     )]
     version: Option<String>,
+    #[clap(
+        long,
+        value_name = "MIRROR",
+        help = "URL or directory of a local image mirror to download versions and images from, falls back to balena cloud"
+    )]
+    image_mirror: Option<String>,
     #[clap(
         short,
         long,
@@ -93,6 +100,12 @@ pub struct Options {
     no_ack: bool,
     #[clap(long, help = "Pretend mode, do not flash device")]
     pretend: bool,
+    #[clap(
+        long,
+        requires = "image",
+        help = "Print the migration plan as JSON and exit without modifying the system, requires --image. Partitions of the image are loop mounted read only to inspect it"
+    )]
+    plan: bool,
     #[clap(long, help = "Internal - stage2 invocation")]
     stage2: bool,
     #[clap(long, help = "Use internal tar instead of external command")]
@@ -103,6 +116,13 @@ pub struct Options {
     no_os_check: bool,
     #[clap(long, help = "Do not check if the target device type is valid")]
     no_dt_check: bool,
+    #[clap(
+        long,
+        value_name = "SUPPORT_MATRIX",
+        value_parser,
+        help = "Device and OS support matrix file replacing the built in one"
+    )]
+    support_matrix: Option<PathBuf>,
     #[clap(long, help = "Do not check if balena API is available")]
     no_api_check: bool,
     #[clap(long, help = "Do not check if balena VPN is available")]
@@ -126,6 +146,13 @@ pub struct Options {
         help = "API/VPN check timeout in seconds."
     )]
     check_timeout: Option<u64>,
+    #[clap(
+        long,
+        value_name = "COUNT",
+        value_parser,
+        help = "Number of times to re-flash the device if validating the written image fails"
+    )]
+    flash_retries: Option<u32>,
     #[clap(
         long,
         short,
@@ -142,6 +169,11 @@ pub struct Options {
         help = "Use INSTALL_DEVICE to flash balena to"
     )]
     flash_to: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Invalidate the boot sector of the disk the system booted from after migrating to another disk"
+    )]
+    disable_old_boot: bool,
     #[clap(
         long,
         help = "Do not create network manager configurations for configured wifis"
@@ -195,6 +227,10 @@ impl Options {
         &self.image
     }
 
+    pub fn image_mirror(&self) -> Option<&str> {
+        self.image_mirror.as_deref()
+    }
+
     pub fn version(&self) -> &str {
         if let Some(ref version) = self.version {
             version.as_str()
@@ -219,6 +255,10 @@ impl Options {
         self.pretend
     }
 
+    pub fn plan(&self) -> bool {
+        self.plan
+    }
+
     pub fn log_file(&self) -> &Option<PathBuf> {
         &self.log_file
     }
@@ -243,6 +283,10 @@ impl Options {
         !self.no_dt_check
     }
 
+    pub fn support_matrix(&self) -> Option<&Path> {
+        self.support_matrix.as_deref()
+    }
+
     pub fn no_efi_setup(&self) -> bool {
         self.no_efi_setup
     }
@@ -263,6 +307,10 @@ impl Options {
         &self.flash_to
     }
 
+    pub fn disable_old_boot(&self) -> bool {
+        self.disable_old_boot
+    }
+
     pub fn check_timeout(&self) -> u64 {
         if let Some(timeout) = self.check_timeout {
             timeout
@@ -271,6 +319,14 @@ impl Options {
         }
     }
 
+    pub fn flash_retries(&self) -> u32 {
+        if let Some(retries) = self.flash_retries {
+            retries
+        } else {
+            DEFAULT_FLASH_RETRIES
+        }
+    }
+
     pub fn no_wifis(&self) -> bool {
         self.no_wifis
     }
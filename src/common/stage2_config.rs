@@ -2,7 +2,10 @@ use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
-use crate::common::error::{Result, ToError};
+use crate::common::{
+    device_type::DeviceType,
+    error::{Result, ToError},
+};
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub(crate) struct UmountPart {
@@ -17,6 +20,20 @@ pub(crate) struct LogDevice {
     pub fs_type: String,
 }
 
+/// Bootloader EEPROM update changing the boot order, staged in the takeover
+/// directory by stage1 and installed by stage2 once the new disk is flashed,
+/// verified and configured
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct EepromUpdate {
+    /// BOOT_ORDER of the update, the boot mode of the new disk first
+    pub boot_order: u32,
+    /// partition on the old disk the bootloader loads the update from
+    pub boot_part: PathBuf,
+    pub boot_fs_type: String,
+    /// staged files, as seen from the stage2 root
+    pub files: Vec<PathBuf>,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub(crate) struct Stage2Config {
     pub log_dev: Option<LogDevice>,
@@ -25,19 +42,37 @@ pub(crate) struct Stage2Config {
     pub fallback_log_filename: String,
     pub fallback_log_dirname: String,
     pub flash_dev: PathBuf,
+    pub flash_retries: u32,
     pub pretend: bool,
     pub umount_parts: Vec<UmountPart>,
+    pub old_init_path: PathBuf,
+    pub swap_devices: Vec<PathBuf>,
     pub work_dir: PathBuf,
     pub image_path: PathBuf,
     pub config_path: PathBuf,
     pub backup_path: Option<PathBuf>,
-    pub device_type: String,
+    pub app_id: Option<u64>,
+    /// base64 encoded DER private key to decrypt the backup
+    pub backup_key: Option<String>,
+    pub device_type: DeviceType,
+    pub source_os: String,
     pub tty: PathBuf,
     pub api_endpoint: String,
     pub api_key: String,
     pub uuid: String,
     pub report_hup_progress: bool,
     pub change_dt_to: Option<String>,
+    /// EFI loader in the boot partition of the new image the EFI boot entry
+    /// points to, the signed loader if Secure Boot is enabled
+    pub efi_loader: Option<String>,
+    pub no_efi_setup: bool,
+    /// Disk the old system booted from when migrating to another disk, its
+    /// boot sector is invalidated once the new disk is flashed and verified
+    pub disable_boot_dev: Option<PathBuf>,
+    pub eeprom_update: Option<EepromUpdate>,
+    /// memory writing the boot firmware takes, counted in the space stage2
+    /// checks before flashing
+    pub boot_firmware_space: u64,
 }
 
 #[allow(dead_code)]
@@ -62,13 +97,15 @@ impl Stage2Config {
         &self.flash_dev
     }
 
-    /// Remove value for api_key from serialization output. Useful for logging.
-    /// Expects input is a multiline string.
+    /// Remove values for api_key and backup_key from serialization output.
+    /// Useful for logging. Expects input is a multiline string.
     pub fn sanitize_text(serialized: &str) -> String {
         let mut clean_txt = String::new();
         for element in serialized.lines() {
             if element.starts_with("api_key") {
                 clean_txt.push_str("api_key: <hidden>");
+            } else if element.starts_with("backup_key") {
+                clean_txt.push_str("backup_key: <hidden>");
             } else {
                 clean_txt.push_str(element);
             }
@@ -77,3 +114,42 @@ impl Stage2Config {
         clean_txt
     }
 }
+
+#[cfg(test)]
+impl Stage2Config {
+    /// A config for tests, they set the fields they depend on
+    pub fn for_tests() -> Stage2Config {
+        Stage2Config {
+            log_dev: None,
+            log_level: "info".to_string(),
+            fallback_log: false,
+            fallback_log_filename: "fallback.log".to_string(),
+            fallback_log_dirname: "fallback_log".to_string(),
+            flash_dev: PathBuf::from("/dev/sda"),
+            flash_retries: 2,
+            pretend: false,
+            umount_parts: Vec::new(),
+            old_init_path: PathBuf::from("/sbin/init"),
+            swap_devices: Vec::new(),
+            work_dir: PathBuf::from("/tmp"),
+            image_path: PathBuf::from("/tmp/balena.img"),
+            config_path: PathBuf::from("/tmp/config.json"),
+            backup_path: None,
+            app_id: None,
+            backup_key: None,
+            device_type: DeviceType::IntelNuc,
+            source_os: String::new(),
+            tty: PathBuf::from("/dev/tty1"),
+            api_endpoint: "https://api.balena-cloud.com".to_string(),
+            api_key: "key".to_string(),
+            uuid: String::new(),
+            report_hup_progress: false,
+            change_dt_to: None,
+            efi_loader: None,
+            no_efi_setup: false,
+            disable_boot_dev: None,
+            eeprom_update: None,
+            boot_firmware_space: 0,
+        }
+    }
+}
@@ -12,7 +12,10 @@ use crate::{
         stage2_config::Stage2Config,
         whereis, Error, Result, ToError,
     },
-    stage2::read_stage2_config,
+    stage2::{
+        read_stage2_config,
+        recovery::{recover, restart_old_init, RECOVERY_EXIT_CODE},
+    },
     ErrorKind,
 };
 use log::{error, info, trace, warn, Level};
@@ -31,6 +34,7 @@ use std::mem::MaybeUninit;
 use std::os::raw::c_int;
 use std::path::PathBuf;
 use std::process::Command;
+use std::ptr::null_mut;
 use std::str::FromStr;
 use std::thread::sleep;
 use std::time::Duration;
@@ -38,7 +42,8 @@ use std::time::Duration;
 use crate::common::{stage2_config::LogDevice, system::symlink};
 use libc::{
     close, dup2, getpid, open, pipe, sigfillset, sigprocmask, sigset_t, wait, O_CREAT, O_TRUNC,
-    O_WRONLY, SIG_BLOCK, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO,
+    O_WRONLY, SIG_BLOCK, SIG_SETMASK, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO, WEXITSTATUS,
+    WIFEXITED,
 };
 
 const INITIAL_LOG_LEVEL: Level = Level::Trace;
@@ -383,7 +388,7 @@ pub fn init() -> ! {
         }
     }
 
-    let _child_pid = match Command::new(format!("/bin/{}", env!("CARGO_PKG_NAME")))
+    let child_pid = match Command::new(format!("/bin/{}", env!("CARGO_PKG_NAME")))
         .args(["--stage2", "--s2-log-level", &s2_config.log_level])
         .spawn()
     {
@@ -416,6 +421,15 @@ pub fn init() -> ! {
                 } else {
                     warn!("wait returned error, errno: {}", sys_error);
                 }
+            } else if pid as u32 == child_pid
+                && WIFEXITED(status)
+                && WEXITSTATUS(status) == RECOVERY_EXIT_CODE
+            {
+                info!("Stage 2 worker restored the original system");
+                // the blocked signals would be inherited by the original init,
+                // leaving it unable to reap children or shut down
+                sigprocmask(SIG_SETMASK, &old_signals, null_mut());
+                restart_old_init(&s2_config, OLD_ROOT_MP);
             } else {
                 trace!(
                     "Stage 2 wait loop {}, status: {}, pid: {}",
@@ -541,8 +555,13 @@ fn stage2_init_err_handler(pre_pivot_root: bool, s2_config: &Stage2Config) -> !
         }
     }
 
+    // nothing has been flashed yet, so return to the original system
+    let old_root = if pre_pivot_root { "/" } else { OLD_ROOT_MP };
+    recover(s2_config, old_root, "Failed to set up stage2");
+
     if s2_config.fallback_log {
         let _ = persist_fallback_log_to_data_partition(s2_config, false);
     }
-    reboot();
+
+    restart_old_init(s2_config, old_root);
 }
@@ -27,13 +27,17 @@ pub(crate) mod migrate_info;
 
 mod block_device_info;
 mod defs;
-mod device;
-mod device_impl;
+pub(crate) mod device;
+pub(crate) mod device_impl;
 
 mod exe_copy;
 
 mod checks;
+mod image_info;
 mod image_retrieval;
+mod migration_plan;
+mod secure_boot;
+mod support_matrix;
 mod utils;
 mod wifi_config;
 
@@ -42,7 +46,7 @@ use crate::{
         call,
         defs::{
             BALENA_DATA_MP, BALENA_OS_NAME, NIX_NONE, OLD_ROOT_MP, STAGE2_CONFIG_NAME, SWAPOFF_CMD,
-            SYSTEM_CONNECTIONS_DIR, SYSTEM_PROXY_DIR, SYS_EFIVARS_DIR, SYS_EFI_DIR, TELINIT_CMD,
+            SYSTEM_CONNECTIONS_DIR, SYSTEM_PROXY_DIR, SYS_EFIVARS_DIR, TELINIT_CMD,
         },
         error::{Error, ErrorKind, Result, ToError},
         file_exists, format_size_with_unit, get_mem_info, get_os_name,
@@ -52,12 +56,17 @@ use crate::{
         system::copy_dir,
     },
     stage1::{
-        block_device_info::BlockDevice, block_device_info::BlockDeviceInfo, exe_copy::ExeCopy,
-        migrate_info::MigrateInfo, utils::mount_fs,
+        block_device_info::BlockDevice,
+        block_device_info::BlockDeviceInfo,
+        exe_copy::ExeCopy,
+        image_info::ImageInfo,
+        migrate_info::MigrateInfo,
+        migration_plan::MigrationPlan,
+        utils::{get_swap_devices, mount_fs},
     },
 };
 
-use crate::common::defs::{DD_CMD, EFIBOOTMGR_CMD, MTD_DEBUG_CMD, TAKEOVER_DIR};
+use crate::common::defs::TAKEOVER_DIR;
 use crate::common::dir_exists;
 use crate::common::logging::open_fallback_log_file;
 use crate::common::stage2_config::LogDevice;
@@ -259,49 +268,58 @@ fn mount_sys_filesystems(
     Ok(())
 }
 
-fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
+/// Selects the commands to copy to the takeover directory and gathers their
+/// dependencies.
+fn get_copy_commands(opts: &Options, mig_info: &MigrateInfo) -> Result<ExeCopy> {
+    match ExeCopy::new(mig_info.device().stage2_commands(opts)?) {
+        Ok(commands) => {
+            debug!(
+                "Space required for commands: {}",
+                format_size_with_unit(commands.get_req_space())
+            );
+            Ok(commands)
+        }
+        Err(why) => Err(Error::from_upstream_error(
+            Box::new(why),
+            "Failed to gather dependencies for copied commands",
+        )),
+    }
+}
+
+/// Gathers everything `prepare` would decide without modifying the system.
+fn get_migration_plan(
+    opts: &Options,
+    mig_info: &MigrateInfo,
+    image_info: &ImageInfo,
+) -> Result<MigrationPlan> {
+    let commands = get_copy_commands(opts, mig_info)?;
+    let block_dev_info = get_block_dev_info()?;
+    let flash_dev = get_flash_device(opts, &block_dev_info)?;
+
+    MigrationPlan::new(
+        mig_info,
+        image_info,
+        &commands,
+        flash_dev.get_dev_path(),
+        get_umount_parts(flash_dev, &block_dev_info)?,
+        get_log_device(opts, &block_dev_info),
+        commands.get_req_space() + S1_XTRA_FS_SIZE,
+    )
+}
+
+fn prepare(opts: &Options, mig_info: &mut MigrateInfo, image_info: &ImageInfo) -> Result<()> {
     info!("Preparing for takeover..");
 
     // *********************************************************
-    // turn off swap
+    // turn off swap, remembering what was active so stage2 can restore it
+    let swap_devices = get_swap_devices()?;
     call_command!(SWAPOFF_CMD, &["-a"], "Failed to disable SWAP")?;
 
     // *********************************************************
     // calculate required memory
 
-    let mut req_space: u64 = 0;
-    let mut copy_commands = vec![DD_CMD];
-
-    // If device is a Jetson Xavier, don't copy over efibootmgr because the old L4T does not use EFI
-    if mig_info.is_x86()
-        && !opts.no_efi_setup()
-        && !mig_info.is_jetson_xavier()
-        && dir_exists(SYS_EFI_DIR)?
-    {
-        copy_commands.push(EFIBOOTMGR_CMD)
-    }
-
-    if mig_info.is_jetson_xavier_nx() {
-        copy_commands.push(MTD_DEBUG_CMD)
-    }
-
-    let commands = match ExeCopy::new(copy_commands) {
-        Ok(commands) => {
-            let cmd_space = commands.get_req_space();
-            debug!(
-                "Space required for commands: {}",
-                format_size_with_unit(cmd_space)
-            );
-            req_space += cmd_space;
-            commands
-        }
-        Err(why) => {
-            return Err(Error::from_upstream_error(
-                Box::new(why),
-                "Failed to gather dependencies for copied commands",
-            ));
-        }
-    };
+    let commands = get_copy_commands(opts, mig_info)?;
+    let req_space = commands.get_req_space() + S1_XTRA_FS_SIZE;
 
     let (mem_tot, mem_free) = get_mem_info()?;
     info!(
@@ -311,10 +329,10 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
     );
 
     // TODO: maybe kill some procs first
-    if mem_free < req_space + S1_XTRA_FS_SIZE {
+    if mem_free < req_space {
         return Err(Error::with_context(ErrorKind::InvState, &format!(
             "Not enough memory space found to copy files to RAMFS, required size is {} free memory is {}",
-            format_size_with_unit(req_space + S1_XTRA_FS_SIZE),
+            format_size_with_unit(req_space),
             format_size_with_unit(mem_free)
         )));
     }
@@ -400,34 +418,34 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
 
     let block_dev_info = get_block_dev_info()?;
 
-    let flash_dev = if let Some(flash_dev) = opts.flash_to() {
-        if let Some(flash_dev) = block_dev_info.get_devices().get(flash_dev) {
-            flash_dev
-        } else {
-            return Err(Error::with_context(
-                ErrorKind::InvState,
-                &format!(
-                    "Could not find configured flash device '{}'",
-                    flash_dev.display()
-                ),
-            ));
-        }
-    } else {
-        block_dev_info.get_root_device()
-    };
-
-    if !file_exists(flash_dev.as_ref().get_dev_path()) {
-        return Err(Error::with_context(
-            ErrorKind::DeviceNotFound,
-            &format!(
-                "The device could not be found: '{}'",
-                flash_dev.get_dev_path().display()
-            ),
-        ));
-    }
+    let flash_dev = get_flash_device(opts, &block_dev_info)?;
 
     let log_device = get_log_device(opts, &block_dev_info);
 
+    // *********************************************************
+    // check that the firmware can boot the new disk when migrating to another
+    // disk, the boot order is only changed in stage2
+
+    let root_dev = block_dev_info.get_root_device().get_dev_path();
+    let (old_boot_dev, eeprom_update) = if root_dev != flash_dev.get_dev_path() {
+        info!(
+            "Migrating from '{}' to '{}'",
+            root_dev.display(),
+            flash_dev.get_dev_path().display()
+        );
+        let eeprom_update = mig_info.device().prepare_boot_device(
+            opts,
+            &takeover_dir,
+            &flash_dev.get_dev_path(),
+        )?;
+        (Some(root_dev), eeprom_update)
+    } else {
+        if opts.disable_old_boot() {
+            warn!("Ignoring --disable-old-boot, the system is migrated on the disk it booted from");
+        }
+        (None, None)
+    };
+
     // collect partitions that need to be unmounted
 
     let s2_cfg = Stage2Config {
@@ -437,8 +455,11 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
         fallback_log_filename: opts.fallback_log_filename().to_string(),
         fallback_log_dirname: opts.fallback_log_dir().to_string(),
         flash_dev: flash_dev.get_dev_path(),
+        flash_retries: opts.flash_retries(),
         pretend: opts.pretend(),
         umount_parts: get_umount_parts(flash_dev, &block_dev_info)?,
+        old_init_path: old_init_path.clone(),
+        swap_devices,
         work_dir: opts
             .work_dir()
             .canonicalize()
@@ -449,7 +470,12 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
         image_path: mig_info.image_path().to_path_buf(),
         config_path: mig_info.balena_cfg().get_path().to_path_buf(),
         backup_path: mig_info.backup().map(|backup_path| backup_path.to_owned()),
-        device_type: mig_info.get_device_type_name().to_string(),
+        app_id: mig_info.balena_cfg().get_app_id().ok(),
+        backup_key: mig_info
+            .backup_key()
+            .map(|backup_key| backup_key.to_owned()),
+        device_type: mig_info.device().get_device_type(),
+        source_os: mig_info.os_name().to_string(),
         tty: read_link("/proc/self/fd/1")
             .upstream_with_context("Failed to read tty from '/proc/self/fd/1'")?,
         api_endpoint: mig_info.balena_cfg().get_api_endpoint()?,
@@ -462,6 +488,11 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
             .unwrap_or_else(|_| "".to_owned()),
         report_hup_progress: opts.report_hup_progress(),
         change_dt_to: opts.change_dt_to().clone(),
+        efi_loader: image_info.efi_loader_path(),
+        no_efi_setup: opts.no_efi_setup(),
+        disable_boot_dev: old_boot_dev.filter(|_| opts.disable_old_boot()),
+        eeprom_update,
+        boot_firmware_space: mig_info.device().boot_firmware_space(image_info)?,
     };
 
     let s2_cfg_path = takeover_dir.join(STAGE2_CONFIG_NAME);
@@ -535,6 +566,41 @@ fn get_block_dev_info() -> Result<BlockDeviceInfo> {
     Ok(block_dev_info)
 }
 
+/// Gets the device to flash, either the one requested with `--flash-to` or
+/// the device the root file system resides on.
+fn get_flash_device<'a>(
+    opts: &Options,
+    block_dev_info: &'a BlockDeviceInfo,
+) -> Result<&'a Rc<dyn BlockDevice>> {
+    let flash_dev = if let Some(flash_dev) = opts.flash_to() {
+        if let Some(flash_dev) = block_dev_info.get_devices().get(flash_dev) {
+            flash_dev
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "Could not find configured flash device '{}'",
+                    flash_dev.display()
+                ),
+            ));
+        }
+    } else {
+        block_dev_info.get_root_device()
+    };
+
+    if !file_exists(flash_dev.as_ref().get_dev_path()) {
+        return Err(Error::with_context(
+            ErrorKind::DeviceNotFound,
+            &format!(
+                "The device could not be found: '{}'",
+                flash_dev.get_dev_path().display()
+            ),
+        ));
+    }
+
+    Ok(flash_dev)
+}
+
 /// Gets the log device to use for stage 2 logs. Returns `None` if the user
 /// didn't request a log device, or if the requested log device is not usable.
 /// If the log device is not usable, an error message is logged.
@@ -658,9 +724,10 @@ pub fn stage1(opts: &Options) -> Result<()> {
         }
     };
 
-    match do_early_checks(opts) {
-        Ok(_) => {
+    let image_info = match do_early_checks(opts, &mig_info) {
+        Ok(image_info) => {
             info!("Early checks passed");
+            image_info
         }
         Err(why) => {
             return Err(Error::from_upstream(
@@ -668,6 +735,14 @@ pub fn stage1(opts: &Options) -> Result<()> {
                 "Failed early checks, exiting",
             ));
         }
+    };
+
+    if opts.plan() {
+        // only scans the backup in plan mode
+        mig_info.create_backup(opts)?;
+        let plan = get_migration_plan(opts, &mig_info, &image_info)?;
+        println!("{}", plan.to_json()?);
+        return Ok(());
     }
 
     if !opts.no_ack() {
@@ -699,8 +774,14 @@ pub fn stage1(opts: &Options) -> Result<()> {
     }
 
     if opts.migrate() {
-        match prepare(opts, &mut mig_info) {
+        // the backup stops services, so it is created once the migration was
+        // confirmed, right before the system is prepared
+        match mig_info
+            .create_backup(opts)
+            .and_then(|_| prepare(opts, &mut mig_info, &image_info))
+        {
             Ok(_) => {
+                mig_info.keep_services_stopped();
                 info!("Takeover initiated successfully, please wait for the device to be reflashed and reboot");
                 sync();
                 sleep(Duration::from_secs(10));
@@ -1,5 +1,5 @@
-use log::{debug, info, trace};
-use regex::Regex;
+use log::{debug, info, trace, warn};
+use openssl::pkey::{PKey, Public};
 use std::fs::read_dir;
 use std::path::{Path, PathBuf};
 
@@ -12,90 +12,94 @@ mod rust_tar_archiver;
 
 mod ext_tar_archiver;
 
+pub mod scan;
+
+pub mod hooks;
+
+pub mod services;
+
 use crate::{
     common::{
         error::{Error, ErrorKind, Result, ToError},
-        path_append,
+        format_size_with_unit, path_append,
     },
     stage1::backup::{
-        archiver::Archiver, config::VolumeConfig, ext_tar_archiver::ExtTarArchiver,
+        archiver::{Archiver, BackupEntry, EntryKind},
+        config::{ItemConfig, ItemFilter, VolumeConfig},
+        ext_tar_archiver::ExtTarArchiver,
         rust_tar_archiver::RustTarArchiver,
     },
 };
 
+/// Settings of the backup item being collected
+struct ItemContext<'a> {
+    item: &'a ItemConfig,
+    filter: ItemFilter,
+    mode: Option<u32>,
+    size: u64,
+}
+
+impl<'a> ItemContext<'a> {
+    fn new(item: &'a ItemConfig) -> Result<ItemContext<'a>> {
+        Ok(ItemContext {
+            item,
+            filter: ItemFilter::new(item.filter.as_ref())?,
+            mode: item.mode()?,
+            size: 0,
+        })
+    }
+
+    fn entry(&self, kind: EntryKind, target: PathBuf, source: PathBuf) -> BackupEntry {
+        BackupEntry {
+            kind,
+            target,
+            source,
+            uid: self.item.uid,
+            gid: self.item.gid,
+            mode: self.mode,
+        }
+    }
+
+    fn add_size(&mut self, size: u64) -> Result<()> {
+        self.size += size;
+        match self.item.max_size {
+            Some(max_size) if self.size > max_size => Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Backup item '{}' exceeds its maximum size of {}",
+                    self.item.source,
+                    format_size_with_unit(max_size)
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Collect the entries below dir_path, rel_path is the path relative to the
+/// item source and depth the number of directory levels below it.
+/// Directories are only included if they contain any entries.
 fn archive_dir(
     dir_path: &Path,
+    rel_path: &Path,
     target_path: &Path,
-    archiver: &mut impl Archiver,
-    filter: &Option<Regex>,
-) -> Result<bool> {
+    depth: usize,
+    ctx: &mut ItemContext,
+) -> Result<Vec<BackupEntry>> {
     trace!(
-        "archive_dir: dir_path: '{}', target_path: '{}' filter: {:?}",
+        "archive_dir: dir_path: '{}', target_path: '{}' depth: {}",
         dir_path.display(),
         target_path.display(),
-        filter
+        depth
     );
-    let mut written = false;
 
+    let mut dir_entries = Vec::new();
     for entry in read_dir(dir_path).upstream_with_context(&format!(
         "Failed to list directory backup source: '{}'",
         dir_path.display()
     ))? {
         match entry {
-            Ok(dir_entry) => {
-                let source_path = dir_entry.path();
-                let source_file = source_path.file_name().unwrap();
-                debug!("processing source: '{}'", source_path.display());
-                let metadata = dir_entry.metadata().upstream_with_context(&format!(
-                    "Failed to retrieve metadata for file: '{}'",
-                    source_path.display()
-                ))?;
-
-                if metadata.is_dir() {
-                    if archive_dir(
-                        &source_path,
-                        &path_append(target_path, source_file),
-                        archiver,
-                        filter,
-                    )? {
-                        written = true;
-                    }
-                } else if let Some(filter) = filter {
-                    if filter.is_match(&source_path.to_string_lossy()) {
-                        let target = path_append(target_path, source_file);
-                        archiver
-                            .add_file(target.as_path(), source_path.as_path())
-                            .upstream_with_context(&format!(
-                                "Failed to append file: '{}' to archive path: '{}'",
-                                source_path.display(),
-                                target.display()
-                            ))?;
-                        written = true;
-                        debug!(
-                            "appended source: '{}'  to archive as '{}'",
-                            source_path.display(),
-                            target.display()
-                        );
-                    } else {
-                        debug!("No match on file: '{}'", &source_path.display());
-                    }
-                } else {
-                    let target = path_append(target_path, source_file);
-                    archiver
-                        .add_file(target.as_path(), source_path.as_path())
-                        .upstream_with_context(&format!(
-                            "Failed to append file: '{}' to archive path: '{}'",
-                            source_path.display(),
-                            target.display()
-                        ))?;
-                    written = true;
-                    debug!(
-                        "appended source: '{}'  to archive as '{}'",
-                        source_path.display(),
-                        target.display()
-                    );
-                }
-            }
+            Ok(dir_entry) => dir_entries.push(dir_entry),
             Err(why) => {
                 return Err(Error::with_all(
                     ErrorKind::Upstream,
@@ -105,15 +109,130 @@ fn archive_dir(
             }
         }
     }
+    // same order for every archiver and every run
+    dir_entries.sort_by_key(|dir_entry| dir_entry.file_name());
 
-    Ok(written)
+    let mut entries = Vec::new();
+    for dir_entry in dir_entries {
+        let source_path = dir_entry.path();
+        let source_file = dir_entry.file_name();
+        let entry_rel = path_append(rel_path, &source_file);
+        let target = path_append(target_path, &source_file);
+        debug!("processing source: '{}'", source_path.display());
+
+        if ctx.filter.is_denied(&entry_rel) {
+            debug!("Denied by filter: '{}'", source_path.display());
+            continue;
+        }
+
+        let link_md = dir_entry.metadata().upstream_with_context(&format!(
+            "Failed to retrieve metadata for file: '{}'",
+            source_path.display()
+        ))?;
+
+        if link_md.file_type().is_symlink() && ctx.item.preserve_symlinks {
+            if ctx.filter.is_allowed(&source_path, &entry_rel) {
+                debug!("appending symlink: '{}'", source_path.display());
+                entries.push(ctx.entry(EntryKind::Symlink, target, source_path));
+            }
+            continue;
+        }
+
+        let metadata = match source_path.metadata() {
+            Ok(metadata) => metadata,
+            Err(why) => {
+                warn!(
+                    "Skipping '{}', failed to retrieve metadata: {}",
+                    source_path.display(),
+                    why
+                );
+                continue;
+            }
+        };
+
+        if metadata.is_dir() {
+            if ctx
+                .item
+                .max_depth
+                .is_some_and(|max_depth| depth >= max_depth)
+            {
+                debug!("Maximum depth reached at '{}'", source_path.display());
+                continue;
+            }
+            let children = archive_dir(&source_path, &entry_rel, &target, depth + 1, ctx)?;
+            if !children.is_empty() {
+                entries.push(ctx.entry(EntryKind::Dir, target, source_path));
+                entries.extend(children);
+            }
+        } else if ctx.filter.is_allowed(&source_path, &entry_rel) {
+            ctx.add_size(metadata.len())?;
+            debug!(
+                "appending source: '{}' as '{}'",
+                source_path.display(),
+                target.display()
+            );
+            entries.push(ctx.entry(EntryKind::File, target, source_path));
+        } else {
+            debug!("No match on file: '{}'", &source_path.display());
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Collect the archive entries of a backup item of volume
+fn collect_item(volume: &str, item: &ItemConfig) -> Result<Vec<BackupEntry>> {
+    let item_src = PathBuf::from(&item.source)
+        .canonicalize()
+        .upstream_with_context(&format!("Failed to process source '{}'", item.source))?;
+
+    debug!("processing item: source. '{}'", item_src.display());
+
+    let mut ctx = ItemContext::new(item)?;
+    if let Ok(metadata) = item_src.metadata() {
+        if metadata.is_dir() {
+            debug!("source: '{}' is a directory", item_src.display());
+            if let Some(ref target) = item.target {
+                let target_path = path_append(PathBuf::from(volume), target);
+                let children = archive_dir(&item_src, Path::new(""), &target_path, 0, &mut ctx)?;
+                if children.is_empty() {
+                    Ok(children)
+                } else {
+                    let mut entries = vec![ctx.entry(EntryKind::Dir, target_path, item_src)];
+                    entries.extend(children);
+                    Ok(entries)
+                }
+            } else {
+                archive_dir(&item_src, Path::new(""), Path::new(volume), 0, &mut ctx)
+            }
+        } else {
+            debug!("source: '{}' is a file", item_src.display());
+            let target = if let Some(ref target) = item.target {
+                path_append(PathBuf::from(volume), target)
+            } else {
+                path_append(PathBuf::from(volume), item_src.file_name().unwrap())
+            };
+            ctx.add_size(metadata.len())?;
+            debug!("target: '{}'", target.display());
+            Ok(vec![ctx.entry(EntryKind::File, target, item_src)])
+        }
+    } else {
+        Err(Error::with_context(
+            ErrorKind::NotFound,
+            &format!("Missing source for backup: '{}'", item.source),
+        ))
+    }
 }
 
 #[allow(dead_code)]
-pub(crate) fn create_ext(file: &Path, config: Vec<VolumeConfig>) -> Result<bool> {
+pub(crate) fn create_ext(
+    file: &Path,
+    config: Vec<VolumeConfig>,
+    public_key: Option<&PKey<Public>>,
+) -> Result<bool> {
     if !config.is_empty() {
         info!("creating new backup in '{}", file.display());
-        let mut archiver = ExtTarArchiver::new(file)?;
+        let mut archiver = ExtTarArchiver::new(file, public_key)?;
         if create_int(&mut archiver, config)? {
             info!("The backup was created successfully");
             Ok(true)
@@ -127,10 +246,14 @@ pub(crate) fn create_ext(file: &Path, config: Vec<VolumeConfig>) -> Result<bool>
     }
 }
 
-pub(crate) fn create<P: AsRef<Path>>(file: P, config: Vec<VolumeConfig>) -> Result<bool> {
+pub(crate) fn create<P: AsRef<Path>>(
+    file: P,
+    config: Vec<VolumeConfig>,
+    public_key: Option<&PKey<Public>>,
+) -> Result<bool> {
     if !config.is_empty() {
         info!("creating new backup in '{}", file.as_ref().display());
-        let mut archiver = RustTarArchiver::new(file)?;
+        let mut archiver = RustTarArchiver::new(file, public_key)?;
         if create_int(&mut archiver, config)? {
             info!("The backup was created successfully");
             Ok(true)
@@ -145,8 +268,6 @@ pub(crate) fn create<P: AsRef<Path>>(file: P, config: Vec<VolumeConfig>) -> Resu
 }
 
 fn create_int(archiver: &mut impl Archiver, config: Vec<VolumeConfig>) -> Result<bool> {
-    // TODO: stop selected services, containers, add this to backup config
-
     trace!("create_int entered with: {:?}", config);
 
     let mut written = false;
@@ -154,61 +275,22 @@ fn create_int(archiver: &mut impl Archiver, config: Vec<VolumeConfig>) -> Result
         info!("backup to volume: '{}'", volume.volume);
 
         for item in &volume.items {
-            let item_src = PathBuf::from(&item.source)
-                .canonicalize()
-                .upstream_with_context(&format!("Failed to process source '{}'", item.source))?;
-
-            debug!("processing item: source. '{}'", item_src.display());
-
-            if let Ok(metadata) = item_src.metadata() {
-                if metadata.is_dir() {
-                    let target_path = if let Some(ref target) = item.target {
-                        path_append(PathBuf::from(&volume.volume), target)
-                    } else {
-                        PathBuf::from(&volume.volume)
-                    };
-
-                    debug!("source: '{}' is a directory", item_src.display());
-                    let filter = if let Some(ref filter) = item.filter {
-                        Some(Regex::new(filter).upstream_with_context(&format!(
-                            "Failed to create regular expression from filter '{}'",
-                            filter
-                        ))?)
-                    } else {
-                        None
-                    };
-
-                    if archive_dir(&item_src, &target_path, archiver, &filter)? {
-                        written = true;
-                    }
-                } else {
-                    debug!("source: '{}' is a file", item_src.display());
-                    let target = if let Some(ref target) = item.target {
-                        path_append(PathBuf::from(&volume.volume), target)
-                    } else {
-                        path_append(PathBuf::from(&volume.volume), item_src.file_name().unwrap())
-                    };
-
-                    debug!("target: '{}'", target.display());
-                    archiver
-                        .add_file(target.as_path(), item_src.as_path())
-                        .upstream_with_context(&format!(
-                            "Failed to append '{}' to archive path '{}'",
-                            item_src.display(),
-                            target.display()
-                        ))?;
-                    written = true;
-                    debug!(
-                        "appended source: '{}'  to archive as '{}'",
-                        item_src.display(),
-                        target.display()
-                    );
-                }
-            } else {
-                return Err(Error::with_context(
-                    ErrorKind::NotFound,
-                    &format!("Missing source for backup: '{}'", item.source),
-                ));
+            let entries = collect_item(&volume.volume, item)?;
+            for entry in &entries {
+                archiver.add_entry(entry).upstream_with_context(&format!(
+                    "Failed to append '{}' to archive path '{}'",
+                    entry.source.display(),
+                    entry.target.display()
+                ))?;
+                trace!(
+                    "appended source: '{}'  to archive as '{}'",
+                    entry.source.display(),
+                    entry.target.display()
+                );
+            }
+
+            if !entries.is_empty() {
+                written = true;
             }
         }
     }
@@ -220,3 +302,110 @@ fn create_int(archiver: &mut impl Archiver, config: Vec<VolumeConfig>) -> Result
     debug!("create_int: returning {}", written);
     Ok(written)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::backup_crypt::open_backup;
+    use crate::stage1::backup::config::backup_cfg_from_file;
+    use flate2::read::GzDecoder;
+    use openssl::{pkey::Private, rsa::Rsa};
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::os::unix::fs::symlink;
+    use tar::Archive;
+
+    // path, entry type, uid, gid, mode, size, link name, mtime
+    type EntryInfo = (PathBuf, u8, u64, u64, u32, u64, Option<PathBuf>, u64);
+
+    fn list_archive(path: &Path, private_key: Option<&PKey<Private>>) -> Vec<EntryInfo> {
+        let mut archive = Archive::new(GzDecoder::new(open_backup(path, private_key).unwrap()));
+        let mut entries: Vec<EntryInfo> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let header = entry.header();
+                (
+                    entry.path().unwrap().components().collect(),
+                    header.entry_type().as_byte(),
+                    header.uid().unwrap(),
+                    header.gid().unwrap(),
+                    header.mode().unwrap(),
+                    header.size().unwrap(),
+                    entry
+                        .link_name()
+                        .unwrap()
+                        .map(|link_name| link_name.to_path_buf()),
+                    header.mtime().unwrap(),
+                )
+            })
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn archivers_create_same_archive() {
+        let test_dir = temp_dir().join("takeover-backup-create");
+        let _res = remove_dir_all(&test_dir);
+        let source = test_dir.join("source");
+        create_dir_all(source.join("app/cache")).unwrap();
+        create_dir_all(source.join("app/deep/deeper")).unwrap();
+        write(source.join("app/app.conf"), "setting=1\n").unwrap();
+        write(source.join("app/cache/app.conf"), "cached\n").unwrap();
+        write(source.join("app/deep/deeper/app.conf"), "too deep\n").unwrap();
+        write(source.join("app/app.log"), "log\n").unwrap();
+        write(source.join("top.conf"), "top\n").unwrap();
+        symlink("app/app.conf", source.join("link.conf")).unwrap();
+
+        let cfg_path = test_dir.join("backup.yml");
+        write(
+            &cfg_path,
+            format!(
+                "- volume: data\n  items:\n  - source: {}\n    target: config\n    \
+                 filter:\n      allow: ['*.conf']\n      deny: ['cache']\n    \
+                 max_depth: 1\n    preserve_symlinks: true\n    uid: 1000\n    gid: 1001\n    mode: \"0640\"\n",
+                source.display()
+            ),
+        )
+        .unwrap();
+
+        let rust_archive = test_dir.join("rust.tgz");
+        let ext_archive = test_dir.join("ext.tgz");
+        let volumes = || backup_cfg_from_file(&cfg_path).unwrap().volumes;
+        assert!(create(&rust_archive, volumes(), None).unwrap());
+        assert!(create_ext(&ext_archive, volumes(), None).unwrap());
+
+        let rust_entries = list_archive(&rust_archive, None);
+        assert_eq!(rust_entries, list_archive(&ext_archive, None));
+
+        let private_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let public_key =
+            PKey::public_key_from_pem(&private_key.public_key_to_pem().unwrap()).unwrap();
+        assert!(create(&rust_archive, volumes(), Some(&public_key)).unwrap());
+        assert!(create_ext(&ext_archive, volumes(), Some(&public_key)).unwrap());
+        assert_eq!(
+            rust_entries,
+            list_archive(&rust_archive, Some(&private_key))
+        );
+        assert_eq!(rust_entries, list_archive(&ext_archive, Some(&private_key)));
+
+        let paths: Vec<&Path> = rust_entries.iter().map(|entry| entry.0.as_path()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                Path::new("data/config"),
+                Path::new("data/config/app"),
+                Path::new("data/config/app/app.conf"),
+                Path::new("data/config/link.conf"),
+                Path::new("data/config/top.conf"),
+            ]
+        );
+        let app_conf = &rust_entries[2];
+        assert_eq!((app_conf.2, app_conf.3, app_conf.4), (1000, 1001, 0o640));
+        assert_eq!(rust_entries[3].6, Some(PathBuf::from("app/app.conf")));
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+}
@@ -1,7 +1,81 @@
-use crate::common::error::Result;
-use std::path::Path;
+use crate::common::{
+    backup_crypt::EncryptWriter,
+    error::{Result, ToError},
+};
+use openssl::pkey::{PKey, Public};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
-pub trait Archiver {
-    fn add_file(&mut self, target: &Path, source: &Path) -> Result<()>;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// A single archive entry, ownership and permissions are taken from source
+/// unless overridden
+#[derive(Debug, Clone)]
+pub(crate) struct BackupEntry {
+    pub kind: EntryKind,
+    /// path in the archive
+    pub target: PathBuf,
+    pub source: PathBuf,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// only applied to files
+    pub mode: Option<u32>,
+}
+
+pub(crate) trait Archiver {
+    fn add_entry(&mut self, entry: &BackupEntry) -> Result<()>;
     fn finish(&mut self) -> Result<()>;
 }
+
+/// The compressed archive is written to the backup file through this,
+/// encrypting it if a public key is configured
+pub(crate) enum BackupWriter {
+    Plain(File),
+    Encrypted(EncryptWriter<File>),
+}
+
+impl BackupWriter {
+    pub fn create(file: &Path, public_key: Option<&PKey<Public>>) -> Result<BackupWriter> {
+        let out_file = File::create(file).upstream_with_context(&format!(
+            "Failed to create backup in file '{}'",
+            file.display()
+        ))?;
+        if let Some(public_key) = public_key {
+            Ok(BackupWriter::Encrypted(EncryptWriter::new(
+                out_file, public_key,
+            )?))
+        } else {
+            Ok(BackupWriter::Plain(out_file))
+        }
+    }
+
+    /// Must be called once the archive is complete
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self {
+            BackupWriter::Plain(file) => file.flush(),
+            BackupWriter::Encrypted(writer) => writer.finish(),
+        }
+    }
+}
+
+impl Write for BackupWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            BackupWriter::Plain(file) => file.write(buf),
+            BackupWriter::Encrypted(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            BackupWriter::Plain(file) => file.flush(),
+            BackupWriter::Encrypted(writer) => writer.flush(),
+        }
+    }
+}
@@ -1,15 +1,66 @@
-use crate::common::error::{Result, ToError};
+use crate::common::error::{Error, ErrorKind, Result, ToError};
 
+use openssl::pkey::{Id, PKey, Public};
+use regex::Regex;
 use serde::Deserialize;
-use std::fs::read_to_string;
-use std::path::Path;
+use std::fs::{read, read_to_string};
+use std::path::{Path, PathBuf};
+
+// list entries with this prefix are regular expressions, all others are globs
+const REGEX_PREFIX: &str = "regex:";
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum FilterConfig {
+    /// a single regular expression matched against the full source path of files
+    Regex(String),
+    /// glob or regular expression patterns matched against the path relative to the item source
+    Lists {
+        #[serde(default)]
+        allow: Vec<String>,
+        #[serde(default)]
+        deny: Vec<String>,
+    },
+}
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct ItemConfig {
     pub source: String,
     pub target: Option<String>,
-    // TODO: filter.allow, filter.deny
-    pub filter: Option<String>,
+    pub filter: Option<FilterConfig>,
+    /// number of directory levels below source to descend into
+    pub max_depth: Option<usize>,
+    /// maximum size in bytes of all files of the item
+    pub max_size: Option<u64>,
+    /// store symbolic links as links instead of the files they point to
+    #[serde(default)]
+    pub preserve_symlinks: bool,
+    /// override the owner of all entries
+    pub uid: Option<u32>,
+    /// override the group of all entries
+    pub gid: Option<u32>,
+    /// override the permissions of files, octal string like "0640"
+    pub mode: Option<String>,
+}
+
+impl ItemConfig {
+    pub fn mode(&self) -> Result<Option<u32>> {
+        if let Some(mode) = &self.mode {
+            let digits = mode.trim().trim_start_matches("0o");
+            match u32::from_str_radix(digits, 8) {
+                Ok(mode) if mode <= 0o7777 => Ok(Some(mode)),
+                _ => Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!(
+                        "Invalid mode '{}' for backup item '{}', expected an octal number like \"0640\"",
+                        mode, self.source
+                    ),
+                )),
+            }
+        } else {
+            Ok(None)
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -18,8 +69,231 @@ pub(crate) struct VolumeConfig {
     pub items: Vec<ItemConfig>,
 }
 
-pub(crate) fn backup_cfg_from_file<P: AsRef<Path>>(file: P) -> Result<Vec<VolumeConfig>> {
-    serde_yaml::from_str(
+#[derive(Debug, Deserialize)]
+pub(crate) struct EncryptionConfig {
+    /// path of a PEM encoded RSA public key
+    pub public_key: PathBuf,
+}
+
+/// A service or container that is stopped while the backup is created
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ServiceConfig {
+    /// a systemd unit
+    Systemd(String),
+    /// a SysV init service
+    Sysv(String),
+    /// a docker or balena-engine container
+    Container(String),
+}
+
+/// Commands run with 'sh -c' before and after creating the backup
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct HooksConfig {
+    #[serde(default)]
+    pub pre: Vec<String>,
+    #[serde(default)]
+    pub post: Vec<String>,
+    /// timeout in seconds for every command
+    pub timeout: Option<u64>,
+}
+
+/// The backup configuration, a list of volumes is accepted as configuration
+/// without encryption, services or hooks
+#[derive(Debug, Deserialize)]
+pub(crate) struct BackupConfig {
+    pub encryption: Option<EncryptionConfig>,
+    #[serde(default)]
+    pub stop: Vec<ServiceConfig>,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    pub volumes: Vec<VolumeConfig>,
+}
+
+impl BackupConfig {
+    /// Load the public key the archive is encrypted with, if configured
+    pub fn public_key(&self) -> Result<Option<PKey<Public>>> {
+        if let Some(encryption) = &self.encryption {
+            let pem = read(&encryption.public_key).upstream_with_context(&format!(
+                "Failed to read backup public key from '{}'",
+                encryption.public_key.display()
+            ))?;
+            let public_key =
+                PKey::public_key_from_pem(&pem).upstream_with_context(&format!(
+                    "Failed to parse backup public key from '{}', expected a PEM encoded RSA public key",
+                    encryption.public_key.display()
+                ))?;
+            if public_key.id() != Id::RSA {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!(
+                        "The backup public key in '{}' is not an RSA key",
+                        encryption.public_key.display()
+                    ),
+                ));
+            }
+            Ok(Some(public_key))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Pattern {
+    /// a glob without '/' is matched against the file name only
+    Glob {
+        regex: Regex,
+        name_only: bool,
+    },
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> Result<Pattern> {
+        if let Some(regex) = pattern.strip_prefix(REGEX_PREFIX) {
+            Ok(Pattern::Regex(Regex::new(regex).upstream_with_context(
+                &format!(
+                    "Failed to create regular expression from filter '{}'",
+                    regex
+                ),
+            )?))
+        } else {
+            Ok(Pattern::Glob {
+                regex: Regex::new(&glob_to_regex(pattern)).upstream_with_context(&format!(
+                    "Failed to create regular expression from glob '{}'",
+                    pattern
+                ))?,
+                name_only: !pattern.contains('/'),
+            })
+        }
+    }
+
+    fn is_match(&self, rel_path: &Path) -> bool {
+        match self {
+            Pattern::Glob { regex, name_only } => {
+                if *name_only {
+                    rel_path
+                        .file_name()
+                        .is_some_and(|name| regex.is_match(&name.to_string_lossy()))
+                } else {
+                    regex.is_match(&rel_path.to_string_lossy())
+                }
+            }
+            Pattern::Regex(regex) => regex.is_match(&rel_path.to_string_lossy()),
+        }
+    }
+}
+
+/// Translate a glob to an anchored regular expression. '*' and '?' do not
+/// match '/', '**' matches across directories.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(curr) = chars.next() {
+        match curr {
+            '*' => {
+                if chars.peek() == Some(&'*') {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        regex.push_str("(?:.*/)?");
+                    } else {
+                        regex.push_str(".*");
+                    }
+                } else {
+                    regex.push_str("[^/]*");
+                }
+            }
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    regex.push('^');
+                }
+                for class_char in chars.by_ref() {
+                    if class_char == ']' {
+                        break;
+                    }
+                    if class_char == '\\' || class_char == '[' {
+                        regex.push('\\');
+                    }
+                    regex.push(class_char);
+                }
+                regex.push(']');
+            }
+            _ => regex.push_str(&regex::escape(&curr.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// The compiled filter of a backup item
+#[derive(Debug, Default)]
+pub(crate) struct ItemFilter {
+    regex: Option<Regex>,
+    allow: Vec<Pattern>,
+    deny: Vec<Pattern>,
+}
+
+impl ItemFilter {
+    pub fn new(config: Option<&FilterConfig>) -> Result<ItemFilter> {
+        match config {
+            None => Ok(ItemFilter::default()),
+            Some(FilterConfig::Regex(filter)) => Ok(ItemFilter {
+                regex: Some(Regex::new(filter).upstream_with_context(&format!(
+                    "Failed to create regular expression from filter '{}'",
+                    filter
+                ))?),
+                ..ItemFilter::default()
+            }),
+            Some(FilterConfig::Lists { allow, deny }) => Ok(ItemFilter {
+                regex: None,
+                allow: allow
+                    .iter()
+                    .map(|pattern| Pattern::new(pattern))
+                    .collect::<Result<Vec<Pattern>>>()?,
+                deny: deny
+                    .iter()
+                    .map(|pattern| Pattern::new(pattern))
+                    .collect::<Result<Vec<Pattern>>>()?,
+            }),
+        }
+    }
+
+    /// Denied files and directories are skipped, directories are not descended into
+    pub fn is_denied(&self, rel_path: &Path) -> bool {
+        self.deny.iter().any(|pattern| pattern.is_match(rel_path))
+    }
+
+    /// Whether a file is included, all files are if no allow patterns are configured
+    pub fn is_allowed(&self, source_path: &Path, rel_path: &Path) -> bool {
+        if let Some(regex) = &self.regex {
+            regex.is_match(&source_path.to_string_lossy())
+        } else {
+            self.allow.is_empty() || self.allow.iter().any(|pattern| pattern.is_match(rel_path))
+        }
+    }
+}
+
+fn parse_backup_cfg(config: &str) -> std::result::Result<BackupConfig, serde_yaml::Error> {
+    let value: serde_yaml::Value = serde_yaml::from_str(config)?;
+    if value.is_sequence() {
+        Ok(BackupConfig {
+            encryption: None,
+            stop: Vec::new(),
+            hooks: HooksConfig::default(),
+            volumes: serde_yaml::from_value(value)?,
+        })
+    } else {
+        serde_yaml::from_value(value)
+    }
+}
+
+pub(crate) fn backup_cfg_from_file<P: AsRef<Path>>(file: P) -> Result<BackupConfig> {
+    parse_backup_cfg(
         &read_to_string(file.as_ref()).upstream_with_context(&format!(
             "Failed to read backup configuration from file: '{}'",
             file.as_ref().display()
@@ -30,3 +304,107 @@ pub(crate) fn backup_cfg_from_file<P: AsRef<Path>>(file: P) -> Result<Vec<Volume
         file.as_ref().display()
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKUP_CFG: &str = r#"
+- volume: data
+  items:
+  - source: /home/user/data
+    filter: 'balena-.*'
+  - source: /home/user/config
+    target: config
+    filter:
+      allow: ['*.conf', 'regex:^keys/.*\.pem$']
+      deny: ['**/tmp', 'old-*']
+    max_depth: 2
+    max_size: 1048576
+    preserve_symlinks: true
+    uid: 1000
+    mode: "0640"
+"#;
+
+    #[test]
+    fn parses_item_filters() {
+        let config: Vec<VolumeConfig> = serde_yaml::from_str(BACKUP_CFG).unwrap();
+        let items = &config[0].items;
+
+        let legacy = ItemFilter::new(items[0].filter.as_ref()).unwrap();
+        assert!(legacy.is_allowed(Path::new("/home/user/data/balena-1"), Path::new("balena-1")));
+        assert!(!legacy.is_allowed(Path::new("/home/user/data/other"), Path::new("other")));
+        assert_eq!(items[0].mode().unwrap(), None);
+
+        let item = &items[1];
+        let filter = ItemFilter::new(item.filter.as_ref()).unwrap();
+        let source = Path::new("/home/user/config");
+        assert!(filter.is_allowed(&source.join("app/app.conf"), Path::new("app/app.conf")));
+        assert!(filter.is_allowed(&source.join("keys/dev.pem"), Path::new("keys/dev.pem")));
+        assert!(!filter.is_allowed(&source.join("app/dev.pem"), Path::new("app/dev.pem")));
+        assert!(filter.is_denied(Path::new("tmp")));
+        assert!(filter.is_denied(Path::new("app/cache/tmp")));
+        assert!(filter.is_denied(Path::new("app/old-app.conf")));
+        assert!(!filter.is_denied(Path::new("app/tmpfile")));
+
+        assert_eq!(item.max_depth, Some(2));
+        assert_eq!(item.max_size, Some(1048576));
+        assert!(item.preserve_symlinks);
+        assert_eq!(item.uid, Some(1000));
+        assert_eq!(item.gid, None);
+        assert_eq!(item.mode().unwrap(), Some(0o640));
+    }
+
+    #[test]
+    fn parses_backup_config_formats() {
+        let legacy = parse_backup_cfg(BACKUP_CFG).unwrap();
+        assert!(legacy.encryption.is_none());
+        assert!(legacy.stop.is_empty());
+        assert!(legacy.hooks.pre.is_empty());
+        assert_eq!(legacy.volumes.len(), 1);
+        assert!(legacy.public_key().unwrap().is_none());
+
+        let config = parse_backup_cfg(
+            r#"
+encryption:
+  public_key: /etc/takeover/backup.pub
+stop:
+- systemd: postgresql.service
+- sysv: mysql
+- container: db
+hooks:
+  pre: ['db-dump --all']
+  post: ['rm -f /tmp/db.dump']
+  timeout: 30
+volumes:
+- volume: data
+  items:
+  - source: /home/user/data
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.stop,
+            vec![
+                ServiceConfig::Systemd("postgresql.service".to_string()),
+                ServiceConfig::Sysv("mysql".to_string()),
+                ServiceConfig::Container("db".to_string()),
+            ]
+        );
+        assert_eq!(config.hooks.pre, vec!["db-dump --all"]);
+        assert_eq!(config.hooks.post, vec!["rm -f /tmp/db.dump"]);
+        assert_eq!(config.hooks.timeout, Some(30));
+        assert_eq!(
+            config.encryption.unwrap().public_key,
+            PathBuf::from("/etc/takeover/backup.pub")
+        );
+        assert_eq!(config.volumes[0].items[0].source, "/home/user/data");
+    }
+
+    #[test]
+    fn translates_globs() {
+        assert_eq!(glob_to_regex("*.conf"), r"^[^/]*\.conf$");
+        assert_eq!(glob_to_regex("**/cache/*"), r"^(?:.*/)?cache/[^/]*$");
+        assert_eq!(glob_to_regex("log[!0-9]?"), r"^log[^0-9][^/]$");
+    }
+}
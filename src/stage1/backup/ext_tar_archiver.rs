@@ -1,48 +1,75 @@
+use flate2::{write::GzEncoder, Compression};
 use log::{debug, warn};
-use std::fs::{create_dir_all, remove_dir_all};
+use nix::sys::stat::{utimensat, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use openssl::pkey::{PKey, Public};
+use std::fs::{
+    create_dir_all, read_link, remove_dir_all, remove_file, set_permissions, symlink_metadata,
+    write, Permissions,
+};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink as unix_symlink, MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use tar::{Archive, Builder, EntryType};
 
 use crate::common::system::symlink;
 use crate::stage1::utils::mktemp;
 use crate::{
     common::{
-        call,
-        defs::{BACKUP_ARCH_NAME, TAR_CMD},
+        defs::TAR_CMD,
         dir_exists,
-        error::{Error, ErrorKind, Result, ToError},
+        error::{Result, ToError},
         path_append,
     },
-    stage1::backup::archiver::Archiver,
+    stage1::backup::archiver::{Archiver, BackupEntry, BackupWriter, EntryKind},
 };
 
-// use external tar / gzip for archiving
-// strategy is to link  (ln -s ) all files / directories to a temporary directory
-// and tar/gizip that directory on finish
+// uid, gid and mode overrides of a group of files
+type Overrides = (Option<u32>, Option<u32>, Option<u32>);
+
+// use external tar for archiving
+// strategy is to link  (ln -s ) all files to a temporary directory and
+// tar that directory on finish, dereferencing the links.
+// Directories and preserved symlinks are recreated in the temporary
+// directory with the permissions of their source and archived with its
+// ownership, files are
+// archived in groups sharing the same overrides so the result matches the
+// archive created by RustTarArchiver. The output of tar is streamed into the
+// compressed and optionally encrypted archive, nothing unencrypted is written
+// to disk.
 #[cfg(target_os = "linux")]
 pub(crate) struct ExtTarArchiver {
     tmp_dir: PathBuf,
     archive: PathBuf,
+    builder: Builder<GzEncoder<BackupWriter>>,
+    // directories and symlinks, archived without dereferencing
+    entries: Vec<BackupEntry>,
+    files: Vec<(Overrides, Vec<PathBuf>)>,
 }
 
 #[cfg(target_os = "linux")]
 impl ExtTarArchiver {
-    pub fn new<P: AsRef<Path>>(file: P) -> Result<ExtTarArchiver> {
+    pub fn new<P: AsRef<Path>>(
+        file: P,
+        public_key: Option<&PKey<Public>>,
+    ) -> Result<ExtTarArchiver> {
         const NO_PATH: Option<&Path> = None;
         Ok(ExtTarArchiver {
             tmp_dir: mktemp(true, None, None, NO_PATH)?,
             archive: PathBuf::from(file.as_ref()),
+            builder: Builder::new(GzEncoder::new(
+                BackupWriter::create(file.as_ref(), public_key)?,
+                Compression::default(),
+            )),
+            entries: Vec::new(),
+            files: Vec::new(),
         })
     }
-}
 
-#[cfg(target_os = "linux")]
-impl Archiver for ExtTarArchiver {
-    fn add_file(&mut self, target: &Path, source: &Path) -> Result<()> {
-        debug!(
-            "ExtTarArchiver::add_file: '{}' , '{}'",
-            target.display(),
-            source.display()
-        );
+    fn create_parent_dir(&self, target: &Path) -> Result<()> {
         if let Some(parent_dir) = target.parent() {
             let parent_dir = path_append(&self.tmp_dir, parent_dir);
             if !dir_exists(&parent_dir).upstream_with_context(&format!(
@@ -50,7 +77,7 @@ impl Archiver for ExtTarArchiver {
                 parent_dir.display()
             ))? {
                 debug!(
-                    "ExtTarArchiver::add_file: create directory '{}'",
+                    "ExtTarArchiver::add_entry: create directory '{}'",
                     parent_dir.display()
                 );
                 create_dir_all(&parent_dir).upstream_with_context(&format!(
@@ -59,36 +86,222 @@ impl Archiver for ExtTarArchiver {
                 ))?;
             }
         }
+        Ok(())
+    }
+
+    /// Apply permissions and modification time of the source to the
+    /// recreated directory or symlink, returns the uid and gid to archive it
+    /// with. The ownership is set by tar, so no root privileges are needed.
+    fn apply_metadata(&self, entry: &BackupEntry) -> Result<(u32, u32)> {
+        let path = path_append(&self.tmp_dir, &entry.target);
+        let source_md = symlink_metadata(&entry.source).upstream_with_context(&format!(
+            "Failed to retrieve metadata for '{}'",
+            entry.source.display()
+        ))?;
+
+        if entry.kind == EntryKind::Dir {
+            set_permissions(&path, Permissions::from_mode(source_md.mode() & 0o7777))
+                .upstream_with_context(&format!(
+                    "Failed to set permissions of '{}'",
+                    path.display()
+                ))?;
+        }
+
+        let mtime = TimeSpec::new(source_md.mtime(), 0);
+        utimensat(None, &path, &mtime, &mtime, UtimensatFlags::NoFollowSymlink)
+            .upstream_with_context(&format!(
+                "Failed to set modification time of '{}'",
+                path.display()
+            ))?;
+        Ok((
+            entry.uid.unwrap_or(source_md.uid()),
+            entry.gid.unwrap_or(source_md.gid()),
+        ))
+    }
+
+    /// Run tar on the listed paths of the temporary directory and append the
+    /// entries it writes to stdout to the archive
+    fn run_tar(&mut self, args: &[&str], list: &[PathBuf]) -> Result<()> {
+        let list_path = PathBuf::from(format!("{}.list", self.tmp_dir.display()));
+        let mut content = Vec::new();
+        for path in list {
+            content.extend_from_slice(path.as_os_str().as_bytes());
+            content.push(0);
+        }
+        write(&list_path, content).upstream_with_context(&format!(
+            "Failed to write file list '{}'",
+            list_path.display()
+        ))?;
+
+        let tmp_dir = self.tmp_dir.to_string_lossy().to_string();
+        let list_arg = list_path.to_string_lossy().to_string();
+        let mut tar_args = vec!["--numeric-owner", "--no-recursion", "--null"];
+        tar_args.extend_from_slice(args);
+        tar_args.extend_from_slice(&["-cf", "-", "-C", &tmp_dir, "-T", &list_arg]);
+        debug!("ExtTarArchiver::run_tar: {} {:?}", TAR_CMD, tar_args);
+
+        let res = self.append_tar_output(&tar_args);
+        let _res = remove_file(&list_path);
+        res.upstream_with_context(&format!(
+            "Failed to create archive in '{}'",
+            self.archive.display()
+        ))
+    }
+
+    fn append_tar_output(&mut self, tar_args: &[&str]) -> io::Result<()> {
+        let mut child = Command::new(TAR_CMD)
+            .args(tar_args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // read stderr concurrently so tar does not block on a full pipe
+        let mut stderr = child.stderr.take();
+        let stderr_reader = thread::spawn(move || {
+            let mut output = String::new();
+            if let Some(stderr) = stderr.as_mut() {
+                let _res = stderr.read_to_string(&mut output);
+            }
+            output
+        });
+
+        let res = match child.stdout.take() {
+            Some(stdout) => self.append_entries(stdout),
+            None => Ok(()),
+        };
+        if res.is_err() {
+            // tar would block on the pipe nobody reads any more
+            let _res = child.kill();
+        }
+        let status = child.wait()?;
+        let stderr = stderr_reader.join().unwrap_or_default();
+        res?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!(
+                "{} failed with {}, stderr: {}",
+                TAR_CMD, status, stderr
+            )))
+        }
+    }
 
-        let lnk_target = path_append(&self.tmp_dir, target);
+    fn append_entries<R: Read>(&mut self, tar_output: R) -> io::Result<()> {
+        let mut archive = Archive::new(tar_output);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let mut header = entry.header().clone();
+            let path = entry.path()?.into_owned();
+            match header.entry_type() {
+                EntryType::Symlink | EntryType::Link => {
+                    let link_name = entry.link_name()?.unwrap_or_default().into_owned();
+                    self.builder.append_link(&mut header, path, link_name)?
+                }
+                _ => self.builder.append_data(&mut header, path, &mut entry)?,
+            }
+        }
+        Ok(())
+    }
+}
 
+#[cfg(target_os = "linux")]
+impl Archiver for ExtTarArchiver {
+    fn add_entry(&mut self, entry: &BackupEntry) -> Result<()> {
         debug!(
-            "ExtTarArchiver::add_file: link '{}' to '{}'",
-            source.display(),
-            lnk_target.display()
+            "ExtTarArchiver::add_entry: {:?} '{}' , '{}'",
+            entry.kind,
+            entry.target.display(),
+            entry.source.display()
         );
 
-        symlink(source, &lnk_target).upstream_with_context(&format!(
-            "Failed to link '{}' to '{}'",
-            source.display(),
-            lnk_target.display()
-        ))?;
+        self.create_parent_dir(&entry.target)?;
+        let lnk_target = path_append(&self.tmp_dir, &entry.target);
+
+        match entry.kind {
+            EntryKind::File => {
+                debug!(
+                    "ExtTarArchiver::add_entry: link '{}' to '{}'",
+                    entry.source.display(),
+                    lnk_target.display()
+                );
+
+                symlink(&entry.source, &lnk_target).upstream_with_context(&format!(
+                    "Failed to link '{}' to '{}'",
+                    entry.source.display(),
+                    lnk_target.display()
+                ))?;
+
+                let overrides = (entry.uid, entry.gid, entry.mode);
+                if let Some((_, files)) =
+                    self.files.iter_mut().find(|(group, _)| *group == overrides)
+                {
+                    files.push(entry.target.clone());
+                } else {
+                    self.files.push((overrides, vec![entry.target.clone()]));
+                }
+            }
+            EntryKind::Dir => {
+                create_dir_all(&lnk_target).upstream_with_context(&format!(
+                    "Failed to create directory '{}'",
+                    lnk_target.display()
+                ))?;
+                self.entries.push(entry.clone());
+            }
+            EntryKind::Symlink => {
+                let link_target = read_link(&entry.source).upstream_with_context(&format!(
+                    "Failed to read link '{}'",
+                    entry.source.display()
+                ))?;
+                unix_symlink(&link_target, &lnk_target).upstream_with_context(&format!(
+                    "Failed to link '{}' to '{}'",
+                    link_target.display(),
+                    lnk_target.display()
+                ))?;
+                self.entries.push(entry.clone());
+            }
+        }
         Ok(())
     }
 
     fn finish(&mut self) -> Result<()> {
-        let _res = call_command!(
-            TAR_CMD,
-            &[
-                "-h",
-                "-czf",
-                BACKUP_ARCH_NAME,
-                "-C",
-                &*self.tmp_dir.to_string_lossy(),
-                ".",
-            ],
-            &format!("Failed to create archive in '{}'", self.archive.display(),)
-        )?;
+        // all entries exist now, so the directory times are not changed any
+        // more, entries are archived in groups sharing the same ownership
+        let mut owners: Vec<((u32, u32), Vec<PathBuf>)> = Vec::new();
+        for entry in &self.entries {
+            let owner = self.apply_metadata(entry)?;
+            if let Some((_, list)) = owners.iter_mut().find(|(group, _)| *group == owner) {
+                list.push(entry.target.clone());
+            } else {
+                owners.push((owner, vec![entry.target.clone()]));
+            }
+        }
+
+        for ((uid, gid), list) in owners {
+            let owner = format!("--owner=:{}", uid);
+            let group = format!("--group=:{}", gid);
+            self.run_tar(&[&owner, &group], &list)?;
+        }
+
+        for ((uid, gid, mode), files) in self.files.clone() {
+            let owner = uid.map(|uid| format!("--owner=:{}", uid));
+            let group = gid.map(|gid| format!("--group=:{}", gid));
+            let mode = mode.map(|mode| format!("--mode={:04o}", mode));
+            let mut args = vec!["-h"];
+            for arg in [&owner, &group, &mode].iter().copied().flatten() {
+                args.push(arg);
+            }
+            self.run_tar(&args, &files)?;
+        }
+
+        self.builder
+            .finish()
+            .and_then(|_| self.builder.get_mut().try_finish())
+            .and_then(|_| self.builder.get_mut().get_mut().finish())
+            .upstream_with_context(&format!(
+                "Failed to compress the backup to '{}'",
+                self.archive.display()
+            ))?;
 
         if let Err(why) = remove_dir_all(&self.tmp_dir) {
             warn!(
@@ -0,0 +1,157 @@
+use log::{debug, info, warn};
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread::{self, sleep, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::common::{
+    defs::SHELL_CMD,
+    error::{Error, ErrorKind, Result, ToError},
+};
+
+// timeout of a hook command if none is configured
+const DEFAULT_HOOK_TIMEOUT: u64 = 60;
+const HOOK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Run the pre or post backup hook commands in order. Fails on the first
+/// command that fails or does not finish within timeout seconds.
+pub(crate) fn run_hooks(kind: &str, commands: &[String], timeout: Option<u64>) -> Result<()> {
+    let timeout = Duration::from_secs(timeout.unwrap_or(DEFAULT_HOOK_TIMEOUT));
+    for command in commands {
+        run_hook(kind, command, timeout)?;
+    }
+    Ok(())
+}
+
+/// Runs the post backup hooks when dropped, so they run once the pre hooks
+/// ran, however creating the backup ends. Failures are logged.
+pub(crate) struct PostHooks<'a> {
+    commands: &'a [String],
+    timeout: Option<u64>,
+}
+
+impl<'a> PostHooks<'a> {
+    pub fn new(commands: &'a [String], timeout: Option<u64>) -> PostHooks<'a> {
+        PostHooks { commands, timeout }
+    }
+}
+
+impl Drop for PostHooks<'_> {
+    fn drop(&mut self) {
+        if let Err(why) = run_hooks("post", self.commands, self.timeout) {
+            warn!("Failed to run post backup hooks: {}", why);
+        }
+    }
+}
+
+fn read_output<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _res = pipe.read_to_end(&mut output);
+        }
+        String::from_utf8_lossy(&output).to_string()
+    })
+}
+
+fn run_hook(kind: &str, command: &str, timeout: Duration) -> Result<()> {
+    info!("Running {} backup hook '{}'", kind, command);
+    let start = Instant::now();
+
+    // in its own process group, so the commands started by the shell can be
+    // killed with it on timeout
+    let mut child = Command::new(SHELL_CMD)
+        .args(["-c", command])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .upstream_with_context(&format!("Failed to run {} backup hook '{}'", kind, command))?;
+
+    let stdout = read_output(child.stdout.take());
+    let stderr = read_output(child.stderr.take());
+
+    let status: Option<ExitStatus> = loop {
+        if let Some(status) = child.try_wait().upstream_with_context(&format!(
+            "Failed to wait for {} backup hook '{}'",
+            kind, command
+        ))? {
+            break Some(status);
+        }
+        if start.elapsed() >= timeout {
+            unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) };
+            let _res = child.wait();
+            break None;
+        }
+        sleep(HOOK_POLL_INTERVAL);
+    };
+
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    for line in stdout.lines() {
+        info!("  {}: {}", kind, line);
+    }
+    for line in stderr.lines() {
+        info!("  {} (stderr): {}", kind, line);
+    }
+
+    match status {
+        Some(status) if status.success() => {
+            info!(
+                "The {} backup hook '{}' succeeded after {:.1}s",
+                kind,
+                command,
+                start.elapsed().as_secs_f64()
+            );
+            Ok(())
+        }
+        Some(status) => Err(Error::with_context(
+            ErrorKind::ExecProcess,
+            &format!(
+                "The {} backup hook '{}' failed with {}, stderr: {}",
+                kind,
+                command,
+                status,
+                stderr.trim()
+            ),
+        )),
+        None => {
+            debug!("run_hook: killed '{}' after {:?}", command, timeout);
+            Err(Error::with_context(
+                ErrorKind::Timeout,
+                &format!(
+                    "The {} backup hook '{}' did not finish within {}s and was killed",
+                    kind,
+                    command,
+                    timeout.as_secs()
+                ),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_hooks_with_timeout() {
+        assert!(run_hooks(
+            "pre",
+            &["echo dumped".to_string(), "true".to_string()],
+            None
+        )
+        .is_ok());
+
+        let err = run_hooks("pre", &["echo failed >&2; exit 3".to_string()], None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ExecProcess);
+
+        // the sleep started by the shell is killed too, so this returns quickly
+        let start = Instant::now();
+        let err = run_hooks("post", &["sleep 30; true".to_string()], Some(1)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -1,27 +1,30 @@
 use crate::{
     common::error::{Result, ToError},
-    stage1::backup::archiver::Archiver,
+    stage1::backup::archiver::{Archiver, BackupEntry, BackupWriter, EntryKind},
 };
 
 use flate2::{write::GzEncoder, Compression};
-use std::fs::File;
+use openssl::pkey::{PKey, Public};
+use std::fs::{metadata, read_link, symlink_metadata, File};
+use std::io;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
-use tar::Builder;
+use tar::{Builder, Header, HeaderMode};
 
 pub(crate) struct RustTarArchiver {
-    archive: Builder<GzEncoder<File>>,
+    archive: Builder<GzEncoder<BackupWriter>>,
 }
 
 // use rust internal tar / gzip for archiving
 
 impl RustTarArchiver {
-    pub fn new<P: AsRef<Path>>(file: P) -> Result<RustTarArchiver> {
+    pub fn new<P: AsRef<Path>>(
+        file: P,
+        public_key: Option<&PKey<Public>>,
+    ) -> Result<RustTarArchiver> {
         Ok(RustTarArchiver {
             archive: Builder::new(GzEncoder::new(
-                File::create(file.as_ref()).upstream_with_context(&format!(
-                    "Failed to create backup in file '{}'",
-                    file.as_ref().display()
-                ))?,
+                BackupWriter::create(file.as_ref(), public_key)?,
                 Compression::default(),
             )),
         })
@@ -29,19 +32,68 @@ impl RustTarArchiver {
 }
 
 impl Archiver for RustTarArchiver {
-    fn add_file(&mut self, target: &Path, source: &Path) -> Result<()> {
-        self.archive
-            .append_path_with_name(source, target)
-            .upstream_with_context(&format!(
-                "Failed to append file: '{}' to archive path: '{}'",
-                source.display(),
-                target.display()
-            ))
+    fn add_entry(&mut self, entry: &BackupEntry) -> Result<()> {
+        let source_md = if entry.kind == EntryKind::Symlink {
+            symlink_metadata(&entry.source)
+        } else {
+            metadata(&entry.source)
+        }
+        .upstream_with_context(&format!(
+            "Failed to retrieve metadata for '{}'",
+            entry.source.display()
+        ))?;
+
+        // numeric ids only, the same as external tar with --numeric-owner
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(&source_md, HeaderMode::Complete);
+        // permission bits only, the file type is part of the entry type
+        header.set_mode(source_md.mode() & 0o7777);
+        if let Some(uid) = entry.uid {
+            header.set_uid(uid.into());
+        }
+        if let Some(gid) = entry.gid {
+            header.set_gid(gid.into());
+        }
+
+        let res = match entry.kind {
+            EntryKind::File => {
+                if let Some(mode) = entry.mode {
+                    header.set_mode(mode);
+                }
+                let file = File::open(&entry.source).upstream_with_context(&format!(
+                    "Failed to open '{}'",
+                    entry.source.display()
+                ))?;
+                self.archive.append_data(&mut header, &entry.target, file)
+            }
+            EntryKind::Dir => {
+                header.set_size(0);
+                self.archive
+                    .append_data(&mut header, &entry.target, io::empty())
+            }
+            EntryKind::Symlink => {
+                header.set_size(0);
+                let link_target = read_link(&entry.source).upstream_with_context(&format!(
+                    "Failed to read link '{}'",
+                    entry.source.display()
+                ))?;
+                self.archive
+                    .append_link(&mut header, &entry.target, link_target)
+            }
+        };
+
+        res.upstream_with_context(&format!(
+            "Failed to append '{}' to archive path: '{}'",
+            entry.source.display(),
+            entry.target.display()
+        ))
     }
 
     fn finish(&mut self) -> Result<()> {
         self.archive
             .finish()
+            .and_then(|_| self.archive.get_mut().try_finish())
+            .and_then(|_| self.archive.get_mut().get_mut().finish())
             .upstream_with_context("Failed to create backup archive")
     }
 }
@@ -0,0 +1,228 @@
+use flate2::{write::GzEncoder, Compression};
+use log::{debug, info};
+use std::cmp::Reverse;
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+use crate::{
+    common::{
+        error::{Result, ToError},
+        format_size_with_unit,
+    },
+    stage1::backup::{
+        archiver::{BackupEntry, EntryKind},
+        collect_item,
+        config::VolumeConfig,
+    },
+};
+
+// tar header and end of archive sizes, file data is padded to full blocks
+const TAR_BLOCK_SIZE: u64 = 512;
+const TAR_END_SIZE: u64 = 2 * TAR_BLOCK_SIZE;
+// bytes compressed from the start of every file to estimate the compression ratio
+const SAMPLE_SIZE: u64 = 64 * 1024;
+// upper limit of sampled bytes for an item to keep the scan quick
+const MAX_ITEM_SAMPLE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Sizes of a single backup item
+#[derive(Debug)]
+pub(crate) struct ItemScan {
+    pub volume: String,
+    pub source: String,
+    pub files: usize,
+    /// size of the file contents
+    pub size: u64,
+    /// size of the item in the uncompressed tar archive
+    pub archive_size: u64,
+    /// estimated size of the item in the compressed archive
+    pub compressed_size: u64,
+}
+
+/// The result of scanning a backup configuration without creating the archive
+#[derive(Debug)]
+pub(crate) struct BackupScan {
+    pub items: Vec<ItemScan>,
+}
+
+impl BackupScan {
+    pub fn size(&self) -> u64 {
+        self.items.iter().map(|item| item.size).sum()
+    }
+
+    /// Space needed to extract the archive, the size of the uncompressed tar archive
+    pub fn archive_size(&self) -> u64 {
+        self.items.iter().map(|item| item.archive_size).sum::<u64>() + TAR_END_SIZE
+    }
+
+    /// Estimated size of backup.tgz
+    pub fn compressed_size(&self) -> u64 {
+        self.items
+            .iter()
+            .map(|item| item.compressed_size)
+            .sum::<u64>()
+            + TAR_END_SIZE
+    }
+
+    /// Log the totals and the items sorted by size, largest first
+    pub fn log_summary(&self) {
+        info!(
+            "Backup scan: {} in {} files, archive size {}, estimated compressed size {}",
+            format_size_with_unit(self.size()),
+            self.items.iter().map(|item| item.files).sum::<usize>(),
+            format_size_with_unit(self.archive_size()),
+            format_size_with_unit(self.compressed_size())
+        );
+
+        let total = self.archive_size().max(1);
+        let mut items: Vec<&ItemScan> = self.items.iter().collect();
+        items.sort_by_key(|item| Reverse(item.archive_size));
+        for item in items {
+            info!(
+                "  {:5.1}% {:>10} ({} compressed) in {} files: volume '{}', source '{}'",
+                item.archive_size as f64 * 100.0 / total as f64,
+                format_size_with_unit(item.archive_size),
+                format_size_with_unit(item.compressed_size),
+                item.files,
+                item.volume,
+                item.source
+            );
+        }
+    }
+}
+
+/// Scan the backup configuration, determining the size of every item and
+/// estimating its compressed size by compressing samples of its files
+pub(crate) fn scan_backup(config: &[VolumeConfig]) -> Result<BackupScan> {
+    let mut items = Vec::new();
+    for volume in config {
+        for item in &volume.items {
+            let entries = collect_item(&volume.volume, item)?;
+            let item_scan = scan_item(&volume.volume, &item.source, &entries)?;
+            debug!("scan_backup: {:?}", item_scan);
+            items.push(item_scan);
+        }
+    }
+    Ok(BackupScan { items })
+}
+
+fn scan_item(volume: &str, source: &str, entries: &[BackupEntry]) -> Result<ItemScan> {
+    let mut files = 0;
+    let mut size = 0;
+    let mut archive_size = 0;
+    let mut sampled = 0;
+    let mut sample_compressed = 0;
+
+    for entry in entries {
+        archive_size += TAR_BLOCK_SIZE;
+        if entry.kind != EntryKind::File {
+            continue;
+        }
+
+        let file_size = entry
+            .source
+            .metadata()
+            .upstream_with_context(&format!(
+                "Failed to retrieve metadata for '{}'",
+                entry.source.display()
+            ))?
+            .len();
+        files += 1;
+        size += file_size;
+        archive_size += file_size.div_ceil(TAR_BLOCK_SIZE) * TAR_BLOCK_SIZE;
+
+        if sampled < MAX_ITEM_SAMPLE_SIZE {
+            let (raw, compressed) = compress_sample(entry)?;
+            sampled += raw;
+            sample_compressed += compressed;
+        }
+    }
+
+    // headers and padding compress well, the sampled ratio is conservative
+    let compressed_size = if sampled > 0 {
+        (archive_size as f64 * sample_compressed as f64 / sampled as f64) as u64
+    } else {
+        archive_size
+    };
+
+    Ok(ItemScan {
+        volume: volume.to_string(),
+        source: source.to_string(),
+        files,
+        size,
+        archive_size,
+        compressed_size,
+    })
+}
+
+/// Compress the start of the file, returns the raw and compressed sizes
+fn compress_sample(entry: &BackupEntry) -> Result<(u64, u64)> {
+    let file = File::open(&entry.source)
+        .upstream_with_context(&format!("Failed to open '{}'", entry.source.display()))?;
+    let mut encoder = GzEncoder::new(CountingSink::default(), Compression::default());
+    let raw = io::copy(&mut file.take(SAMPLE_SIZE), &mut encoder)
+        .upstream_with_context(&format!("Failed to read '{}'", entry.source.display()))?;
+    let compressed = encoder
+        .finish()
+        .upstream_with_context("Failed to compress sample")?
+        .count;
+    Ok((raw, compressed))
+}
+
+#[derive(Default)]
+struct CountingSink {
+    count: u64,
+}
+
+impl Write for CountingSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.count += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    #[test]
+    fn scans_backup_items() {
+        let test_dir = temp_dir().join("takeover-backup-scan");
+        let _res = remove_dir_all(&test_dir);
+        create_dir_all(test_dir.join("big")).unwrap();
+        create_dir_all(test_dir.join("small")).unwrap();
+        write(test_dir.join("big/zeros.bin"), vec![0u8; 1024 * 1024]).unwrap();
+        write(test_dir.join("small/a.txt"), "a").unwrap();
+        write(test_dir.join("small/b.txt"), "bb").unwrap();
+
+        let config: Vec<VolumeConfig> = serde_yaml::from_str(&format!(
+            "- volume: data\n  items:\n  - source: {0}/big\n  - source: {0}/small\n    target: small\n",
+            test_dir.display()
+        ))
+        .unwrap();
+
+        let scan = scan_backup(&config).unwrap();
+        assert_eq!(scan.items.len(), 2);
+        assert_eq!(scan.size(), 1024 * 1024 + 3);
+
+        let big = &scan.items[0];
+        assert_eq!((big.files, big.archive_size), (1, 512 + 1024 * 1024));
+        // zeros compress to a fraction of their size
+        assert!(big.compressed_size < big.archive_size / 10);
+
+        // one directory entry and two files padded to a block each
+        let small = &scan.items[1];
+        assert_eq!((small.files, small.archive_size), (2, 3 * 512 + 2 * 512));
+        assert_eq!(
+            scan.archive_size(),
+            big.archive_size + small.archive_size + 1024
+        );
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+use log::{info, warn};
+use std::fmt::{self, Display};
+use which::which;
+
+use crate::{
+    common::{
+        call,
+        defs::{BALENA_ENGINE_CMD, DOCKER_CMD, SERVICE_CMD, SYSTEMCTL_CMD},
+        error::{Error, ErrorKind, Result},
+        CmdRes,
+    },
+    stage1::backup::config::ServiceConfig,
+};
+
+impl Display for ServiceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceConfig::Systemd(unit) => write!(f, "systemd unit '{}'", unit),
+            ServiceConfig::Sysv(name) => write!(f, "SysV service '{}'", name),
+            ServiceConfig::Container(name) => write!(f, "container '{}'", name),
+        }
+    }
+}
+
+/// Services and containers stopped for the backup. They are started again
+/// when this is dropped, unless the migration is under way.
+#[derive(Debug, Default)]
+pub(crate) struct StoppedServices {
+    services: Vec<ServiceConfig>,
+}
+
+impl StoppedServices {
+    /// Stop the configured services in order. Services that are not running
+    /// are skipped and not started again later. If stopping a service fails,
+    /// the services stopped so far are started again.
+    pub fn stop(config: &[ServiceConfig]) -> Result<StoppedServices> {
+        let mut stopped = StoppedServices::default();
+        for service in config {
+            if !is_running(service)? {
+                info!("The {} is not running", service);
+                continue;
+            }
+            info!("Stopping {} for the backup", service);
+            control(service, "stop")?;
+            stopped.services.push(service.clone());
+        }
+        Ok(stopped)
+    }
+
+    /// Leave the services stopped, the migration is under way
+    pub fn keep_stopped(&mut self) {
+        if !self.services.is_empty() {
+            info!(
+                "Leaving {} service(s) stopped for the migration",
+                self.services.len()
+            );
+            self.services.clear();
+        }
+    }
+
+    /// Start the stopped services in reverse order, failures are logged
+    pub fn restart(&mut self) {
+        while let Some(service) = self.services.pop() {
+            info!("Starting {} again", service);
+            if let Err(why) = control(&service, "start") {
+                warn!("Failed to start {} again: {}", service, why);
+            }
+        }
+    }
+}
+
+impl Drop for StoppedServices {
+    fn drop(&mut self) {
+        self.restart();
+    }
+}
+
+/// balena-engine on balenaOS, docker anywhere else
+fn container_cmd() -> Result<&'static str> {
+    if which(BALENA_ENGINE_CMD).is_ok() {
+        Ok(BALENA_ENGINE_CMD)
+    } else if which(DOCKER_CMD).is_ok() {
+        Ok(DOCKER_CMD)
+    } else {
+        Err(Error::with_context(
+            ErrorKind::NotFound,
+            &format!(
+                "Neither '{}' nor '{}' could be found to stop containers",
+                BALENA_ENGINE_CMD, DOCKER_CMD
+            ),
+        ))
+    }
+}
+
+fn service_call(service: &ServiceConfig, action: &str) -> Result<CmdRes> {
+    match service {
+        ServiceConfig::Systemd(unit) => call(SYSTEMCTL_CMD, &[action, unit], true),
+        ServiceConfig::Sysv(name) => call(SERVICE_CMD, &[name, action], true),
+        ServiceConfig::Container(name) => call(container_cmd()?, &[action, name], true),
+    }
+}
+
+fn is_running(service: &ServiceConfig) -> Result<bool> {
+    match service {
+        ServiceConfig::Systemd(_) => Ok(service_call(service, "is-active")?.status.success()),
+        ServiceConfig::Sysv(_) => Ok(service_call(service, "status")?.status.success()),
+        ServiceConfig::Container(name) => {
+            let cmd_res = call(
+                container_cmd()?,
+                &["inspect", "--format", "{{.State.Running}}", name],
+                true,
+            )?;
+            if cmd_res.status.success() {
+                Ok(cmd_res.stdout == "true")
+            } else {
+                Err(Error::with_context(
+                    ErrorKind::NotFound,
+                    &format!(
+                        "Failed to inspect {}, stderr: {}",
+                        service,
+                        cmd_res.stderr.trim()
+                    ),
+                ))
+            }
+        }
+    }
+}
+
+fn control(service: &ServiceConfig, action: &str) -> Result<()> {
+    let cmd_res = service_call(service, action)?;
+    if cmd_res.status.success() {
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::ExecProcess,
+            &format!(
+                "Failed to {} {}, stderr: {}",
+                action,
+                service,
+                cmd_res.stderr.trim()
+            ),
+        ))
+    }
+}
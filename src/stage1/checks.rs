@@ -1,12 +1,22 @@
-use log::error;
+use std::fs::read_to_string;
 
-use super::{block_device_info::BlockDeviceInfo, get_block_dev_info, get_log_device};
-use crate::common::{is_admin, Error, Options, Result};
+use log::{error, info};
+
+use super::{
+    block_device_info::{BlockDevice, BlockDeviceInfo},
+    get_block_dev_info, get_flash_device, get_log_device,
+    image_info::ImageInfo,
+    migrate_info::MigrateInfo,
+};
+use crate::common::{format_size_with_unit, is_admin, Error, Options, Result, ToError};
+
+// /sys/class/block/<dev>/size is always in 512 byte sectors
+const SYSFS_SECTOR_SIZE: u64 = 512;
 
 /// Performs checks to ensure that the program can run properly with the
 /// provided command-line options. Returns an error if the program cannot run
-/// for some reason.
-pub(crate) fn do_early_checks(opts: &Options) -> Result<()> {
+/// for some reason, otherwise the information gathered from the image.
+pub(crate) fn do_early_checks(opts: &Options, mig_info: &MigrateInfo) -> Result<ImageInfo> {
     if !is_admin()? {
         error!("please run this program as root");
         return Err(Error::displayed());
@@ -19,7 +29,84 @@ pub(crate) fn do_early_checks(opts: &Options) -> Result<()> {
         return Err(Error::displayed());
     }
 
-    Ok(())
+    check_image(opts, mig_info, &block_dev_info)
+}
+
+/// Checks that the balenaOS image contains the expected partitions, fits on
+/// the flash device and was built for the device type in config.json.
+fn check_image(
+    opts: &Options,
+    mig_info: &MigrateInfo,
+    block_dev_info: &BlockDeviceInfo,
+) -> Result<ImageInfo> {
+    let image_info = match ImageInfo::new(mig_info.image_path(), &opts.work_dir()) {
+        Ok(image_info) => image_info,
+        Err(why) => {
+            error!(
+                "The balenaOS image '{}' could not be validated, error: {}",
+                mig_info.image_path().display(),
+                why
+            );
+            return Err(Error::displayed());
+        }
+    };
+
+    let flash_dev = get_flash_device(opts, block_dev_info)?;
+    let dev_size = get_device_size(flash_dev.as_ref())?;
+    if image_info.required_size() > dev_size {
+        error!(
+            "The balenaOS image requires {} but the flash device '{}' has only {}",
+            format_size_with_unit(image_info.required_size()),
+            flash_dev.get_dev_path().display(),
+            format_size_with_unit(dev_size)
+        );
+        return Err(Error::displayed());
+    }
+
+    // --change-dt-to overrides the device type in config.json
+    let device_type = if let Some(change_to) = opts.change_dt_to() {
+        change_to.to_owned()
+    } else {
+        mig_info.balena_cfg().get_device_type()?
+    };
+
+    if image_info.device_type() != device_type {
+        error!(
+            "The balenaOS image was built for device type '{}' but config.json requires '{}'",
+            image_info.device_type(),
+            device_type
+        );
+        return Err(Error::displayed());
+    }
+
+    mig_info.device().check_image(&image_info)?;
+
+    // the boot firmware is written from memory in stage2, see get_required_space
+    let firmware_space = mig_info.device().boot_firmware_space(&image_info)?;
+    if firmware_space > 0 {
+        info!(
+            "Writing the boot firmware of the balenaOS image takes {} of memory",
+            format_size_with_unit(firmware_space)
+        );
+    }
+
+    info!(
+        "The balenaOS image fits on '{}' and matches device type '{}'",
+        flash_dev.get_dev_path().display(),
+        device_type
+    );
+
+    Ok(image_info)
+}
+
+fn get_device_size(device: &dyn BlockDevice) -> Result<u64> {
+    let size_path = format!("/sys/class/block/{}/size", device.get_name());
+    let sectors = read_to_string(&size_path)
+        .upstream_with_context(&format!("Failed to read '{}'", size_path))?
+        .trim()
+        .parse::<u64>()
+        .upstream_with_context(&format!("Failed to parse device size from '{}'", size_path))?;
+    Ok(sectors * SYSFS_SECTOR_SIZE)
 }
 
 /// Checks if the log device requested with `--log-device` is suitable for
@@ -1,15 +1,108 @@
 use std::fmt::{self, Debug, Display};
+use std::path::Path;
 
-use crate::stage1::defs::DeviceType;
+use log::warn;
 
+use crate::{
+    common::{
+        device_type::DeviceType,
+        stage2_config::{EepromUpdate, Stage2Config},
+        Options, Result,
+    },
+    stage1::{image_info::ImageInfo, support_matrix::SupportMatrix},
+};
+
+/// A board supported by takeover. Stage1 detects the board and checks it,
+/// stage2 gets the board back from the DeviceType in the stage2 config and
+/// runs its flashing steps.
+///
+/// The device type slugs and operating systems supported by a board are kept
+/// in the support matrix.
 pub(crate) trait Device {
-    fn supports_device_type(&self, dev_type: &str) -> bool;
     fn get_device_type(&self) -> DeviceType;
+
+    /// Can the board run balenaOS for the device type slug, by default any slug
+    /// of its device type in the support matrix
+    fn supports_device_type(&self, matrix: &SupportMatrix, slug: &str) -> bool {
+        matrix.supports_device_type(self.get_device_type(), slug)
+    }
+
+    /// Board specific checks of the balenaOS image in stage1
+    fn check_image(&self, _image_info: &ImageInfo) -> Result<()> {
+        Ok(())
+    }
+
+    /// The memory writing the boot firmware of the image takes in stage2,
+    /// added to the space checked before the image is flashed
+    fn boot_firmware_space(&self, _image_info: &ImageInfo) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Stage1 checks for making the firmware boot flash_dev after the
+    /// migration, called when flashing another disk than the one the running
+    /// system booted from. The boot of the running system is left untouched,
+    /// an EEPROM update is only staged in takeover_dir for set_boot_order.
+    fn prepare_boot_device(
+        &self,
+        opts: &Options,
+        _takeover_dir: &Path,
+        flash_dev: &Path,
+    ) -> Result<Option<EepromUpdate>> {
+        warn_boot_order(self.get_device_type(), opts, flash_dev);
+        Ok(None)
+    }
+
+    /// Executables stage2 needs on this board, copied to the takeover directory
+    fn stage2_commands(&self, _opts: &Options) -> Result<Vec<&'static str>> {
+        Ok(Vec::new())
+    }
+
+    /// Stage2 steps run before the image is flashed, a failure restores the
+    /// original system
+    fn pre_flash(&self, _s2_cfg: &Stage2Config) -> Result<()> {
+        Ok(())
+    }
+
+    /// Stage2 steps run after flashing with the boot partition of the new
+    /// image mounted on boot_mp
+    fn setup_boot(&self, _s2_cfg: &Stage2Config, _boot_mp: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    /// Stage2 steps making the firmware boot the new disk, run once it is
+    /// flashed, verified and configured
+    fn set_boot_order(&self, _s2_cfg: &Stage2Config) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether the board needs write_boot_firmware, the rootA partition of
+    /// the new image is only mounted for these
+    fn has_boot_firmware(&self) -> bool {
+        false
+    }
+
+    /// Stage2 steps writing the boot firmware shipped in the new image, with
+    /// its rootA partition mounted on root_mp
+    fn write_boot_firmware(&self, _s2_cfg: &Stage2Config, _root_mp: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Warn that the firmware might keep booting the old disk, unless its boot
+/// sector is invalidated
+pub(crate) fn warn_boot_order(device_type: DeviceType, opts: &Options, flash_dev: &Path) {
+    if !opts.disable_old_boot() {
+        warn!(
+            "Takeover can not change the boot order of the {}, make sure the firmware boots from '{}' or use --disable-old-boot",
+            device_type,
+            flash_dev.display()
+        );
+    }
 }
 
 impl Display for dyn Device {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{},", self.get_device_type())
+        write!(f, "{}", self.get_device_type())
     }
 }
 
@@ -1,41 +1,56 @@
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use std::fs::read_to_string;
 
 use crate::common::ToError;
 use crate::{
-    common::{get_os_name, Error, ErrorKind, Options, Result},
-    stage1::{defs::OSArch, device::Device, utils::get_os_arch},
+    common::{device_type::DeviceType, path_append, Error, ErrorKind, Options, Result},
+    stage1::{
+        defs::OSArch,
+        device::Device,
+        support_matrix::{BoardInfo, OsRelease, SupportMatrix},
+        utils::get_os_arch,
+    },
 };
 
 mod beaglebone;
+pub(crate) mod boot_firmware;
 mod dummy;
+pub(crate) mod efi_boot;
+mod generic_arm;
 mod intel_nuc;
 mod jetson_xavier;
 mod raspberrypi;
 
 const DEVICE_TREE_MODEL: &str = "/proc/device-tree/model";
+const DEVICE_TREE_COMPATIBLE: &str = "/proc/device-tree/compatible";
+const DMI_ID_DIR: &str = "/sys/class/dmi/id";
 
-pub(crate) fn check_os(supported: &[&str], opts: &Options, dev_type: &str) -> Result<bool> {
-    let os_name = get_os_name()?;
-    info!("Detected OS name is {}", os_name);
+pub(crate) fn check_os(
+    matrix: &SupportMatrix,
+    device_type: DeviceType,
+    opts: &Options,
+) -> Result<bool> {
+    let os_release = OsRelease::read()?;
+    info!("Detected OS name is {}", os_release.name);
+    debug!("check_os: {:?}", os_release);
 
-    let os_supported = supported.iter().any(|&r| r == os_name);
+    let os_supported = matrix.supports_os(device_type, &os_release)?;
 
     if !os_supported {
         if opts.os_check() {
             error!(
                 "The OS '{}' has not been tested with {} for device type {}, to override this check use the no-os-check option on the command line",
-                os_name,
-                dev_type,
-                env!("CARGO_PKG_NAME")
+                os_release.name,
+                env!("CARGO_PKG_NAME"),
+                device_type
             );
             Ok(false)
         } else {
             warn!(
                 "The OS '{}' has not been tested with {} for device type {}, proceeding due to no-os-check option",
-                os_name,
-                dev_type,
-                env!("CARGO_PKG_NAME"));
+                os_release.name,
+                env!("CARGO_PKG_NAME"),
+                device_type);
             Ok(true)
         }
     } else {
@@ -43,7 +58,48 @@ pub(crate) fn check_os(supported: &[&str], opts: &Options, dev_type: &str) -> Re
     }
 }
 
-pub(crate) fn get_device(opts: &Options) -> Result<Box<dyn Device>> {
+/// The NUL separated compatible strings of the device tree, most specific first
+fn read_compatible() -> Vec<String> {
+    match read_to_string(DEVICE_TREE_COMPATIBLE) {
+        Ok(compatible) => compatible
+            .split('\0')
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| entry.to_string())
+            .collect(),
+        Err(why) => {
+            debug!(
+                "read_compatible: failed to read '{}', error: {}",
+                DEVICE_TREE_COMPATIBLE, why
+            );
+            Vec::new()
+        }
+    }
+}
+
+/// Get the board for a device type without any checks, used in stage2 to get
+/// the board detected in stage1 back
+pub(crate) fn from_device_type(device_type: DeviceType) -> Box<dyn Device> {
+    match device_type {
+        DeviceType::BeagleboneGreen => Box::new(beaglebone::BeagleboneGreen {}),
+        DeviceType::BeagleboneBlack => Box::new(beaglebone::BeagleboneBlack {}),
+        DeviceType::BeagleboardXM => Box::new(beaglebone::BeagleboardXM {}),
+        DeviceType::IntelNuc => Box::new(intel_nuc::IntelNuc),
+        DeviceType::RaspberryPi1 => Box::new(raspberrypi::RaspberryPi1),
+        DeviceType::RaspberryPi2 => Box::new(raspberrypi::RaspberryPi2),
+        DeviceType::RaspberryPi3 => Box::new(raspberrypi::RaspberryPi3),
+        DeviceType::RaspberryPi4 => Box::new(raspberrypi::RaspberryPi4_64),
+        DeviceType::RaspberryPi400 => Box::new(raspberrypi::RaspberryPi400),
+        DeviceType::RaspberryPiCM4 => Box::new(raspberrypi::RaspberryPiCM4),
+        DeviceType::RaspberryPiZero2W => Box::new(raspberrypi::RaspberryPiZero2W),
+        DeviceType::RaspberryPi5 => Box::new(raspberrypi::RaspberryPi5),
+        DeviceType::Dummy => Box::new(dummy::Dummy::new()),
+        DeviceType::JetsonXavier => Box::new(jetson_xavier::JetsonXavier),
+        DeviceType::JetsonXavierNX => Box::new(jetson_xavier::JetsonXavierNX),
+        DeviceType::GenericArm => Box::new(generic_arm::GenericArm::new(Vec::new(), Vec::new())),
+    }
+}
+
+pub(crate) fn get_device(opts: &Options, matrix: &SupportMatrix) -> Result<Box<dyn Device>> {
     let os_arch = get_os_arch()?;
     info!("Detected OS Architecture is {:?}", os_arch);
 
@@ -52,7 +108,10 @@ pub(crate) fn get_device(opts: &Options) -> Result<Box<dyn Device>> {
         return Ok(Box::new(dummy::Dummy::new()));
     }
 
-    match os_arch {
+    // the board of an ARM device, generic ARM boards support the slugs their
+    // compatible strings match
+    let mut board = None;
+    let device_type = match os_arch {
         OSArch::ARMHF | OSArch::ARM64 => {
             let dev_tree_model = String::from(
                 read_to_string(DEVICE_TREE_MODEL)
@@ -64,33 +123,69 @@ pub(crate) fn get_device(opts: &Options) -> Result<Box<dyn Device>> {
                     .trim_end(),
             );
 
-            if let Some(device) = raspberrypi::is_rpi(opts, &dev_tree_model)? {
-                return Ok(device);
-            }
+            let board_info = BoardInfo {
+                compatible: read_compatible(),
+                revision_type: if dev_tree_model.starts_with(raspberrypi::RPI_MODEL_PREFIX) {
+                    raspberrypi::get_revision_type()?
+                } else {
+                    None
+                },
+                model: dev_tree_model,
+            };
+            debug!("get_device: {:?}", board_info);
 
-            if let Some(device) = beaglebone::is_bb(opts, &dev_tree_model)? {
-                return Ok(device);
+            if let Some(device) = matrix.detect_board(&board_info)? {
+                let device_type = device.device_type;
+                board = Some(board_info);
+                device_type
+            } else {
+                let message = format!(
+                    "Your device type: '{}' ({}) is not supported by balena-migrate.",
+                    board_info.model,
+                    board_info.compatible.join(", ")
+                );
+                error!("{}", message);
+                return Err(Error::with_context(ErrorKind::InvState, &message));
             }
-
-            if let Some(device) = jetson_xavier::is_jetson_xavier(opts, &dev_tree_model)? {
-                return Ok(device);
+        }
+        OSArch::AMD64 => {
+            let read_dmi = |key: &str| read_to_string(path_append(DMI_ID_DIR, key)).ok();
+            if let Some(device) = matrix.detect_dmi(read_dmi)? {
+                device.device_type
+            } else {
+                let message = format!(
+                    "Your device type: '{}' is not supported by balena-migrate.",
+                    read_dmi("product_name").unwrap_or_default().trim()
+                );
+                error!("{}", message);
+                return Err(Error::with_context(ErrorKind::InvState, &message));
             }
+        }
+    };
+    info!("Identified {}", device_type);
+
+    if opts.migrate() && !check_os(matrix, device_type, opts)? {
+        return Err(Error::displayed());
+    }
 
-            let message = format!(
-                "Your device type: '{}' is not supported by balena-migrate.",
-                dev_tree_model
+    match (device_type, board) {
+        (DeviceType::GenericArm, Some(board)) => {
+            let slugs = matrix.compatible_slugs(device_type, &board)?;
+            info!(
+                "The board '{}' is compatible with device types {}",
+                board.model,
+                slugs.join(", ")
             );
-            error!("{}", message);
-            Err(Error::with_context(ErrorKind::InvState, &message))
+            let firmware_slugs = slugs
+                .iter()
+                .filter(|slug| matrix.needs_boot_firmware(device_type, slug))
+                .cloned()
+                .collect();
+            Ok(Box::new(generic_arm::GenericArm::new(
+                slugs,
+                firmware_slugs,
+            )))
         }
-        OSArch::AMD64 => Ok(Box::new(intel_nuc::IntelNuc::from_config(opts)?)),
-        /*            OSArch::I386 => {
-                    migrator.init_i386()?;
-                },
-        */
-        _ => Err(Error::with_context(
-            ErrorKind::InvParam,
-            &format!("get_device: unexpected OsArch encountered: {:?}", os_arch),
-        )),
+        _ => Ok(from_device_type(device_type)),
     }
 }
@@ -0,0 +1,577 @@
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::fs::{metadata, read, read_to_string, remove_file, write, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::common::{
+    call,
+    defs::{BOOT_FIRMWARE_MANIFEST_NAME, MTD_DEBUG_CMD},
+    file_exists, find_file, path_append, Error, ErrorKind, Result, ToError,
+};
+
+// tmpfs directory in stage2 the previous boot firmware is saved to
+const FIRMWARE_BACKUP_DIR: &str = "/tmp";
+
+/// How a boot firmware region is written
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RegionKind {
+    /// eMMC hardware boot partition like /dev/mmcblk0boot0, written as a block
+    /// device after clearing its force_ro flag, see
+    /// https://www.kernel.org/doc/Documentation/mmc/mmc-dev-parts.txt
+    MmcBoot,
+    /// MTD device like the QSPI flash on /dev/mtd0, erased and written with mtd_debug
+    Mtd,
+}
+
+/// A firmware image and the region of a boot device it is written to
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) struct FirmwareRegion {
+    /// the image file, relative to the directory of the manifest
+    pub image: PathBuf,
+    pub device: PathBuf,
+    pub kind: RegionKind,
+    #[serde(default)]
+    pub offset: u64,
+    /// size of the region, the image must fit into it. MTD regions are erased
+    /// in full so the size must be a multiple of the erase block size.
+    pub size: Option<u64>,
+}
+
+/// The boot firmware manifest shipped in the root partition of the balenaOS
+/// image, listing the regions the boot firmware is written to, eg.:
+/// ```json
+/// { "regions": [
+///     { "image": "boot0_mtdblock0.img", "device": "/dev/mtd0", "kind": "mtd", "size": 33554432 }
+/// ] }
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) struct BootManifest {
+    pub regions: Vec<FirmwareRegion>,
+}
+
+impl BootManifest {
+    fn parse(manifest: &str) -> Result<BootManifest> {
+        serde_json::from_str(manifest)
+            .upstream_with_context("Failed to parse boot firmware manifest")
+    }
+}
+
+/// Write the boot firmware from the new image mounted on root_mp. The regions
+/// are taken from the manifest in the image, images that do not ship one fall
+/// back to legacy_regions whose image files are searched for in the image.
+pub(crate) fn write_boot_firmware(root_mp: &Path, legacy_regions: &[FirmwareRegion]) -> Result<()> {
+    match find_regions(root_mp, legacy_regions)? {
+        Some(regions) => write_regions(&regions, Path::new(FIRMWARE_BACKUP_DIR)),
+        None => {
+            info!("No boot firmware manifest found in the new image, boot firmware is not written");
+            Ok(())
+        }
+    }
+}
+
+/// The memory write_boot_firmware takes in stage2 for the new image mounted
+/// on root_mp, None if the image ships no boot firmware. The previous contents
+/// of all regions are kept in tmpfs, and every region is written and verified,
+/// or restored, from two buffers of its size.
+pub(crate) fn boot_firmware_space(
+    root_mp: &Path,
+    legacy_regions: &[FirmwareRegion],
+) -> Result<Option<u64>> {
+    let regions = match find_regions(root_mp, legacy_regions)? {
+        Some(regions) => regions,
+        None => return Ok(None),
+    };
+
+    let mut backups = 0;
+    let mut buffers = 0;
+    for (image_path, region) in &regions {
+        let size = region.size.unwrap_or(check_image(region, image_path)?);
+        backups += size;
+        buffers = buffers.max(2 * size);
+    }
+    Ok(Some(backups + buffers))
+}
+
+/// The regions to write and the paths of their images in the new image
+/// mounted on root_mp, None if there is neither a manifest nor legacy_regions
+fn find_regions(
+    root_mp: &Path,
+    legacy_regions: &[FirmwareRegion],
+) -> Result<Option<Vec<(PathBuf, FirmwareRegion)>>> {
+    let manifest_path = find_file(BOOT_FIRMWARE_MANIFEST_NAME, root_mp);
+
+    if manifest_path.as_os_str().is_empty() {
+        if legacy_regions.is_empty() {
+            return Ok(None);
+        }
+        info!("No boot firmware manifest found in the new image, using the legacy boot firmware layout");
+        Ok(Some(
+            legacy_regions
+                .iter()
+                .map(|region| {
+                    let image_name = region.image.file_name().unwrap_or_default();
+                    (
+                        find_file(&image_name.to_string_lossy(), root_mp),
+                        region.clone(),
+                    )
+                })
+                .collect(),
+        ))
+    } else {
+        info!("Using boot firmware manifest '{}'", manifest_path.display());
+        let manifest = BootManifest::parse(
+            &read_to_string(&manifest_path)
+                .upstream_with_context(&format!("Failed to read '{}'", manifest_path.display()))?,
+        )?;
+        let manifest_dir = manifest_path.parent().unwrap_or(root_mp);
+        Ok(Some(
+            manifest
+                .regions
+                .into_iter()
+                .map(|region| (manifest_dir.join(&region.image), region))
+                .collect(),
+        ))
+    }
+}
+
+/// Write all regions, saving the previous contents of each region to
+/// backup_dir first. If a region can not be written or verified, the regions
+/// written so far are restored so the device still boots its old firmware.
+fn write_regions(regions: &[(PathBuf, FirmwareRegion)], backup_dir: &Path) -> Result<()> {
+    // check all images before anything is written
+    let mut image_sizes = Vec::new();
+    for (image_path, region) in regions {
+        image_sizes.push(check_image(region, image_path)?);
+    }
+
+    let mut backups: Vec<FirmwareBackup> = Vec::new();
+    for (idx, ((image_path, region), image_size)) in regions.iter().zip(image_sizes).enumerate() {
+        let res = FirmwareBackup::create(region, image_size, backup_dir, idx).and_then(|backup| {
+            backups.push(backup);
+            write_region(region, image_path)
+        });
+
+        if let Err(why) = res {
+            error!(
+                "Failed to write boot firmware to '{}', restoring the previous boot firmware",
+                region.device.display()
+            );
+            for backup in backups.iter().rev() {
+                if let Err(restore_why) = backup.restore() {
+                    error!(
+                        "Failed to restore the previous boot firmware to '{}', error: {:?}",
+                        backup.region.device.display(),
+                        restore_why
+                    );
+                }
+            }
+            return Err(why);
+        }
+
+        info!(
+            "Boot firmware '{}' was written to '{}' and verified",
+            image_path.display(),
+            region.device.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Make sure the image exists and fits into the region, returns the image size
+fn check_image(region: &FirmwareRegion, image_path: &Path) -> Result<u64> {
+    if image_path.as_os_str().is_empty() || !file_exists(image_path) {
+        return Err(Error::with_context(
+            ErrorKind::FileNotFound,
+            &format!(
+                "The boot firmware image '{}' could not be found in the new image",
+                region.image.display()
+            ),
+        ));
+    }
+
+    let image_size = metadata(image_path)
+        .upstream_with_context(&format!("Failed to stat '{}'", image_path.display()))?
+        .len();
+
+    if let Some(size) = region.size {
+        if image_size > size {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "The boot firmware image '{}' of {} bytes does not fit into the region of {} bytes on '{}'",
+                    image_path.display(),
+                    image_size,
+                    size,
+                    region.device.display()
+                ),
+            ));
+        }
+    }
+
+    Ok(image_size)
+}
+
+fn write_region(region: &FirmwareRegion, image_path: &Path) -> Result<()> {
+    debug!(
+        "write_region: writing '{}' to {:?}",
+        image_path.display(),
+        region
+    );
+
+    let image = read(image_path)
+        .upstream_with_context(&format!("Failed to read '{}'", image_path.display()))?;
+
+    match region.kind {
+        RegionKind::MmcBoot => write_mmc_boot(region, &image)?,
+        RegionKind::Mtd => write_mtd(region, image_path, image.len() as u64)?,
+    }
+
+    verify_region(region, &image)
+}
+
+/// A copy of the previous contents of a boot firmware region, kept in tmpfs so
+/// it can be written back if writing the new boot firmware fails
+struct FirmwareBackup {
+    /// the saved region, covering the full region or the size of the new image
+    region: FirmwareRegion,
+    file: PathBuf,
+}
+
+impl FirmwareBackup {
+    fn create(
+        region: &FirmwareRegion,
+        image_size: u64,
+        backup_dir: &Path,
+        idx: usize,
+    ) -> Result<FirmwareBackup> {
+        let size = region.size.unwrap_or(image_size);
+        let file = path_append(backup_dir, format!("boot-firmware-backup-{}.bin", idx));
+
+        let mut dev_file = File::open(&region.device)
+            .upstream_with_context(&format!("Failed to open '{}'", region.device.display()))?;
+        dev_file
+            .seek(SeekFrom::Start(region.offset))
+            .upstream_with_context(&format!("Failed to seek on '{}'", region.device.display()))?;
+        let mut saved = vec![0u8; size as usize];
+        dev_file
+            .read_exact(&mut saved)
+            .upstream_with_context(&format!(
+                "Failed to save {} bytes at offset {} of '{}'",
+                size,
+                region.offset,
+                region.device.display()
+            ))?;
+        write(&file, &saved)
+            .upstream_with_context(&format!("Failed to write '{}'", file.display()))?;
+
+        debug!(
+            "FirmwareBackup: saved {} bytes at offset {} of '{}' to '{}'",
+            size,
+            region.offset,
+            region.device.display(),
+            file.display()
+        );
+
+        Ok(FirmwareBackup {
+            region: FirmwareRegion {
+                image: file.clone(),
+                size: Some(size),
+                ..region.clone()
+            },
+            file,
+        })
+    }
+
+    /// Write the saved contents back to the region and verify them
+    fn restore(&self) -> Result<()> {
+        write_region(&self.region, &self.file)?;
+        info!(
+            "Restored the previous boot firmware to '{}'",
+            self.region.device.display()
+        );
+        Ok(())
+    }
+}
+
+impl Drop for FirmwareBackup {
+    fn drop(&mut self) {
+        if let Err(why) = remove_file(&self.file) {
+            warn!("Failed to remove '{}', error: {}", self.file.display(), why);
+        }
+    }
+}
+
+fn write_mmc_boot(region: &FirmwareRegion, image: &[u8]) -> Result<()> {
+    let force_ro = force_ro_path(&region.device);
+    if let Some(force_ro) = &force_ro {
+        write(force_ro, "0").upstream_with_context(&format!(
+            "Failed to enable writing to '{}'",
+            region.device.display()
+        ))?;
+    }
+
+    let res = write_at(&region.device, region.offset, image);
+
+    if let Some(force_ro) = &force_ro {
+        if let Err(why) = write(force_ro, "1") {
+            warn!(
+                "Failed to set '{}' read only again: {}",
+                region.device.display(),
+                why
+            );
+        }
+    }
+
+    res
+}
+
+fn write_at(device: &Path, offset: u64, image: &[u8]) -> Result<()> {
+    let mut target = OpenOptions::new()
+        .write(true)
+        .open(device)
+        .upstream_with_context(&format!(
+            "Failed to open '{}' for writing",
+            device.display()
+        ))?;
+    target
+        .seek(SeekFrom::Start(offset))
+        .upstream_with_context(&format!("Failed to seek on '{}'", device.display()))?;
+    target
+        .write_all(image)
+        .upstream_with_context(&format!("Failed to write to '{}'", device.display()))?;
+    target
+        .sync_all()
+        .upstream_with_context(&format!("Failed to sync '{}'", device.display()))
+}
+
+/// The force_ro flag of an eMMC boot partition, boot partitions are read only by default
+fn force_ro_path(device: &Path) -> Option<PathBuf> {
+    let path = PathBuf::from("/sys/block")
+        .join(device.file_name()?)
+        .join("force_ro");
+    if file_exists(&path) {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+fn write_mtd(region: &FirmwareRegion, image_path: &Path, image_size: u64) -> Result<()> {
+    let mtd_debug = format!("/bin/{}", MTD_DEBUG_CMD);
+    let device = region.device.to_string_lossy();
+    let offset = region.offset.to_string();
+
+    call_command!(
+        &mtd_debug,
+        &[
+            "erase",
+            &device,
+            &offset,
+            &region.size.unwrap_or(image_size).to_string()
+        ],
+        &format!("Failed to erase '{}'", device)
+    )?;
+
+    call_command!(
+        &mtd_debug,
+        &[
+            "write",
+            &device,
+            &offset,
+            &image_size.to_string(),
+            &image_path.to_string_lossy()
+        ],
+        &format!("Failed to write '{}' to '{}'", image_path.display(), device)
+    )?;
+
+    Ok(())
+}
+
+/// Read the region back from the device and compare it to the image
+fn verify_region(region: &FirmwareRegion, image: &[u8]) -> Result<()> {
+    let device = &region.device;
+    let mut target = File::open(device)
+        .upstream_with_context(&format!("Failed to open '{}'", device.display()))?;
+
+    // make sure we read back what is on the device rather than what is in the buffer cache
+    let fadvise_res =
+        unsafe { libc::posix_fadvise(target.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    if fadvise_res != 0 {
+        debug!(
+            "verify_region: posix_fadvise on '{}' returned {}",
+            device.display(),
+            fadvise_res
+        );
+    }
+
+    target
+        .seek(SeekFrom::Start(region.offset))
+        .upstream_with_context(&format!("Failed to seek on '{}'", device.display()))?;
+    let mut written = vec![0u8; image.len()];
+    target
+        .read_exact(&mut written)
+        .upstream_with_context(&format!("Failed to read back '{}'", device.display()))?;
+
+    match image
+        .iter()
+        .zip(written.iter())
+        .position(|(expected, found)| expected != found)
+    {
+        Some(pos) => Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "Verification of '{}' failed, the written data differs at offset 0x{:x}",
+                device.display(),
+                region.offset + pos as u64
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    #[test]
+    fn parses_manifest() {
+        let manifest = BootManifest::parse(
+            r#"{ "regions": [
+                { "image": "boot0_mmcblk0boot0.img", "device": "/dev/mmcblk0boot0", "kind": "mmc_boot" },
+                { "image": "qspi.img", "device": "/dev/mtd0", "kind": "mtd", "offset": 4096, "size": 33554432 }
+            ] }"#,
+        )
+        .unwrap();
+        assert_eq!(manifest.regions.len(), 2);
+        assert_eq!(manifest.regions[0].kind, RegionKind::MmcBoot);
+        assert_eq!(manifest.regions[0].offset, 0);
+        assert_eq!(manifest.regions[0].size, None);
+        assert_eq!(manifest.regions[1].kind, RegionKind::Mtd);
+        assert_eq!(manifest.regions[1].offset, 4096);
+        assert_eq!(manifest.regions[1].size, Some(0x2000000));
+
+        assert!(BootManifest::parse(
+            r#"{ "regions": [ { "image": "a.img", "device": "/dev/mtd0", "kind": "nand" } ] }"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn calculates_boot_firmware_space() {
+        let root_mp = temp_dir().join("takeover-boot-firmware-root");
+        let firmware_dir = root_mp.join("opt/firmware");
+        create_dir_all(&firmware_dir).unwrap();
+        write(firmware_dir.join("boot0.img"), vec![0u8; 3000]).unwrap();
+        write(firmware_dir.join("qspi.img"), vec![0u8; 1000]).unwrap();
+
+        // no manifest and no legacy layout, nothing is written
+        assert_eq!(boot_firmware_space(&root_mp, &[]).unwrap(), None);
+
+        // legacy regions without a size are saved in the size of the image
+        let legacy = FirmwareRegion {
+            image: PathBuf::from("boot0.img"),
+            device: PathBuf::from("/dev/mmcblk0boot0"),
+            kind: RegionKind::MmcBoot,
+            offset: 0,
+            size: None,
+        };
+        assert_eq!(
+            boot_firmware_space(&root_mp, &[legacy]).unwrap(),
+            Some(3 * 3000)
+        );
+
+        write(
+            firmware_dir.join(BOOT_FIRMWARE_MANIFEST_NAME),
+            r#"{ "regions": [
+                { "image": "boot0.img", "device": "/dev/mmcblk0boot0", "kind": "mmc_boot" },
+                { "image": "qspi.img", "device": "/dev/mtd0", "kind": "mtd", "size": 4096 }
+            ] }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            boot_firmware_space(&root_mp, &[]).unwrap(),
+            Some(3000 + 4096 + 2 * 4096)
+        );
+
+        remove_file(firmware_dir.join("qspi.img")).unwrap();
+        assert!(boot_firmware_space(&root_mp, &[]).is_err());
+
+        remove_dir_all(&root_mp).unwrap();
+    }
+
+    #[test]
+    fn writes_and_verifies_region() {
+        let image_path = temp_dir().join("takeover-boot-firmware-image.img");
+        let device_path = temp_dir().join("takeover-boot-firmware-device.img");
+        let image: Vec<u8> = (0..3000u32).map(|idx| idx as u8).collect();
+        write(&image_path, &image).unwrap();
+        write(&device_path, vec![0xFFu8; 8192]).unwrap();
+
+        let mut region = FirmwareRegion {
+            image: image_path.clone(),
+            device: device_path.clone(),
+            kind: RegionKind::MmcBoot,
+            offset: 1024,
+            size: Some(4096),
+        };
+        write_region(&region, &image_path).unwrap();
+        let device = read(&device_path).unwrap();
+        assert_eq!(&device[1024..4024], image.as_slice());
+        assert!(device[..1024].iter().all(|byte| *byte == 0xFF));
+
+        region.size = Some(2048);
+        assert!(check_image(&region, &image_path).is_err());
+
+        region.size = None;
+        let mut other = image.clone();
+        other[100] ^= 0xFF;
+        assert!(verify_region(&region, &other).is_err());
+
+        remove_file(&image_path).unwrap();
+        remove_file(&device_path).unwrap();
+    }
+
+    #[test]
+    fn restores_regions_on_failure() {
+        let backup_dir = temp_dir().join("takeover-boot-firmware-backup");
+        create_dir_all(&backup_dir).unwrap();
+        let image_path = backup_dir.join("image.img");
+        let device_path = backup_dir.join("device.img");
+        let image = vec![0x5Au8; 2048];
+        let original: Vec<u8> = (0..4096u32).map(|idx| (idx % 251) as u8).collect();
+        write(&image_path, &image).unwrap();
+        write(&device_path, &original).unwrap();
+
+        let written = FirmwareRegion {
+            image: image_path.clone(),
+            device: device_path.clone(),
+            kind: RegionKind::MmcBoot,
+            offset: 512,
+            size: Some(3072),
+        };
+        // the second region can not be saved, so the first one has to be restored
+        let missing = FirmwareRegion {
+            device: backup_dir.join("missing.img"),
+            ..written.clone()
+        };
+
+        let regions = vec![
+            (image_path.clone(), written.clone()),
+            (image_path.clone(), missing),
+        ];
+        assert!(write_regions(&regions, &backup_dir).is_err());
+        assert_eq!(read(&device_path).unwrap(), original);
+        assert!(!backup_dir.join("boot-firmware-backup-0.bin").exists());
+
+        write_regions(&regions[..1], &backup_dir).unwrap();
+        assert_eq!(&read(&device_path).unwrap()[512..2560], image.as_slice());
+
+        remove_dir_all(&backup_dir).unwrap();
+    }
+}
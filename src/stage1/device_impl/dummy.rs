@@ -1,4 +1,4 @@
-use crate::{stage1::defs::DeviceType, stage1::device::Device};
+use crate::{common::device_type::DeviceType, stage1::device::Device};
 
 /// The Dummy device skips all compatibility checks. This is useful when the
 /// user's actual device type is not supported by takeover, but it is
@@ -13,12 +13,8 @@ impl Dummy {
 }
 
 impl Device for Dummy {
-    fn supports_device_type(&self, _dev_type: &str) -> bool {
-        // When using the Dummy device type, we want to skip all DT-specific
-        // code. We achieve that by saying that the Dummy device type does not
-        // support any device type.
-        false
-    }
+    // The support matrix has no entries for the Dummy device type, so it does
+    // not support any device type slug or OS.
     fn get_device_type(&self) -> DeviceType {
         DeviceType::Dummy
     }
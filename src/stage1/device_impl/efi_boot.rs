@@ -0,0 +1,356 @@
+use log::{debug, info, warn};
+use regex::Regex;
+use std::convert::TryInto;
+use std::fs::remove_dir;
+use std::path::Path;
+
+use crate::common::{
+    defs::{BALENA_BOOT_PART, EFI_LOADER_FILE, SYS_EFI_DIR},
+    dir_exists,
+    disk_util::{Disk, LabelType, PartitionIterator},
+    efivars::{delete_efi_var, list_efi_vars, read_efi_var, write_efi_var, EFI_GLOBAL_VARIABLE},
+    path_append,
+    stage2_config::Stage2Config,
+    Error, ErrorKind, Result,
+};
+
+// description of the boot entry for balenaOS, entries using it are replaced
+const BOOT_ENTRY_LABEL: &str = "resinOS";
+const BOOT_ORDER_VAR: &str = "BootOrder";
+const LOAD_OPTION_ACTIVE: u32 = 0x1;
+
+// EFI system partition type C12A7328-F81F-11D2-BA4B-00A0C93EC93B as stored in the GPT
+const ESP_TYPE_GUID: [u8; 16] = [
+    0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b,
+];
+
+// device path node types, see chapter 10 of the UEFI specification
+const MEDIA_DEVICE_PATH: u8 = 0x04;
+const MEDIA_HARDDRIVE_DP: u8 = 0x01;
+const MEDIA_FILEPATH_DP: u8 = 0x04;
+const END_DEVICE_PATH: [u8; 4] = [0x7f, 0xff, 0x04, 0x00];
+const HARDDRIVE_DP_SIZE: u16 = 42;
+
+/// The path of a file in the EFI system partition as used in device paths,
+/// eg. \EFI\BOOT\bootx64.efi for EFI/BOOT/bootx64.efi
+pub(crate) fn efi_file_path(path: &str) -> String {
+    format!("\\{}", path.trim_start_matches('/').replace('/', "\\"))
+}
+
+/// The partition identifier in a hard drive media device path
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PartitionSignature {
+    Gpt([u8; 16]),
+    Mbr(u32),
+}
+
+/// The EFI system partition of the flashed image
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct EfiPartition {
+    number: u32,
+    start_lba: u64,
+    size_lba: u64,
+    signature: PartitionSignature,
+}
+
+impl EfiPartition {
+    /// Find the EFI system partition in the partition table written from the
+    /// image, GPT images are searched for the ESP type and then for the boot
+    /// partition name, MBR images use the boot partition
+    fn find(device: &Path) -> Result<EfiPartition> {
+        let mut disk = Disk::from_drive_file(device, None)?;
+        if disk.get_label()? == LabelType::GPT {
+            let gpt = disk.read_gpt().map_err(|why| {
+                Error::with_context(
+                    ErrorKind::InvState,
+                    &format!(
+                        "Failed to read GPT from '{}', error: {}",
+                        device.display(),
+                        why
+                    ),
+                )
+            })?;
+            let (number, part) = gpt
+                .iter()
+                .find(|(_, part)| part.is_used() && part.partition_type_guid == ESP_TYPE_GUID)
+                .or_else(|| {
+                    gpt.iter().find(|(_, part)| {
+                        part.is_used() && part.partition_name.as_str() == BALENA_BOOT_PART
+                    })
+                })
+                .ok_or_else(|| {
+                    Error::with_context(
+                        ErrorKind::NotFound,
+                        &format!("No EFI system partition found on '{}'", device.display()),
+                    )
+                })?;
+
+            Ok(EfiPartition {
+                number,
+                start_lba: part.starting_lba,
+                size_lba: part.ending_lba + 1 - part.starting_lba,
+                signature: PartitionSignature::Gpt(part.unique_partition_guid),
+            })
+        } else {
+            let disk_id = (*PartitionIterator::new(&mut disk)?.get_disk_id()).ok_or_else(|| {
+                Error::with_context(
+                    ErrorKind::NotFound,
+                    &format!("No MBR disk signature found on '{}'", device.display()),
+                )
+            })?;
+            let (boot_part, _root_a_part, _data_part) = disk.get_balena_partitions()?;
+            Ok(EfiPartition {
+                number: boot_part.index as u32,
+                start_lba: boot_part.start_lba,
+                size_lba: boot_part.num_sectors,
+                signature: PartitionSignature::Mbr(disk_id),
+            })
+        }
+    }
+
+    /// Device path of a file on the partition
+    fn device_path(&self, file_path: &str) -> Vec<u8> {
+        let mut path = vec![MEDIA_DEVICE_PATH, MEDIA_HARDDRIVE_DP];
+        path.extend_from_slice(&HARDDRIVE_DP_SIZE.to_le_bytes());
+        path.extend_from_slice(&self.number.to_le_bytes());
+        path.extend_from_slice(&self.start_lba.to_le_bytes());
+        path.extend_from_slice(&self.size_lba.to_le_bytes());
+        match self.signature {
+            PartitionSignature::Gpt(guid) => {
+                path.extend_from_slice(&guid);
+                // GPT partition format, GUID signature
+                path.extend_from_slice(&[0x02, 0x02]);
+            }
+            PartitionSignature::Mbr(disk_id) => {
+                path.extend_from_slice(&disk_id.to_le_bytes());
+                path.extend_from_slice(&[0; 12]);
+                // MBR partition format, 32 bit signature
+                path.extend_from_slice(&[0x01, 0x01]);
+            }
+        }
+
+        let file = to_ucs2(file_path);
+        path.extend_from_slice(&[MEDIA_DEVICE_PATH, MEDIA_FILEPATH_DP]);
+        path.extend_from_slice(&(4 + file.len() as u16).to_le_bytes());
+        path.extend(file);
+        path.extend_from_slice(&END_DEVICE_PATH);
+        path
+    }
+}
+
+/// An EFI_LOAD_OPTION as stored in the Boot#### variables
+#[derive(Debug, Clone, PartialEq)]
+struct LoadOption {
+    attributes: u32,
+    description: String,
+    file_path_list: Vec<u8>,
+    optional_data: Vec<u8>,
+}
+
+impl LoadOption {
+    fn parse(data: &[u8]) -> Option<LoadOption> {
+        let attributes = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?);
+        let path_len = u16::from_le_bytes(data.get(4..6)?.try_into().ok()?) as usize;
+
+        let mut description = Vec::new();
+        let mut pos = 6;
+        loop {
+            let char = u16::from_le_bytes(data.get(pos..pos + 2)?.try_into().ok()?);
+            pos += 2;
+            if char == 0 {
+                break;
+            }
+            description.push(char);
+        }
+
+        Some(LoadOption {
+            attributes,
+            description: String::from_utf16_lossy(&description),
+            file_path_list: data.get(pos..pos + path_len)?.to_vec(),
+            optional_data: data.get(pos + path_len..)?.to_vec(),
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.attributes.to_le_bytes().to_vec();
+        data.extend_from_slice(&(self.file_path_list.len() as u16).to_le_bytes());
+        data.extend(to_ucs2(&self.description));
+        data.extend_from_slice(&self.file_path_list);
+        data.extend_from_slice(&self.optional_data);
+        data
+    }
+}
+
+/// NUL terminated UCS-2 string as used for descriptions and file paths
+fn to_ucs2(value: &str) -> Vec<u8> {
+    value
+        .encode_utf16()
+        .chain(std::iter::once(0))
+        .flat_map(|char| char.to_le_bytes())
+        .collect()
+}
+
+fn parse_boot_order(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2)
+        .map(|num| u16::from_le_bytes([num[0], num[1]]))
+        .collect()
+}
+
+fn boot_order_bytes(boot_order: &[u16]) -> Vec<u8> {
+    boot_order
+        .iter()
+        .flat_map(|num| num.to_le_bytes())
+        .collect()
+}
+
+/// Create an EFI boot entry for the EFI loader of the flashed image and make
+/// it the first entry in BootOrder. Older balenaOS entries are removed. On
+/// devices booted without EFI the EFI directory is removed from the boot
+/// partition mounted on boot_mp instead.
+pub(crate) fn setup_efi_boot(s2_cfg: &Stage2Config, boot_mp: &Path) -> Result<()> {
+    if s2_cfg.no_efi_setup {
+        info!("Skipping EFI setup due to no-efi-setup option");
+        return Ok(());
+    }
+
+    if !dir_exists(SYS_EFI_DIR)? {
+        let efi_dir = path_append(boot_mp, "EFI");
+        if dir_exists(&efi_dir)? {
+            match remove_dir(&efi_dir) {
+                Ok(_) => {
+                    debug!("Removed EFI directory from '{}'", boot_mp.display());
+                }
+                Err(why) => {
+                    warn!(
+                        "Failed to remove EFI directory from '{}', error: {}",
+                        boot_mp.display(),
+                        why
+                    );
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    let loader = s2_cfg
+        .efi_loader
+        .clone()
+        .unwrap_or_else(|| efi_file_path(EFI_LOADER_FILE));
+    let esp = EfiPartition::find(&s2_cfg.flash_dev)?;
+    debug!("setup_efi_boot: {:?}, loader '{}'", esp, loader);
+
+    let mut boot_order =
+        parse_boot_order(&read_efi_var(BOOT_ORDER_VAR, EFI_GLOBAL_VARIABLE)?.unwrap_or_default());
+
+    // remove entries created for earlier balenaOS installs
+    let boot_var_regex = Regex::new(r"^Boot([0-9A-Fa-f]{4})$").unwrap();
+    let mut used_nums = Vec::new();
+    for name in list_efi_vars(EFI_GLOBAL_VARIABLE)? {
+        let num = match boot_var_regex
+            .captures(&name)
+            .and_then(|captures| u16::from_str_radix(&captures[1], 16).ok())
+        {
+            Some(num) => num,
+            None => continue,
+        };
+
+        let is_balena = read_efi_var(&name, EFI_GLOBAL_VARIABLE)?
+            .and_then(|data| LoadOption::parse(&data))
+            .is_some_and(|option| option.description == BOOT_ENTRY_LABEL);
+        if is_balena {
+            delete_efi_var(&name, EFI_GLOBAL_VARIABLE)?;
+            boot_order.retain(|entry| *entry != num);
+            info!("Removed EFI boot entry {}", name);
+        } else {
+            used_nums.push(num);
+        }
+    }
+
+    let num = (0..=u16::MAX)
+        .find(|num| !used_nums.contains(num))
+        .ok_or_else(|| Error::with_context(ErrorKind::InvState, "No free EFI boot entry found"))?;
+    let name = format!("Boot{:04X}", num);
+
+    let option = LoadOption {
+        attributes: LOAD_OPTION_ACTIVE,
+        description: BOOT_ENTRY_LABEL.to_string(),
+        file_path_list: esp.device_path(&loader),
+        optional_data: Vec::new(),
+    };
+    write_efi_var(&name, EFI_GLOBAL_VARIABLE, &option.to_bytes())?;
+
+    boot_order.insert(0, num);
+    write_efi_var(
+        BOOT_ORDER_VAR,
+        EFI_GLOBAL_VARIABLE,
+        &boot_order_bytes(&boot_order),
+    )?;
+
+    // the firmware may reject or rewrite the boot order, make sure it boots balenaOS
+    let written_order =
+        parse_boot_order(&read_efi_var(BOOT_ORDER_VAR, EFI_GLOBAL_VARIABLE)?.unwrap_or_default());
+    if written_order.first() != Some(&num) {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "The EFI boot entry {} is not the first entry in BootOrder {:04X?}",
+                name, written_order
+            ),
+        ));
+    }
+
+    info!(
+        "Created EFI boot entry {} for '{}' on partition {} of '{}'",
+        name,
+        loader,
+        esp.number,
+        s2_cfg.flash_dev.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_load_option() {
+        let esp = EfiPartition {
+            number: 1,
+            start_lba: 8192,
+            size_lba: 81920,
+            signature: PartitionSignature::Gpt([0xab; 16]),
+        };
+        let option = LoadOption {
+            attributes: LOAD_OPTION_ACTIVE,
+            description: BOOT_ENTRY_LABEL.to_string(),
+            file_path_list: esp.device_path(&efi_file_path("EFI/BOOT/bootx64.efi")),
+            optional_data: Vec::new(),
+        };
+
+        let path = &option.file_path_list;
+        assert_eq!(&path[..4], &[0x04, 0x01, 42, 0]);
+        assert_eq!(&path[4..8], &1u32.to_le_bytes());
+        assert_eq!(&path[8..16], &8192u64.to_le_bytes());
+        assert_eq!(&path[40..42], &[0x02, 0x02]);
+        assert_eq!(&path[42..44], &[0x04, 0x04]);
+        // \EFI\BOOT\bootx64.efi and NUL in UCS-2 plus the 4 byte node header
+        assert_eq!(u16::from_le_bytes([path[44], path[45]]), 4 + 22 * 2);
+        assert_eq!(&path[path.len() - 4..], &END_DEVICE_PATH);
+
+        let data = option.to_bytes();
+        assert_eq!(&data[6..8], &[b'r', 0]);
+        assert_eq!(LoadOption::parse(&data), Some(option));
+        assert_eq!(LoadOption::parse(&data[..10]), None);
+    }
+
+    #[test]
+    fn converts_boot_order() {
+        let boot_order = parse_boot_order(&[0x03, 0x00, 0x01, 0x00, 0x0a, 0x10]);
+        assert_eq!(boot_order, vec![0x0003, 0x0001, 0x100a]);
+        assert_eq!(
+            boot_order_bytes(&boot_order),
+            vec![0x03, 0x00, 0x01, 0x00, 0x0a, 0x10]
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use log::error;
+use std::path::Path;
+use which::which;
+
+use crate::{
+    common::{
+        defs::{MTD_DEBUG_CMD, SYS_EFI_DIR},
+        device_type::DeviceType,
+        dir_exists,
+        stage2_config::{EepromUpdate, Stage2Config},
+        Error, Options, Result,
+    },
+    stage1::{
+        device::{warn_boot_order, Device},
+        device_impl::{boot_firmware::write_boot_firmware, efi_boot::setup_efi_boot},
+        image_info::ImageInfo,
+        support_matrix::SupportMatrix,
+    },
+};
+
+/// An ARM board without board specific steps, identified by its device tree
+/// compatible strings. It runs balenaOS for the device type slugs whose
+/// compatible regexes in the support matrix match the board, rather than any
+/// slug of the generic device type. Boot firmware is only written if the new
+/// image ships a boot firmware manifest, images for the firmware_slugs are
+/// refused without one.
+pub(crate) struct GenericArm {
+    slugs: Vec<String>,
+    firmware_slugs: Vec<String>,
+}
+
+impl GenericArm {
+    pub fn new(slugs: Vec<String>, firmware_slugs: Vec<String>) -> GenericArm {
+        GenericArm {
+            slugs,
+            firmware_slugs,
+        }
+    }
+}
+
+impl Device for GenericArm {
+    fn get_device_type(&self) -> DeviceType {
+        DeviceType::GenericArm
+    }
+
+    fn supports_device_type(&self, _matrix: &SupportMatrix, slug: &str) -> bool {
+        self.slugs.iter().any(|supported| supported == slug)
+    }
+
+    fn check_image(&self, image_info: &ImageInfo) -> Result<()> {
+        let slug = image_info.device_type();
+        if self.firmware_slugs.iter().any(|firmware| firmware == slug)
+            && image_info.boot_firmware_space(&[])?.is_none()
+        {
+            error!(
+                "The balenaOS image for device type '{}' does not ship a boot firmware manifest, takeover can not write the boot firmware the device needs to boot it",
+                slug
+            );
+            return Err(Error::displayed());
+        }
+        Ok(())
+    }
+
+    fn boot_firmware_space(&self, image_info: &ImageInfo) -> Result<u64> {
+        Ok(image_info.boot_firmware_space(&[])?.unwrap_or(0))
+    }
+
+    fn stage2_commands(&self, _opts: &Options) -> Result<Vec<&'static str>> {
+        // needed for boot firmware on MTD devices, if the board has mtd_debug
+        if which(MTD_DEBUG_CMD).is_ok() {
+            Ok(vec![MTD_DEBUG_CMD])
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    fn prepare_boot_device(
+        &self,
+        opts: &Options,
+        _takeover_dir: &Path,
+        flash_dev: &Path,
+    ) -> Result<Option<EepromUpdate>> {
+        if opts.no_efi_setup() || !dir_exists(SYS_EFI_DIR)? {
+            warn_boot_order(self.get_device_type(), opts, flash_dev);
+        }
+        Ok(None)
+    }
+
+    fn setup_boot(&self, s2_cfg: &Stage2Config, boot_mp: &Path) -> Result<()> {
+        // boards booting through UEFI, like arm64 servers, get an EFI boot entry
+        if dir_exists(SYS_EFI_DIR)? {
+            setup_efi_boot(s2_cfg, boot_mp)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn has_boot_firmware(&self) -> bool {
+        true
+    }
+
+    fn write_boot_firmware(&self, _s2_cfg: &Stage2Config, root_mp: &Path) -> Result<()> {
+        write_boot_firmware(root_mp, &[])
+    }
+}
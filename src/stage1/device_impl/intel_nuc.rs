@@ -1,68 +1,46 @@
-use log::{error, info};
+use std::path::Path;
 
-use crate::stage1::device_impl::check_os;
 use crate::{
-    common::{Error, Options, Result},
-    // linux_common::is_secure_boot,
+    common::{
+        defs::SYS_EFI_DIR,
+        device_type::DeviceType,
+        dir_exists,
+        stage2_config::{EepromUpdate, Stage2Config},
+        Options, Result,
+    },
     stage1::{
-        defs::{DeviceType, DEV_TYPE_GEN_AMD64, DEV_TYPE_GEN_X86_64, DEV_TYPE_INTEL_NUC},
-        device::Device,
-        utils::is_secure_boot,
+        device::{warn_boot_order, Device},
+        device_impl::efi_boot::setup_efi_boot,
+        image_info::ImageInfo,
+        secure_boot,
     },
 };
 
-const X86_SLUGS: [&str; 3] = [DEV_TYPE_INTEL_NUC, DEV_TYPE_GEN_X86_64, DEV_TYPE_GEN_AMD64];
-
 pub(crate) struct IntelNuc;
 
-impl IntelNuc {
-    pub fn from_config(opts: &Options) -> Result<IntelNuc> {
-        const SUPPORTED_OSSES: &[&str] = &[
-            "Ubuntu 20.04 LTS",
-            "Ubuntu 18.04.4 LTS",
-            "Ubuntu 18.04.3 LTS",
-            "Ubuntu 18.04.2 LTS",
-            "Ubuntu 16.04.2 LTS",
-            "Ubuntu 16.04.6 LTS",
-            "Ubuntu 14.04.2 LTS",
-            "Ubuntu 14.04.5 LTS",
-            "Ubuntu 14.04.6 LTS",
-            "Manjaro Linux",
-            "balenaOS 4.0.23",
-        ];
-
-        if opts.migrate() {
-            if !check_os(SUPPORTED_OSSES, opts, "Generic x86_64/Intel Nuc")? {
-                return Err(Error::displayed());
-            }
-
-            // **********************************************************************
-            // ** AMD64 specific initialisation/checks
-            // **********************************************************************
+impl Device for IntelNuc {
+    fn get_device_type(&self) -> DeviceType {
+        DeviceType::IntelNuc
+    }
 
-            let secure_boot = is_secure_boot()?;
-            info!(
-                "Secure boot is {}enabled",
-                if secure_boot { "" } else { "not " }
-            );
+    fn check_image(&self, image_info: &ImageInfo) -> Result<()> {
+        secure_boot::check_image(image_info)
+    }
 
-            if secure_boot {
-                error!(
-                    "{} does not currently support systems with secure boot enabled.",
-                    env!("CARGO_PKG_NAME")
-                );
-                return Err(Error::displayed());
-            }
+    fn prepare_boot_device(
+        &self,
+        opts: &Options,
+        _takeover_dir: &Path,
+        flash_dev: &Path,
+    ) -> Result<Option<EepromUpdate>> {
+        // the EFI boot entry written in stage2 points to the new disk
+        if opts.no_efi_setup() || !dir_exists(SYS_EFI_DIR)? {
+            warn_boot_order(self.get_device_type(), opts, flash_dev);
         }
-        Ok(IntelNuc)
+        Ok(None)
     }
-}
 
-impl Device for IntelNuc {
-    fn supports_device_type(&self, dev_type: &str) -> bool {
-        X86_SLUGS.contains(&dev_type)
-    }
-    fn get_device_type(&self) -> DeviceType {
-        DeviceType::IntelNuc
+    fn setup_boot(&self, s2_cfg: &Stage2Config, boot_mp: &Path) -> Result<()> {
+        setup_efi_boot(s2_cfg, boot_mp)
     }
 }
@@ -1,91 +1,114 @@
-use crate::stage1::device_impl::check_os;
+use std::path::{Path, PathBuf};
+
 use crate::{
-    common::{Error, Options, Result},
-    // linux_common::is_secure_boot,
-    stage1::{
+    common::{
         defs::{
-            DeviceType, DEV_TYPE_JETSON_XAVIER, DEV_TYPE_JETSON_XAVIER_NX,
-            DEV_TYPE_JETSON_XAVIER_NX_EMMC,
+            BOOT_BLOB_NAME_JETSON_XAVIER, BOOT_BLOB_NAME_JETSON_XAVIER_NX,
+            BOOT_BLOB_PARTITION_JETSON_XAVIER, BOOT_BLOB_PARTITION_JETSON_XAVIER_NX, MTD_DEBUG_CMD,
         },
+        device_type::DeviceType,
+        file_exists,
+        stage2_config::Stage2Config,
+        Error, ErrorKind, Options, Result,
+    },
+    stage1::{
         device::Device,
+        device_impl::boot_firmware::{write_boot_firmware, FirmwareRegion, RegionKind},
+        image_info::ImageInfo,
     },
 };
-use log::{debug, trace};
-
-pub(crate) fn is_jetson_xavier(
-    opts: &Options,
-    model_string: &str,
-) -> Result<Option<Box<dyn Device>>> {
-    trace!(
-        "JetsonXavier::is_jetson_xavier: entered with model string: '{}'",
-        model_string
-    );
-
-    // Below strings have been taken from the AGX Xavier 8GB Devkit and the Xavier NX SD/eMMC Devkits
-    if model_string.eq("Jetson-AGX") {
-        debug!("match found for Xavier AGX");
-        Ok(Some(Box::new(JetsonXavier::from_config(opts)?)))
-    } else if model_string.eq("NVIDIA Jetson Xavier NX Developer Kit") {
-        debug!("match found for Xavier NX Devkit");
-        Ok(Some(Box::new(JetsonXavierNX::from_config(opts)?)))
-    } else {
-        debug!(
-            "no match for Jetson-AGX or NVIDIA Jetson Xavier NX Developer Kit on: <{}>",
-            model_string
-        );
-        Ok(None)
-    }
-}
 
-const XAVIER_AGX_SLUGS: [&str; 1] = [DEV_TYPE_JETSON_XAVIER];
-const XAVIER_NX_SLUGS: [&str; 2] = [DEV_TYPE_JETSON_XAVIER_NX, DEV_TYPE_JETSON_XAVIER_NX_EMMC];
+// QSPI flash storage size in bytes for Jetson Xavier NX
+const JETSON_XAVIER_NX_QSPI_SIZE: u64 = 0x2000000;
 
 pub(crate) struct JetsonXavier;
 
-impl JetsonXavier {
-    pub fn from_config(opts: &Options) -> Result<JetsonXavier> {
-        const SUPPORTED_OSSES: &[&str] = &["balenaOS 5.1.20", "balenaOS 3.1.3+rev1"];
-
-        if opts.migrate() && !check_os(SUPPORTED_OSSES, opts, "balenaOS 5.1.20")? {
-            return Err(Error::displayed());
-        }
-        // **********************************************************************
-        // ** Xavier AGX specific initialisation/checks
-        // **********************************************************************
-        Ok(JetsonXavier)
-    }
-}
-
 impl Device for JetsonXavier {
-    fn supports_device_type(&self, dev_type: &str) -> bool {
-        XAVIER_AGX_SLUGS.contains(&dev_type)
-    }
     fn get_device_type(&self) -> DeviceType {
         DeviceType::JetsonXavier
     }
-}
 
-pub(crate) struct JetsonXavierNX;
+    fn pre_flash(&self, _s2_cfg: &Stage2Config) -> Result<()> {
+        check_exists(BOOT_BLOB_PARTITION_JETSON_XAVIER)
+    }
+
+    fn has_boot_firmware(&self) -> bool {
+        true
+    }
+
+    fn boot_firmware_space(&self, image_info: &ImageInfo) -> Result<u64> {
+        Ok(image_info
+            .boot_firmware_space(&xavier_regions())?
+            .unwrap_or(0))
+    }
 
-impl JetsonXavierNX {
-    pub fn from_config(opts: &Options) -> Result<JetsonXavierNX> {
-        const SUPPORTED_OSSES: &[&str] = &["balenaOS 5.1.20", "balenaOS 3.1.3+rev1"];
-
-        if opts.migrate() && !check_os(SUPPORTED_OSSES, opts, "balenaOS 5.1.20")? {
-            return Err(Error::displayed());
-        }
-        // **********************************************************************
-        // ** Xavier NX (SD and eMMC) specific initialisation/checks
-        // **********************************************************************
-        Ok(JetsonXavierNX)
+    fn write_boot_firmware(&self, _s2_cfg: &Stage2Config, root_mp: &Path) -> Result<()> {
+        write_boot_firmware(root_mp, &xavier_regions())
     }
 }
 
+/// Images without a boot firmware manifest carry the boot0 partition image
+fn xavier_regions() -> Vec<FirmwareRegion> {
+    vec![FirmwareRegion {
+        image: PathBuf::from(BOOT_BLOB_NAME_JETSON_XAVIER),
+        device: PathBuf::from(BOOT_BLOB_PARTITION_JETSON_XAVIER),
+        kind: RegionKind::MmcBoot,
+        offset: 0,
+        size: None,
+    }]
+}
+
+pub(crate) struct JetsonXavierNX;
+
 impl Device for JetsonXavierNX {
-    fn supports_device_type(&self, dev_type: &str) -> bool {
-        XAVIER_NX_SLUGS.contains(&dev_type)
-    }
     fn get_device_type(&self) -> DeviceType {
         DeviceType::JetsonXavierNX
     }
+
+    fn stage2_commands(&self, _opts: &Options) -> Result<Vec<&'static str>> {
+        Ok(vec![MTD_DEBUG_CMD])
+    }
+
+    fn pre_flash(&self, _s2_cfg: &Stage2Config) -> Result<()> {
+        check_exists(BOOT_BLOB_PARTITION_JETSON_XAVIER_NX)?;
+        check_exists(&format!("/bin/{}", MTD_DEBUG_CMD))
+    }
+
+    fn has_boot_firmware(&self) -> bool {
+        true
+    }
+
+    fn boot_firmware_space(&self, image_info: &ImageInfo) -> Result<u64> {
+        Ok(image_info
+            .boot_firmware_space(&xavier_nx_regions())?
+            .unwrap_or(0))
+    }
+
+    fn write_boot_firmware(&self, _s2_cfg: &Stage2Config, root_mp: &Path) -> Result<()> {
+        write_boot_firmware(root_mp, &xavier_nx_regions())
+    }
+}
+
+/// Images without a boot firmware manifest carry the QSPI flash image
+fn xavier_nx_regions() -> Vec<FirmwareRegion> {
+    vec![FirmwareRegion {
+        image: PathBuf::from(BOOT_BLOB_NAME_JETSON_XAVIER_NX),
+        device: PathBuf::from(BOOT_BLOB_PARTITION_JETSON_XAVIER_NX),
+        kind: RegionKind::Mtd,
+        offset: 0,
+        size: Some(JETSON_XAVIER_NX_QSPI_SIZE),
+    }]
+}
+
+/// The boot firmware is written after the image was flashed, make sure the
+/// target is there before the point of no return
+fn check_exists(path: &str) -> Result<()> {
+    if file_exists(path) {
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::NotFound,
+            &format!("The boot firmware target '{}' could not be found", path),
+        ))
+    }
 }
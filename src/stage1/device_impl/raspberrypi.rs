@@ -1,129 +1,397 @@
-use log::{debug, error, info};
-use regex::Regex;
+use log::{debug, error, info, warn};
+use nix::{
+    mount::{mount, umount, MsFlags},
+    unistd::sync,
+};
+use openssl::sha::sha256;
+use std::fs::{copy, create_dir_all, read, read_to_string, remove_file, write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::stage1::device_impl::check_os;
 use crate::{
-    common::{options::Options, Error, ErrorKind, Result},
-    stage1::{
-        defs::{DeviceType, DEV_TYPE_RPI1, DEV_TYPE_RPI2, DEV_TYPE_RPI3, DEV_TYPE_RPI4_64},
-        device::Device,
+    common::{
+        call,
+        defs::{NIX_NONE, RPI_EEPROM_CONFIG_CMD, RPI_EEPROM_UPDATE_CMD, VCGENCMD_CMD},
+        device_type::DeviceType,
+        path_append,
+        stage2_config::{EepromUpdate, Stage2Config},
+        whereis, Error, ErrorKind, Options, Result, ToError,
     },
+    stage1::device::Device,
 };
 
-// Pi Zero W:  "Raspberry Pi Zero W Rev 1.1"
-// Balena Fin: "Raspberry Pi Compute Module 3 Plus Rev 1.0"
-// RPI 4:      "Raspberry Pi 4 Model B Rev 1.1"
-// RPI 2:      "Raspberry Pi 2 Model B Rev 1.1"
-// RPI Zero W: "Raspberry Pi Zero W Rev 1.1"
-const RPI_MODEL_REGEX: &str = r#"^Raspberry\s+Pi\s+(1|2|3|4|Compute Module 3|Zero)\s+(Model\s+(\S+)|W|Plus)\s+(Rev\s+(\S+))$"#;
-const RPI1_SLUGS: [&str; 1] = [DEV_TYPE_RPI1];
-const RPI2_SLUGS: [&str; 1] = [DEV_TYPE_RPI2];
-const RPI3_SLUGS: [&str; 1] = [DEV_TYPE_RPI3];
-const RPI4_64_SLUGS: [&str; 1] = [DEV_TYPE_RPI4_64];
-
-const SUPPORTED_OSSES: [&str; 4] = [
-    "Raspbian GNU/Linux 8 (jessie)",
-    "Raspbian GNU/Linux 9 (stretch)",
-    "Raspbian GNU/Linux 10 (buster)",
-    "Ubuntu 20.04 LTS",
-];
-
-pub(crate) fn is_rpi(opts: &Options, model_string: &str) -> Result<Option<Box<dyn Device>>> {
-    debug!(
-        "raspberrypi::is_rpi: entered with model string: '{}'",
-        model_string
-    );
+pub(super) const RPI_MODEL_PREFIX: &str = "Raspberry Pi";
 
-    if let Some(captures) = Regex::new(RPI_MODEL_REGEX).unwrap().captures(model_string) {
-        let pitype = captures.get(1).unwrap().as_str();
-        let model = if let Some(model) = captures.get(3) {
-            model.as_str().trim_matches(char::from(0))
-        } else {
-            captures
-                .get(2)
-                .unwrap()
-                .as_str()
-                .trim_matches(char::from(0))
-        };
-
-        let revision = captures
-            .get(5)
-            .unwrap()
-            .as_str()
-            .trim_matches(char::from(0));
-
-        debug!(
-            "raspberrypi::is_rpi: selection entered with string: '{}'",
-            pitype
-        );
+const CPU_INFO: &str = "/proc/cpuinfo";
+// new style revision codes have this bit set and the board type in bits 4-11
+const REVISION_NEW_STYLE: u32 = 1 << 23;
 
-        match pitype {
-            "1" | "Zero" => {
-                info!("Identified RaspberryPi 1/Zero",);
-                Ok(Some(Box::new(RaspberryPi1::from_config(opts)?)))
-            }
-            "2" => {
-                info!("Identified RaspberryPi 2",);
-                Ok(Some(Box::new(RaspberryPi2::from_config(opts)?)))
-            }
-            "3" | "Compute Module 3" => {
-                info!("Identified RaspberryPi 3");
-                Ok(Some(Box::new(RaspberryPi3::from_config(opts)?)))
-            }
-            "4" => {
-                info!("Identified RaspberryPi 4");
-                Ok(Some(Box::new(RaspberryPi4_64::from_config(opts)?)))
-            }
-            _ => {
-                debug!("unknown PI type: '{}'", pitype);
-                let message = format!("The raspberry pi type reported by your device ('{} {} rev {}') is not supported by balena-migrate", pitype, model, revision);
-                error!("{}", message);
-                Err(Error::with_context(ErrorKind::InvParam, &message))
-            }
+// boot modes of the bootloader EEPROM, BOOT_ORDER tries them starting with
+// the lowest nibble
+const BOOT_MODE_SD: u32 = 0x1;
+const BOOT_MODE_USB: u32 = 0x4;
+const BOOT_MODE_NVME: u32 = 0x6;
+// BOOT_ORDER if the EEPROM configuration has none: SD card, USB, restart
+const DEFAULT_BOOT_ORDER: u32 = 0xf41;
+const BOOT_ORDER_MAX_MODES: usize = 8;
+const BOOT_ORDER_KEY: &str = "BOOT_ORDER=";
+const EEPROM_CONFIG_FILE: &str = "bootconf.txt";
+// directory in the takeover directory, the stage2 root, the EEPROM update is
+// staged in
+const EEPROM_UPDATE_DIR: &str = "eeprom-update";
+// files the bootloader looks for in its boot partition to update the EEPROM
+const EEPROM_UPD_FILE: &str = "pieeprom.upd";
+const EEPROM_SIG_FILE: &str = "pieeprom.sig";
+const EEPROM_RECOVERY_FILE: &str = "recovery.bin";
+// stage2 mountpoint of the old boot partition
+const EEPROM_BOOT_MP: &str = "/tmp/eeprom_boot";
+const MOUNTS: &str = "/proc/mounts";
+const FIRMWARE_MPS: &[&str] = &["/boot/firmware", "/boot"];
+
+// OTP register 17 of the Raspberry Pi 3 has this bit set once USB boot is
+// enabled with program_usb_boot_mode=1 in config.txt
+const OTP_USB_BOOT_REG: &str = "17";
+const OTP_USB_BOOT_BIT: u32 = 1 << 29;
+
+/// Get the board type from the revision code in /proc/cpuinfo. Old style
+/// revision codes, used by the first Raspberry Pi 1 boards, have no board type.
+pub(super) fn get_revision_type() -> Result<Option<u32>> {
+    let cpu_info = read_to_string(CPU_INFO)
+        .upstream_with_context(&format!("Failed to read '{}'", CPU_INFO))?;
+    let revision_type = revision_type(&cpu_info);
+    debug!("get_revision_type: {:?}", revision_type);
+    Ok(revision_type)
+}
+
+fn revision_type(cpu_info: &str) -> Option<u32> {
+    let revision = cpu_info.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        if key.trim() == "Revision" {
+            u32::from_str_radix(value.trim(), 16).ok()
+        } else {
+            None
         }
+    })?;
+
+    if revision & REVISION_NEW_STYLE != 0 {
+        Some((revision >> 4) & 0xff)
     } else {
-        debug!("no match for Raspberry PI on: {}", model_string);
-        Ok(None)
+        None
     }
 }
 
-pub(crate) struct RaspberryPi1;
-impl RaspberryPi1 {
-    pub fn from_config(opts: &Options) -> Result<RaspberryPi1> {
-        if opts.migrate() && !check_os(&SUPPORTED_OSSES, opts, "Raspberry PI 1")? {
-            return Err(Error::displayed());
-        }
+/// The bootloader boot mode of a disk, by its kernel name
+fn boot_mode(flash_dev: &Path) -> Option<u32> {
+    let name = flash_dev.file_name()?.to_str()?;
+    if name.starts_with("mmcblk") {
+        Some(BOOT_MODE_SD)
+    } else if name.starts_with("nvme") {
+        Some(BOOT_MODE_NVME)
+    } else if name.starts_with("sd") {
+        Some(BOOT_MODE_USB)
+    } else {
+        None
+    }
+}
 
-        Ok(RaspberryPi1 {})
+/// Move the boot mode to the front of the boot order, keeping the other modes
+/// as fallbacks
+fn boot_order_first(boot_order: u32, mode: u32) -> u32 {
+    let mut modes = vec![mode];
+    let mut order = boot_order;
+    while order != 0 {
+        if order & 0xf != mode {
+            modes.push(order & 0xf);
+        }
+        order >>= 4;
     }
+    modes.truncate(BOOT_ORDER_MAX_MODES);
+    modes
+        .iter()
+        .rev()
+        .fold(0, |order, mode| (order << 4) | mode)
 }
 
-impl Device for RaspberryPi1 {
-    fn supports_device_type(&self, dev_type: &str) -> bool {
-        RPI1_SLUGS.contains(&dev_type)
+fn config_boot_order(config: &str) -> Option<u32> {
+    config.lines().find_map(|line| {
+        let value = line.trim().strip_prefix(BOOT_ORDER_KEY)?.trim();
+        u32::from_str_radix(value.trim_start_matches("0x"), 16).ok()
+    })
+}
+
+fn set_config_boot_order(config: &str, boot_order: u32) -> String {
+    let boot_order_line = format!("{}0x{:x}", BOOT_ORDER_KEY, boot_order);
+    let mut found = false;
+    let mut lines: Vec<String> = config
+        .lines()
+        .map(|line| {
+            if line.trim().starts_with(BOOT_ORDER_KEY) {
+                found = true;
+                boot_order_line.clone()
+            } else {
+                line.to_string()
+            }
+        })
+        .collect();
+    if !found {
+        // the last section of the configuration might be conditional
+        lines.push("[all]".to_string());
+        lines.push(boot_order_line);
     }
+    lines.join("\n") + "\n"
+}
 
-    fn get_device_type(&self) -> DeviceType {
-        DeviceType::RaspberryPi1
+/// Stage a bootloader EEPROM update putting the boot mode of flash_dev first
+/// in BOOT_ORDER in the takeover directory. Nothing is applied here, stage2
+/// installs the update once the new disk is flashed and verified.
+fn stage_eeprom_update(takeover_dir: &Path, flash_dev: &Path) -> Result<Option<EepromUpdate>> {
+    let mode = boot_mode(flash_dev).ok_or_else(|| {
+        Error::with_context(
+            ErrorKind::InvParam,
+            &format!("The bootloader can not boot from '{}'", flash_dev.display()),
+        )
+    })?;
+
+    let eeprom_config = find_eeprom_cmd(RPI_EEPROM_CONFIG_CMD)?;
+    let eeprom_update = find_eeprom_cmd(RPI_EEPROM_UPDATE_CMD)?;
+
+    let config = call_command!(
+        &eeprom_config,
+        &[],
+        "Failed to read the bootloader EEPROM configuration"
+    )?;
+    let boot_order = config_boot_order(&config).unwrap_or(DEFAULT_BOOT_ORDER);
+    let new_boot_order = boot_order_first(boot_order, mode);
+    if new_boot_order == boot_order {
+        info!(
+            "The bootloader already boots from '{}' first, BOOT_ORDER is 0x{:x}",
+            flash_dev.display(),
+            boot_order
+        );
+        return Ok(None);
     }
+
+    let mounts =
+        read_to_string(MOUNTS).upstream_with_context(&format!("Failed to read '{}'", MOUNTS))?;
+    let (boot_part, boot_fs_type) = firmware_partition(&mounts).ok_or_else(|| {
+        Error::with_context(
+            ErrorKind::NotFound,
+            "Could not find the boot partition the bootloader loads EEPROM updates from",
+        )
+    })?;
+
+    // the latest EEPROM image of the installed rpi-eeprom package, as used by
+    // rpi-eeprom-config --apply
+    let image = PathBuf::from(call_command!(
+        &eeprom_update,
+        &["-l"],
+        "Failed to find the latest bootloader EEPROM image"
+    )?);
+
+    let update_dir = path_append(takeover_dir, EEPROM_UPDATE_DIR);
+    create_dir_all(&update_dir)
+        .upstream_with_context(&format!("Failed to create '{}'", update_dir.display()))?;
+
+    let config_path = path_append(&update_dir, EEPROM_CONFIG_FILE);
+    write(&config_path, set_config_boot_order(&config, new_boot_order))
+        .upstream_with_context(&format!("Failed to write '{}'", config_path.display()))?;
+
+    let upd_path = path_append(&update_dir, EEPROM_UPD_FILE);
+    call_command!(
+        &eeprom_config,
+        &[
+            "--config",
+            &config_path.to_string_lossy(),
+            "--out",
+            &upd_path.to_string_lossy(),
+            &image.to_string_lossy()
+        ],
+        "Failed to create the bootloader EEPROM update"
+    )?;
+    remove_file(&config_path)
+        .upstream_with_context(&format!("Failed to remove '{}'", config_path.display()))?;
+
+    // the bootloader checks the update against the sha256 in the signature file
+    let upd = read(&upd_path)
+        .upstream_with_context(&format!("Failed to read '{}'", upd_path.display()))?;
+    let digest: String = sha256(&upd)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let sig_path = path_append(&update_dir, EEPROM_SIG_FILE);
+    write(&sig_path, format!("{}\nts: {}\n", digest, ts))
+        .upstream_with_context(&format!("Failed to write '{}'", sig_path.display()))?;
+
+    let recovery_src = image
+        .parent()
+        .map(|dir| path_append(dir, EEPROM_RECOVERY_FILE))
+        .unwrap_or_default();
+    let recovery_path = path_append(&update_dir, EEPROM_RECOVERY_FILE);
+    copy(&recovery_src, &recovery_path).upstream_with_context(&format!(
+        "Failed to copy '{}' to '{}'",
+        recovery_src.display(),
+        recovery_path.display()
+    ))?;
+
+    info!(
+        "Staged a bootloader EEPROM update changing BOOT_ORDER from 0x{:x} to 0x{:x}",
+        boot_order, new_boot_order
+    );
+
+    Ok(Some(EepromUpdate {
+        boot_order: new_boot_order,
+        boot_part,
+        boot_fs_type,
+        files: [EEPROM_UPD_FILE, EEPROM_SIG_FILE, EEPROM_RECOVERY_FILE]
+            .iter()
+            .map(|name| path_append(path_append("/", EEPROM_UPDATE_DIR), name))
+            .collect(),
+    }))
 }
 
-pub(crate) struct RaspberryPi2;
-impl RaspberryPi2 {
-    pub fn from_config(opts: &Options) -> Result<RaspberryPi2> {
-        if opts.migrate() && !check_os(&SUPPORTED_OSSES, opts, "Raspberry PI 2")? {
-            return Err(Error::displayed());
+fn find_eeprom_cmd(cmd: &str) -> Result<String> {
+    whereis(cmd).error_with_all(
+        ErrorKind::FileNotFound,
+        &format!(
+            "Command '{}' is needed to change the boot order, please install the rpi-eeprom package",
+            cmd
+        ),
+    )
+}
+
+/// The vfat partition mounted on /boot/firmware or /boot, the bootloader loads
+/// the firmware and EEPROM updates from it
+fn firmware_partition(mounts: &str) -> Option<(PathBuf, String)> {
+    FIRMWARE_MPS.iter().find_map(|firmware_mp| {
+        mounts.lines().find_map(|line| {
+            let mut words = line.split_whitespace();
+            let (device, mountpoint, fs_type) = (words.next()?, words.next()?, words.next()?);
+            if mountpoint == *firmware_mp && fs_type == "vfat" {
+                Some((PathBuf::from(device), fs_type.to_string()))
+            } else {
+                None
+            }
+        })
+    })
+}
+
+/// Copy the EEPROM update staged in stage1 to the boot partition of the old
+/// disk, the bootloader flashes it on the next reboot
+fn install_eeprom_update(s2_cfg: &Stage2Config) -> Result<()> {
+    let update = if let Some(update) = &s2_cfg.eeprom_update {
+        update
+    } else {
+        return Ok(());
+    };
+
+    create_dir_all(EEPROM_BOOT_MP)
+        .upstream_with_context(&format!("Failed to create '{}'", EEPROM_BOOT_MP))?;
+    mount(
+        Some(&update.boot_part),
+        EEPROM_BOOT_MP,
+        Some(update.boot_fs_type.as_bytes()),
+        MsFlags::empty(),
+        NIX_NONE,
+    )
+    .upstream_with_context(&format!(
+        "Failed to mount '{}' on '{}'",
+        update.boot_part.display(),
+        EEPROM_BOOT_MP
+    ))?;
+
+    let res: Result<()> = update.files.iter().try_for_each(|file| {
+        let target = path_append(EEPROM_BOOT_MP, file.file_name().unwrap_or_default());
+        copy(file, &target).upstream_with_context(&format!(
+            "Failed to copy '{}' to '{}'",
+            file.display(),
+            target.display()
+        ))?;
+        Ok(())
+    });
+    sync();
+    umount(EEPROM_BOOT_MP)
+        .upstream_with_context(&format!("Failed to unmount '{}'", EEPROM_BOOT_MP))?;
+    res?;
+
+    info!(
+        "Installed the bootloader EEPROM update on '{}', BOOT_ORDER 0x{:x} is applied on the next reboot",
+        update.boot_part.display(),
+        update.boot_order
+    );
+    Ok(())
+}
+
+/// Whether USB boot is enabled in the OTP of a Raspberry Pi 3, None if the
+/// OTP dump has no register 17
+fn otp_usb_boot(otp_dump: &str) -> Option<bool> {
+    otp_dump.lines().find_map(|line| {
+        let (reg, value) = line.split_once(':')?;
+        if reg.trim() == OTP_USB_BOOT_REG {
+            u32::from_str_radix(value.trim(), 16)
+                .ok()
+                .map(|value| value & OTP_USB_BOOT_BIT != 0)
+        } else {
+            None
+        }
+    })
+}
+
+/// The Raspberry Pi 3 has no boot order, it boots from USB if USB boot is
+/// enabled in the OTP and no bootable SD card is found
+fn check_usb_boot(opts: &Options, flash_dev: &Path) -> Result<()> {
+    if boot_mode(flash_dev) != Some(BOOT_MODE_USB) {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "The Raspberry Pi 3 can not boot from '{}'",
+                flash_dev.display()
+            ),
+        ));
+    }
+
+    match whereis(VCGENCMD_CMD) {
+        Ok(vcgencmd) => {
+            let otp_dump = call_command!(&vcgencmd, &["otp_dump"], "Failed to dump the OTP")?;
+            if otp_usb_boot(&otp_dump) == Some(false) {
+                error!(
+                    "USB boot is not enabled, please add 'program_usb_boot_mode=1' to config.txt and reboot once before migrating to '{}'",
+                    flash_dev.display()
+                );
+                return Err(Error::displayed());
+            }
+        }
+        Err(why) => {
+            warn!(
+                "Could not check whether USB boot is enabled, command '{}' not found: {}",
+                VCGENCMD_CMD, why
+            );
         }
+    }
 
-        Ok(RaspberryPi2 {})
+    if !opts.disable_old_boot() {
+        warn!(
+            "The Raspberry Pi 3 boots from the SD card first, remove it after the migration or use --disable-old-boot to boot from '{}'",
+            flash_dev.display()
+        );
     }
+    Ok(())
 }
 
-impl Device for RaspberryPi2 {
-    fn supports_device_type(&self, dev_type: &str) -> bool {
-        RPI2_SLUGS.contains(&dev_type)
+pub(crate) struct RaspberryPi1;
+
+impl Device for RaspberryPi1 {
+    fn get_device_type(&self) -> DeviceType {
+        DeviceType::RaspberryPi1
     }
+}
+
+pub(crate) struct RaspberryPi2;
 
+impl Device for RaspberryPi2 {
     fn get_device_type(&self) -> DeviceType {
         DeviceType::RaspberryPi2
     }
@@ -131,44 +399,170 @@ impl Device for RaspberryPi2 {
 
 pub(crate) struct RaspberryPi3;
 
-impl RaspberryPi3 {
-    pub fn from_config(opts: &Options) -> Result<RaspberryPi3> {
-        if opts.migrate() && !check_os(&SUPPORTED_OSSES, opts, "Raspberry PI 3")? {
-            return Err(Error::displayed());
-        }
+impl Device for RaspberryPi3 {
+    fn get_device_type(&self) -> DeviceType {
+        DeviceType::RaspberryPi3
+    }
 
-        Ok(RaspberryPi3)
+    fn prepare_boot_device(
+        &self,
+        opts: &Options,
+        _takeover_dir: &Path,
+        flash_dev: &Path,
+    ) -> Result<Option<EepromUpdate>> {
+        check_usb_boot(opts, flash_dev)?;
+        Ok(None)
     }
 }
 
-impl Device for RaspberryPi3 {
-    fn supports_device_type(&self, dev_type: &str) -> bool {
-        RPI3_SLUGS.contains(&dev_type)
+pub(crate) struct RaspberryPi4_64;
+
+impl Device for RaspberryPi4_64 {
+    fn get_device_type(&self) -> DeviceType {
+        DeviceType::RaspberryPi4
+    }
+
+    fn prepare_boot_device(
+        &self,
+        _opts: &Options,
+        takeover_dir: &Path,
+        flash_dev: &Path,
+    ) -> Result<Option<EepromUpdate>> {
+        stage_eeprom_update(takeover_dir, flash_dev)
+    }
+
+    fn set_boot_order(&self, s2_cfg: &Stage2Config) -> Result<()> {
+        install_eeprom_update(s2_cfg)
     }
+}
 
+pub(crate) struct RaspberryPi400;
+
+impl Device for RaspberryPi400 {
     fn get_device_type(&self) -> DeviceType {
-        DeviceType::RaspberryPi3
+        DeviceType::RaspberryPi400
+    }
+
+    fn prepare_boot_device(
+        &self,
+        _opts: &Options,
+        takeover_dir: &Path,
+        flash_dev: &Path,
+    ) -> Result<Option<EepromUpdate>> {
+        stage_eeprom_update(takeover_dir, flash_dev)
+    }
+
+    fn set_boot_order(&self, s2_cfg: &Stage2Config) -> Result<()> {
+        install_eeprom_update(s2_cfg)
     }
 }
 
-pub(crate) struct RaspberryPi4_64;
+pub(crate) struct RaspberryPiCM4;
 
-impl RaspberryPi4_64 {
-    pub fn from_config(opts: &Options) -> Result<RaspberryPi4_64> {
-        if opts.migrate() && !check_os(&SUPPORTED_OSSES, opts, "Raspberry PI 4")? {
-            return Err(Error::displayed());
-        }
+impl Device for RaspberryPiCM4 {
+    fn get_device_type(&self) -> DeviceType {
+        DeviceType::RaspberryPiCM4
+    }
 
-        Ok(RaspberryPi4_64)
+    fn prepare_boot_device(
+        &self,
+        _opts: &Options,
+        takeover_dir: &Path,
+        flash_dev: &Path,
+    ) -> Result<Option<EepromUpdate>> {
+        stage_eeprom_update(takeover_dir, flash_dev)
+    }
+
+    fn set_boot_order(&self, s2_cfg: &Stage2Config) -> Result<()> {
+        install_eeprom_update(s2_cfg)
     }
 }
 
-impl Device for RaspberryPi4_64 {
-    fn supports_device_type(&self, dev_type: &str) -> bool {
-        RPI4_64_SLUGS.contains(&dev_type)
+pub(crate) struct RaspberryPiZero2W;
+
+impl Device for RaspberryPiZero2W {
+    fn get_device_type(&self) -> DeviceType {
+        DeviceType::RaspberryPiZero2W
     }
+}
 
+pub(crate) struct RaspberryPi5;
+
+impl Device for RaspberryPi5 {
     fn get_device_type(&self) -> DeviceType {
-        DeviceType::RaspberryPi4
+        DeviceType::RaspberryPi5
+    }
+
+    fn prepare_boot_device(
+        &self,
+        _opts: &Options,
+        takeover_dir: &Path,
+        flash_dev: &Path,
+    ) -> Result<Option<EepromUpdate>> {
+        stage_eeprom_update(takeover_dir, flash_dev)
+    }
+
+    fn set_boot_order(&self, s2_cfg: &Stage2Config) -> Result<()> {
+        install_eeprom_update(s2_cfg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_revision_type() {
+        let cpu_info = "processor\t: 3\nBogoMIPS\t: 108.00\n\nHardware\t: BCM2835\nRevision\t: c04170\nSerial\t\t: 100000001234abcd\n";
+        assert_eq!(revision_type(cpu_info), Some(0x17));
+        assert_eq!(revision_type("Revision\t: a020d3\n"), Some(0x0d));
+        // old style Raspberry Pi 1 B revision
+        assert_eq!(revision_type("Revision\t: 000e\n"), None);
+        assert_eq!(revision_type("processor\t: 0\n"), None);
+    }
+
+    #[test]
+    fn moves_boot_mode_first() {
+        assert_eq!(boot_order_first(0xf41, BOOT_MODE_USB), 0xf14);
+        assert_eq!(boot_order_first(0xf41, BOOT_MODE_SD), 0xf41);
+        assert_eq!(boot_order_first(0xf41, BOOT_MODE_NVME), 0xf416);
+        assert_eq!(boot_order_first(0x12345678, BOOT_MODE_NVME), 0x12345786);
+        assert_eq!(boot_order_first(0x21436578, 0x9), 0x14365789);
+    }
+
+    #[test]
+    fn updates_eeprom_config() {
+        let config = "[all]\nBOOT_UART=0\nBOOT_ORDER=0xf41\n";
+        assert_eq!(config_boot_order(config), Some(0xf41));
+        let config = set_config_boot_order(config, 0xf14);
+        assert_eq!(config, "[all]\nBOOT_UART=0\nBOOT_ORDER=0xf14\n");
+
+        let config = set_config_boot_order("[all]\nBOOT_UART=0\n[cm4]\nPCIE_PROBE=1\n", 0xf416);
+        assert_eq!(config_boot_order(&config), Some(0xf416));
+        assert!(config.ends_with("[cm4]\nPCIE_PROBE=1\n[all]\nBOOT_ORDER=0xf416\n"));
+    }
+
+    #[test]
+    fn finds_firmware_partition() {
+        let mounts = "/dev/mmcblk0p2 / ext4 rw,noatime 0 0\n\
+                      /dev/mmcblk0p1 /boot/firmware vfat rw,relatime 0 0\n";
+        assert_eq!(
+            firmware_partition(mounts),
+            Some((PathBuf::from("/dev/mmcblk0p1"), "vfat".to_string()))
+        );
+        assert_eq!(
+            firmware_partition("/dev/sda2 / ext4 rw 0 0\n/dev/sda3 /boot ext4 rw 0 0\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn reads_otp_usb_boot() {
+        assert_eq!(
+            otp_usb_boot("16:00280000\n17:3020000a\n18:3020000a\n"),
+            Some(true)
+        );
+        assert_eq!(otp_usb_boot("16:00280000\n17:1020000a\n"), Some(false));
+        assert_eq!(otp_usb_boot("16:00280000\n"), None);
     }
 }
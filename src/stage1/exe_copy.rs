@@ -59,6 +59,14 @@ impl ExeCopy {
         self.req_space
     }
 
+    pub fn executables(&self) -> impl Iterator<Item = &str> {
+        self.executables.iter().map(|path| path.as_str())
+    }
+
+    pub fn libraries(&self) -> impl Iterator<Item = &str> {
+        self.libraries.iter().map(|path| path.as_str())
+    }
+
     fn get_libs_for(&mut self) -> Result<()> {
         trace!("get_libs_for: entered");
         let mut check_libs: HashSet<String> = HashSet::new();
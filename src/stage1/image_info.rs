@@ -0,0 +1,355 @@
+use std::cell::OnceCell;
+use std::fs::{read, read_to_string, remove_dir, remove_file, OpenOptions};
+use std::io::copy;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info, warn};
+use nix::mount::{mount, umount, MsFlags};
+use serde_json::Value;
+
+use crate::{
+    common::{
+        compression::Compression,
+        defs::{BALENA_BOOT_FSTYPE, BALENA_ROOTA_FSTYPE, EFI_LOADER_FILE, NIX_NONE},
+        disk_util::{Disk, LabelType, PartInfo, PartitionReader},
+        format_size_with_unit,
+        loop_device::LoopDevice,
+        path_append, Error, ErrorKind, Result, ToError,
+    },
+    stage1::{
+        device_impl::{
+            boot_firmware::{boot_firmware_space, FirmwareRegion},
+            efi_boot::efi_file_path,
+        },
+        secure_boot::authenticode_signature,
+        utils::mktemp,
+    },
+};
+
+const DEVICE_TYPE_FILE: &str = "device-type.json";
+const OS_RELEASE_FILE: &str = "os-release";
+
+/// Information gathered from the balenaOS image before anything is written
+pub(crate) struct ImageInfo {
+    image_path: PathBuf,
+    work_dir: PathBuf,
+    compression: Compression,
+    label: LabelType,
+    required_size: u64,
+    device_type: String,
+    version: Option<String>,
+    efi_loader: Option<EfiLoader>,
+    boot_firmware_space: OnceCell<Option<u64>>,
+}
+
+/// The EFI loader found in the boot partition of the image
+struct EfiLoader {
+    /// path of the loader in the boot partition
+    path: String,
+    /// Authenticode signature of the loader, None for unsigned loaders
+    signature: Option<Vec<u8>>,
+}
+
+impl ImageInfo {
+    /// Inspect the image, the boot partition is extracted to work_dir to read
+    /// the device type
+    pub fn new(image_path: &Path, work_dir: &Path) -> Result<ImageInfo> {
+        let compression = Compression::from_file(image_path)?;
+        debug!(
+            "ImageInfo::new: inspecting {} image '{}'",
+            compression,
+            image_path.display()
+        );
+
+        let mut disk = Disk::from_image(image_path)?;
+        let label = disk.get_label()?;
+        if label == LabelType::Other {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "No partition table found in image '{}'",
+                    image_path.display()
+                ),
+            ));
+        }
+
+        let (boot_part, _root_a_part, _data_part) = disk.get_balena_partitions()?;
+        let required_size = disk.get_required_size()?;
+        let (device_type, version, efi_loader) = read_boot_info(&mut disk, &boot_part, work_dir)?;
+
+        info!(
+            "Image '{}': {}, {:?} partition table, {} required, device type '{}', version '{}'",
+            image_path.display(),
+            compression,
+            label,
+            format_size_with_unit(required_size),
+            device_type,
+            version.as_deref().unwrap_or("unknown")
+        );
+
+        Ok(ImageInfo {
+            image_path: image_path.to_path_buf(),
+            work_dir: work_dir.to_path_buf(),
+            compression,
+            label,
+            required_size,
+            device_type,
+            version,
+            efi_loader,
+            boot_firmware_space: OnceCell::new(),
+        })
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    #[allow(dead_code)]
+    pub fn label(&self) -> &LabelType {
+        &self.label
+    }
+
+    pub fn required_size(&self) -> u64 {
+        self.required_size
+    }
+
+    pub fn device_type(&self) -> &str {
+        &self.device_type
+    }
+
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    /// The path of the EFI loader as used in EFI boot entries, eg. \EFI\BOOT\bootx64.efi
+    pub fn efi_loader_path(&self) -> Option<String> {
+        self.efi_loader
+            .as_ref()
+            .map(|loader| efi_file_path(&loader.path))
+    }
+
+    /// The Authenticode signature of the EFI loader, None if the image has no
+    /// signed EFI loader
+    pub fn efi_signature(&self) -> Option<&[u8]> {
+        self.efi_loader
+            .as_ref()
+            .and_then(|loader| loader.signature.as_deref())
+    }
+
+    /// The memory writing the boot firmware of the image takes in stage2, see
+    /// boot_firmware_space, None if the image ships no boot firmware. The root
+    /// partition is extracted to work_dir for this, so it is only read for
+    /// boards with boot firmware and only once.
+    pub fn boot_firmware_space(&self, legacy_regions: &[FirmwareRegion]) -> Result<Option<u64>> {
+        if let Some(space) = self.boot_firmware_space.get() {
+            return Ok(*space);
+        }
+
+        let mut disk = Disk::from_image(&self.image_path)?;
+        let (_boot_part, root_a_part, _data_part) = disk.get_balena_partitions()?;
+        let space = with_mounted_partition(
+            &mut disk,
+            &root_a_part,
+            BALENA_ROOTA_FSTYPE,
+            &self.work_dir,
+            |mount_dir| boot_firmware_space(mount_dir, legacy_regions),
+        )?;
+        debug!("boot_firmware_space: {:?}", space);
+        Ok(*self.boot_firmware_space.get_or_init(|| space))
+    }
+}
+
+/// Read the device type slug from device-type.json, the OS version from
+/// os-release and the EFI loader from the boot partition
+fn read_boot_info(
+    disk: &mut Disk,
+    boot_part: &PartInfo,
+    work_dir: &Path,
+) -> Result<(String, Option<String>, Option<EfiLoader>)> {
+    with_mounted_partition(disk, boot_part, BALENA_BOOT_FSTYPE, work_dir, |mount_dir| {
+        parse_device_type(&path_append(mount_dir, DEVICE_TYPE_FILE)).map(|device_type| {
+            (
+                device_type,
+                parse_os_version(&path_append(mount_dir, OS_RELEASE_FILE)),
+                read_efi_loader(mount_dir),
+            )
+        })
+    })
+}
+
+/// Extract a partition to a file in work_dir, loop mount it read only and
+/// call read with the mount directory
+fn with_mounted_partition<T, F: FnOnce(&Path) -> Result<T>>(
+    disk: &mut Disk,
+    part: &PartInfo,
+    fs_type: &str,
+    work_dir: &Path,
+    read: F,
+) -> Result<T> {
+    let part_img = mktemp(false, Some("image-part."), Some(".img"), Some(work_dir))?;
+
+    let res = extract_partition(disk, part, &part_img)
+        .and_then(|_| mount_partition(&part_img, fs_type, work_dir, read));
+
+    if let Err(why) = remove_file(&part_img) {
+        warn!(
+            "Failed to remove temporary file '{}', error: {}",
+            part_img.display(),
+            why
+        );
+    }
+
+    res
+}
+
+fn extract_partition(disk: &mut Disk, part: &PartInfo, target: &Path) -> Result<()> {
+    let mut target_file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(target)
+        .upstream_with_context(&format!(
+            "Failed to open '{}' for writing",
+            target.display()
+        ))?;
+
+    let mut reader = PartitionReader::from_disk(part, disk);
+    let bytes = copy(&mut reader, &mut target_file).upstream_with_context(&format!(
+        "Failed to extract partition {} to '{}'",
+        part.index,
+        target.display()
+    ))?;
+
+    debug!(
+        "extract_partition: extracted {} from partition {} to '{}'",
+        format_size_with_unit(bytes),
+        part.index,
+        target.display()
+    );
+    Ok(())
+}
+
+fn mount_partition<T, F: FnOnce(&Path) -> Result<T>>(
+    part_img: &Path,
+    fs_type: &str,
+    work_dir: &Path,
+    read: F,
+) -> Result<T> {
+    let mount_dir = mktemp(true, Some("image-mnt."), None, Some(work_dir))?;
+    let loop_device = LoopDevice::for_file(part_img, None, None, None, true)?;
+
+    let res = mount(
+        Some(loop_device.get_path()),
+        &mount_dir,
+        Some(fs_type.as_bytes()),
+        MsFlags::MS_RDONLY,
+        NIX_NONE,
+    )
+    .upstream_with_context(&format!(
+        "Failed to mount '{}' on '{}'",
+        loop_device.get_path().display(),
+        mount_dir.display()
+    ))
+    .and_then(|_| {
+        let res = read(&mount_dir);
+        umount(&mount_dir)
+            .upstream_with_context(&format!("Failed to unmount '{}'", mount_dir.display()))?;
+        res
+    });
+
+    drop(loop_device);
+    let _res = remove_dir(&mount_dir);
+    res
+}
+
+fn parse_device_type(path: &Path) -> Result<String> {
+    let content = read_to_string(path)
+        .upstream_with_context(&format!("Failed to read '{}'", path.display()))?;
+    let device_type: Value = serde_json::from_str(&content)
+        .upstream_with_context(&format!("Failed to parse '{}'", path.display()))?;
+
+    if let Some(slug) = device_type.get("slug").and_then(|slug| slug.as_str()) {
+        Ok(slug.to_string())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!("No device type slug found in '{}'", path.display()),
+        ))
+    }
+}
+
+/// Images for devices booting without EFI have no EFI loader
+fn read_efi_loader(mount_dir: &Path) -> Option<EfiLoader> {
+    let path = path_append(mount_dir, EFI_LOADER_FILE);
+    match read(&path) {
+        Ok(loader) => {
+            let signature = authenticode_signature(&loader).map(|signature| signature.to_vec());
+            debug!(
+                "read_efi_loader: found {} EFI loader '{}'",
+                if signature.is_some() {
+                    "signed"
+                } else {
+                    "unsigned"
+                },
+                EFI_LOADER_FILE
+            );
+            Some(EfiLoader {
+                path: EFI_LOADER_FILE.to_string(),
+                signature,
+            })
+        }
+        Err(why) => {
+            debug!("Failed to read '{}', error: {}", path.display(), why);
+            None
+        }
+    }
+}
+
+/// Older images might not carry os-release on the boot partition, so the
+/// version is optional
+fn parse_os_version(path: &Path) -> Option<String> {
+    let content = match read_to_string(path) {
+        Ok(content) => content,
+        Err(why) => {
+            debug!("Failed to read '{}', error: {}", path.display(), why);
+            return None;
+        }
+    };
+
+    let mut version = None;
+    for line in content.lines() {
+        if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim().trim_matches('"');
+            match key.trim() {
+                // META_BALENA_VERSION carries the full balenaOS version
+                "META_BALENA_VERSION" => return Some(value.to_string()),
+                "VERSION_ID" | "VERSION" if version.is_none() => version = Some(value.to_string()),
+                _ => (),
+            }
+        }
+    }
+    version
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::write;
+
+    #[test]
+    fn parse_os_release_version() {
+        let path = temp_dir().join("takeover-os-release");
+        write(
+            &path,
+            "ID=\"balena-os\"\nNAME=\"balenaOS\"\nVERSION=\"5.1.20\"\nVERSION_ID=\"5.1.20\"\nMETA_BALENA_VERSION=\"5.1.20+rev1\"\n",
+        )
+        .unwrap();
+        assert_eq!(parse_os_version(&path).as_deref(), Some("5.1.20+rev1"));
+
+        write(&path, "ID=\"balena-os\"\nVERSION_ID=\"2.113.18\"\n").unwrap();
+        assert_eq!(parse_os_version(&path).as_deref(), Some("2.113.18"));
+
+        remove_file(&path).unwrap();
+        assert_eq!(parse_os_version(&path), None);
+    }
+}
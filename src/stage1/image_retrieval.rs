@@ -1,39 +1,37 @@
-use std::fs::File;
-use std::io::copy;
+use std::fs::{metadata, read_to_string, remove_file, rename, write, File, OpenOptions};
+use std::io::{copy, Read};
 use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::Duration;
 
 use log::{debug, error, info, warn, Level};
 
+use openssl::{
+    hash::{Hasher, MessageDigest},
+    sha::Sha256,
+};
 use semver::{Version, VersionReq};
 
+mod image_mirror;
+use image_mirror::{image_name, ImageMirror};
+
 use crate::{
     common::{
-        api_calls::{get_os_image, get_os_versions, Versions},
-        path_append,
+        api_calls::{get_os_image, get_os_versions, ImageChecksum, ImageDownload, Versions},
+        format_size_with_unit, path_append,
         stream_progress::StreamProgress,
         Error, Options, Result, ToError,
     },
-    stage1::{
-        defs::{
-            DEV_TYPE_BBB, DEV_TYPE_BBG, DEV_TYPE_GEN_X86_64, DEV_TYPE_INTEL_NUC,
-            DEV_TYPE_JETSON_XAVIER, DEV_TYPE_RPI1, DEV_TYPE_RPI2, DEV_TYPE_RPI3, DEV_TYPE_RPI4_64,
-        },
-        migrate_info::balena_cfg_json::BalenaCfgJson,
-    },
+    stage1::{migrate_info::balena_cfg_json::BalenaCfgJson, support_matrix::SupportMatrix},
     ErrorKind,
 };
 
-const SUPPORTED_DEVICES: [&str; 9] = [
-    DEV_TYPE_RPI3,
-    DEV_TYPE_RPI2,
-    DEV_TYPE_RPI4_64,
-    DEV_TYPE_RPI1,
-    DEV_TYPE_INTEL_NUC,
-    DEV_TYPE_GEN_X86_64,
-    DEV_TYPE_BBG,
-    DEV_TYPE_BBB,
-    DEV_TYPE_JETSON_XAVIER,
-];
+const DOWNLOAD_ATTEMPTS: u32 = 5;
+const DOWNLOAD_RETRY_DELAY: Duration = Duration::from_secs(10);
+// the image is downloaded to <image>.part and renamed once it has been verified
+const PART_FILE_EXT: &str = "part";
+// holds the sha256 sum of a verified image, in sha256sum format
+const VERIFIED_FILE_EXT: &str = "sha256";
 
 fn parse_versions(versions: &Versions) -> Vec<Version> {
     let mut sem_vers: Vec<Version> = versions
@@ -130,12 +128,13 @@ fn determine_version(ver_str: &str, versions: &Versions) -> Result<Version> {
 
 pub(crate) fn download_image(
     opts: &Options,
+    matrix: &SupportMatrix,
     balena_cfg: &BalenaCfgJson,
     work_dir: &Path,
     device_type: &str,
     version: &str,
 ) -> Result<PathBuf> {
-    if !SUPPORTED_DEVICES.contains(&device_type) {
+    if !matrix.supports_download(device_type) {
         if opts.dt_check() {
             return Err(Error::with_context(
                 ErrorKind::InvParam,
@@ -160,7 +159,21 @@ pub(crate) fn download_image(
         "Failed to retrieve api-endpoint from config.json - unable to retrieve os-image",
     )?;
 
-    let versions = get_os_versions(&api_endpoint, &api_key, device_type)?;
+    let mirror = get_image_mirror(opts, balena_cfg);
+
+    let versions = match &mirror {
+        Some(mirror) => match mirror.get_versions(device_type) {
+            Ok(versions) => versions,
+            Err(why) => {
+                warn!(
+                    "Failed to retrieve versions from image mirror, falling back to balena cloud, error: {}",
+                    why
+                );
+                get_os_versions(&api_endpoint, &api_key, device_type)?
+            }
+        },
+        None => get_os_versions(&api_endpoint, &api_key, device_type)?,
+    };
 
     let version = determine_version(version, &versions)?;
 
@@ -171,25 +184,62 @@ pub(crate) fn download_image(
 
     // TODO: extract OS image for flasher
 
-    let stream = get_os_image(&api_endpoint, &api_key, device_type, &version.to_string())?;
+    let img_file_name = path_append(work_dir, image_name(device_type, &version.to_string()));
 
-    let img_file_name = path_append(
-        work_dir,
-        format!("balena-cloud-{}-{}.img.gz", device_type, version),
-    );
+    if is_verified(&img_file_name)? {
+        info!(
+            "Using previously downloaded image '{}'",
+            img_file_name.display()
+        );
+        return Ok(img_file_name);
+    }
 
-    debug!("Downloading file '{}'", img_file_name.display());
-    let mut file = File::create(&img_file_name).upstream_with_context(&format!(
-        "Failed to create file: '{}'",
-        img_file_name.display()
-    ))?;
+    let part_file_name = img_file_name.with_extension(format!("gz.{}", PART_FILE_EXT));
+    debug!("Downloading file '{}'", part_file_name.display());
+
+    let version = version.to_string();
+    let from_cloud = || {
+        fetch_image(
+            &part_file_name,
+            DOWNLOAD_ATTEMPTS,
+            DOWNLOAD_RETRY_DELAY,
+            |offset, etag| {
+                get_os_image(&api_endpoint, &api_key, device_type, &version, offset, etag)
+            },
+        )
+    };
+
+    let sha256 = if let Some(mirror) = &mirror {
+        info!("Downloading image from mirror {:?}", mirror);
+        match fetch_image(
+            &part_file_name,
+            DOWNLOAD_ATTEMPTS,
+            DOWNLOAD_RETRY_DELAY,
+            |offset, etag| mirror.get_image(device_type, &version, offset, etag),
+        ) {
+            Ok(sha256) => sha256,
+            Err(why) => {
+                warn!(
+                    "Failed to download image from mirror, falling back to balena cloud, error: {}",
+                    why
+                );
+                // do not resume a partial download from a different source
+                let _res = remove_file(&part_file_name);
+                from_cloud()?
+            }
+        }
+    } else {
+        from_cloud()?
+    };
 
-    // TODO: show progress
-    let mut progress = StreamProgress::new(stream, 10, Level::Info, None);
-    copy(&mut progress, &mut file).upstream_with_context(&format!(
-        "Failed to write downloaded data to '{}'",
+    rename(&part_file_name, &img_file_name).upstream_with_context(&format!(
+        "Failed to rename '{}' to '{}'",
+        part_file_name.display(),
         img_file_name.display()
     ))?;
+
+    write_verified(&img_file_name, &sha256)?;
+
     info!(
         "The balena OS image was successfully written to '{}'",
         img_file_name.display()
@@ -198,6 +248,287 @@ pub(crate) fn download_image(
     Ok(img_file_name)
 }
 
+/// The mirror given on the command line takes precedence over the one in config.json
+fn get_image_mirror(opts: &Options, balena_cfg: &BalenaCfgJson) -> Option<ImageMirror> {
+    let location = match opts.image_mirror() {
+        Some(location) => location.to_string(),
+        None => match balena_cfg.get_image_mirror() {
+            Ok(location) => location?,
+            Err(why) => {
+                warn!(
+                    "Failed to read image mirror from config.json, error: {}",
+                    why
+                );
+                return None;
+            }
+        },
+    };
+
+    match ImageMirror::new(&location) {
+        Ok(mirror) => Some(mirror),
+        Err(why) => {
+            warn!(
+                "Ignoring invalid image mirror '{}', error: {}",
+                location, why
+            );
+            None
+        }
+    }
+}
+
+/// Download the image to part_file, resuming from the end of part_file on
+/// every attempt. Returns the sha256 sum of the complete, verified file.
+fn fetch_image<F>(
+    part_file: &Path,
+    attempts: u32,
+    retry_delay: Duration,
+    mut request: F,
+) -> Result<Vec<u8>>
+where
+    F: FnMut(u64, Option<&str>) -> Result<ImageDownload>,
+{
+    let mut size: Option<u64> = None;
+    let mut checksum: Option<ImageChecksum> = None;
+    let mut etag: Option<String> = None;
+    let mut complete = false;
+
+    for attempt in 1..=attempts {
+        if attempt > 1 {
+            sleep(retry_delay);
+        }
+
+        let offset = file_size(part_file);
+        if offset > 0 && Some(offset) == size {
+            complete = true;
+            break;
+        }
+
+        info!(
+            "Downloading image, attempt {} of {}, starting at {}",
+            attempt,
+            attempts,
+            format_size_with_unit(offset)
+        );
+
+        let download = match request(offset, etag.as_deref()) {
+            Ok(download) => download,
+            Err(why) => {
+                if why.kind() == ErrorKind::InvParam && offset > 0 {
+                    warn!("The server rejected the download range, restarting the download");
+                    let _res = remove_file(part_file);
+                } else {
+                    warn!("Failed to request image, error: {}", why);
+                }
+                continue;
+            }
+        };
+
+        if download.offset != offset {
+            warn!("The server did not resume the download, restarting the download");
+        }
+
+        // the server might return the complete image if it has changed, so
+        // only keep what we know about the current one
+        if download.offset == 0 {
+            size = download.size;
+            checksum = download.checksum.clone();
+            etag = download.etag.clone();
+        } else {
+            size = download.size.or(size);
+            checksum = download.checksum.clone().or(checksum);
+            etag = download.etag.clone().or(etag);
+        }
+
+        let mut file = if download.offset == 0 {
+            File::create(part_file)
+        } else {
+            OpenOptions::new().append(true).open(part_file)
+        }
+        .upstream_with_context(&format!("Failed to open file: '{}'", part_file.display()))?;
+
+        let remaining = size.map(|size| size.saturating_sub(download.offset));
+        let mut progress = StreamProgress::new(download.stream, 10, Level::Info, remaining);
+        if let Err(why) = copy(&mut progress, &mut file) {
+            warn!(
+                "Download interrupted at {}, error: {}",
+                format_size_with_unit(file_size(part_file)),
+                why
+            );
+            continue;
+        }
+
+        let written = file_size(part_file);
+        match size {
+            Some(size) if written < size => warn!(
+                "Download ended after {} of {}",
+                format_size_with_unit(written),
+                format_size_with_unit(size)
+            ),
+            _ => {
+                complete = true;
+                break;
+            }
+        }
+    }
+
+    if !complete {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!("Failed to download image after {} attempts", attempts),
+        ));
+    }
+
+    verify_image(part_file, size, checksum.as_ref()).inspect_err(|_why| {
+        // a corrupt download can not be resumed
+        let _res = remove_file(part_file);
+    })
+}
+
+fn file_size(path: &Path) -> u64 {
+    metadata(path).map(|metadata| metadata.len()).unwrap_or(0)
+}
+
+/// Check size and checksum of the downloaded image, returning its sha256 sum
+fn verify_image(
+    path: &Path,
+    size: Option<u64>,
+    checksum: Option<&ImageChecksum>,
+) -> Result<Vec<u8>> {
+    let file_size = file_size(path);
+    if let Some(size) = size {
+        if file_size != size {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "Downloaded image '{}' has size {}, expected {}",
+                    path.display(),
+                    file_size,
+                    size
+                ),
+            ));
+        }
+    } else {
+        warn!("The server did not report the image size, it can not be verified");
+    }
+
+    let (sha256, md5) = file_digests(path)?;
+    let (expected, actual) = match checksum {
+        Some(ImageChecksum::Sha256(expected)) => (expected, &sha256),
+        Some(ImageChecksum::Md5(expected)) => (expected, &md5),
+        Some(ImageChecksum::EtagMd5(expected)) => {
+            if *expected != md5 {
+                warn!(
+                    "The MD5 sum {} of the downloaded image does not match the ETag {} of the server, the ETag is not an MD5 sum on all servers, only the size was verified",
+                    hex_string(&md5),
+                    hex_string(expected)
+                );
+                return Ok(sha256);
+            }
+            (expected, &md5)
+        }
+        None => {
+            warn!("The server did not report an image checksum, only the size was verified");
+            return Ok(sha256);
+        }
+    };
+
+    if expected != actual {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "Checksum mismatch for downloaded image '{}', expected {}, found {}",
+                path.display(),
+                hex_string(expected),
+                hex_string(actual)
+            ),
+        ));
+    }
+
+    info!(
+        "Verified downloaded image '{}', {}",
+        path.display(),
+        format_size_with_unit(file_size)
+    );
+    Ok(sha256)
+}
+
+/// Calculate sha256 and md5 sums of a file
+fn file_digests(path: &Path) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut file = File::open(path)
+        .upstream_with_context(&format!("Failed to open file: '{}'", path.display()))?;
+    let mut sha256 = Sha256::new();
+    let mut md5 =
+        Hasher::new(MessageDigest::md5()).upstream_with_context("Failed to create md5 hasher")?;
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let bytes_read = file
+            .read(&mut buffer)
+            .upstream_with_context(&format!("Failed to read file: '{}'", path.display()))?;
+        if bytes_read == 0 {
+            break;
+        }
+        sha256.update(&buffer[..bytes_read]);
+        md5.update(&buffer[..bytes_read])
+            .upstream_with_context("Failed to calculate md5 sum")?;
+    }
+    let md5 = md5
+        .finish()
+        .upstream_with_context("Failed to calculate md5 sum")?;
+    Ok((sha256.finish().to_vec(), md5.to_vec()))
+}
+
+fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn verified_file(image: &Path) -> PathBuf {
+    let mut file_name = image.as_os_str().to_owned();
+    file_name.push(".");
+    file_name.push(VERIFIED_FILE_EXT);
+    PathBuf::from(file_name)
+}
+
+fn write_verified(image: &Path, sha256: &[u8]) -> Result<()> {
+    let file_name = image
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let verified_file = verified_file(image);
+    write(
+        &verified_file,
+        format!("{}  {}\n", hex_string(sha256), file_name),
+    )
+    .upstream_with_context(&format!(
+        "Failed to write file: '{}'",
+        verified_file.display()
+    ))
+}
+
+/// An image is considered verified if it matches the sha256 sum written
+/// after it was downloaded
+fn is_verified(image: &Path) -> Result<bool> {
+    let verified_file = verified_file(image);
+    if !image.exists() || !verified_file.exists() {
+        return Ok(false);
+    }
+
+    let expected = read_to_string(&verified_file).upstream_with_context(&format!(
+        "Failed to read file: '{}'",
+        verified_file.display()
+    ))?;
+    let expected = expected.split_whitespace().next().unwrap_or("");
+    let (sha256, _md5) = file_digests(image)?;
+    if hex_string(&sha256) == expected {
+        Ok(true)
+    } else {
+        warn!(
+            "Previously downloaded image '{}' does not match its checksum",
+            image.display()
+        );
+        Ok(false)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -271,4 +602,111 @@ mod tests {
             Version::parse("3.2.25").expect("Could not parse version")
         );
     }
+
+    /// Serves the image, failing after fail_after bytes
+    struct FlakyStream {
+        data: Vec<u8>,
+        pos: usize,
+        fail_after: usize,
+    }
+
+    impl Read for FlakyStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.pos >= self.fail_after {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionReset,
+                    "connection reset",
+                ));
+            }
+            let end = self
+                .data
+                .len()
+                .min(self.fail_after)
+                .min(self.pos + buf.len());
+            let bytes = end - self.pos;
+            buf[..bytes].copy_from_slice(&self.data[self.pos..end]);
+            self.pos = end;
+            Ok(bytes)
+        }
+    }
+
+    #[test]
+    fn resumes_and_verifies_download() {
+        let image: Vec<u8> = (0..100_000u32).map(|idx| (idx % 251) as u8).collect();
+        let checksum = ImageChecksum::Sha256(openssl::sha::sha256(&image).to_vec());
+        let part_file = std::env::temp_dir().join("takeover-download.img.gz.part");
+        let _res = remove_file(&part_file);
+
+        let mut offsets = Vec::new();
+        let sha256 = fetch_image(&part_file, 3, Duration::from_secs(0), |offset, etag| {
+            offsets.push((offset, etag.map(|etag| etag.to_string())));
+            Ok(ImageDownload {
+                stream: Box::new(FlakyStream {
+                    data: image[offset as usize..].to_vec(),
+                    pos: 0,
+                    fail_after: if offset == 0 { 40_000 } else { usize::MAX },
+                }),
+                offset,
+                size: Some(image.len() as u64),
+                checksum: if offset == 0 {
+                    Some(checksum.clone())
+                } else {
+                    None
+                },
+                etag: Some("\"v1\"".to_string()),
+            })
+        })
+        .unwrap();
+
+        assert_eq!(
+            offsets,
+            vec![(0, None), (40_000, Some("\"v1\"".to_string()))]
+        );
+        assert_eq!(std::fs::read(&part_file).unwrap(), image);
+        assert_eq!(sha256, openssl::sha::sha256(&image).to_vec());
+
+        // a checksum mismatch discards the download
+        let res = fetch_image(&part_file, 1, Duration::from_secs(0), |_offset, _etag| {
+            Ok(ImageDownload {
+                stream: Box::new(std::io::Cursor::new(image.clone())),
+                offset: 0,
+                size: Some(image.len() as u64),
+                checksum: Some(ImageChecksum::Md5(vec![0u8; 16])),
+                etag: None,
+            })
+        });
+        assert!(res.is_err());
+        assert!(!part_file.exists());
+
+        // ETags are not an MD5 sum on all servers, so they do not discard it
+        let sha256 = fetch_image(&part_file, 1, Duration::from_secs(0), |_offset, _etag| {
+            Ok(ImageDownload {
+                stream: Box::new(std::io::Cursor::new(image.clone())),
+                offset: 0,
+                size: Some(image.len() as u64),
+                checksum: Some(ImageChecksum::EtagMd5(vec![0u8; 16])),
+                etag: None,
+            })
+        })
+        .unwrap();
+        assert_eq!(sha256, openssl::sha::sha256(&image).to_vec());
+        remove_file(&part_file).unwrap();
+    }
+
+    #[test]
+    fn reuses_verified_image() {
+        let image = std::env::temp_dir().join("takeover-verified.img.gz");
+        write(&image, b"balenaOS image").unwrap();
+        assert!(!is_verified(&image).unwrap());
+
+        let (sha256, _md5) = file_digests(&image).unwrap();
+        write_verified(&image, &sha256).unwrap();
+        assert!(is_verified(&image).unwrap());
+
+        write(&image, b"corrupted image").unwrap();
+        assert!(!is_verified(&image).unwrap());
+
+        remove_file(&image).unwrap();
+        remove_file(verified_file(&image)).unwrap();
+    }
 }
@@ -0,0 +1,258 @@
+use std::fs::{metadata, read_to_string, File};
+use std::io::{Seek, SeekFrom};
+use std::path::PathBuf;
+
+use log::{debug, warn};
+use reqwest::{blocking::Client, header};
+use url::Url;
+
+use crate::common::{
+    api_calls::{add_range_headers, to_image_download, ImageChecksum, ImageDownload, Versions},
+    path_append, Error, ErrorKind, Result, ToError,
+};
+
+// index of the versions available for a device type, a JSON array of version strings
+const VERSIONS_FILE: &str = "versions.json";
+// optional checksum file next to the image, in sha256sum format
+const CHECKSUM_EXT: &str = "sha256";
+
+/// A local source of balenaOS images, either served over HTTP or as a directory
+/// e.g. on a network share. Both use the same layout:
+/// ```text
+/// <mirror>/<device-type>/versions.json
+/// <mirror>/<device-type>/balena-cloud-<device-type>-<version>.img.gz
+/// <mirror>/<device-type>/balena-cloud-<device-type>-<version>.img.gz.sha256
+/// ```
+/// A takeover work directory can be copied into the device type directory as
+/// the checksum files written after downloading match this layout.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ImageMirror {
+    Http(Url),
+    Dir(PathBuf),
+}
+
+pub(crate) fn image_name(device_type: &str, version: &str) -> String {
+    format!("balena-cloud-{}-{}.img.gz", device_type, version)
+}
+
+impl ImageMirror {
+    pub fn new(location: &str) -> Result<ImageMirror> {
+        if location.starts_with("http://") || location.starts_with("https://") {
+            // make sure the path is treated as a directory when joining
+            let location = if location.ends_with('/') {
+                location.to_string()
+            } else {
+                format!("{}/", location)
+            };
+            Ok(ImageMirror::Http(
+                Url::parse(&location).upstream_with_context(&format!(
+                    "Failed to parse image mirror url '{}'",
+                    location
+                ))?,
+            ))
+        } else {
+            let path = PathBuf::from(location);
+            if path.is_dir() {
+                Ok(ImageMirror::Dir(path))
+            } else {
+                Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!("Image mirror directory '{}' does not exist", location),
+                ))
+            }
+        }
+    }
+
+    fn url(base: &Url, device_type: &str, file_name: &str) -> Result<Url> {
+        base.join(&format!("{}/{}", device_type, file_name))
+            .upstream_with_context(&format!(
+                "Failed to create mirror url for '{}/{}'",
+                device_type, file_name
+            ))
+    }
+
+    fn path(base: &PathBuf, device_type: &str, file_name: &str) -> PathBuf {
+        path_append(path_append(base, device_type), file_name)
+    }
+
+    fn http_get(url: &Url, headers: header::HeaderMap) -> Result<reqwest::blocking::Response> {
+        debug!("ImageMirror: requesting '{}'", url);
+        Client::builder()
+            .default_headers(headers)
+            .build()
+            .upstream_with_context("Failed to create https client")?
+            .get(url.as_str())
+            .send()
+            .upstream_with_context(&format!("Failed to send https request url: '{}'", url))
+    }
+
+    /// Retrieve the versions available on the mirror for the device type
+    pub fn get_versions(&self, device_type: &str) -> Result<Versions> {
+        let versions = match self {
+            ImageMirror::Http(base) => {
+                let url = ImageMirror::url(base, device_type, VERSIONS_FILE)?;
+                let res = ImageMirror::http_get(&url, header::HeaderMap::new())?;
+                if !res.status().is_success() {
+                    return Err(Error::with_context(
+                        ErrorKind::InvState,
+                        &format!("Request to '{}' failed with status: {}", url, res.status()),
+                    ));
+                }
+                res.text()
+                    .upstream_with_context(&format!("Failed to read response from '{}'", url))?
+            }
+            ImageMirror::Dir(base) => {
+                let path = ImageMirror::path(base, device_type, VERSIONS_FILE);
+                read_to_string(&path)
+                    .upstream_with_context(&format!("Failed to read '{}'", path.display()))?
+            }
+        };
+
+        serde_json::from_str::<Versions>(&versions)
+            .upstream_with_context("Failed to parse image mirror versions")
+    }
+
+    /// Request the image from offset, see api_calls::get_os_image
+    pub fn get_image(
+        &self,
+        device_type: &str,
+        version: &str,
+        offset: u64,
+        etag: Option<&str>,
+    ) -> Result<ImageDownload> {
+        let file_name = image_name(device_type, version);
+        let checksum_name = format!("{}.{}", file_name, CHECKSUM_EXT);
+        let mut download = match self {
+            ImageMirror::Http(base) => {
+                let url = ImageMirror::url(base, device_type, &file_name)?;
+                let mut headers = header::HeaderMap::new();
+                add_range_headers(&mut headers, offset, etag)?;
+                to_image_download(ImageMirror::http_get(&url, headers)?, offset)?
+            }
+            ImageMirror::Dir(base) => {
+                let path = ImageMirror::path(base, device_type, &file_name);
+                let size = metadata(&path)
+                    .upstream_with_context(&format!("Failed to stat '{}'", path.display()))?
+                    .len();
+                if offset > size {
+                    return Err(Error::with_context(
+                        ErrorKind::InvParam,
+                        &format!("Image download range starting at {} is invalid", offset),
+                    ));
+                }
+                let mut file = File::open(&path)
+                    .upstream_with_context(&format!("Failed to open '{}'", path.display()))?;
+                file.seek(SeekFrom::Start(offset))
+                    .upstream_with_context(&format!("Failed to seek '{}'", path.display()))?;
+                ImageDownload {
+                    stream: Box::new(file),
+                    offset,
+                    size: Some(size),
+                    checksum: None,
+                    etag: None,
+                }
+            }
+        };
+
+        // the checksum file is more reliable than an ETag
+        if matches!(download.checksum, None | Some(ImageChecksum::EtagMd5(_))) {
+            if let Some(checksum) = self.get_checksum(device_type, &checksum_name) {
+                download.checksum = Some(checksum);
+            }
+        }
+
+        Ok(download)
+    }
+
+    /// The checksum file is optional, failing to read it is not an error
+    fn get_checksum(&self, device_type: &str, file_name: &str) -> Option<ImageChecksum> {
+        let content = match self {
+            ImageMirror::Http(base) => ImageMirror::url(base, device_type, file_name)
+                .and_then(|url| ImageMirror::http_get(&url, header::HeaderMap::new()))
+                .ok()
+                .filter(|res| res.status().is_success())
+                .and_then(|res| res.text().ok()),
+            ImageMirror::Dir(base) => {
+                read_to_string(ImageMirror::path(base, device_type, file_name)).ok()
+            }
+        }?;
+
+        match parse_sha256sum(&content) {
+            Some(checksum) => Some(checksum),
+            None => {
+                warn!("Ignoring invalid checksum file '{}' on mirror", file_name);
+                None
+            }
+        }
+    }
+}
+
+fn parse_sha256sum(content: &str) -> Option<ImageChecksum> {
+    let digest = content.split_whitespace().next()?;
+    if digest.len() != 64 {
+        return None;
+    }
+    (0..digest.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&digest[idx..idx + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()
+        .map(ImageChecksum::Sha256)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::io::Read;
+
+    #[test]
+    fn serves_from_directory() {
+        let base = temp_dir().join("takeover-mirror");
+        let dt_dir = base.join("raspberrypi4-64");
+        create_dir_all(&dt_dir).unwrap();
+        write(dt_dir.join(VERSIONS_FILE), r#"["5.1.20+rev1", "4.0.26"]"#).unwrap();
+        let image_name = image_name("raspberrypi4-64", "5.1.20+rev1");
+        write(dt_dir.join(&image_name), b"balenaOS image").unwrap();
+        write(
+            dt_dir.join(format!("{}.{}", image_name, CHECKSUM_EXT)),
+            format!("{}  {}\n", "ab".repeat(32), image_name),
+        )
+        .unwrap();
+
+        let mirror = ImageMirror::new(base.to_str().unwrap()).unwrap();
+        assert_eq!(
+            mirror.get_versions("raspberrypi4-64").unwrap(),
+            vec!["5.1.20+rev1".to_string(), "4.0.26".to_string()]
+        );
+
+        let mut download = mirror
+            .get_image("raspberrypi4-64", "5.1.20+rev1", 8, None)
+            .unwrap();
+        assert_eq!(download.offset, 8);
+        assert_eq!(download.size, Some(14));
+        assert_eq!(
+            download.checksum,
+            Some(ImageChecksum::Sha256(vec![0xab; 32]))
+        );
+        let mut data = String::new();
+        download.stream.read_to_string(&mut data).unwrap();
+        assert_eq!(data, " image");
+
+        assert!(mirror
+            .get_image("raspberrypi4-64", "4.0.26", 0, None)
+            .is_err());
+        assert!(mirror.get_versions("intel-nuc").is_err());
+
+        remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn parses_mirror_location() {
+        assert_eq!(
+            ImageMirror::new("http://mirror.local/balena").unwrap(),
+            ImageMirror::Http(Url::parse("http://mirror.local/balena/").unwrap())
+        );
+        assert!(ImageMirror::new("/does/not/exist").is_err());
+    }
+}
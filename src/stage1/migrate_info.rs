@@ -1,29 +1,38 @@
 use file_diff::diff_files;
 use log::{debug, error, info, warn};
 use nix::mount::umount;
+use openssl::pkey::{PKey, Private, Public};
+use std::env::var;
 use std::fs::{read_dir, read_to_string, remove_dir_all, File, OpenOptions};
 use std::path::{Path, PathBuf};
 use std::ptr::read_volatile;
 
 use crate::common::defs::{
     BACKUP_ARCH_NAME, BALENA_NETWORK_MANAGER_BIND_MOUNT, BALENA_OS_BOOT_MP, BALENA_OS_NAME,
-    BALENA_SYSTEM_CONNECTIONS_BOOT_PATH, BALENA_SYSTEM_PROXY_BOOT_PATH, SYSTEM_CONNECTIONS_DIR,
+    BALENA_SYSTEM_CONNECTIONS_BOOT_PATH, BALENA_SYSTEM_PROXY_BOOT_PATH, S2_XTRA_FS_SIZE,
+    SYSTEM_CONNECTIONS_DIR,
 };
 use crate::{
     common::{
-        file_exists, get_os_name, options::Options, path_append, Error, ErrorKind, Result, ToError,
+        api_calls::get_release_volumes,
+        backup_crypt::{encode_private_key, private_key_from_pem},
+        disk_util::{Disk, DEF_BLOCK_SIZE},
+        file_exists, format_size_with_unit, get_mem_info, get_os_name,
+        options::Options,
+        path_append, Error, ErrorKind, Result, ToError,
     },
     stage1::{
-        backup::config::backup_cfg_from_file,
+        backup::config::{backup_cfg_from_file, BackupConfig, VolumeConfig},
+        backup::hooks::{run_hooks, PostHooks},
+        backup::scan::{scan_backup, BackupScan},
+        backup::services::StoppedServices,
         backup::{create, create_ext},
-        defs::{
-            DEV_TYPE_GEN_X86_64, DEV_TYPE_JETSON_XAVIER, DEV_TYPE_JETSON_XAVIER_NX,
-            GZIP_MAGIC_COOKIE, MAX_CONFIG_JSON,
-        },
+        defs::{BACKUP_KEY_CFG_NAME, BACKUP_KEY_ENV, GZIP_MAGIC_COOKIE, MAX_CONFIG_JSON},
         device::Device,
         device_impl::get_device,
         image_retrieval::download_image,
         migrate_info::balena_cfg_json::BalenaCfgJson,
+        support_matrix::SupportMatrix,
         utils::mktemp,
         wifi_config::WifiConfig,
     },
@@ -52,13 +61,22 @@ pub(crate) struct MigrateInfo {
     wifis: Vec<WifiConfig>,
     nwmgr_files: Vec<PathBuf>,
     system_proxy_files: Vec<PathBuf>,
+    // checked backup configuration, taken by create_backup
+    backup_cfg: Option<BackupConfig>,
     backup: Option<PathBuf>,
+    // encoded private key to decrypt the backup in stage2
+    backup_key: Option<String>,
+    // scan of the backup configuration, the only backup information in a plan
+    backup_scan: Option<BackupScan>,
+    // started again if the migration is aborted
+    stopped_services: StoppedServices,
 }
 
 #[allow(dead_code)]
 impl MigrateInfo {
     pub fn new(opts: &Options) -> Result<MigrateInfo> {
-        let device = get_device(opts)?;
+        let matrix = SupportMatrix::load(opts)?;
+        let device = get_device(opts, &matrix)?;
         let os_name = get_os_name()?;
         info!(
             "Detected device type: {} running {}",
@@ -87,7 +105,7 @@ impl MigrateInfo {
         };
 
         if opts.migrate() {
-            config.check(opts, &*device)?;
+            config.check(opts, &*device, &matrix)?;
         }
 
         info!(
@@ -132,6 +150,7 @@ impl MigrateInfo {
 
             let image_path = download_image(
                 opts,
+                &matrix,
                 &config,
                 &work_dir,
                 device_type.as_str(),
@@ -234,18 +253,18 @@ impl MigrateInfo {
             }
         }
 
-        let backup = if let Some(backup_cfg) = opts.backup_config() {
-            let backup_path = path_append(&work_dir, BACKUP_ARCH_NAME);
-            let created = if opts.tar_internal() {
-                create(backup_path.as_path(), backup_cfg_from_file(backup_cfg)?)?
-            } else {
-                create_ext(backup_path.as_path(), backup_cfg_from_file(backup_cfg)?)?
-            };
-            if created {
-                Some(backup_path)
-            } else {
-                None
+        // always taken out of config.json, even if the backup is not encrypted
+        let private_key = get_backup_key(&mut config)?;
+        let mut backup_key = None;
+        // the backup configuration is only checked here, the backup is
+        // created by create_backup once the migration was confirmed
+        let backup_cfg = if let Some(backup_cfg) = opts.backup_config() {
+            let backup_cfg = backup_cfg_from_file(backup_cfg)?;
+            if let Some(public_key) = &backup_cfg.public_key()? {
+                backup_key = Some(check_backup_key(public_key, private_key.as_ref())?);
             }
+            check_backup_volumes(&config, &backup_cfg.volumes)?;
+            Some(backup_cfg)
         } else {
             None
         };
@@ -279,10 +298,65 @@ impl MigrateInfo {
             wifis,
             nwmgr_files,
             system_proxy_files,
-            backup,
+            backup_cfg,
+            backup: None,
+            backup_key,
+            backup_scan: None,
+            stopped_services: StoppedServices::default(),
         })
     }
 
+    /// Run the backup hooks, stop the configured services and create the
+    /// backup archive in the work dir, in plan mode the volumes are only
+    /// scanned. Called after the early checks and the confirmation, so a
+    /// migration that is refused or not confirmed leaves the system untouched.
+    pub fn create_backup(&mut self, opts: &Options) -> Result<()> {
+        let backup_cfg = match self.backup_cfg.take() {
+            Some(backup_cfg) => backup_cfg,
+            None => return Ok(()),
+        };
+
+        if opts.plan() {
+            // the plan leaves the system alone, no hooks, no stopped
+            // services and no archive
+            info!("Scanning the backup for the plan, the backup hooks are not run");
+            let scan = scan_backup(&backup_cfg.volumes)?;
+            scan.log_summary();
+            check_backup_size(&scan, &self.image_path)?;
+            self.backup_scan = Some(scan);
+            return Ok(());
+        }
+
+        let public_key = backup_cfg.public_key()?;
+        let backup_path = path_append(&self.work_dir, BACKUP_ARCH_NAME);
+
+        // pre hooks may create files that are part of the backup
+        let _post_hooks = PostHooks::new(&backup_cfg.hooks.post, backup_cfg.hooks.timeout);
+        run_hooks("pre", &backup_cfg.hooks.pre, backup_cfg.hooks.timeout)?;
+        let scan = scan_backup(&backup_cfg.volumes)?;
+        scan.log_summary();
+        check_backup_size(&scan, &self.image_path)?;
+        self.stopped_services = StoppedServices::stop(&backup_cfg.stop)?;
+        let created = if opts.tar_internal() {
+            create(
+                backup_path.as_path(),
+                backup_cfg.volumes,
+                public_key.as_ref(),
+            )?
+        } else {
+            create_ext(
+                backup_path.as_path(),
+                backup_cfg.volumes,
+                public_key.as_ref(),
+            )?
+        };
+        self.backup_scan = Some(scan);
+        if created {
+            self.backup = Some(backup_path);
+        }
+        Ok(())
+    }
+
     pub fn update_config(&mut self) -> Result<()> {
         if self.config.is_modified() {
             let target_path = mktemp(false, Some("config."), Some(".json"), Some(&self.work_dir))?;
@@ -304,16 +378,8 @@ impl MigrateInfo {
         self.device.to_string()
     }
 
-    pub fn is_x86(&self) -> bool {
-        self.device.supports_device_type(DEV_TYPE_GEN_X86_64)
-    }
-
-    pub fn is_jetson_xavier(&self) -> bool {
-        self.device.supports_device_type(DEV_TYPE_JETSON_XAVIER)
-    }
-
-    pub fn is_jetson_xavier_nx(&self) -> bool {
-        self.device.supports_device_type(DEV_TYPE_JETSON_XAVIER_NX)
+    pub fn device(&self) -> &dyn Device {
+        self.device.as_ref()
     }
 
     pub fn backup(&self) -> Option<&Path> {
@@ -324,6 +390,24 @@ impl MigrateInfo {
         }
     }
 
+    pub fn work_dir(&self) -> &Path {
+        &self.work_dir
+    }
+
+    pub fn backup_scan(&self) -> Option<&BackupScan> {
+        self.backup_scan.as_ref()
+    }
+
+    /// Called once the migration is under way, services stopped for the
+    /// backup are started again otherwise
+    pub fn keep_services_stopped(&mut self) {
+        self.stopped_services.keep_stopped();
+    }
+
+    pub fn backup_key(&self) -> Option<&str> {
+        self.backup_key.as_deref()
+    }
+
     pub(crate) fn os_name(&self) -> &str {
         self.os_name.as_ref()
     }
@@ -473,6 +557,125 @@ impl MigrateInfo {
     }
 }
 
+/// Make sure every volume in the backup configuration is a named volume of the
+/// release the fleet should be running, stage2 restores the backup to these
+/// volumes.
+fn check_backup_volumes(config: &BalenaCfgJson, backup_cfg: &[VolumeConfig]) -> Result<()> {
+    let app_id = config.get_app_id()?;
+    let release_volumes =
+        match get_release_volumes(&config.get_api_endpoint()?, &config.get_api_key()?, app_id)? {
+            Some(release_volumes) => release_volumes,
+            None => {
+                warn!(
+                    "Fleet {} has no target release, backup volumes can not be checked",
+                    app_id
+                );
+                return Ok(());
+            }
+        };
+
+    debug!("Target release volumes: {:?}", release_volumes);
+
+    let unknown: Vec<&str> = backup_cfg
+        .iter()
+        .map(|volume| volume.volume.as_str())
+        .filter(|volume| !release_volumes.iter().any(|name| name == volume))
+        .collect();
+
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        error!(
+            "The backup volume(s) {:?} are not named volumes of the target release of fleet {}, release volumes: {:?}",
+            unknown, app_id, release_volumes
+        );
+        Err(Error::displayed())
+    }
+}
+
+/// Get the private key to decrypt the backup in stage2 from config.json or
+/// the environment. The key is removed from config.json.
+fn get_backup_key(config: &mut BalenaCfgJson) -> Result<Option<PKey<Private>>> {
+    let pem = if let Some(pem) = config.take_backup_key()? {
+        debug!("get_backup_key: found backup key in config.json");
+        Some(pem)
+    } else {
+        var(BACKUP_KEY_ENV).ok()
+    };
+    pem.map(|pem| private_key_from_pem(&pem)).transpose()
+}
+
+/// Make sure the encrypted backup can be restored in stage2, the private key
+/// must be available and match the public key of the backup configuration.
+/// Returns the private key encoded for the stage2 config.
+fn check_backup_key(
+    public_key: &PKey<Public>,
+    private_key: Option<&PKey<Private>>,
+) -> Result<String> {
+    if let Some(private_key) = private_key {
+        if private_key.public_eq(public_key) {
+            info!("The backup will be encrypted");
+            encode_private_key(private_key)
+        } else {
+            error!(
+                "The backup key does not match the public key of the backup configuration, the backup could not be restored"
+            );
+            Err(Error::displayed())
+        }
+    } else {
+        error!(
+            "The backup is configured to be encrypted but no backup key was found in config.json ('{}') or the environment ({}), the backup could not be restored",
+            BACKUP_KEY_CFG_NAME, BACKUP_KEY_ENV
+        );
+        Err(Error::displayed())
+    }
+}
+
+/// Make sure the backup fits into RAM together with the image in stage2 and
+/// can be extracted to the data partition of the image
+fn check_backup_size(backup_scan: &BackupScan, image_path: &Path) -> Result<()> {
+    let image_size = image_path
+        .metadata()
+        .upstream_with_context(&format!(
+            "Failed to retrieve size of image '{}'",
+            image_path.display()
+        ))?
+        .len();
+    let (_mem_tot, mem_free) = get_mem_info()?;
+    let req_mem = backup_scan.compressed_size() + image_size + S2_XTRA_FS_SIZE;
+    if req_mem > mem_free {
+        error!(
+            "The backup of an estimated {} does not fit into memory together with the image, required size is {}, free memory is {}. Please reduce the backup configuration, see the backup scan above for the largest items",
+            format_size_with_unit(backup_scan.compressed_size()),
+            format_size_with_unit(req_mem),
+            format_size_with_unit(mem_free)
+        );
+        return Err(Error::displayed());
+    }
+
+    let (_boot_part, _root_a_part, data_part) =
+        Disk::from_image(image_path)?.get_balena_partitions()?;
+    let data_part_size = data_part.num_sectors * DEF_BLOCK_SIZE as u64;
+    if backup_scan.archive_size() > data_part_size {
+        error!(
+            "The backup of {} does not fit into the data partition of {} in image '{}'. Please reduce the backup configuration, see the backup scan above for the largest items",
+            format_size_with_unit(backup_scan.archive_size()),
+            format_size_with_unit(data_part_size),
+            image_path.display()
+        );
+        return Err(Error::displayed());
+    }
+
+    debug!(
+        "check_backup_size: backup fits, {} of {} free memory, {} of {} data partition",
+        format_size_with_unit(req_mem),
+        format_size_with_unit(mem_free),
+        format_size_with_unit(backup_scan.archive_size()),
+        format_size_with_unit(data_part_size)
+    );
+    Ok(())
+}
+
 /// Compares the two locations of balenaOS that contain NetworkManager
 /// connection files:
 ///
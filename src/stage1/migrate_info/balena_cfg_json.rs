@@ -1,6 +1,9 @@
 use crate::{
     common::{Error, ErrorKind, Options, Result, ToError},
-    stage1::{device::Device, utils::check_tcp_connect},
+    stage1::{
+        defs::BACKUP_KEY_CFG_NAME, device::Device, support_matrix::SupportMatrix,
+        utils::check_tcp_connect,
+    },
 };
 
 use log::{debug, error, info};
@@ -69,12 +72,12 @@ impl BalenaCfgJson {
         Ok(())
     }
 
-    pub fn check(&self, opts: &Options, device: &dyn Device) -> Result<()> {
+    pub fn check(&self, opts: &Options, device: &dyn Device, matrix: &SupportMatrix) -> Result<()> {
         info!("Configured for fleet id: {}", self.get_app_id()?);
 
         let device_type = self.get_device_type()?;
         if opts.dt_check() {
-            if !device.supports_device_type(device_type.as_str()) {
+            if !device.supports_device_type(matrix, device_type.as_str()) {
                 error!("The device type configured in config.json ({}) is not supported by the detected device type {:?}",
                    device_type, device.get_device_type());
                 return Err(Error::displayed());
@@ -206,6 +209,29 @@ impl BalenaCfgJson {
         self.get_uint_val("vpnPort")
     }
 
+    /// Optional location of a local image mirror, see --image-mirror
+    pub fn get_image_mirror(&self) -> Result<Option<String>> {
+        match self.get_str_val("imageMirror") {
+            Ok(value) => Ok(Some(value)),
+            Err(why) if why.kind() == ErrorKind::NotFound => Ok(None),
+            Err(why) => Err(why),
+        }
+    }
+
+    /// Remove the backup key so it is not transferred to the boot partition
+    /// of the new installation
+    pub fn take_backup_key(&mut self) -> Result<Option<String>> {
+        match self.get_str_val(BACKUP_KEY_CFG_NAME) {
+            Ok(value) => {
+                self.config.remove(BACKUP_KEY_CFG_NAME);
+                self.modified = true;
+                Ok(Some(value))
+            }
+            Err(why) if why.kind() == ErrorKind::NotFound => Ok(None),
+            Err(why) => Err(why),
+        }
+    }
+
     pub fn get_device_type(&self) -> Result<String> {
         self.get_str_val("deviceType")
     }
@@ -0,0 +1,178 @@
+use std::fs::metadata;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::{
+    common::{
+        defs::BACKUP_ARCH_NAME,
+        get_mem_info, path_append,
+        stage2_config::{LogDevice, UmountPart},
+        Result, ToError,
+    },
+    stage1::{exe_copy::ExeCopy, image_info::ImageInfo, migrate_info::MigrateInfo},
+};
+
+#[derive(Debug, Serialize)]
+pub(crate) struct PlanFile {
+    path: PathBuf,
+    size: u64,
+}
+
+impl PlanFile {
+    fn new<P: AsRef<Path>>(path: P) -> Result<PlanFile> {
+        let path = path.as_ref();
+        let size = metadata(path)
+            .upstream_with_context(&format!("Failed to stat '{}'", path.display()))?
+            .len();
+        Ok(PlanFile {
+            path: path.to_path_buf(),
+            size,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct PlanMemory {
+    /// space required in the takeover tmpfs for executables and libraries
+    takeover_fs: u64,
+    /// space required in stage2 for the image, config.json and backup
+    transfer_fs: u64,
+    required: u64,
+    free: u64,
+    total: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct PlanImage {
+    path: PathBuf,
+    size: u64,
+    compression: String,
+    required_size: u64,
+    device_type: String,
+    version: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct PlanBackupItem {
+    volume: String,
+    source: String,
+    files: usize,
+    size: u64,
+    compressed_size: u64,
+}
+
+/// The backup as scanned, the plan does not create the archive
+#[derive(Debug, Serialize)]
+pub(crate) struct PlanBackup {
+    path: PathBuf,
+    /// estimated size of the compressed archive
+    size: u64,
+    /// size of the extracted archive
+    archive_size: u64,
+    items: Vec<PlanBackupItem>,
+}
+
+/// Everything stage1 would decide in `prepare`, emitted by `--plan`
+#[derive(Debug, Serialize)]
+pub(crate) struct MigrationPlan {
+    os_name: String,
+    device_type: String,
+    flash_device: PathBuf,
+    umount_parts: Vec<UmountPart>,
+    log_device: Option<LogDevice>,
+    executables: Vec<PlanFile>,
+    libraries: Vec<PlanFile>,
+    memory: PlanMemory,
+    wifis: Vec<String>,
+    nwmgr_files: Vec<PathBuf>,
+    system_proxy_files: Vec<PathBuf>,
+    backup: Option<PlanBackup>,
+    image: PlanImage,
+}
+
+impl MigrationPlan {
+    pub fn new(
+        mig_info: &MigrateInfo,
+        image_info: &ImageInfo,
+        commands: &ExeCopy,
+        flash_device: PathBuf,
+        umount_parts: Vec<UmountPart>,
+        log_device: Option<LogDevice>,
+        takeover_fs: u64,
+    ) -> Result<MigrationPlan> {
+        let mut executables = commands
+            .executables()
+            .map(PlanFile::new)
+            .collect::<Result<Vec<PlanFile>>>()?;
+        executables.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut libraries = commands
+            .libraries()
+            .map(PlanFile::new)
+            .collect::<Result<Vec<PlanFile>>>()?;
+        libraries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let image = PlanFile::new(mig_info.image_path())?;
+        let config = PlanFile::new(mig_info.balena_cfg().get_path())?;
+
+        let backup = mig_info.backup_scan().map(|scan| PlanBackup {
+            path: path_append(mig_info.work_dir(), BACKUP_ARCH_NAME),
+            size: scan.compressed_size(),
+            archive_size: scan.archive_size(),
+            items: scan
+                .items
+                .iter()
+                .map(|item| PlanBackupItem {
+                    volume: item.volume.clone(),
+                    source: item.source.clone(),
+                    files: item.files,
+                    size: item.size,
+                    compressed_size: item.compressed_size,
+                })
+                .collect(),
+        });
+
+        let transfer_fs =
+            image.size + config.size + backup.as_ref().map_or(0, |backup| backup.size);
+
+        let (total, free) = get_mem_info()?;
+
+        Ok(MigrationPlan {
+            os_name: mig_info.os_name().to_string(),
+            device_type: mig_info.get_device_type_name(),
+            flash_device,
+            umount_parts,
+            log_device,
+            executables,
+            libraries,
+            memory: PlanMemory {
+                takeover_fs,
+                transfer_fs,
+                required: takeover_fs + transfer_fs,
+                free,
+                total,
+            },
+            wifis: mig_info
+                .wifis()
+                .iter()
+                .map(|wifi| wifi.get_ssid().to_string())
+                .collect(),
+            nwmgr_files: mig_info.nwmgr_files().clone(),
+            system_proxy_files: mig_info.system_proxy_files().clone(),
+            backup,
+            image: PlanImage {
+                path: image.path,
+                size: image.size,
+                compression: image_info.compression().to_string(),
+                required_size: image_info.required_size(),
+                device_type: image_info.device_type().to_string(),
+                version: image_info.version().map(|version| version.to_string()),
+            },
+        })
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).upstream_with_context("Failed to serialize plan")
+    }
+}
@@ -0,0 +1,318 @@
+use log::{debug, error, info, warn};
+
+use crate::{
+    common::{
+        defs::SYS_EFI_DIR,
+        dir_exists,
+        efivars::{read_efi_var, EFI_GLOBAL_VARIABLE, EFI_IMAGE_SECURITY_DATABASE},
+        Error, Result,
+    },
+    stage1::image_info::ImageInfo,
+};
+
+// EFI_CERT_X509_GUID a5c059a1-94e4-4aa7-87b5-ab155c2bf072 as stored in
+// EFI_SIGNATURE_LIST, the first three fields are little endian
+const EFI_CERT_X509_GUID: [u8; 16] = [
+    0xa1, 0x59, 0xc0, 0xa5, 0xe4, 0x94, 0xa7, 0x4a, 0x87, 0xb5, 0xab, 0x15, 0x5c, 0x2b, 0xf0, 0x72,
+];
+// size of the SignatureType GUID, ListSize, HeaderSize and SignatureSize fields
+const SIGNATURE_LIST_HEADER_SIZE: usize = 28;
+// size of the SignatureOwner GUID in front of every signature
+const SIGNATURE_OWNER_SIZE: usize = 16;
+
+// WIN_CERT_TYPE_PKCS_SIGNED_DATA, an Authenticode signature
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+// index of the certificate table in the PE optional header data directories
+const PE_SECURITY_DIRECTORY: usize = 4;
+const PE32_MAGIC: u16 = 0x10b;
+const PE32_PLUS_MAGIC: u16 = 0x20b;
+
+/// Secure Boot state read from the SecureBoot and SetupMode EFI variables
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) struct SecureBootState {
+    pub secure_boot: bool,
+    pub setup_mode: bool,
+}
+
+impl SecureBootState {
+    /// Read the state from efivarfs, systems booted without EFI or without
+    /// the variables have Secure Boot disabled
+    pub fn read() -> Result<SecureBootState> {
+        if !dir_exists(SYS_EFI_DIR)? {
+            debug!("SecureBootState::read: not booted using EFI");
+            return Ok(SecureBootState::default());
+        }
+
+        let state = SecureBootState {
+            secure_boot: read_flag("SecureBoot")?,
+            setup_mode: read_flag("SetupMode")?,
+        };
+        debug!("SecureBootState::read: {:?}", state);
+        Ok(state)
+    }
+
+    /// In setup mode the firmware does not verify signatures, so only
+    /// Secure Boot outside of setup mode restricts what boots
+    pub fn is_enforced(&self) -> bool {
+        self.secure_boot && !self.setup_mode
+    }
+}
+
+fn read_flag(name: &str) -> Result<bool> {
+    Ok(
+        read_efi_var(name, EFI_GLOBAL_VARIABLE)?.and_then(|value| value.first().copied())
+            == Some(1),
+    )
+}
+
+/// Check that the EFI loader of the balenaOS image can boot with the Secure
+/// Boot state of the device. Images without a signed loader are refused while
+/// Secure Boot is enforced, signed loaders must be signed by a certificate in
+/// the db signature database, if db contains any certificates.
+pub(crate) fn check_image(image_info: &ImageInfo) -> Result<()> {
+    let state = SecureBootState::read()?;
+    info!(
+        "Secure boot is {}{}",
+        if state.secure_boot {
+            "enabled"
+        } else {
+            "not enabled"
+        },
+        if state.setup_mode {
+            ", the firmware is in setup mode"
+        } else {
+            ""
+        }
+    );
+
+    if !state.is_enforced() {
+        return Ok(());
+    }
+
+    let signature = match image_info.efi_signature() {
+        Some(signature) => signature,
+        None => {
+            error!(
+                "Secure boot is enabled but the balenaOS image does not contain a signed EFI loader, please use an image signed for secure boot"
+            );
+            return Err(Error::displayed());
+        }
+    };
+
+    let db = read_efi_var("db", EFI_IMAGE_SECURITY_DATABASE)?.unwrap_or_default();
+    let db_certs = x509_certificates(&db);
+
+    if db_certs.is_empty() {
+        warn!("No certificates found in the secure boot db, the signature of the EFI loader of the balenaOS image could not be checked");
+        return Ok(());
+    }
+
+    if is_signed_by(signature, &db_certs) {
+        info!("The EFI loader of the balenaOS image is signed by a certificate enrolled in the secure boot db");
+        Ok(())
+    } else {
+        error!(
+            "The EFI loader of the balenaOS image is not signed by any of the {} certificates enrolled in the secure boot db, the device would not boot the image",
+            db_certs.len()
+        );
+        Err(Error::displayed())
+    }
+}
+
+/// The DER encoded X509 certificates in a list of EFI_SIGNATURE_LIST
+/// structures, as found in the db variable. Hashes are skipped.
+fn x509_certificates(sig_lists: &[u8]) -> Vec<&[u8]> {
+    let mut certs = Vec::new();
+    let mut pos = 0;
+    while pos + SIGNATURE_LIST_HEADER_SIZE <= sig_lists.len() {
+        let list = &sig_lists[pos..];
+        let list_size = read_u32(list, 16) as usize;
+        let header_size = read_u32(list, 20) as usize;
+        let sig_size = read_u32(list, 24) as usize;
+        if list_size < SIGNATURE_LIST_HEADER_SIZE || list_size > list.len() {
+            warn!("Invalid EFI signature list at offset {}", pos);
+            break;
+        }
+
+        if list[..16] == EFI_CERT_X509_GUID && sig_size > SIGNATURE_OWNER_SIZE {
+            let mut sig_pos = SIGNATURE_LIST_HEADER_SIZE + header_size;
+            while sig_pos + sig_size <= list_size {
+                certs.push(&list[sig_pos + SIGNATURE_OWNER_SIZE..sig_pos + sig_size]);
+                sig_pos += sig_size;
+            }
+        }
+        pos += list_size;
+    }
+    certs
+}
+
+/// The Authenticode signature of a PE image, the PKCS#7 SignedData from the
+/// first WIN_CERTIFICATE in the certificate table
+pub(crate) fn authenticode_signature(image: &[u8]) -> Option<&[u8]> {
+    let pe_offset = read_u32(image.get(0x3c..)?, 0) as usize;
+    if image.get(pe_offset..pe_offset + 4)? != b"PE\0\0" {
+        return None;
+    }
+
+    // the optional header follows the 20 byte COFF header
+    let opt_header = image.get(pe_offset + 24..)?;
+    let (rva_count_offset, dirs_offset) = match read_u16(opt_header, 0) {
+        PE32_MAGIC => (92, 96),
+        PE32_PLUS_MAGIC => (108, 112),
+        _ => return None,
+    };
+    if (read_u32(opt_header.get(rva_count_offset..)?, 0) as usize) <= PE_SECURITY_DIRECTORY {
+        return None;
+    }
+
+    // the certificate table uses a file offset rather than a virtual address
+    let dir = opt_header.get(dirs_offset + PE_SECURITY_DIRECTORY * 8..)?;
+    let table_offset = read_u32(dir, 0) as usize;
+    let table_size = read_u32(dir, 4) as usize;
+    let table = image.get(table_offset..table_offset.checked_add(table_size)?)?;
+
+    let cert_len = read_u32(table, 0) as usize;
+    if read_u16(table.get(6..)?, 0) != WIN_CERT_TYPE_PKCS_SIGNED_DATA {
+        return None;
+    }
+    table.get(8..cert_len)
+}
+
+/// Whether the signature was made by one of the certificates or by a
+/// certificate they issued. The signature itself is verified by the firmware,
+/// this only looks for the certificate or its subject as issuer in the
+/// certificates and signer infos of the signature.
+fn is_signed_by(signature: &[u8], certs: &[&[u8]]) -> bool {
+    certs.iter().any(|cert| {
+        contains(signature, cert)
+            || subject_name(cert).is_some_and(|subject| contains(signature, subject))
+    })
+}
+
+fn contains(data: &[u8], pattern: &[u8]) -> bool {
+    !pattern.is_empty() && data.windows(pattern.len()).any(|window| window == pattern)
+}
+
+/// The DER encoded subject Name of a DER encoded X509 certificate
+fn subject_name(cert: &[u8]) -> Option<&[u8]> {
+    let (_, cert_content) = der_element(cert)?;
+    let (_, mut fields) = der_element(cert_content)?;
+
+    // skip the optional [0] version, serialNumber, signature, issuer and validity
+    if fields.first() == Some(&0xa0) {
+        fields = &fields[der_element(fields)?.0.len()..];
+    }
+    for _ in 0..4 {
+        fields = &fields[der_element(fields)?.0.len()..];
+    }
+    Some(der_element(fields)?.0)
+}
+
+/// Split off the first DER element, returns the element and its content
+fn der_element(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len_byte = *data.get(1)?;
+    let (header_len, content_len) = if len_byte & 0x80 == 0 {
+        (2, len_byte as usize)
+    } else {
+        let len_bytes = (len_byte & 0x7f) as usize;
+        if len_bytes == 0 || len_bytes > 4 {
+            return None;
+        }
+        let content_len = data
+            .get(2..2 + len_bytes)?
+            .iter()
+            .fold(0usize, |len, byte| (len << 8) | *byte as usize);
+        (2 + len_bytes, content_len)
+    };
+    let element = data.get(..header_len.checked_add(content_len)?)?;
+    Some((element, &element[header_len..]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    data.get(offset..offset + 4).map_or(0, |bytes| {
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    })
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    data.get(offset..offset + 2)
+        .map_or(0, |bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a minimal certificate: SEQUENCE { tbsCertificate SEQUENCE { [0] version,
+    // serialNumber, signature, issuer, validity, subject, ... } }
+    fn certificate(issuer: &[u8], subject: &[u8]) -> Vec<u8> {
+        let mut tbs = vec![0xa0, 0x03, 0x02, 0x01, 0x02, 0x02, 0x01, 0x01, 0x30, 0x00];
+        tbs.extend_from_slice(issuer);
+        tbs.extend_from_slice(&[0x30, 0x00]);
+        tbs.extend_from_slice(subject);
+        let mut tbs_seq = vec![0x30, tbs.len() as u8];
+        tbs_seq.extend(tbs);
+        let mut cert = vec![0x30, 0x81, tbs_seq.len() as u8];
+        cert.extend(tbs_seq);
+        cert
+    }
+
+    #[test]
+    fn finds_db_certificate_in_signature() {
+        let ca_name = [0x30, 0x06, 0x31, 0x04, 0x0c, 0x02, b'C', b'A'];
+        let signer_name = [0x30, 0x06, 0x31, 0x04, 0x0c, 0x02, b'S', b'I'];
+        let other_name = [0x30, 0x06, 0x31, 0x04, 0x0c, 0x02, b'X', b'X'];
+        let ca_cert = certificate(&other_name, &ca_name);
+        assert_eq!(subject_name(&ca_cert), Some(&ca_name[..]));
+
+        // db with a SHA256 hash list followed by a list holding the CA certificate
+        let mut db = vec![0u8; 16];
+        db.extend_from_slice(&(SIGNATURE_LIST_HEADER_SIZE as u32 + 48).to_le_bytes());
+        db.extend_from_slice(&0u32.to_le_bytes());
+        db.extend_from_slice(&48u32.to_le_bytes());
+        db.extend_from_slice(&[0x11; 48]);
+        let sig_size = (SIGNATURE_OWNER_SIZE + ca_cert.len()) as u32;
+        db.extend_from_slice(&EFI_CERT_X509_GUID);
+        db.extend_from_slice(&(SIGNATURE_LIST_HEADER_SIZE as u32 + sig_size).to_le_bytes());
+        db.extend_from_slice(&0u32.to_le_bytes());
+        db.extend_from_slice(&sig_size.to_le_bytes());
+        db.extend_from_slice(&[0x22; SIGNATURE_OWNER_SIZE]);
+        db.extend_from_slice(&ca_cert);
+
+        let certs = x509_certificates(&db);
+        assert_eq!(certs, vec![ca_cert.as_slice()]);
+
+        // a signature carrying a signer certificate issued by the CA
+        let mut signature = vec![0x30, 0x80];
+        signature.extend(certificate(&ca_name, &signer_name));
+        assert!(is_signed_by(&signature, &certs));
+
+        let mut other_signature = vec![0x30, 0x80];
+        other_signature.extend(certificate(&other_name, &signer_name));
+        assert!(!is_signed_by(&other_signature, &certs));
+    }
+
+    #[test]
+    fn extracts_authenticode_signature() {
+        let pkcs7 = [0x30, 0x03, 0x02, 0x01, 0x05];
+        let mut image = vec![0u8; 0x200];
+        image[0..2].copy_from_slice(b"MZ");
+        image[0x3c..0x40].copy_from_slice(&0x80u32.to_le_bytes());
+        image[0x80..0x84].copy_from_slice(b"PE\0\0");
+        let opt = 0x80 + 24;
+        image[opt..opt + 2].copy_from_slice(&PE32_PLUS_MAGIC.to_le_bytes());
+        image[opt + 108..opt + 112].copy_from_slice(&16u32.to_le_bytes());
+        assert_eq!(authenticode_signature(&image), None);
+
+        let table_offset = image.len() as u32;
+        let cert_len = 8 + pkcs7.len() as u32;
+        let dir = opt + 112 + PE_SECURITY_DIRECTORY * 8;
+        image[dir..dir + 4].copy_from_slice(&table_offset.to_le_bytes());
+        image[dir + 4..dir + 8].copy_from_slice(&cert_len.to_le_bytes());
+        image.extend_from_slice(&cert_len.to_le_bytes());
+        image.extend_from_slice(&0x0200u16.to_le_bytes());
+        image.extend_from_slice(&WIN_CERT_TYPE_PKCS_SIGNED_DATA.to_le_bytes());
+        image.extend_from_slice(&pkcs7);
+        assert_eq!(authenticode_signature(&image), Some(&pkcs7[..]));
+    }
+}
@@ -0,0 +1,526 @@
+use log::{debug, info};
+use regex::Regex;
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+
+use crate::common::{
+    device_type::DeviceType,
+    error::{Error, ErrorKind, Result, ToError},
+    get_os_name, get_os_release_value,
+    options::Options,
+};
+
+// built in support matrix, replaced by the file given with --support-matrix
+const BUILTIN_SUPPORT_MATRIX: &str = include_str!("support_matrix.yml");
+
+/// An operating system takeover was tested with
+#[derive(Debug, Deserialize)]
+pub(crate) struct OsPattern {
+    /// regex matched against PRETTY_NAME
+    name: Option<String>,
+    /// matched against ID
+    id: Option<String>,
+    /// comma separated requirements like '>=18.04, <=20.04' matched against VERSION_ID
+    version: Option<String>,
+}
+
+/// A device type slug and the board it runs on
+#[derive(Debug, Deserialize)]
+pub(crate) struct DeviceSupport {
+    pub slug: String,
+    pub device_type: DeviceType,
+    /// regexes matched against the device tree model
+    #[serde(default)]
+    models: Vec<String>,
+    /// regexes matched against the device tree compatible strings
+    #[serde(default)]
+    compatible: Vec<String>,
+    /// board types of new style Raspberry Pi revision codes
+    #[serde(default)]
+    revision_types: Vec<u32>,
+    /// regexes matched against the files in /sys/class/dmi/id
+    dmi: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    os: Vec<OsPattern>,
+    /// balenaOS images can be downloaded for the slug
+    #[serde(default)]
+    pub download: bool,
+    /// the board only boots balenaOS with the boot firmware from the image,
+    /// images without a boot firmware manifest are refused
+    #[serde(default)]
+    pub boot_firmware: bool,
+}
+
+/// The devices and operating systems takeover was tested with
+#[derive(Debug, Deserialize)]
+pub(crate) struct SupportMatrix {
+    devices: Vec<DeviceSupport>,
+}
+
+/// What the device tree and /proc/cpuinfo tell about an ARM board
+#[derive(Debug, Default)]
+pub(crate) struct BoardInfo {
+    pub model: String,
+    /// entries of /proc/device-tree/compatible, most specific first
+    pub compatible: Vec<String>,
+    /// board type from a new style Raspberry Pi revision code
+    pub revision_type: Option<u32>,
+}
+
+/// The running OS as described in /etc/os-release
+#[derive(Debug)]
+pub(crate) struct OsRelease {
+    pub name: String,
+    pub id: Option<String>,
+    pub version_id: Option<String>,
+}
+
+impl OsRelease {
+    pub fn read() -> Result<OsRelease> {
+        Ok(OsRelease {
+            name: get_os_name()?,
+            id: get_os_release_value("ID")?,
+            version_id: get_os_release_value("VERSION_ID")?,
+        })
+    }
+}
+
+impl SupportMatrix {
+    /// Load the support matrix from the --support-matrix file or the built in one
+    pub fn load(opts: &Options) -> Result<SupportMatrix> {
+        if let Some(path) = opts.support_matrix() {
+            info!("Using support matrix from '{}'", path.display());
+            let yaml = read_to_string(path).upstream_with_context(&format!(
+                "Failed to read support matrix from '{}'",
+                path.display()
+            ))?;
+            SupportMatrix::parse(&yaml).upstream_with_context(&format!(
+                "Failed to parse support matrix from '{}'",
+                path.display()
+            ))
+        } else {
+            SupportMatrix::parse(BUILTIN_SUPPORT_MATRIX)
+        }
+    }
+
+    pub fn parse(yaml: &str) -> Result<SupportMatrix> {
+        let matrix: SupportMatrix =
+            serde_yaml::from_str(yaml).upstream_with_context("Failed to parse support matrix")?;
+
+        // fail early on invalid patterns rather than when they are needed
+        for device in &matrix.devices {
+            for pattern in device.models.iter().chain(device.compatible.iter()) {
+                compile(pattern)?;
+            }
+            for pattern in device.dmi.iter().flat_map(|dmi| dmi.values()) {
+                compile(pattern)?;
+            }
+            for os in &device.os {
+                if let Some(name) = &os.name {
+                    compile(name)?;
+                }
+                if let Some(version) = &os.version {
+                    version_matches(version, "0")?;
+                }
+            }
+        }
+        Ok(matrix)
+    }
+
+    /// Find an ARM board by its Raspberry Pi revision code, its device tree
+    /// compatible strings or its model, in that order
+    pub fn detect_board(&self, board: &BoardInfo) -> Result<Option<&DeviceSupport>> {
+        if let Some(revision_type) = board.revision_type {
+            if let Some(device) = self
+                .devices
+                .iter()
+                .find(|device| device.revision_types.contains(&revision_type))
+            {
+                debug!(
+                    "detect_board: revision type 0x{:02x} matches {}",
+                    revision_type, device.slug
+                );
+                return Ok(Some(device));
+            }
+        }
+
+        for compatible in &board.compatible {
+            for device in &self.devices {
+                for pattern in &device.compatible {
+                    if compile(pattern)?.is_match(compatible) {
+                        debug!(
+                            "detect_board: '{}' matches compatible '{}' of {}",
+                            compatible, pattern, device.slug
+                        );
+                        return Ok(Some(device));
+                    }
+                }
+            }
+        }
+
+        for device in &self.devices {
+            for pattern in &device.models {
+                if compile(pattern)?.is_match(&board.model) {
+                    debug!(
+                        "detect_board: '{}' matches model '{}' of {}",
+                        board.model, pattern, device.slug
+                    );
+                    return Ok(Some(device));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find the board of an x86 device from its DMI data, read_dmi returns the
+    /// contents of a file in /sys/class/dmi/id
+    pub fn detect_dmi<F: Fn(&str) -> Option<String>>(
+        &self,
+        read_dmi: F,
+    ) -> Result<Option<&DeviceSupport>> {
+        for device in &self.devices {
+            if let Some(dmi) = &device.dmi {
+                let mut matches = true;
+                for (key, pattern) in dmi {
+                    let value = read_dmi(key).unwrap_or_default();
+                    if !compile(pattern)?.is_match(value.trim()) {
+                        matches = false;
+                        break;
+                    }
+                }
+                if matches {
+                    debug!("detect_dmi: DMI data matches {}", device.slug);
+                    return Ok(Some(device));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Can a board of device_type run balenaOS for slug
+    pub fn supports_device_type(&self, device_type: DeviceType, slug: &str) -> bool {
+        self.devices
+            .iter()
+            .any(|device| device.device_type == device_type && device.slug == slug)
+    }
+
+    /// The slugs of device_type whose compatible regexes match the board
+    pub fn compatible_slugs(
+        &self,
+        device_type: DeviceType,
+        board: &BoardInfo,
+    ) -> Result<Vec<String>> {
+        let mut slugs = Vec::new();
+        for device in self
+            .devices
+            .iter()
+            .filter(|device| device.device_type == device_type)
+        {
+            for pattern in &device.compatible {
+                let regex = compile(pattern)?;
+                if board.compatible.iter().any(|entry| regex.is_match(entry)) {
+                    slugs.push(device.slug.clone());
+                    break;
+                }
+            }
+        }
+        Ok(slugs)
+    }
+
+    /// Does a board of device_type need the boot firmware from the image to
+    /// run balenaOS for slug
+    pub fn needs_boot_firmware(&self, device_type: DeviceType, slug: &str) -> bool {
+        self.devices.iter().any(|device| {
+            device.device_type == device_type && device.slug == slug && device.boot_firmware
+        })
+    }
+
+    /// Can balenaOS images be downloaded for slug
+    pub fn supports_download(&self, slug: &str) -> bool {
+        self.devices
+            .iter()
+            .any(|device| device.download && device.slug == slug)
+    }
+
+    /// Was takeover tested with the OS on a board of device_type
+    pub fn supports_os(&self, device_type: DeviceType, os_release: &OsRelease) -> Result<bool> {
+        for device in self
+            .devices
+            .iter()
+            .filter(|device| device.device_type == device_type)
+        {
+            for os in &device.os {
+                if os.matches(os_release)? {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl OsPattern {
+    fn matches(&self, os_release: &OsRelease) -> Result<bool> {
+        if let Some(name) = &self.name {
+            if !compile(name)?.is_match(&os_release.name) {
+                return Ok(false);
+            }
+        }
+
+        if let Some(id) = &self.id {
+            if os_release.id.as_deref() != Some(id.as_str()) {
+                return Ok(false);
+            }
+        }
+
+        if let Some(version) = &self.version {
+            match &os_release.version_id {
+                Some(version_id) => {
+                    // an OS version that is not numeric, like 5.1.20+rev1, is
+                    // not in any range
+                    if !version_matches(version, version_id).unwrap_or(false) {
+                        return Ok(false);
+                    }
+                }
+                None => return Ok(false),
+            }
+        }
+
+        Ok(self.name.is_some() || self.id.is_some())
+    }
+}
+
+fn compile(pattern: &str) -> Result<Regex> {
+    Regex::new(pattern).upstream_with_context(&format!(
+        "Invalid regular expression '{}' in support matrix",
+        pattern
+    ))
+}
+
+fn parse_version(version: &str) -> Result<Vec<u64>> {
+    version
+        .trim()
+        .split('.')
+        .map(|part| {
+            part.parse::<u64>().map_err(|_| {
+                Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!("Invalid version '{}' in support matrix", version),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Compare versions by their numeric components, missing components count as 0
+fn compare_versions(left: &[u64], right: &[u64]) -> Ordering {
+    for idx in 0..left.len().max(right.len()) {
+        let ordering = left
+            .get(idx)
+            .unwrap_or(&0)
+            .cmp(right.get(idx).unwrap_or(&0));
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Check version against comma separated requirements like '>=18.04, <=20.04'
+fn version_matches(requirements: &str, version: &str) -> Result<bool> {
+    let version = parse_version(version)?;
+    for requirement in requirements.split(',') {
+        let requirement = requirement.trim();
+        let (operator, required) = ["<=", ">=", "<", ">", "="]
+            .iter()
+            .find_map(|&operator| {
+                requirement
+                    .strip_prefix(operator)
+                    .map(|required| (operator, required))
+            })
+            .unwrap_or(("=", requirement));
+
+        let ordering = compare_versions(&version, &parse_version(required)?);
+        let matches = match operator {
+            "<=" => ordering != Ordering::Greater,
+            ">=" => ordering != Ordering::Less,
+            "<" => ordering == Ordering::Less,
+            ">" => ordering == Ordering::Greater,
+            _ => ordering == Ordering::Equal,
+        };
+        if !matches {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn os_release(name: &str, id: &str, version_id: &str) -> OsRelease {
+        OsRelease {
+            name: name.to_string(),
+            id: Some(id.to_string()),
+            version_id: Some(version_id.to_string()),
+        }
+    }
+
+    #[test]
+    fn detects_builtin_devices() {
+        let matrix = SupportMatrix::parse(BUILTIN_SUPPORT_MATRIX).unwrap();
+
+        let board = |model: &str, compatible: &[&str], revision_type: Option<u32>| BoardInfo {
+            model: model.to_string(),
+            compatible: compatible.iter().map(|entry| entry.to_string()).collect(),
+            revision_type,
+        };
+        let detect = |board: BoardInfo| matrix.detect_board(&board).unwrap().map(|d| d.device_type);
+
+        assert_eq!(
+            detect(board("Raspberry Pi 4 Model B Rev 1.1", &[], None)),
+            Some(DeviceType::RaspberryPi4)
+        );
+        assert_eq!(
+            detect(board(
+                "Raspberry Pi Compute Module 3 Plus Rev 1.0",
+                &[],
+                None
+            )),
+            Some(DeviceType::RaspberryPi3)
+        );
+        assert_eq!(
+            detect(board("TI AM335x BeagleBone", &[], None)),
+            Some(DeviceType::BeagleboneGreen)
+        );
+        assert_eq!(detect(board("Unknown Board", &[], None)), None);
+
+        // models the model regexes do not know, by revision code or compatible
+        assert_eq!(
+            detect(board("Raspberry Pi 5 Model B Rev 1.0", &[], Some(0x17))),
+            Some(DeviceType::RaspberryPi5)
+        );
+        assert_eq!(
+            detect(board("Raspberry Pi 400 Rev 1.0", &[], Some(0x13))),
+            Some(DeviceType::RaspberryPi400)
+        );
+        assert_eq!(
+            detect(board(
+                "Raspberry Pi Compute Module 4 Rev 1.1",
+                &["raspberrypi,4-compute-module", "brcm,bcm2711"],
+                None
+            )),
+            Some(DeviceType::RaspberryPiCM4)
+        );
+        assert_eq!(
+            detect(board(
+                "Raspberry Pi Zero 2 W Rev 1.0",
+                &["raspberrypi,model-zero-2-w", "brcm,bcm2837"],
+                None
+            )),
+            Some(DeviceType::RaspberryPiZero2W)
+        );
+        assert_eq!(
+            detect(board(
+                "Raspberry Pi Zero W Rev 1.1",
+                &["raspberrypi,model-zero-w"],
+                None
+            )),
+            Some(DeviceType::RaspberryPi1)
+        );
+
+        let device = matrix.detect_dmi(|_| None).unwrap().unwrap();
+        assert_eq!(device.device_type, DeviceType::IntelNuc);
+
+        // generic boards by their compatible strings only
+        let rockpro64 = board(
+            "Pine64 RockPro64 v2.1",
+            &[
+                "pine64,rockpro64-v2.1",
+                "pine64,rockpro64",
+                "rockchip,rk3399",
+            ],
+            None,
+        );
+        assert_eq!(detect(board("Pine64 RockPro64 v2.1", &[], None)), None);
+        assert_eq!(
+            matrix.detect_board(&rockpro64).unwrap().unwrap().slug,
+            "rockpro64"
+        );
+        assert_eq!(
+            matrix
+                .compatible_slugs(DeviceType::GenericArm, &rockpro64)
+                .unwrap(),
+            vec!["rockpro64".to_string()]
+        );
+
+        assert!(matrix.needs_boot_firmware(DeviceType::GenericArm, "jetson-orin-nano-devkit-nvme"));
+        assert!(!matrix.needs_boot_firmware(DeviceType::GenericArm, "rockpro64"));
+
+        assert!(matrix.supports_device_type(DeviceType::IntelNuc, "generic-amd64"));
+        assert!(!matrix.supports_device_type(DeviceType::RaspberryPi3, "raspberrypi4-64"));
+        assert!(matrix.supports_device_type(DeviceType::RaspberryPi3, "raspberrypi3-64"));
+        assert!(matrix.supports_device_type(DeviceType::RaspberryPiCM4, "raspberrypicm4-ioboard"));
+        assert!(matrix.supports_download("intel-nuc"));
+        assert!(!matrix.supports_download("generic-amd64"));
+    }
+
+    #[test]
+    fn checks_os_patterns() {
+        let matrix = SupportMatrix::parse(BUILTIN_SUPPORT_MATRIX).unwrap();
+        let ubuntu_18 = os_release("Ubuntu 18.04.4 LTS", "ubuntu", "18.04");
+        let ubuntu_24 = os_release("Ubuntu 24.04 LTS", "ubuntu", "24.04");
+        let balena = os_release("balenaOS 5.1.20", "balena-os", "5.1.20");
+        assert!(matrix
+            .supports_os(DeviceType::IntelNuc, &ubuntu_18)
+            .unwrap());
+        assert!(!matrix
+            .supports_os(DeviceType::IntelNuc, &ubuntu_24)
+            .unwrap());
+        // only the point releases takeover was tested with
+        assert!(!matrix
+            .supports_os(
+                DeviceType::IntelNuc,
+                &os_release("Ubuntu 18.04.6 LTS", "ubuntu", "18.04")
+            )
+            .unwrap());
+        assert!(!matrix
+            .supports_os(
+                DeviceType::IntelNuc,
+                &os_release("Ubuntu 19.10", "ubuntu", "19.10")
+            )
+            .unwrap());
+        assert!(!matrix
+            .supports_os(DeviceType::GenericArm, &ubuntu_24)
+            .unwrap());
+        assert!(matrix
+            .supports_os(DeviceType::JetsonXavierNX, &balena)
+            .unwrap());
+
+        // adding an OS is a change to the matrix
+        let matrix = SupportMatrix::parse(
+            "devices:\n- slug: intel-nuc\n  device_type: IntelNuc\n  dmi:\n    sys_vendor: '^Intel'\n  \
+             os:\n  - id: ubuntu\n    version: '>=22.04, <25'\n",
+        )
+        .unwrap();
+        assert!(matrix
+            .supports_os(DeviceType::IntelNuc, &ubuntu_24)
+            .unwrap());
+        assert!(matrix
+            .detect_dmi(|key| (key == "sys_vendor").then(|| "Intel Corporation\n".to_string()))
+            .unwrap()
+            .is_some());
+        assert!(matrix.detect_dmi(|_| None).unwrap().is_none());
+
+        assert!(version_matches(">=8, <=10", "10").unwrap());
+        assert!(!version_matches(">=8, <10", "10").unwrap());
+        assert!(version_matches("=20.04", "20.4").unwrap());
+        assert!(SupportMatrix::parse(
+            "devices:\n- slug: x\n  device_type: Dummy\n  os:\n  - id: x\n    version: '>=a'\n"
+        )
+        .is_err());
+    }
+}
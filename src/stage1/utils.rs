@@ -9,22 +9,20 @@ use std::path::{Path, PathBuf};
 
 use crate::{
     common::{
-        call,
-        defs::{MOKUTIL_CMD, NIX_NONE, SYS_EFI_DIR},
+        defs::NIX_NONE,
         dir_exists,
         system::{mkdir, mknod, uname},
-        whereis, Error, ErrorKind, Result, ToError,
+        Error, ErrorKind, Result, ToError,
     },
     stage1::defs::OSArch,
 };
 
-use log::{error, trace, warn};
-use regex::Regex;
+use log::trace;
 
 use crate::common::path_append;
 use crate::stage1::migrate_info::MigrateInfo;
 
-use std::fs::create_dir_all;
+use std::fs::{create_dir_all, read_to_string};
 use std::io::Read;
 
 pub(crate) fn get_os_arch() -> Result<OSArch> {
@@ -34,7 +32,6 @@ pub(crate) fn get_os_arch() -> Result<OSArch> {
     let machine = uname_res.get_machine();
     match machine {
         "x86_64" => Ok(OSArch::AMD64),
-        "i386" => Ok(OSArch::I386),
         "armv7l" => Ok(OSArch::ARMHF),
         "armv6l" => Ok(OSArch::ARMHF),
         "aarch64" => Ok(OSArch::ARM64),
@@ -45,63 +42,6 @@ pub(crate) fn get_os_arch() -> Result<OSArch> {
     }
 }
 
-/******************************************************************
- * Try to find out if secure boot is enabled using mokutil
- * assuming secure boot is not enabled if mokutil is absent
- ******************************************************************/
-
-pub(crate) fn is_secure_boot() -> Result<bool> {
-    trace!("is_secure_boot: entered");
-
-    // TODO: check for efi vars
-
-    if dir_exists(SYS_EFI_DIR)? {
-        let mokutil_path = match whereis(MOKUTIL_CMD) {
-            Ok(path) => path,
-            Err(_why) => {
-                warn!("The mokutil command '{}' could not be found", MOKUTIL_CMD);
-                return Ok(false);
-            }
-        };
-
-        let cmd_res = call(&mokutil_path, &["--sb-state"], true)?;
-        if cmd_res.stderr.is_empty() {
-            let regex = Regex::new(r"^SecureBoot\s+(disabled|enabled)$").unwrap();
-            let lines = cmd_res.stdout.lines();
-            for line in lines {
-                if let Some(cap) = regex.captures(line) {
-                    if cap.get(1).unwrap().as_str() == "enabled" {
-                        return Ok(true);
-                    } else {
-                        return Ok(false);
-                    }
-                }
-            }
-
-            error!(
-                "is_secure_boot: failed to parse command output: '{}'",
-                cmd_res.stdout
-            );
-            Err(Error::with_context(
-                ErrorKind::InvParam,
-                "is_secure_boot: failed to parse command output",
-            ))
-        } else if cmd_res
-            .stderr
-            .starts_with("This system doesn't support Secure Boot")
-        {
-            Ok(false)
-        } else {
-            Err(Error::with_context(
-                ErrorKind::ExecProcess,
-                &format!("mokutil returned an error message: '{}'", cmd_res.stderr),
-            ))
-        }
-    } else {
-        Ok(false)
-    }
-}
-
 pub(crate) fn mktemp<P: AsRef<Path>>(
     dir: bool,
     prefix: Option<&str>,
@@ -238,11 +178,40 @@ impl<'a> Read for ReadBuffer<'a> {
     }
 }
 
+/// Return the swap devices and files currently in use
+pub(crate) fn get_swap_devices() -> Result<Vec<PathBuf>> {
+    let swaps =
+        read_to_string("/proc/swaps").upstream_with_context("Failed to read '/proc/swaps'")?;
+    Ok(parse_swaps(&swaps))
+}
+
+fn parse_swaps(swaps: &str) -> Vec<PathBuf> {
+    // skip the header line: Filename Type Size Used Priority
+    swaps
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .map(PathBuf::from)
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::io::copy;
 
+    #[test]
+    fn test_parse_swaps() {
+        const SWAPS: &str = "Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority\n\
+            /dev/sda3                               partition\t2097148\t\t0\t\t-2\n\
+            /swapfile                               file\t\t1048572\t\t0\t\t-3\n";
+        assert_eq!(
+            parse_swaps(SWAPS),
+            vec![PathBuf::from("/dev/sda3"), PathBuf::from("/swapfile")]
+        );
+        assert!(parse_swaps("Filename\tType\tSize\tUsed\tPriority\n").is_empty());
+    }
+
     #[test]
     fn test_read_buffer() {
         const BUFFER: [u8; 16] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
@@ -1,9 +1,15 @@
-use std::fs::{
-    copy, create_dir, create_dir_all, read_dir, read_to_string, remove_dir, File, OpenOptions,
-};
-use std::io::{self, Read, Write};
+mod backup_restore;
+mod block_writer;
+mod disk_backup;
+mod hup_progress;
+mod migration_report;
+pub(crate) mod recovery;
+
+use std::fmt::{self, Display, Formatter};
+use std::fs::{copy, create_dir, create_dir_all, read_dir, read_to_string, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::os::unix::io::AsRawFd;
-use std::process::{Command, Stdio};
+use std::process::exit;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
@@ -14,54 +20,63 @@ use nix::{
 
 use std::path::{Path, PathBuf};
 
-use flate2::read::GzDecoder;
 use libc::{ioctl, MS_RDONLY, MS_REMOUNT, SIGKILL, SIGTERM};
+use openssl::sha::sha256;
 
 use log::{debug, error, info, trace, warn, Level};
 use mod_logger::{LogDestination, Logger, NO_STREAM};
 
-use crate::common::defs::MTD_DEBUG_CMD;
-use crate::common::logging::{open_fallback_log_file, persist_fallback_log_to_data_partition};
+use crate::common::logging::{
+    open_fallback_log_file, persist_fallback_log_to_data_partition, write_to_data_partition,
+};
 
 use crate::common::reboot;
 
 use crate::common::{
     api_calls::{notify_hup_progress, patch_device_type},
-    call,
+    backup_crypt::decode_private_key,
+    compression::{decompress, open_image},
     defs::{
         IoctlReq, BACKUP_ARCH_NAME, BALENA_BOOT_FSTYPE, BALENA_BOOT_MP, BALENA_BOOT_PART,
         BALENA_CONFIG_PATH, BALENA_DATA_FSTYPE, BALENA_DATA_PART, BALENA_IMAGE_NAME,
-        BALENA_IMAGE_PATH, BALENA_PART_MP, BALENA_ROOTA_FSTYPE, BALENA_ROOTA_PART,
-        BOOT_BLOB_NAME_JETSON_XAVIER, BOOT_BLOB_NAME_JETSON_XAVIER_NX,
-        BOOT_BLOB_PARTITION_JETSON_XAVIER, BOOT_BLOB_PARTITION_JETSON_XAVIER_NX, DD_CMD,
-        DISK_BY_LABEL_PATH, EFIBOOTMGR_CMD, JETSON_XAVIER_HW_PART_FORCE_RO_FILE, NIX_NONE,
-        OLD_ROOT_MP, STAGE2_CONFIG_NAME, SYSTEM_CONNECTIONS_DIR, SYSTEM_PROXY_DIR, SYS_EFI_DIR,
+        BALENA_IMAGE_PATH, BALENA_PART_MP, BALENA_ROOTA_FSTYPE, DISK_BY_LABEL_PATH, NIX_NONE,
+        OLD_ROOT_MP, S2_XTRA_FS_SIZE, STAGE2_CONFIG_NAME, SYSTEM_CONNECTIONS_DIR, SYSTEM_PROXY_DIR,
     },
     dir_exists,
-    disk_util::{Disk, LabelType, PartInfo, PartitionIterator, DEF_BLOCK_SIZE},
+    disk_util::{Disk, PartInfo, DEF_BLOCK_SIZE},
     error::{Error, ErrorKind, Result, ToError},
-    file_exists, find_file, format_size_with_unit, get_mem_info,
+    file_exists, format_size_with_unit, get_mem_info,
     loop_device::LoopDevice,
     options::Options,
     path_append,
     stage2_config::{Stage2Config, UmountPart},
     system::{fuser, get_process_infos},
 };
-use regex::Regex;
 
-const DD_BLOCK_SIZE: usize = 128 * 1024; // 4_194_304;
+use self::backup_restore::restore_backup;
+use self::block_writer::{fill_buffer, BlockWriter, FLASH_BLOCK_SIZE};
+use self::disk_backup::{DiskBackup, DISK_BACKUP_HEAD_SIZE, DISK_BACKUP_TAIL_SIZE};
+use self::hup_progress::{HupProgress, ProgressReader};
+use self::migration_report::{MigrationReport, Phase};
+use self::recovery::{recover, RECOVERY_EXIT_CODE};
+use crate::stage1::{device::Device, device_impl::from_device_type};
 
-// QSPI flash storage size in bytes for Jetson Xavier NX
-const JETSON_XAVIER_NX_QSPI_SIZE: &str = "0x2000000";
 const VALIDATE_MAX_ERR: usize = 20;
-const DO_VALIDATE: bool = false;
-const VALIDATE_BLOCK_SIZE: usize = 64 * 1024; // 4_194_304;
+const VALIDATE_BLOCK_SIZE: usize = 4 * 1024 * 1024;
 
 const IOCTL_BLK_RRPART: IoctlReq = 0x1295;
+const IOCTL_BLK_FLSBUF: IoctlReq = 0x1261;
+
+// the firmware only boots disks with this signature at the end of the MBR
+const MBR_SIGNATURE_OFFSET: u64 = 510;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
 
 const TRANSFER_DIR: &str = "/transfer";
+// tmpfs directory the partition table regions of the flash device are saved to
+const DISK_BACKUP_DIR: &str = "/tmp";
 
-const S2_XTRA_FS_SIZE: u64 = 10 * 1024 * 1024;
+// range of the HUP progress percentage covered by flashing the image
+const FLASH_PROGRESS: (u8, u8) = (20, 80);
 
 fn get_required_space(s2_cfg: &Stage2Config) -> Result<u64> {
     let curr_file = path_append(OLD_ROOT_MP, &s2_cfg.image_path);
@@ -73,6 +88,8 @@ fn get_required_space(s2_cfg: &Stage2Config) -> Result<u64> {
         ))?
         .len();
 
+    req_size += DISK_BACKUP_HEAD_SIZE + DISK_BACKUP_TAIL_SIZE + s2_cfg.boot_firmware_space;
+
     let curr_file = path_append(OLD_ROOT_MP, &s2_cfg.config_path);
     req_size += curr_file
         .metadata()
@@ -536,236 +553,14 @@ fn transfer_boot_files<P: AsRef<Path>>(dev_root: P) -> Result<()> {
 }
 
 pub fn get_partition_infos(device: &Path) -> Result<(PartInfo, PartInfo, PartInfo)> {
-    let mut disk = Disk::from_drive_file(device, None)?;
-
-    let mut boot_part: Option<PartInfo> = None;
-    let mut root_a_part: Option<PartInfo> = None;
-    let mut data_part: Option<PartInfo> = None;
-
-    // GPT provides a 'protective MBR' at LBA 0 to identify itself in a backward
-    // compatible way. So, read the GPT header if so.
-    let is_gpt = disk.get_label()? == LabelType::GPT;
-
-    if !is_gpt {
-        let part_iterator = PartitionIterator::new(&mut disk)?;
-        for partition in part_iterator {
-            debug!(
-                "partition: {}, start: {}, sectors: {}",
-                partition.index, partition.start_lba, partition.num_sectors
-            );
-
-            match partition.index {
-                1 => {
-                    boot_part = Some(partition);
-                }
-                // rootA partition is needed solely in the context of Jetson migrations,
-                // in which case we will mount it to extract the new Jetson specific boot blob
-                // to be written to the QSPI flash or boot partition.
-                2 => {
-                    root_a_part = Some(partition);
-                }
-                3..=5 => debug!("Skipping partition {}", partition.index),
-                6 => {
-                    data_part = Some(partition);
-                    break;
-                }
-                _ => {
-                    return Err(Error::with_context(
-                        ErrorKind::InvParam,
-                        &format!("Invalid partition index encountered: {}", partition.index),
-                    ));
-                }
-            }
-        }
-    } else {
-        // Use the iterator built into gptman and populate the PartInfo structs
-        // for the boot and data partitions as best we can.
-        let gpt = match disk.read_gpt() {
-            Ok(gpt_res) => gpt_res,
-            Err(e) => {
-                return Err(Error::with_context(
-                    ErrorKind::InvState,
-                    &format!("Failed to read GPT header, error: {} ", e),
-                ));
-            }
-        };
-        for (i, p) in gpt.iter() {
-            if p.is_used() {
-                debug!(
-                    "Partition #{}: type = {:?}, size = {} bytes, starting lba = {}, name = {}",
-                    i,
-                    p.partition_type_guid,
-                    p.size().unwrap() * gpt.sector_size,
-                    p.starting_lba,
-                    p.partition_name.as_str()
-                );
-
-                if p.partition_name.as_str() == BALENA_BOOT_PART {
-                    boot_part = Some(PartInfo {
-                        index: i as usize,
-                        ptype: 0x83, // MBR Linux byte; really this is the EFI system
-                        // partition, but MBR doesn't define this type.
-                        status: 0, // Not clear what this should be
-                        start_lba: p.starting_lba,
-                        num_sectors: p.size().unwrap(),
-                    })
-                } else if p.partition_name.as_str() == BALENA_ROOTA_PART {
-                    root_a_part = Some(PartInfo {
-                        index: i as usize,
-                        ptype: 0x83, // MBR Linux byte; really this is the EFI system
-                        // partition, but MBR doesn't define this type.
-                        status: 0, // Not clear what this should be
-                        start_lba: p.starting_lba,
-                        num_sectors: p.size().unwrap(),
-                    })
-                } else if p.partition_name.as_str() == BALENA_DATA_PART {
-                    data_part = Some(PartInfo {
-                        index: i as usize,
-                        ptype: 0x83, // MBR Linux byte
-                        status: 0,   // not clear what this should be
-                        start_lba: p.starting_lba,
-                        num_sectors: p.size().unwrap(),
-                    });
-                }
-            }
-        }
-    }
-
-    if let Some(boot_part) = boot_part {
-        if let Some(data_part) = data_part {
-            if let Some(root_a_part) = root_a_part {
-                Ok((boot_part, root_a_part, data_part))
-            } else {
-                Err(Error::with_context(
-                    ErrorKind::NotFound,
-                    &format!(
-                        "RootA partition could not be found on '{}",
-                        device.display()
-                    ),
-                ))
-            }
-        } else {
-            Err(Error::with_context(
-                ErrorKind::NotFound,
-                &format!("Data partition could not be found on '{}", device.display()),
-            ))
-        }
-    } else {
-        Err(Error::with_context(
-            ErrorKind::NotFound,
-            &format!("Boot partition could not be found on '{}", device.display()),
-        ))
-    }
-}
-
-fn efi_setup(device: &Path) -> Result<()> {
-    let efi_boot_mgr = format!("/bin/{}", EFIBOOTMGR_CMD);
-    if dir_exists(SYS_EFI_DIR)? {
-        match call_command!(&efi_boot_mgr, &[], "Failed to execute efibootmgr") {
-            Ok(cmd_stdout) => {
-                // TODO: setup efi boot
-                let efivar_regex =
-                    Regex::new(r#"\s*Boot([0-9,a-f,A-F]{4})\*?\s+resinOS.*"#).unwrap();
-                for line in cmd_stdout.lines() {
-                    if let Some(captures) = efivar_regex.captures(line) {
-                        let boot_num = captures.get(1).unwrap().as_str();
-                        match call_command!(&efi_boot_mgr, &["-B", "-b", boot_num]) {
-                            Ok(_) => (),
-                            Err(why) => {
-                                error!(
-                                    "Failed to delete boot manager '{}' as {}, error: {}",
-                                    line, boot_num, why
-                                );
-                            }
-                        }
-                    }
-                }
-                match call_command!(
-                    &efi_boot_mgr,
-                    &[
-                        "-c",
-                        "-d",
-                        &*device.to_string_lossy(),
-                        "-p",
-                        "1",
-                        "-L",
-                        "resinOS",
-                        "-l",
-                        r"\EFI\BOOT\bootx64.efi"
-                    ]
-                ) {
-                    Ok(_) => (),
-                    Err(why) => error!("Failed to setup EFI boot, error {}", why),
-                }
-            }
-            Err(why) => {
-                error!("Failed to execute '{}', error: {}", efi_boot_mgr, why);
-            }
-        }
-    } else {
-        let efi_dir = path_append(BALENA_BOOT_MP, "EFI");
-        if dir_exists(&efi_dir)? {
-            match remove_dir(&efi_dir) {
-                Ok(_) => {
-                    debug!("Removed EFI directory from '{}'", BALENA_BOOT_MP);
-                }
-                Err(why) => {
-                    warn!(
-                        "Failed to remove EFI directory from '{}', error: {}",
-                        BALENA_BOOT_MP, why
-                    );
-                }
-            }
-        }
-    }
-
-    Ok(())
-}
-
-// This functionality is specific to Jetson AGX Xavier and Xavier NX migration.
-// In the current format, the migration is expected to be performed from
-// a balenaOS install to another balenaOS install.
-fn write_boot_blob(s2_config: &Stage2Config, mount_path: PathBuf) {
-    let img_path;
-
-    if s2_config.device_type.starts_with("Jetson Xavier AGX") {
-        img_path = find_file(BOOT_BLOB_NAME_JETSON_XAVIER, &mount_path);
-
-        debug!("boot0_image_path is: '{}'", img_path.display());
-        debug!("target device is: '{}'", BOOT_BLOB_PARTITION_JETSON_XAVIER);
-
-        // Enable writing to /dev/mmcblk0boot0/
-        let force_ro = "0";
-        std::fs::write(JETSON_XAVIER_HW_PART_FORCE_RO_FILE, force_ro)
-            .expect("Could not set hw boot partition rw!");
-
-        let boot0_data = std::fs::read(img_path).unwrap();
-        debug!("boot blob - bytes read from disk: '{}' ", boot0_data.len());
-
-        std::fs::write(BOOT_BLOB_PARTITION_JETSON_XAVIER, boot0_data)
-            .expect("Could not write hw boot partition!");
-        debug!("Jetson Xavier AGX boot blob was written");
-    } else if s2_config.device_type.starts_with("Jetson Xavier NX") {
-        img_path = find_file(BOOT_BLOB_NAME_JETSON_XAVIER_NX, &mount_path);
-
-        debug!("boot0_image_path is: '{}'", img_path.display());
-        debug!(
-            "boot0_image_dev is: '{}'",
-            BOOT_BLOB_PARTITION_JETSON_XAVIER_NX
-        );
-
-        match flash_qspi(&img_path) {
-            FlashState::Success => {
-                info!("Xavier NX QSPI written succesfully!")
-            }
-            _ => {
-                warn!("Failed to write QSPI!")
-            }
-        }
-    }
+    Disk::from_drive_file(device, None)?.get_balena_partitions()
 }
 
-fn raw_mount_balena(s2_cfg: &Stage2Config) -> Result<()> {
+fn raw_mount_balena(
+    s2_cfg: &Stage2Config,
+    board: &dyn Device,
+    report: &mut MigrationReport,
+) -> Result<()> {
     let device = &s2_cfg.flash_dev;
     debug!("raw_mount_balena called");
 
@@ -821,9 +616,11 @@ fn raw_mount_balena(s2_cfg: &Stage2Config) -> Result<()> {
 
     // TODO: copy files
 
-    transfer_boot_files(BALENA_PART_MP)?;
+    report.record(Phase::BootFiles, || transfer_boot_files(BALENA_PART_MP))?;
 
-    efi_setup(device)?;
+    report.record(Phase::EfiSetup, || {
+        board.setup_boot(s2_cfg, Path::new(BALENA_PART_MP))
+    })?;
 
     sync();
 
@@ -832,13 +629,10 @@ fn raw_mount_balena(s2_cfg: &Stage2Config) -> Result<()> {
     info!("Unmounted boot partition from {}", BALENA_PART_MP);
 
     // After the internal storage is flashed with the new balenaOS image,
-    // we mount the rootA partition, extract the Jetson boot blob from it
-    // and the use it to program the QSPI or the boot partition of the device.
-    // The new Jetson balenaOS image is expected to include a pre-built boot blog
-    // taken from an provisioning made with jetson-flash.
-    if s2_cfg.device_type.starts_with("Jetson Xavier AGX")
-        || s2_cfg.device_type.starts_with("Jetson Xavier NX")
-    {
+    // we mount the rootA partition for boards that write boot firmware taken
+    // from it, like the Jetson boot blob programmed to the QSPI or the boot
+    // partition of the device.
+    if board.has_boot_firmware() {
         let mut loop_device = LoopDevice::get_free(true)?;
         info!("Create loop device: '{}'", loop_device.get_path().display());
         let byte_offset = root_a_part.start_lba * DEF_BLOCK_SIZE as u64;
@@ -880,7 +674,9 @@ fn raw_mount_balena(s2_cfg: &Stage2Config) -> Result<()> {
             BALENA_PART_MP
         );
 
-        write_boot_blob(s2_cfg, PathBuf::from(BALENA_PART_MP));
+        report.record(Phase::BootBlob, || {
+            board.write_boot_firmware(s2_cfg, Path::new(BALENA_PART_MP))
+        })?;
 
         sync();
 
@@ -924,20 +720,25 @@ fn raw_mount_balena(s2_cfg: &Stage2Config) -> Result<()> {
             BALENA_PART_MP
         );
 
-        // TODO: copy files
-
-        let target_path = path_append(BALENA_PART_MP, BACKUP_ARCH_NAME);
-        copy(&backup_path, &target_path).upstream_with_context(&format!(
-            "Failed to copy '{}' to '{}'",
-            backup_path.display(),
-            target_path.display()
-        ))?;
-
-        info!(
-            "copied '{}' to '{}'",
-            backup_path.display(),
-            target_path.display()
-        );
+        let app_id = s2_cfg.app_id.ok_or_else(|| {
+            Error::with_context(
+                ErrorKind::InvParam,
+                "No application id found in config.json, can not restore backup to volumes",
+            )
+        })?;
+        let backup_key = s2_cfg
+            .backup_key
+            .as_deref()
+            .map(decode_private_key)
+            .transpose()?;
+        report.record_bytes(Phase::BackupRestore, || {
+            restore_backup(
+                &backup_path,
+                Path::new(BALENA_PART_MP),
+                app_id,
+                backup_key.as_ref(),
+            )
+        })?;
 
         sync();
 
@@ -1027,39 +828,75 @@ fn sys_mount_balena() -> Result<()> {
     Ok(())
 }
 
-enum FlashState {
-    Success,
-    FailRecoverable,
-    FailNonRecoverable,
+/// Describes where and why writing to a device failed
+#[derive(Debug)]
+pub(crate) struct FlashFailure {
+    offset: u64,
+    reason: String,
 }
 
-fn fill_buffer<I: Read>(buffer: &mut [u8], input: &mut I) -> Result<usize> {
-    // fill buffer
-    let mut buff_fill: usize = 0;
-    loop {
-        let bytes_read = input
-            .read(&mut buffer[buff_fill..])
-            .upstream_with_context("Failed to read from  input stream")?;
-
-        if bytes_read > 0 {
-            buff_fill += bytes_read;
-            if buff_fill < buffer.len() {
-                continue;
-            }
+impl FlashFailure {
+    pub fn new(offset: u64, reason: &str) -> FlashFailure {
+        FlashFailure {
+            offset,
+            reason: reason.to_string(),
         }
-        break;
     }
+}
+
+impl Display for FlashFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at offset 0x{:x}:{}",
+            self.reason,
+            self.offset,
+            format_size_with_unit(self.offset)
+        )
+    }
+}
+
+/// Recoverable failures leave the target device untouched,
+/// non recoverable ones leave it partially written
+enum FlashState {
+    Success(u64),
+    FailRecoverable(FlashFailure),
+    FailNonRecoverable(FlashFailure),
+}
+
+struct Validation {
+    bytes_checked: u64,
+    mismatches: Vec<u64>,
+}
+
+impl Validation {
+    fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
 
-    Ok(buff_fill)
+fn flush_buffers(target: &File, target_path: &Path) {
+    // make sure we read back what is on the device rather than what is in the buffer cache
+    let ioctl_res = unsafe { ioctl(target.as_raw_fd(), IOCTL_BLK_FLSBUF) };
+    if ioctl_res == 0 {
+        debug!(
+            "Validate: BLKFLSBUF IOCTRL to '{}' returned {}",
+            target_path.display(),
+            ioctl_res
+        );
+    } else {
+        warn!(
+            "Validate: BLKFLSBUF IOCTRL to '{}' failed with error: {}",
+            target_path.display(),
+            io::Error::last_os_error()
+        );
+    }
 }
 
-fn validate(target_path: &Path, image_path: &Path) -> Result<bool> {
+fn validate(target_path: &Path, image_path: &Path) -> Result<Validation> {
     debug!("Validate: opening: '{}'", image_path.display());
 
-    let mut decoder = GzDecoder::new(File::open(image_path).upstream_with_context(&format!(
-        "Validate: Failed to open image file '{}'",
-        image_path.display(),
-    ))?);
+    let mut decoder = open_image(image_path)?;
 
     debug!("Validate: opening output file '{}'", target_path.display());
     let mut target = OpenOptions::new()
@@ -1072,213 +909,166 @@ fn validate(target_path: &Path, image_path: &Path) -> Result<bool> {
             target_path.display(),
         ))?;
 
-    let mut gz_buffer: [u8; VALIDATE_BLOCK_SIZE] = [0; VALIDATE_BLOCK_SIZE];
-    let mut tgt_buffer: [u8; VALIDATE_BLOCK_SIZE] = [0; VALIDATE_BLOCK_SIZE];
+    flush_buffers(&target, target_path);
+
+    let mut img_buffer = vec![0u8; VALIDATE_BLOCK_SIZE];
+    let mut tgt_buffer = vec![0u8; VALIDATE_BLOCK_SIZE];
+
+    let mut res = Validation {
+        bytes_checked: 0,
+        mismatches: Vec::new(),
+    };
 
-    let mut byte_offset: u64 = 0;
-    let mut err_count = 0;
+    let start_time = Instant::now();
 
     loop {
-        let gz_read = fill_buffer(&mut gz_buffer, &mut decoder)?;
-        let tgt_read = fill_buffer(&mut tgt_buffer, &mut target)?;
-        if gz_read == 0 {
+        let img_read = fill_buffer(&mut img_buffer, &mut decoder).upstream_with_context(
+            &format!("Validate: Failed to read image '{}'", image_path.display()),
+        )?;
+        if img_read == 0 {
             break;
         }
 
-        if gz_read > tgt_read {
+        let tgt_read = fill_buffer(&mut tgt_buffer[..img_read], &mut target)
+            .upstream_with_context(&format!(
+                "Validate: Failed to read output file '{}'",
+                target_path.display()
+            ))?;
+        if tgt_read < img_read {
             warn!(
-                "Validate: file size mismatch at offset {:x}:{}: gzip stream {} output stream {}",
-                byte_offset,
-                format_size_with_unit(byte_offset),
-                gz_read,
+                "Validate: size mismatch at offset 0x{:x}:{}: image {} target {}",
+                res.bytes_checked,
+                format_size_with_unit(res.bytes_checked),
+                img_read,
                 tgt_read
             );
-            return Ok(false);
-        } else {
-            for idx in 0..gz_read {
-                if gz_buffer[idx] != tgt_buffer[idx] {
-                    warn!(
-                        "Validate: byte mismatch at offset 0x{:x}:{}: {:x} != {:x}",
-                        byte_offset + idx as u64,
-                        format_size_with_unit(byte_offset + idx as u64),
-                        gz_buffer[idx],
-                        tgt_buffer[idx]
-                    );
-                    err_count += 1;
-                    if err_count >= VALIDATE_MAX_ERR {
-                        return Ok(false);
-                    }
-                }
+            res.mismatches.push(res.bytes_checked);
+            return Ok(res);
+        }
+
+        let img_digest = sha256(&img_buffer[..img_read]);
+        let tgt_digest = sha256(&tgt_buffer[..tgt_read]);
+        if img_digest != tgt_digest {
+            warn!(
+                "Validate: checksum mismatch in chunk at offset 0x{:x}:{}: image {} target {}",
+                res.bytes_checked,
+                format_size_with_unit(res.bytes_checked),
+                hex_digest(&img_digest),
+                hex_digest(&tgt_digest)
+            );
+            res.mismatches.push(res.bytes_checked);
+            if res.mismatches.len() >= VALIDATE_MAX_ERR {
+                return Ok(res);
             }
-            byte_offset += gz_read as u64;
         }
 
-        if gz_read < VALIDATE_BLOCK_SIZE {
+        res.bytes_checked += img_read as u64;
+
+        if img_read < VALIDATE_BLOCK_SIZE {
             break;
         }
     }
 
-    Ok(err_count == 0)
+    let elapsed = Instant::now().duration_since(start_time).as_secs();
+    info!(
+        "Validate: checked {} bytes, {} in {} seconds, {} mismatching chunks",
+        res.bytes_checked,
+        format_size_with_unit(res.bytes_checked),
+        elapsed,
+        res.mismatches.len()
+    );
+
+    Ok(res)
 }
 
-// This function is used only for balenaOS to balenaOS
-// migration of Jetson Xavier AGX and Xavier NX
-fn flash_qspi(image_path: &Path) -> FlashState {
-    let mut flash_qspi_res = FlashState::Success;
-    debug!("entered flash_qspi");
-
-    match call_command!(
-        &format!("/bin/{}", MTD_DEBUG_CMD),
-        &[
-            "erase",
-            BOOT_BLOB_PARTITION_JETSON_XAVIER_NX,
-            "0",
-            JETSON_XAVIER_NX_QSPI_SIZE
-        ],
-        "Failed to execute mtdebug!"
-    ) {
-        Ok(cmd_stdout) => {
-            for line in cmd_stdout.lines() {
-                info!("line: {}", line);
-            }
-        }
-        _ => {
-            warn!("Error executing mtd_debug erase!")
-        }
-    }
+fn hex_digest(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
 
-    match call_command!(
-        &format!("/bin/{}", MTD_DEBUG_CMD),
-        &[
-            "write",
-            BOOT_BLOB_PARTITION_JETSON_XAVIER_NX,
-            "0",
-            JETSON_XAVIER_NX_QSPI_SIZE,
-            &image_path.to_string_lossy()
-        ],
-        "Failed to execute mtdebug!"
-    ) {
-        Ok(cmd_stdout) => {
-            for line in cmd_stdout.lines() {
-                info!("line: {}", line);
-            }
-        }
-        _ => {
-            warn!("Error executing mtd_debug write!");
-            flash_qspi_res = FlashState::FailRecoverable; // TODO: try flash back old boot0 image as fallback
-        }
+/// Clear the boot signature in the MBR of the disk the old system booted from,
+/// so the firmware skips it and boots the migrated disk. The partition table,
+/// or the protective MBR of GPT disks, is left intact.
+fn disable_boot_sector(device: &Path) -> Result<()> {
+    let mut disk = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device)
+        .upstream_with_context(&format!("Failed to open '{}'", device.display()))?;
+
+    let mut signature = [0u8; 2];
+    disk.seek(SeekFrom::Start(MBR_SIGNATURE_OFFSET))
+        .and_then(|_| disk.read_exact(&mut signature))
+        .upstream_with_context(&format!(
+            "Failed to read the boot signature of '{}'",
+            device.display()
+        ))?;
+    if signature != MBR_SIGNATURE {
+        info!(
+            "'{}' has no boot signature, found {:02x}{:02x}",
+            device.display(),
+            signature[0],
+            signature[1]
+        );
+        return Ok(());
     }
 
-    info!("Executed mtd_debug");
+    disk.seek(SeekFrom::Start(MBR_SIGNATURE_OFFSET))
+        .and_then(|_| disk.write_all(&[0u8; 2]))
+        .and_then(|_| disk.sync_all())
+        .upstream_with_context(&format!(
+            "Failed to clear the boot signature of '{}'",
+            device.display()
+        ))?;
 
-    info!("leaving flash_qspi()");
-    flash_qspi_res
+    info!(
+        "Cleared the boot signature of '{}', write 55aa at offset {} to make it bootable again",
+        device.display(),
+        MBR_SIGNATURE_OFFSET
+    );
+    Ok(())
 }
 
-fn flash_external(target_path: &Path, image_path: &Path, dd_cmd: &str) -> FlashState {
-    let mut fail_res = FlashState::FailRecoverable;
-
-    let mut decoder = GzDecoder::new(match File::open(image_path) {
-        Ok(file) => file,
+fn flash_external(target_path: &Path, image_path: &Path, progress: &HupProgress) -> FlashState {
+    // progress is measured on the image file as the decompressed size is unknown
+    let mut decoder = match File::open(image_path)
+        .and_then(|file| file.metadata().map(|metadata| (file, metadata.len())))
+        .upstream_with_context("Failed to open image file")
+        .and_then(|(file, size)| {
+            decompress(ProgressReader::new(
+                file,
+                size,
+                FLASH_PROGRESS,
+                "Flashing balenaOS image",
+                progress,
+            ))
+        }) {
+        Ok(decoder) => decoder,
         Err(why) => {
-            error!(
-                "Flash: Failed to open image file '{}', error: {:?}",
-                image_path.display(),
-                why
-            );
-            return fail_res;
+            return FlashState::FailRecoverable(FlashFailure::new(
+                0,
+                &format!(
+                    "Failed to open image file '{}', error: {}",
+                    image_path.display(),
+                    why
+                ),
+            ));
         }
-    });
-
-    debug!("invoking dd");
-    match Command::new(dd_cmd)
-        .args([
-            &format!("of={}", &target_path.to_string_lossy()),
-            &format!("bs={}", DD_BLOCK_SIZE),
-        ])
-        .stdin(Stdio::piped())
-        .spawn()
-    {
-        Ok(mut dd_cmd) => {
-            if let Some(stdin) = dd_cmd.stdin.as_mut() {
-                let mut buffer: [u8; DD_BLOCK_SIZE] = [0; DD_BLOCK_SIZE];
-                let mut tot_bytes: u64 = 0;
-                let start_time = Instant::now();
-                fail_res = FlashState::FailNonRecoverable;
-
-                loop {
-                    // fill buffer
-                    match fill_buffer(&mut buffer, &mut decoder) {
-                        Ok(buff_fill) => {
-                            if buff_fill > 0 {
-                                match stdin.write_all(&buffer) {
-                                    Ok(_) => {
-                                        tot_bytes += buff_fill as u64;
-                                        if buff_fill < DD_BLOCK_SIZE {
-                                            break;
-                                        }
-                                    }
-                                    Err(why) => {
-                                        error!("Failed to write to dd stdin at offset 0x{:x}:{} error {:?}",
-                                               tot_bytes,
-                                               format_size_with_unit(tot_bytes),
-                                               why);
-                                        return fail_res;
-                                    }
-                                }
-                            } else {
-                                break;
-                            }
-                        }
-                        Err(why) => {
-                            error!(
-                                "Failed to read compressed data from '{}' at offset 0x{:x}:{}, error: {}:?",
-                                image_path.display(),
-                                tot_bytes,
-                                format_size_with_unit(tot_bytes),
-                                why
-                            );
-                            return fail_res;
-                        }
-                    };
-                }
+    };
 
-                let elapsed = Instant::now().duration_since(start_time).as_secs();
-                info!(
-                    "Wrote {} bytes, {} to dd in {} seconds @ {}/sec",
-                    tot_bytes,
-                    format_size_with_unit(tot_bytes),
-                    elapsed,
-                    format_size_with_unit(tot_bytes / elapsed.max(1)),
-                );
-            } else {
-                error!("Failed to retrieve dd stdin");
-                return FlashState::FailRecoverable;
-            }
+    let mut writer = match BlockWriter::open(target_path) {
+        Ok(writer) => writer,
+        Err(failure) => return FlashState::FailRecoverable(failure),
+    };
 
-            match dd_cmd.wait() {
-                Ok(status) => {
-                    if status.success() {
-                        info!("dd terminated successfully");
-                        FlashState::Success
-                    } else {
-                        error!("dd terminated with exit code: {:?}", status.code());
-                        FlashState::FailNonRecoverable
-                    }
-                }
-                Err(why) => {
-                    error!(
-                        "Failure waiting for dd command termination, error: {:?}",
-                        why
-                    );
-                    fail_res
-                }
+    match writer.write_image(&mut decoder) {
+        Ok(bytes) => FlashState::Success(bytes),
+        Err(failure) => {
+            if failure.offset == 0 {
+                FlashState::FailRecoverable(failure)
+            } else {
+                FlashState::FailNonRecoverable(failure)
             }
         }
-        Err(why) => {
-            error!("Failed to execute '{}', error: {:?}", DD_CMD, why);
-            fail_res
-        }
     }
 }
 
@@ -1308,32 +1098,58 @@ pub fn stage2(opts: &Options) -> ! {
 
     setup_logging(&s2_config);
 
-    match kill_procs(opts.s2_log_level()) {
+    let mut report = MigrationReport::new(&s2_config);
+    let progress = HupProgress::new(&s2_config);
+    progress.update(5, "Stopping processes");
+
+    match report.record(Phase::KillProcs, || kill_procs(opts.s2_log_level())) {
         Ok(_) => (),
         Err(why) => {
             error!("kill_procs failed, error {}", why);
-            stage2_err_handler(&s2_config);
+            stage2_recover(
+                &s2_config,
+                &mut report,
+                &progress,
+                "Failed to kill processes",
+            );
         }
     };
+    progress.update(10, "Processes stopped, copying files");
 
-    match copy_files(&s2_config) {
+    match report.record(Phase::CopyFiles, || copy_files(&s2_config)) {
         Ok(_) => (),
         Err(why) => {
             error!("Failed to copy files to RAMFS, error: {:?}", why);
-            stage2_err_handler(&s2_config);
+            stage2_recover(
+                &s2_config,
+                &mut report,
+                &progress,
+                "Failed to copy files to RAMFS",
+            );
         }
     }
+    progress.update(15, "Files copied, unmounting partitions");
 
-    match unmount_partitions(&s2_config.umount_parts) {
+    match report.record(Phase::Unmount, || {
+        unmount_partitions(&s2_config.umount_parts)
+    }) {
         Ok(_) => (),
         Err(why) => {
             error!("unmount_partitions failed; {:?}", why);
-            stage2_err_handler(&s2_config);
+            stage2_recover(
+                &s2_config,
+                &mut report,
+                &progress,
+                "Failed to unmount partitions",
+            );
         }
     }
+    progress.update(20, "Partitions unmounted");
 
     if s2_config.pretend {
         info!("Not flashing due to pretend mode");
+        report.finish(None);
+        persist_report(&s2_config, &report, false);
         let _ = persist_fallback_log_to_data_partition(&s2_config, false);
         reboot();
     }
@@ -1343,58 +1159,174 @@ pub fn stage2(opts: &Options) -> ! {
     let image_path = path_append(TRANSFER_DIR, BALENA_IMAGE_PATH);
     debug!("OS image exists - {}", image_path.exists());
 
-    match flash_external(
-        &s2_config.flash_dev,
-        &image_path,
-        &format!("/bin/{}", DD_CMD),
-    ) {
-        FlashState::Success => (),
-        _ => {
-            sleep(Duration::from_secs(10));
-            stage2_err_handler(&s2_config);
+    let disk_backup = match report.record(Phase::DiskBackup, || {
+        DiskBackup::create(&s2_config.flash_dev, Path::new(DISK_BACKUP_DIR))
+    }) {
+        Ok(disk_backup) => disk_backup,
+        Err(why) => {
+            error!("Failed to save partition table regions, error: {:?}", why);
+            stage2_recover(
+                &s2_config,
+                &mut report,
+                &progress,
+                "Failed to save partition table regions",
+            );
         }
+    };
+
+    let board = from_device_type(s2_config.device_type);
+    if let Err(why) = report.record(Phase::PreFlash, || board.pre_flash(&s2_config)) {
+        error!(
+            "Pre flash steps failed for device type {}, error: {:?}",
+            s2_config.device_type, why
+        );
+        stage2_recover(&s2_config, &mut report, &progress, "Pre flash steps failed");
     }
 
-    sync();
-    sleep(Duration::from_secs(5));
+    let max_attempts = s2_config.flash_retries + 1;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        info!("Flashing image, attempt {} of {}", attempt, max_attempts);
+
+        report.begin(Phase::Flash);
+        match flash_external(&s2_config.flash_dev, &image_path, &progress) {
+            FlashState::Success(bytes) => report.succeed(Some(bytes)),
+            FlashState::FailRecoverable(failure) => {
+                error!("Flash: failed before writing to the device: {}", failure);
+                report.fail(&failure.to_string());
+                if attempt == 1 {
+                    // nothing has been written to the device yet
+                    stage2_recover(
+                        &s2_config,
+                        &mut report,
+                        &progress,
+                        "Failed to start flashing",
+                    );
+                }
+                sleep(Duration::from_secs(10));
+                stage2_err_handler(&s2_config, &mut report, &progress, &image_path);
+            }
+            FlashState::FailNonRecoverable(failure) => {
+                error!("Flash: failed writing to the device: {}", failure);
+                report.fail(&failure.to_string());
+                // the device still holds the original OS apart from the start,
+                // so the saved regions can undo the damage
+                if attempt == 1
+                    && failure.offset + FLASH_BLOCK_SIZE as u64 <= disk_backup.head_size()
+                {
+                    match report.record(Phase::DiskRestore, || disk_backup.restore()) {
+                        Ok(_) => stage2_recover(
+                            &s2_config,
+                            &mut report,
+                            &progress,
+                            "Failed to flash, the partition table regions were restored",
+                        ),
+                        Err(why) => {
+                            error!(
+                                "Failed to restore partition table regions, error: {:?}",
+                                why
+                            )
+                        }
+                    }
+                }
+                sleep(Duration::from_secs(10));
+                stage2_err_handler(&s2_config, &mut report, &progress, &image_path);
+            }
+        }
 
-    if DO_VALIDATE {
+        sync();
+
+        progress.update(FLASH_PROGRESS.1, "Validating image");
+        report.begin(Phase::Validate);
         match validate(&s2_config.flash_dev, &image_path) {
             Ok(res) => {
-                if res {
-                    info!("Image validated successfully");
+                if res.is_ok() {
+                    info!(
+                        "Image validated successfully, {} checked",
+                        format_size_with_unit(res.bytes_checked)
+                    );
+                    report.succeed(Some(res.bytes_checked));
+                    progress.update(85, "Image validated, transferring configuration");
+                    break;
                 } else {
-                    error!("Image validation failed");
+                    error!(
+                        "Image validation failed with {} mismatching chunks, first at offset 0x{:x}",
+                        res.mismatches.len(),
+                        res.mismatches[0]
+                    );
+                    report.fail(&format!(
+                        "{} mismatching chunks, first at offset 0x{:x}",
+                        res.mismatches.len(),
+                        res.mismatches[0]
+                    ));
                 }
             }
             Err(why) => {
                 error!("Image validation returned error: {:?}", why);
+                report.fail(&why.to_string());
             }
         }
-    }
 
-    sleep(Duration::from_secs(5));
+        if attempt >= max_attempts {
+            error!(
+                "Giving up after {} failed flash attempts on '{}'",
+                attempt,
+                s2_config.flash_dev.display()
+            );
+            stage2_err_handler(&s2_config, &mut report, &progress, &image_path);
+        }
+    }
 
     if (opts.s2_log_level() == Level::Debug) || (opts.s2_log_level() == Level::Trace) {
         use crate::common::debug::check_loop_control;
         check_loop_control("Stage2 after flash", "/dev");
     }
 
-    if let Err(why) = raw_mount_balena(&s2_config) {
+    let transfer_error = if let Err(why) = raw_mount_balena(&s2_config, board.as_ref(), &mut report)
+    {
         error!("Failed to transfer files to balena OS, error: {:?}", why);
+        Some(format!(
+            "Failed to transfer files to balena OS, error: {}",
+            why
+        ))
     } else {
         info!("Migration completed successfully");
+        progress.update(95, "Configuration transferred");
+        None
+    };
+
+    // only change the boot order and give up on the old disk once the new one
+    // is flashed, verified and configured
+    if transfer_error.is_none() && s2_config.eeprom_update.is_some() {
+        if let Err(why) = report.record(Phase::BootOrder, || board.set_boot_order(&s2_config)) {
+            error!("Failed to change the boot order, error: {:?}", why);
+        }
+    }
+
+    if let (None, Some(old_boot_dev)) = (&transfer_error, &s2_config.disable_boot_dev) {
+        if let Err(why) = report.record(Phase::DisableOldBoot, || disable_boot_sector(old_boot_dev))
+        {
+            error!(
+                "Failed to invalidate the boot sector of '{}', error: {:?}",
+                old_boot_dev.display(),
+                why
+            );
+        }
     }
 
     // Notify balena API that takeover is complete.
+    progress.stop();
     if s2_config.report_hup_progress {
-        match notify_hup_progress(
-            &s2_config.api_endpoint,
-            &s2_config.api_key,
-            &s2_config.uuid,
-            "100",
-            "Update successful, rebooting",
-        ) {
+        match report.record(Phase::HupNotify, || {
+            notify_hup_progress(
+                &s2_config.api_endpoint,
+                &s2_config.api_key,
+                &s2_config.uuid,
+                "100",
+                "Update successful, rebooting",
+            )
+        }) {
             Ok(_) => {
                 info!("HUP progress notification OK");
             }
@@ -1405,12 +1337,14 @@ pub fn stage2(opts: &Options) -> ! {
     }
     // Update device API with new device type if changed.
     if let Some(change_to) = &s2_config.change_dt_to {
-        match patch_device_type(
-            &s2_config.api_endpoint,
-            &s2_config.api_key,
-            change_to,
-            &s2_config.uuid,
-        ) {
+        match report.record(Phase::ApiPatch, || {
+            patch_device_type(
+                &s2_config.api_endpoint,
+                &s2_config.api_key,
+                change_to,
+                &s2_config.uuid,
+            )
+        }) {
             Ok(_) => {
                 info!(
                     "Successfully patched device type to: {} for device {}",
@@ -1421,6 +1355,9 @@ pub fn stage2(opts: &Options) -> ! {
         }
     }
 
+    report.finish(transfer_error.as_deref());
+    persist_report(&s2_config, &report, true);
+
     sync();
     // if the fallback log option was selected, we transfer the logs from tmpfs to the new data partition
     if s2_config.fallback_log {
@@ -1469,7 +1406,96 @@ fn print_active_processes() -> Result<()> {
     Ok(())
 }
 
-fn stage2_err_handler(s2_config: &Stage2Config) -> ! {
+/// Write the migration report to the fallback log directory on the data partition,
+/// returns whether it was written
+fn persist_report(
+    s2_config: &Stage2Config,
+    report: &MigrationReport,
+    is_new_image_flashed: bool,
+) -> bool {
+    if let Err(why) = write_to_data_partition(s2_config, is_new_image_flashed, |dest_dir| {
+        report.write(dest_dir)
+    }) {
+        error!("Failed to persist migration report, error: {:?}", why);
+        false
+    } else {
+        true
+    }
+}
+
+/// Whether the start of the flash device, holding the partition table, matches
+/// the image, so the partitions of the new image can be found on the device
+fn partition_table_written(target_path: &Path, image_path: &Path) -> Result<bool> {
+    let mut decoder = open_image(image_path)?;
+    let mut target = File::open(target_path)
+        .upstream_with_context(&format!("Failed to open '{}'", target_path.display()))?;
+    flush_buffers(&target, target_path);
+
+    let mut img_buffer = vec![0u8; DISK_BACKUP_HEAD_SIZE as usize];
+    let mut tgt_buffer = vec![0u8; DISK_BACKUP_HEAD_SIZE as usize];
+    let img_read = fill_buffer(&mut img_buffer, &mut decoder)
+        .upstream_with_context(&format!("Failed to read image '{}'", image_path.display()))?;
+    let tgt_read = fill_buffer(&mut tgt_buffer[..img_read], &mut target)
+        .upstream_with_context(&format!("Failed to read '{}'", target_path.display()))?;
+    Ok(img_read > 0 && tgt_read == img_read && img_buffer[..img_read] == tgt_buffer[..img_read])
+}
+
+/// Handle failures before anything was written to the flash device by
+/// restoring the original system. The worker exits with RECOVERY_EXIT_CODE
+/// for init to start the original init.
+fn stage2_recover(
+    s2_config: &Stage2Config,
+    report: &mut MigrationReport,
+    progress: &HupProgress,
+    reason: &str,
+) -> ! {
+    recover(s2_config, OLD_ROOT_MP, reason);
+
+    report.finish(Some(reason));
+    persist_report(s2_config, report, false);
+    notify_failure(s2_config, progress);
+
+    if s2_config.fallback_log {
+        let _ = persist_fallback_log_to_data_partition(s2_config, false);
+    }
+
+    Logger::flush();
+    sync();
+    exit(RECOVERY_EXIT_CODE);
+}
+
+/// Handle failures after flashing started. The report is written on a best
+/// effort basis: to the data partition of the new image if its partition table
+/// made it to the flash device, to the data partition of the original system
+/// otherwise, which only exists on balenaOS.
+fn stage2_err_handler(
+    s2_config: &Stage2Config,
+    report: &mut MigrationReport,
+    progress: &HupProgress,
+    image_path: &Path,
+) -> ! {
+    report.finish(Some("Migration aborted"));
+    let table_written = match partition_table_written(&s2_config.flash_dev, image_path) {
+        Ok(written) => written,
+        Err(why) => {
+            warn!("Failed to check the partition table, error: {:?}", why);
+            false
+        }
+    };
+    if !(table_written && persist_report(s2_config, report, true)) {
+        persist_report(s2_config, report, false);
+    }
+    notify_failure(s2_config, progress);
+
+    if s2_config.fallback_log {
+        let _ = persist_fallback_log_to_data_partition(s2_config, false);
+    }
+
+    reboot();
+}
+
+fn notify_failure(s2_config: &Stage2Config, progress: &HupProgress) {
+    progress.stop();
     // Notify balena API that takeover failed.
     if s2_config.report_hup_progress {
         match notify_hup_progress(
@@ -1487,10 +1513,70 @@ fn stage2_err_handler(s2_config: &Stage2Config) -> ! {
             }
         }
     }
+}
 
-    if s2_config.fallback_log {
-        let _ = persist_fallback_log_to_data_partition(s2_config, false);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::env::temp_dir;
+    use std::fs::{read, remove_file};
+
+    const TEST_IMAGE: &str = "test_data/part.img.gz";
+
+    fn write_target(name: &str) -> PathBuf {
+        let image_path = Path::new(env!("CARGO_MANIFEST_DIR")).join(TEST_IMAGE);
+        let target_path = temp_dir().join(name);
+        let mut decoder = GzDecoder::new(File::open(image_path).unwrap());
+        let mut target = File::create(&target_path).unwrap();
+        io::copy(&mut decoder, &mut target).unwrap();
+        target_path
     }
 
-    reboot();
+    #[test]
+    fn checks_partition_table() {
+        let image_path = Path::new(env!("CARGO_MANIFEST_DIR")).join(TEST_IMAGE);
+        let target_path = write_target("takeover-partition-table.img");
+        assert!(partition_table_written(&target_path, &image_path).unwrap());
+
+        let mut target = OpenOptions::new().write(true).open(&target_path).unwrap();
+        target.seek(SeekFrom::Start(0x1be)).unwrap();
+        target.write_all(&[0xff; 16]).unwrap();
+        drop(target);
+        assert!(!partition_table_written(&target_path, &image_path).unwrap());
+        remove_file(&target_path).unwrap();
+    }
+
+    #[test]
+    fn disables_boot_sector() {
+        let target_path = write_target("takeover-boot-sector.img");
+        disable_boot_sector(&target_path).unwrap();
+        let disk = read(&target_path).unwrap();
+        assert_eq!(&disk[510..512], &[0u8, 0u8]);
+        // a disk without boot signature is left alone
+        disable_boot_sector(&target_path).unwrap();
+        remove_file(&target_path).unwrap();
+    }
+
+    #[test]
+    fn validate_detects_mismatch() {
+        let image_path = Path::new(env!("CARGO_MANIFEST_DIR")).join(TEST_IMAGE);
+
+        let target_path = write_target("takeover-validate-ok.img");
+        let res = validate(&target_path, &image_path).unwrap();
+        assert!(res.is_ok());
+        assert!(res.bytes_checked > 0);
+        remove_file(&target_path).unwrap();
+
+        let target_path = write_target("takeover-validate-bad.img");
+        let mut target = OpenOptions::new().write(true).open(&target_path).unwrap();
+        target.seek(SeekFrom::Start(0x1000)).unwrap();
+        target.write_all(&[0xAA; 16]).unwrap();
+        drop(target);
+
+        let res = validate(&target_path, &image_path).unwrap();
+        assert!(!res.is_ok());
+        assert_eq!(res.mismatches, vec![0]);
+        remove_file(&target_path).unwrap();
+    }
 }
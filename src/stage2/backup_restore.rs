@@ -0,0 +1,198 @@
+use std::fs::create_dir_all;
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use log::{debug, info};
+use openssl::pkey::{PKey, Private};
+use tar::Archive;
+
+use crate::common::{
+    backup_crypt::open_backup, defs::BALENA_VOLUMES_DIR, format_size_with_unit, path_append, Error,
+    ErrorKind, Result, ToError,
+};
+
+/// Directory of a named volume of the app in the balena-engine volumes directory.
+/// balena-engine names the volumes of an app <app id>_<volume name>.
+fn volume_data_dir(data_mp: &Path, app_id: u64, volume: &str) -> PathBuf {
+    path_append(
+        path_append(data_mp, BALENA_VOLUMES_DIR),
+        format!("{}_{}/_data", app_id, volume),
+    )
+}
+
+/// Split an archive path into the volume name and the path inside the
+/// volume, rejecting anything that could escape the volume directory
+fn split_entry_path(path: &Path) -> Result<(String, PathBuf)> {
+    let mut components = path
+        .components()
+        .filter(|component| !matches!(component, Component::CurDir));
+
+    let volume = match components.next() {
+        Some(Component::Normal(volume)) => volume.to_string_lossy().to_string(),
+        _ => {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!("Invalid backup archive entry '{}'", path.display()),
+            ))
+        }
+    };
+
+    let mut rel_path = PathBuf::new();
+    for component in components {
+        if let Component::Normal(name) = component {
+            rel_path.push(name);
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!("Invalid backup archive entry '{}'", path.display()),
+            ));
+        }
+    }
+
+    Ok((volume, rel_path))
+}
+
+/// Extract the backup archive to the volumes of the app on the data partition
+/// mounted on data_mp. The top level directories of the archive are the
+/// volume names. Encrypted archives are decrypted with private_key.
+/// Returns the number of bytes restored.
+pub(crate) fn restore_backup(
+    archive_path: &Path,
+    data_mp: &Path,
+    app_id: u64,
+    private_key: Option<&PKey<Private>>,
+) -> Result<u64> {
+    info!(
+        "Restoring backup '{}' to volumes of app {}",
+        archive_path.display(),
+        app_id
+    );
+
+    let mut archive = Archive::new(GzDecoder::new(open_backup(archive_path, private_key)?));
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+
+    let mut volumes: Vec<String> = Vec::new();
+    let mut restored: u64 = 0;
+
+    for entry in archive.entries().upstream_with_context(&format!(
+        "Failed to read backup archive '{}'",
+        archive_path.display()
+    ))? {
+        let mut entry = entry.upstream_with_context("Failed to read backup archive entry")?;
+        let entry_path = entry
+            .path()
+            .upstream_with_context("Failed to read backup archive entry path")?
+            .to_path_buf();
+
+        let (volume, rel_path) = split_entry_path(&entry_path)?;
+        let volume_dir = volume_data_dir(data_mp, app_id, &volume);
+        if !volumes.contains(&volume) {
+            create_dir_all(&volume_dir).upstream_with_context(&format!(
+                "Failed to create volume directory '{}'",
+                volume_dir.display()
+            ))?;
+            info!(
+                "Restoring volume '{}' to '{}'",
+                volume,
+                volume_dir.display()
+            );
+            volumes.push(volume);
+        }
+
+        if rel_path.as_os_str().is_empty() {
+            continue;
+        }
+
+        let target = path_append(&volume_dir, &rel_path);
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent).upstream_with_context(&format!(
+                "Failed to create directory '{}'",
+                parent.display()
+            ))?;
+        }
+
+        entry.unpack(&target).upstream_with_context(&format!(
+            "Failed to extract '{}' to '{}'",
+            entry_path.display(),
+            target.display()
+        ))?;
+        restored += entry.size();
+        debug!(
+            "restore_backup: extracted '{}' to '{}'",
+            entry_path.display(),
+            target.display()
+        );
+    }
+
+    info!(
+        "Restored {} to {} volume(s)",
+        format_size_with_unit(restored),
+        volumes.len()
+    );
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::env::temp_dir;
+    use std::fs::{read, remove_dir_all, File};
+    use tar::{Builder, Header};
+
+    fn append(builder: &mut Builder<GzEncoder<File>>, path: &str, data: &[u8]) {
+        let mut header = Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_uid(unsafe { libc::getuid() }.into());
+        header.set_gid(unsafe { libc::getgid() }.into());
+        header.set_cksum();
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+
+    #[test]
+    fn restores_to_volume_dirs() {
+        let test_dir = temp_dir().join("takeover-backup-restore");
+        let data_mp = test_dir.join("data");
+        create_dir_all(&data_mp).unwrap();
+
+        let archive_path = test_dir.join("backup.tgz");
+        let mut builder = Builder::new(GzEncoder::new(
+            File::create(&archive_path).unwrap(),
+            Compression::default(),
+        ));
+        append(
+            &mut builder,
+            "test-volume-1/config/app.conf",
+            b"setting=1\n",
+        );
+        append(&mut builder, "test-volume-2/data.bin", &[1, 2, 3, 4]);
+        builder.into_inner().unwrap().finish().unwrap();
+
+        assert_eq!(
+            restore_backup(&archive_path, &data_mp, 1234, None).unwrap(),
+            14
+        );
+        assert_eq!(
+            read(data_mp.join("docker/volumes/1234_test-volume-1/_data/config/app.conf")).unwrap(),
+            b"setting=1\n"
+        );
+        assert_eq!(
+            read(data_mp.join("docker/volumes/1234_test-volume-2/_data/data.bin")).unwrap(),
+            vec![1, 2, 3, 4]
+        );
+
+        remove_dir_all(&test_dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_escaping_entries() {
+        assert_eq!(
+            split_entry_path(Path::new("./vol/dir/file")).unwrap(),
+            ("vol".to_string(), PathBuf::from("dir/file"))
+        );
+        assert!(split_entry_path(Path::new("vol/../../etc/passwd")).is_err());
+        assert!(split_entry_path(Path::new("/etc/passwd")).is_err());
+    }
+}
@@ -0,0 +1,385 @@
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::fs::{read_to_string, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::time::Instant;
+
+use libc::{ioctl, O_DIRECT, O_SYNC};
+use log::{debug, info, warn};
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+
+use crate::common::{defs::IoctlReq, format_size_with_unit};
+
+use super::FlashFailure;
+
+// size of the blocks written to the device
+pub(crate) const FLASH_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+// fsync the device every time this many bytes have been written
+const FLASH_SYNC_INTERVAL: u64 = 64 * 1024 * 1024;
+// alignment of the write buffer in memory, required for O_DIRECT
+const BUFFER_ALIGN: usize = 4096;
+const DEF_SECTOR_SIZE: usize = 512;
+
+const IOCTL_BLK_SSZGET: IoctlReq = 0x1268;
+const IOCTL_BLK_ZEROOUT: IoctlReq = 0x127f;
+
+/// A zero initialised heap buffer aligned to BUFFER_ALIGN as required by O_DIRECT
+struct AlignedBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    fn new(size: usize) -> AlignedBuffer {
+        let layout = Layout::from_size_align(size, BUFFER_ALIGN).unwrap();
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        AlignedBuffer { ptr, layout }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr, self.layout) }
+    }
+}
+
+/// Writes an uncompressed image stream to a block device using direct,
+/// synchronous IO
+pub(crate) struct BlockWriter {
+    device: File,
+    path: PathBuf,
+    sector_size: usize,
+    direct: bool,
+    discard: bool,
+}
+
+impl BlockWriter {
+    pub fn open(path: &Path) -> Result<BlockWriter, FlashFailure> {
+        let (device, direct) = match OpenOptions::new()
+            .write(true)
+            .create(false)
+            .custom_flags(O_DIRECT | O_SYNC)
+            .open(path)
+        {
+            Ok(device) => (device, true),
+            Err(why) => {
+                // some file systems (e.g. older tmpfs) do not support O_DIRECT
+                if why.raw_os_error() == Some(libc::EINVAL) {
+                    warn!(
+                        "BlockWriter: O_DIRECT is not supported on '{}', using buffered IO",
+                        path.display()
+                    );
+                    let device = OpenOptions::new()
+                        .write(true)
+                        .create(false)
+                        .custom_flags(O_SYNC)
+                        .open(path)
+                        .map_err(|why| {
+                            FlashFailure::new(
+                                0,
+                                &format!("Failed to open '{}', error: {}", path.display(), why),
+                            )
+                        })?;
+                    (device, false)
+                } else {
+                    return Err(FlashFailure::new(
+                        0,
+                        &format!("Failed to open '{}', error: {}", path.display(), why),
+                    ));
+                }
+            }
+        };
+
+        let is_block_dev = device
+            .metadata()
+            .map(|metadata| metadata.file_type().is_block_device())
+            .unwrap_or(false);
+
+        let (sector_size, discard) = if is_block_dev {
+            (get_sector_size(&device), supports_discard(path))
+        } else {
+            (DEF_SECTOR_SIZE, false)
+        };
+
+        debug!(
+            "BlockWriter: opened '{}', direct: {}, sector size: {}, discard: {}",
+            path.display(),
+            direct,
+            sector_size,
+            discard
+        );
+
+        Ok(BlockWriter {
+            device,
+            path: path.to_path_buf(),
+            sector_size,
+            direct,
+            discard,
+        })
+    }
+
+    /// Copy the input stream to the device, returning the number of bytes written.
+    pub fn write_image<R: Read>(&mut self, input: &mut R) -> Result<u64, FlashFailure> {
+        let mut buffer = AlignedBuffer::new(FLASH_BLOCK_SIZE);
+        let mut offset: u64 = 0;
+        let mut last_sync: u64 = 0;
+        let mut skipped: u64 = 0;
+        let start_time = Instant::now();
+
+        loop {
+            let buff_fill = fill_buffer(&mut buffer, input).map_err(|why| {
+                FlashFailure::new(
+                    offset,
+                    &format!("Failed to read image data, error: {}", why),
+                )
+            })?;
+
+            if buff_fill == 0 {
+                break;
+            }
+
+            let data = &buffer[..buff_fill];
+            if self.discard
+                && buff_fill % self.sector_size == 0
+                && data.iter().all(|byte| *byte == 0)
+            {
+                self.zero_out(offset, buff_fill as u64)?;
+                skipped += buff_fill as u64;
+            } else if buff_fill % self.sector_size == 0 {
+                self.write_at(offset, data)?;
+            } else {
+                // the trailing partial block can not be written with O_DIRECT
+                self.set_direct(false, offset)?;
+                self.write_at(offset, data)?;
+            }
+
+            offset += buff_fill as u64;
+
+            if offset - last_sync >= FLASH_SYNC_INTERVAL {
+                self.sync(offset)?;
+                last_sync = offset;
+                debug!(
+                    "BlockWriter: {} written to '{}'",
+                    format_size_with_unit(offset),
+                    self.path.display()
+                );
+            }
+
+            if buff_fill < FLASH_BLOCK_SIZE {
+                break;
+            }
+        }
+
+        self.sync(offset)?;
+
+        let elapsed = Instant::now().duration_since(start_time).as_secs();
+        info!(
+            "Wrote {} bytes, {} to '{}' in {} seconds @ {}/sec, {} zero blocks discarded",
+            offset,
+            format_size_with_unit(offset),
+            self.path.display(),
+            elapsed,
+            format_size_with_unit(offset / elapsed.max(1)),
+            format_size_with_unit(skipped),
+        );
+
+        Ok(offset)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), FlashFailure> {
+        let mut written = 0;
+        while written < data.len() {
+            match self.device.write(&data[written..]) {
+                Ok(0) => {
+                    return Err(FlashFailure::new(
+                        offset + written as u64,
+                        &format!("No space left on '{}'", self.path.display()),
+                    ));
+                }
+                Ok(bytes) => written += bytes,
+                Err(why) => {
+                    if why.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(FlashFailure::new(
+                        offset + written as u64,
+                        &format!(
+                            "Failed to write to '{}', error: {}",
+                            self.path.display(),
+                            why
+                        ),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn zero_out(&mut self, offset: u64, len: u64) -> Result<(), FlashFailure> {
+        // BLKZEROOUT lets the device unmap the range instead of writing the zeros
+        let range: [u64; 2] = [offset, len];
+        let ioctl_res = unsafe { ioctl(self.device.as_raw_fd(), IOCTL_BLK_ZEROOUT, &range) };
+        if ioctl_res != 0 {
+            return Err(FlashFailure::new(
+                offset,
+                &format!(
+                    "BLKZEROOUT IOCTRL to '{}' failed with error: {}",
+                    self.path.display(),
+                    io::Error::last_os_error()
+                ),
+            ));
+        }
+
+        // BLKZEROOUT does not move the file position
+        let pos = unsafe {
+            libc::lseek64(
+                self.device.as_raw_fd(),
+                (offset + len) as i64,
+                libc::SEEK_SET,
+            )
+        };
+        if pos < 0 {
+            return Err(FlashFailure::new(
+                offset,
+                &format!(
+                    "Failed to seek on '{}', error: {}",
+                    self.path.display(),
+                    io::Error::last_os_error()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn set_direct(&mut self, direct: bool, offset: u64) -> Result<(), FlashFailure> {
+        if self.direct == direct {
+            return Ok(());
+        }
+
+        let mut flags = OFlag::O_SYNC;
+        if direct {
+            flags |= OFlag::O_DIRECT;
+        }
+
+        fcntl(self.device.as_raw_fd(), FcntlArg::F_SETFL(flags)).map_err(|why| {
+            FlashFailure::new(
+                offset,
+                &format!(
+                    "Failed to set file flags on '{}', error: {}",
+                    self.path.display(),
+                    why
+                ),
+            )
+        })?;
+        self.direct = direct;
+        Ok(())
+    }
+
+    fn sync(&mut self, offset: u64) -> Result<(), FlashFailure> {
+        self.device.sync_all().map_err(|why| {
+            FlashFailure::new(
+                offset,
+                &format!("Failed to sync '{}', error: {}", self.path.display(), why),
+            )
+        })
+    }
+}
+
+/// Read from input until buffer is full or input is exhausted, returns the
+/// number of bytes read
+pub(crate) fn fill_buffer<I: Read>(buffer: &mut [u8], input: &mut I) -> io::Result<usize> {
+    let mut buff_fill: usize = 0;
+    while buff_fill < buffer.len() {
+        match input.read(&mut buffer[buff_fill..]) {
+            Ok(0) => break,
+            Ok(bytes_read) => buff_fill += bytes_read,
+            Err(why) => {
+                if why.kind() != io::ErrorKind::Interrupted {
+                    return Err(why);
+                }
+            }
+        }
+    }
+    Ok(buff_fill)
+}
+
+fn get_sector_size(device: &File) -> usize {
+    let mut sector_size: libc::c_int = 0;
+    let ioctl_res = unsafe { ioctl(device.as_raw_fd(), IOCTL_BLK_SSZGET, &mut sector_size) };
+    if ioctl_res == 0 && sector_size > 0 {
+        sector_size as usize
+    } else {
+        warn!(
+            "BLKSSZGET IOCTRL failed with error: {}, assuming {} byte sectors",
+            io::Error::last_os_error(),
+            DEF_SECTOR_SIZE
+        );
+        DEF_SECTOR_SIZE
+    }
+}
+
+fn supports_discard(path: &Path) -> bool {
+    let dev_name = match path.canonicalize() {
+        Ok(path) => match path.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => return false,
+        },
+        Err(_) => return false,
+    };
+
+    match read_to_string(format!(
+        "/sys/class/block/{}/queue/discard_max_bytes",
+        dev_name
+    )) {
+        Ok(max_bytes) => max_bytes.trim().parse::<u64>().unwrap_or(0) > 0,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::{read, remove_file};
+
+    #[test]
+    fn write_image_to_file() {
+        let target_path = temp_dir().join("takeover-block-writer.img");
+        File::create(&target_path).unwrap();
+
+        // two full blocks, one of them zero, and an unaligned tail
+        let mut image = vec![0u8; 2 * FLASH_BLOCK_SIZE + 1000];
+        image[..FLASH_BLOCK_SIZE]
+            .iter_mut()
+            .enumerate()
+            .for_each(|(idx, byte)| *byte = idx as u8);
+        image[2 * FLASH_BLOCK_SIZE..].fill(0x5A);
+
+        let mut writer = BlockWriter::open(&target_path).unwrap();
+        let written = writer.write_image(&mut image.as_slice()).unwrap();
+        assert_eq!(written, image.len() as u64);
+        assert_eq!(read(&target_path).unwrap(), image);
+
+        remove_file(&target_path).unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+use std::fs::{remove_file, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use log::{debug, info, warn};
+
+use crate::common::{format_size_with_unit, path_append, Error, ErrorKind, Result, ToError};
+
+// size of the region saved from the start of the device, holds the
+// partition table and the boot loader areas in front of the first partition
+pub(crate) const DISK_BACKUP_HEAD_SIZE: u64 = 8 * 1024 * 1024;
+// size of the region saved from the end of the device, holds the backup GPT
+pub(crate) const DISK_BACKUP_TAIL_SIZE: u64 = 1024 * 1024;
+
+struct BackupRegion {
+    offset: u64,
+    size: u64,
+    file: PathBuf,
+}
+
+/// A copy of the regions of the flash device that hold partition tables,
+/// kept in tmpfs so they can be written back if flashing fails early
+pub(crate) struct DiskBackup {
+    device: PathBuf,
+    regions: Vec<BackupRegion>,
+}
+
+impl DiskBackup {
+    /// Save the start and end of device to files in backup_dir
+    pub fn create(device: &Path, backup_dir: &Path) -> Result<DiskBackup> {
+        let mut dev_file = File::open(device)
+            .upstream_with_context(&format!("Failed to open '{}'", device.display()))?;
+        let dev_size = dev_file
+            .seek(SeekFrom::End(0))
+            .upstream_with_context(&format!(
+                "Failed to determine size of '{}'",
+                device.display()
+            ))?;
+
+        let head_size = DISK_BACKUP_HEAD_SIZE.min(dev_size);
+        let mut regions = vec![BackupRegion {
+            offset: 0,
+            size: head_size,
+            file: path_append(backup_dir, "disk-backup-head.bin"),
+        }];
+
+        if dev_size > head_size {
+            let tail_size = DISK_BACKUP_TAIL_SIZE.min(dev_size - head_size);
+            regions.push(BackupRegion {
+                offset: dev_size - tail_size,
+                size: tail_size,
+                file: path_append(backup_dir, "disk-backup-tail.bin"),
+            });
+        }
+
+        for region in &regions {
+            dev_file
+                .seek(SeekFrom::Start(region.offset))
+                .upstream_with_context(&format!("Failed to seek on '{}'", device.display()))?;
+            let mut backup_file = File::create(&region.file)
+                .upstream_with_context(&format!("Failed to create '{}'", region.file.display()))?;
+            let copied = io::copy(&mut (&mut dev_file).take(region.size), &mut backup_file)
+                .upstream_with_context(&format!(
+                    "Failed to save {} at offset {} of '{}'",
+                    format_size_with_unit(region.size),
+                    region.offset,
+                    device.display()
+                ))?;
+            if copied != region.size {
+                return Err(Error::with_context(
+                    ErrorKind::InvState,
+                    &format!(
+                        "Saved only {} of {} bytes at offset {} of '{}'",
+                        copied,
+                        region.size,
+                        region.offset,
+                        device.display()
+                    ),
+                ));
+            }
+            debug!(
+                "DiskBackup: saved {} at offset {} to '{}'",
+                format_size_with_unit(region.size),
+                region.offset,
+                region.file.display()
+            );
+        }
+
+        info!(
+            "Saved partition table regions of '{}' to '{}'",
+            device.display(),
+            backup_dir.display()
+        );
+
+        Ok(DiskBackup {
+            device: device.to_path_buf(),
+            regions,
+        })
+    }
+
+    /// Number of bytes at the start of the device covered by the backup.
+    /// A write that failed below this offset can be undone by restore.
+    pub fn head_size(&self) -> u64 {
+        self.regions
+            .iter()
+            .find(|region| region.offset == 0)
+            .map_or(0, |region| region.size)
+    }
+
+    /// Write the saved regions back to the device
+    pub fn restore(&self) -> Result<()> {
+        let mut dev_file = OpenOptions::new()
+            .write(true)
+            .open(&self.device)
+            .upstream_with_context(&format!(
+                "Failed to open '{}' for writing",
+                self.device.display()
+            ))?;
+
+        for region in &self.regions {
+            let mut backup_file = File::open(&region.file)
+                .upstream_with_context(&format!("Failed to open '{}'", region.file.display()))?;
+            dev_file
+                .seek(SeekFrom::Start(region.offset))
+                .upstream_with_context(&format!("Failed to seek on '{}'", self.device.display()))?;
+            io::copy(&mut backup_file, &mut dev_file).upstream_with_context(&format!(
+                "Failed to restore '{}' to offset {} of '{}'",
+                region.file.display(),
+                region.offset,
+                self.device.display()
+            ))?;
+        }
+
+        dev_file
+            .flush()
+            .upstream_with_context(&format!("Failed to flush '{}'", self.device.display()))?;
+        dev_file
+            .sync_all()
+            .upstream_with_context(&format!("Failed to sync '{}'", self.device.display()))?;
+
+        info!(
+            "Restored partition table regions of '{}'",
+            self.device.display()
+        );
+        Ok(())
+    }
+}
+
+impl Drop for DiskBackup {
+    fn drop(&mut self) {
+        for region in &self.regions {
+            if let Err(why) = remove_file(&region.file) {
+                warn!(
+                    "Failed to remove '{}', error: {}",
+                    region.file.display(),
+                    why
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, read, remove_dir_all, write};
+
+    #[test]
+    fn restores_head_and_tail() {
+        let backup_dir = temp_dir().join("takeover-disk-backup");
+        create_dir_all(&backup_dir).unwrap();
+        let device = backup_dir.join("disk.img");
+
+        let size = (DISK_BACKUP_HEAD_SIZE + 3 * DISK_BACKUP_TAIL_SIZE) as usize;
+        let original: Vec<u8> = (0..size).map(|idx| (idx % 253) as u8).collect();
+        write(&device, &original).unwrap();
+
+        let backup = DiskBackup::create(&device, &backup_dir).unwrap();
+        assert_eq!(backup.head_size(), DISK_BACKUP_HEAD_SIZE);
+
+        // overwrite the start and the end of the disk
+        let mut corrupted = original.clone();
+        corrupted[..4096].fill(0);
+        corrupted[size - 512..].fill(0);
+        write(&device, &corrupted).unwrap();
+
+        backup.restore().unwrap();
+        assert_eq!(read(&device).unwrap(), original);
+
+        drop(backup);
+        assert!(!backup_dir.join("disk-backup-head.bin").exists());
+        remove_dir_all(&backup_dir).unwrap();
+    }
+}
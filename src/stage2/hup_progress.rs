@@ -0,0 +1,281 @@
+use std::io::{self, Read};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::common::{api_calls::notify_hup_progress, stage2_config::Stage2Config, Result};
+
+// minimum time between two updates that only change the percentage
+const HUP_PROGRESS_INTERVAL: Duration = Duration::from_secs(15);
+
+type Notifier = Box<dyn Fn(u8, &str) -> Result<()> + Send>;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Update {
+    pct: u8,
+    state: String,
+}
+
+#[derive(Default)]
+struct State {
+    /// the latest update not yet picked up by the worker, newer updates replace it
+    pending: Option<Update>,
+    stopped: bool,
+    last: Option<(Update, Instant)>,
+}
+
+impl State {
+    /// Decide whether an update is worth sending. A new state string is always
+    /// sent, percentage changes at most once per HUP_PROGRESS_INTERVAL.
+    fn accept(&self, update: &Update, now: Instant) -> bool {
+        match &self.last {
+            None => true,
+            Some((last, sent)) => {
+                if last.state != update.state {
+                    true
+                } else if last.pct == update.pct {
+                    false
+                } else {
+                    now.duration_since(*sent) >= HUP_PROGRESS_INTERVAL
+                }
+            }
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// Reports stage2 progress to the balena API (provisioning_progress and
+/// provisioning_state of the device) from a background thread, so a missing
+/// network never holds up the migration. Updates are rate limited and
+/// superseded by newer ones if they could not be sent in time.
+#[derive(Clone)]
+pub(crate) struct HupProgress {
+    shared: Option<Arc<Shared>>,
+}
+
+impl HupProgress {
+    pub fn new(s2_config: &Stage2Config) -> HupProgress {
+        if !s2_config.report_hup_progress {
+            return HupProgress { shared: None };
+        }
+
+        let api_endpoint = s2_config.api_endpoint.clone();
+        let api_key = s2_config.api_key.clone();
+        let uuid = s2_config.uuid.clone();
+        HupProgress::with_notifier(Box::new(move |pct, state| {
+            notify_hup_progress(&api_endpoint, &api_key, &uuid, &pct.to_string(), state)
+        }))
+    }
+
+    fn with_notifier(notifier: Notifier) -> HupProgress {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            cond: Condvar::new(),
+            worker: Mutex::new(None),
+        });
+
+        let worker_shared = shared.clone();
+        let handle = thread::spawn(move || run_worker(&worker_shared, notifier));
+        if let Ok(mut worker) = shared.worker.lock() {
+            *worker = Some(handle);
+        }
+
+        HupProgress {
+            shared: Some(shared),
+        }
+    }
+
+    /// Queue a progress update, never blocks on the network
+    pub fn update(&self, pct: u8, state: &str) {
+        let shared = if let Some(shared) = &self.shared {
+            shared
+        } else {
+            return;
+        };
+
+        let update = Update {
+            pct: pct.min(100),
+            state: state.to_string(),
+        };
+        let now = Instant::now();
+
+        if let Ok(mut guard) = shared.state.lock() {
+            if guard.stopped || !guard.accept(&update, now) {
+                return;
+            }
+            debug!("HupProgress: queueing {}% '{}'", update.pct, update.state);
+            guard.last = Some((update.clone(), now));
+            guard.pending = Some(update);
+            shared.cond.notify_one();
+        }
+    }
+
+    /// Stop the worker, dropping updates that have not been sent yet.
+    /// Waits for a request in flight so it can not overwrite the final
+    /// notification.
+    pub fn stop(&self) {
+        let shared = if let Some(shared) = &self.shared {
+            shared
+        } else {
+            return;
+        };
+
+        if let Ok(mut guard) = shared.state.lock() {
+            guard.stopped = true;
+            guard.pending = None;
+            shared.cond.notify_one();
+        }
+
+        let handle = shared
+            .worker
+            .lock()
+            .ok()
+            .and_then(|mut worker| worker.take());
+        if let Some(handle) = handle {
+            if handle.join().is_err() {
+                warn!("HupProgress: progress worker panicked");
+            }
+        }
+    }
+}
+
+fn run_worker(shared: &Shared, notifier: Notifier) {
+    loop {
+        let update = {
+            let mut guard = match shared.state.lock() {
+                Ok(guard) => guard,
+                Err(_) => return,
+            };
+            while guard.pending.is_none() && !guard.stopped {
+                guard = match shared.cond.wait(guard) {
+                    Ok(guard) => guard,
+                    Err(_) => return,
+                };
+            }
+            if guard.stopped {
+                return;
+            }
+            guard.pending.take()
+        };
+
+        if let Some(update) = update {
+            match notifier(update.pct, &update.state) {
+                Ok(_) => debug!("HupProgress: reported {}% '{}'", update.pct, update.state),
+                Err(why) => warn!(
+                    "HupProgress: failed to report {}% '{}', error: {}",
+                    update.pct, update.state, why
+                ),
+            }
+        }
+    }
+}
+
+/// Reader that reports the share of the input consumed as progress in the
+/// range start_pct to end_pct
+pub(crate) struct ProgressReader<R: Read> {
+    inner: R,
+    read: u64,
+    total: u64,
+    start_pct: u8,
+    end_pct: u8,
+    state: String,
+    progress: HupProgress,
+}
+
+impl<R: Read> ProgressReader<R> {
+    pub fn new(
+        inner: R,
+        total: u64,
+        (start_pct, end_pct): (u8, u8),
+        state: &str,
+        progress: &HupProgress,
+    ) -> ProgressReader<R> {
+        ProgressReader {
+            inner,
+            read: 0,
+            total,
+            start_pct,
+            end_pct,
+            state: state.to_string(),
+            progress: progress.clone(),
+        }
+    }
+
+    fn pct(&self) -> u8 {
+        let done = self.read.min(self.total);
+        let span = u64::from(self.end_pct.saturating_sub(self.start_pct));
+        self.start_pct + (span * done / self.total.max(1)) as u8
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.read += bytes_read as u64;
+        self.progress.update(self.pct(), &self.state);
+        Ok(bytes_read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(pct: u8, state: &str) -> Update {
+        Update {
+            pct,
+            state: state.to_string(),
+        }
+    }
+
+    #[test]
+    fn rate_limits_progress() {
+        let now = Instant::now();
+        let mut state = State::default();
+        assert!(state.accept(&update(10, "Flashing"), now));
+
+        state.last = Some((update(10, "Flashing"), now));
+        assert!(!state.accept(&update(10, "Flashing"), now));
+        assert!(!state.accept(&update(12, "Flashing"), now + Duration::from_secs(1)));
+        assert!(state.accept(&update(12, "Flashing"), now + HUP_PROGRESS_INTERVAL));
+        assert!(state.accept(&update(12, "Validating"), now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn reports_read_progress() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let notifier_sent = sent.clone();
+        let progress = HupProgress::with_notifier(Box::new(move |pct, state| {
+            notifier_sent.lock().unwrap().push(update(pct, state));
+            Ok(())
+        }));
+
+        let data = vec![0u8; 1000];
+        let mut reader =
+            ProgressReader::new(data.as_slice(), 1000, (20, 80), "Flashing", &progress);
+        let mut buffer = [0u8; 500];
+        reader.read_exact(&mut buffer).unwrap();
+        assert_eq!(reader.pct(), 50);
+        reader.read_exact(&mut buffer).unwrap();
+        assert_eq!(reader.pct(), 80);
+
+        // give the worker a chance to pick up the first update before stopping
+        let start = Instant::now();
+        while sent.lock().unwrap().is_empty() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        progress.stop();
+        progress.update(100, "Done");
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.first(), Some(&update(50, "Flashing")));
+        assert!(!sent.contains(&update(100, "Done")));
+    }
+}
@@ -0,0 +1,228 @@
+use std::fmt::Display;
+use std::fs::write;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info};
+use serde::Serialize;
+
+use crate::common::{
+    defs::MIGRATION_REPORT_NAME, device_type::DeviceType, path_append, stage2_config::Stage2Config,
+    Result, ToError,
+};
+
+/// The steps stage2 goes through, in the order they are executed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Phase {
+    KillProcs,
+    CopyFiles,
+    Unmount,
+    DiskBackup,
+    PreFlash,
+    Flash,
+    DiskRestore,
+    Validate,
+    BootFiles,
+    EfiSetup,
+    BootBlob,
+    BackupRestore,
+    BootOrder,
+    DisableOldBoot,
+    HupNotify,
+    ApiPatch,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct PhaseReport {
+    phase: Phase,
+    /// seconds since the unix epoch
+    started: u64,
+    duration_ms: u64,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Records what stage2 did, persisted as JSON on the data partition so it
+/// can be picked up once balenaOS is running
+#[derive(Debug, Serialize)]
+pub(crate) struct MigrationReport {
+    takeover_version: String,
+    source_os: String,
+    device_type: DeviceType,
+    flash_dev: PathBuf,
+    started: u64,
+    finished: Option<u64>,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    phases: Vec<PhaseReport>,
+    #[serde(skip)]
+    phase_start: Option<Instant>,
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+impl MigrationReport {
+    pub fn new(s2_config: &Stage2Config) -> MigrationReport {
+        MigrationReport {
+            takeover_version: env!("CARGO_PKG_VERSION").to_string(),
+            source_os: s2_config.source_os.clone(),
+            device_type: s2_config.device_type,
+            flash_dev: s2_config.flash_dev.clone(),
+            started: unix_time(),
+            finished: None,
+            success: false,
+            error: None,
+            phases: Vec::new(),
+            phase_start: None,
+        }
+    }
+
+    /// Start a new phase, it is recorded as failed until succeed is called
+    pub fn begin(&mut self, phase: Phase) {
+        debug!("MigrationReport: entering phase {:?}", phase);
+        self.phases.push(PhaseReport {
+            phase,
+            started: unix_time(),
+            duration_ms: 0,
+            success: false,
+            bytes: None,
+            error: None,
+        });
+        self.phase_start = Some(Instant::now());
+    }
+
+    pub fn succeed(&mut self, bytes: Option<u64>) {
+        if let Some(phase) = self.end_phase() {
+            phase.success = true;
+            phase.bytes = bytes;
+        }
+    }
+
+    pub fn fail(&mut self, error: &str) {
+        if let Some(phase) = self.end_phase() {
+            phase.success = false;
+            phase.error = Some(error.to_string());
+        }
+    }
+
+    /// Record the outcome of the current phase from a result
+    pub fn end<T, E: Display>(&mut self, res: &std::result::Result<T, E>) {
+        match res {
+            Ok(_) => self.succeed(None),
+            Err(why) => self.fail(&why.to_string()),
+        }
+    }
+
+    /// Run function as the given phase and record its outcome
+    pub fn record<T, F>(&mut self, phase: Phase, function: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        self.begin(phase);
+        let res = function();
+        self.end(&res);
+        res
+    }
+
+    /// Like record, for phases that return the number of bytes written
+    pub fn record_bytes<F>(&mut self, phase: Phase, function: F) -> Result<u64>
+    where
+        F: FnOnce() -> Result<u64>,
+    {
+        self.begin(phase);
+        let res = function();
+        match &res {
+            Ok(bytes) => self.succeed(Some(*bytes)),
+            Err(why) => self.fail(&why.to_string()),
+        }
+        res
+    }
+
+    fn end_phase(&mut self) -> Option<&mut PhaseReport> {
+        let duration_ms = self
+            .phase_start
+            .take()
+            .map(|start| start.elapsed().as_millis() as u64)
+            .unwrap_or(0);
+        let phase = self.phases.last_mut()?;
+        phase.duration_ms = duration_ms;
+        Some(phase)
+    }
+
+    /// Close the report, error is None if the migration succeeded
+    pub fn finish(&mut self, error: Option<&str>) {
+        if self.phase_start.is_some() {
+            self.fail("Phase did not complete");
+        }
+        self.finished = Some(unix_time());
+        self.success = error.is_none();
+        self.error = error.map(|error| error.to_string());
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).upstream_with_context("Failed to serialize report")
+    }
+
+    /// Write the report to MIGRATION_REPORT_NAME in dest_dir
+    pub fn write<P: AsRef<Path>>(&self, dest_dir: P) -> Result<()> {
+        let report_path = path_append(dest_dir, MIGRATION_REPORT_NAME);
+        write(&report_path, self.to_json()?).upstream_with_context(&format!(
+            "Failed to write migration report to '{}'",
+            report_path.display()
+        ))?;
+        info!("Wrote migration report to '{}'", report_path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{Error, ErrorKind};
+    use serde_json::Value;
+
+    #[test]
+    fn report_records_phases() {
+        let s2_config = Stage2Config {
+            source_os: "Ubuntu 22.04".to_string(),
+            ..Stage2Config::for_tests()
+        };
+
+        let mut report = MigrationReport::new(&s2_config);
+        report.begin(Phase::Flash);
+        report.succeed(Some(4096));
+        let res: Result<()> = report.record(Phase::EfiSetup, || {
+            Err(Error::with_context(ErrorKind::InvState, "no efi"))
+        });
+        assert!(res.is_err());
+        report.begin(Phase::BackupRestore);
+        report.finish(Some("Migration aborted"));
+
+        let report: Value = serde_json::from_str(&report.to_json().unwrap()).unwrap();
+        assert_eq!(report["source_os"], "Ubuntu 22.04");
+        assert_eq!(report["takeover_version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(report["success"], false);
+        assert_eq!(report["error"], "Migration aborted");
+
+        let phases = report["phases"].as_array().unwrap();
+        assert_eq!(phases.len(), 3);
+        assert_eq!(phases[0]["phase"], "flash");
+        assert_eq!(phases[0]["success"], true);
+        assert_eq!(phases[0]["bytes"], 4096);
+        assert_eq!(phases[1]["phase"], "efi_setup");
+        assert_eq!(phases[1]["success"], false);
+        assert!(phases[1]["error"].as_str().unwrap().contains("no efi"));
+        assert_eq!(phases[2]["phase"], "backup_restore");
+        assert_eq!(phases[2]["error"], "Phase did not complete");
+    }
+}
@@ -0,0 +1,262 @@
+use std::fs::{read_to_string, write};
+use std::os::unix::fs::chroot;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libc::{getpid, signal, SIGKILL, SIGRTMAX, SIGSTOP, SIG_DFL};
+use log::{error, info, warn};
+use mod_logger::Logger;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use serde::Serialize;
+
+use crate::common::{
+    defs::{NIX_NONE, RECOVERY_MARKER_NAME},
+    path_append, path_to_cstring, reboot,
+    stage2_config::{Stage2Config, UmountPart},
+    Error, ErrorKind, Result, ToError,
+};
+
+/// Exit code used by the stage2 worker to tell init that the original system
+/// was restored and the original init should be started
+pub(crate) const RECOVERY_EXIT_CODE: i32 = 75;
+
+#[derive(Debug, Serialize)]
+struct RecoveryStep {
+    step: String,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Written to the work directory so it is obvious why the device is still
+/// running the original OS
+#[derive(Debug, Serialize)]
+struct RecoveryMarker {
+    /// seconds since the unix epoch
+    time: u64,
+    reason: String,
+    steps: Vec<RecoveryStep>,
+}
+
+impl RecoveryMarker {
+    fn record(&mut self, step: &str, res: Result<()>) {
+        match res {
+            Ok(_) => {
+                info!("recovery: {} succeeded", step);
+                self.steps.push(RecoveryStep {
+                    step: step.to_string(),
+                    success: true,
+                    error: None,
+                });
+            }
+            Err(why) => {
+                error!("recovery: {} failed, error: {}", step, why);
+                self.steps.push(RecoveryStep {
+                    step: step.to_string(),
+                    success: false,
+                    error: Some(why.to_string()),
+                });
+            }
+        }
+    }
+}
+
+/// Undo the changes made to the original system before anything was flashed.
+/// old_root is where the original root file system is found, '/' before
+/// pivot_root, OLD_ROOT_MP after.
+/// Every step is attempted even if previous ones failed.
+pub(crate) fn recover(s2_config: &Stage2Config, old_root: &str, reason: &str) {
+    warn!("Restoring the original system, reason: {}", reason);
+
+    let mut marker = RecoveryMarker {
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0),
+        reason: reason.to_string(),
+        steps: Vec::new(),
+    };
+
+    // takeover was bind-mounted over the original init in stage1, init is
+    // still executing it so it can only be detached
+    let init_path = path_append(old_root, &s2_config.old_init_path);
+    marker.record(
+        "unmount_init",
+        umount2(&init_path, MntFlags::MNT_DETACH)
+            .upstream_with_context(&format!("Failed to unmount '{}'", init_path.display())),
+    );
+
+    // partitions were unmounted in order, restore them the other way round
+    for part in s2_config.umount_parts.iter().rev() {
+        marker.record(
+            &format!("remount '{}'", part.mountpoint.display()),
+            remount_rw(part, old_root),
+        );
+    }
+
+    for swap_device in &s2_config.swap_devices {
+        marker.record(
+            &format!("swapon '{}'", swap_device.display()),
+            swapon(&path_append(old_root, swap_device)),
+        );
+    }
+
+    let marker_path = path_append(
+        path_append(old_root, &s2_config.work_dir),
+        RECOVERY_MARKER_NAME,
+    );
+    match serde_json::to_string_pretty(&marker) {
+        Ok(content) => {
+            if let Err(why) = write(&marker_path, content) {
+                error!(
+                    "Failed to write recovery marker to '{}', error: {}",
+                    marker_path.display(),
+                    why
+                );
+            } else {
+                info!("Wrote recovery marker to '{}'", marker_path.display());
+            }
+        }
+        Err(why) => error!("Failed to serialize recovery marker, error: {}", why),
+    }
+}
+
+/// Replace the current process with the original init, making the original
+/// OS start its services again. Only possible in PID 1, reboots otherwise or
+/// if init can not be started. The caller has to unblock the signals it
+/// blocked, the signal mask is inherited by the original init.
+pub(crate) fn restart_old_init(s2_config: &Stage2Config, old_root: &str) -> ! {
+    if unsafe { getpid() } != 1 {
+        warn!("Not running as PID 1, rebooting to restart the original OS");
+        reboot();
+    }
+
+    if old_root != "/" {
+        // the equivalent of switch_root, move the original root back to '/'
+        let res = std::env::set_current_dir(old_root)
+            .upstream_with_context(&format!("Failed to change directory to '{}'", old_root))
+            .and_then(|_| {
+                mount(Some("."), "/", NIX_NONE, MsFlags::MS_MOVE, NIX_NONE)
+                    .upstream_with_context(&format!("Failed to move '{}' to '/'", old_root))
+            })
+            .and_then(|_| chroot(".").upstream_with_context("Failed to chroot to '.'"))
+            .and_then(|_| {
+                std::env::set_current_dir("/")
+                    .upstream_with_context("Failed to change directory to '/'")
+            });
+
+        if let Err(why) = res {
+            error!("Failed to restore the original root, error: {}", why);
+            reboot();
+        }
+    }
+
+    // ignored signals stay ignored across exec, the original init expects the
+    // defaults
+    reset_signal_handlers();
+
+    info!(
+        "Starting the original init '{}'",
+        s2_config.old_init_path.display()
+    );
+    Logger::flush();
+    let why = Command::new(&s2_config.old_init_path).exec();
+    error!(
+        "Failed to start '{}', error: {}",
+        s2_config.old_init_path.display(),
+        why
+    );
+    reboot();
+}
+
+fn reset_signal_handlers() {
+    for sig in 1..=SIGRTMAX() {
+        if sig != SIGKILL && sig != SIGSTOP {
+            unsafe { signal(sig, SIG_DFL) };
+        }
+    }
+}
+
+/// Make sure the partition is mounted read-write again, it was either
+/// unmounted or remounted read-only
+fn remount_rw(part: &UmountPart, old_root: &str) -> Result<()> {
+    let mountpoint = path_append(old_root, &part.mountpoint);
+    let mounts =
+        read_to_string("/proc/mounts").upstream_with_context("Failed to read '/proc/mounts'")?;
+
+    let flags = match is_read_only(&mounts, &mountpoint) {
+        Some(false) => return Ok(()),
+        Some(true) => MsFlags::MS_REMOUNT,
+        None => MsFlags::empty(),
+    };
+
+    mount(
+        Some(part.dev_name.as_path()),
+        &mountpoint,
+        Some(part.fs_type.as_str()),
+        flags,
+        NIX_NONE,
+    )
+    .upstream_with_context(&format!(
+        "Failed to mount '{}' on '{}' read-write",
+        part.dev_name.display(),
+        mountpoint.display()
+    ))
+}
+
+/// Look up mountpoint in the contents of /proc/mounts, returns None if it
+/// is not mounted, otherwise whether it is mounted read-only
+fn is_read_only(mounts: &str, mountpoint: &Path) -> Option<bool> {
+    // later entries hide earlier ones on the same mountpoint
+    mounts.lines().rev().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() > 3 && Path::new(fields[1]) == mountpoint {
+            Some(fields[3].split(',').any(|option| option == "ro"))
+        } else {
+            None
+        }
+    })
+}
+
+fn swapon(path: &Path) -> Result<()> {
+    let c_path = path_to_cstring(path)?;
+    if unsafe { libc::swapon(c_path.as_ptr(), 0) } == 0 {
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::Upstream,
+            &format!(
+                "Failed to enable swap on '{}', error: {}",
+                path.display(),
+                std::io::Error::last_os_error()
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTS: &str = "/dev/sda2 /mnt/old_root ext4 ro,relatime 0 0\n\
+        proc /mnt/old_root/proc proc rw,nosuid,nodev,noexec,relatime 0 0\n\
+        /dev/sda1 /mnt/old_root/boot/efi vfat rw,relatime,fmask=0077 0 0\n\
+        /dev/sda3 /mnt/old_root/data ext4 rw,relatime 0 0\n\
+        /dev/sda3 /mnt/old_root/data ext4 ro,relatime 0 0\n";
+
+    #[test]
+    fn finds_read_only_mounts() {
+        assert_eq!(is_read_only(MOUNTS, Path::new("/mnt/old_root")), Some(true));
+        assert_eq!(
+            is_read_only(MOUNTS, Path::new("/mnt/old_root/boot/efi")),
+            Some(false)
+        );
+        assert_eq!(
+            is_read_only(MOUNTS, Path::new("/mnt/old_root/data")),
+            Some(true)
+        );
+        assert_eq!(is_read_only(MOUNTS, Path::new("/mnt/old_root/home")), None);
+    }
+}